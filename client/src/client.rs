@@ -0,0 +1,741 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use reqwest::{Method, RequestBuilder, Response, StatusCode, header};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use scim2_rs::CreateGroupRequest;
+use scim2_rs::CreateUserRequest;
+use scim2_rs::Filter;
+use scim2_rs::Group;
+use scim2_rs::ListResponse;
+use scim2_rs::PatchRequest;
+use scim2_rs::QueryParams;
+use scim2_rs::Resource;
+use scim2_rs::SingleResourceResponse;
+use scim2_rs::StoredParts;
+use scim2_rs::TypedListResponse;
+use scim2_rs::User;
+
+use crate::ClientError;
+
+const CONTENT_TYPE_SCIM_JSON: &str = "application/scim+json";
+
+/// What has to be escaped in a path segment (RFC 3986, section 3.3)
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+const DEFAULT_PAGE_SIZE: usize = 100;
+
+/// A client for the Users and Groups of a SCIM service provider, at a base
+/// URL like `https://example.com/scim/v2`
+///
+/// Resources come back as `StoredParts`, whose `meta.version` can be given
+/// as `if_match` to the methods that change a resource, so that they fail
+/// with `ClientError::is_precondition_failed` if it changed in the meantime.
+#[derive(Clone)]
+pub struct ScimClient {
+    base_url: String,
+    client: reqwest::Client,
+    bearer_token: Option<String>,
+    page_size: usize,
+}
+
+/// One page of a list of resources (RFC 7644, section 3.4.2.4)
+#[derive(Debug, Clone)]
+pub struct Page<R: Resource> {
+    /// How many resources match, over all pages
+    pub total_results: usize,
+
+    /// The 1-based index of the first resource of this page
+    pub start_index: usize,
+
+    pub resources: Vec<StoredParts<R>>,
+}
+
+impl ScimClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            bearer_token: None,
+            page_size: DEFAULT_PAGE_SIZE,
+        }
+    }
+
+    pub fn with_bearer_token(mut self, token: impl Into<String>) -> Self {
+        self.bearer_token = Some(token.into());
+        self
+    }
+
+    /// Send requests with `client`, for example one with timeouts or a proxy
+    pub fn with_http_client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// How many resources to ask for at a time when listing all of them
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        self.page_size = page_size.max(1);
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub async fn get_user(
+        &self,
+        id: &str,
+    ) -> Result<StoredParts<User>, ClientError> {
+        self.get(id).await
+    }
+
+    pub async fn create_user(
+        &self,
+        request: &CreateUserRequest,
+    ) -> Result<StoredParts<User>, ClientError> {
+        self.create(request).await
+    }
+
+    pub async fn replace_user(
+        &self,
+        id: &str,
+        request: &CreateUserRequest,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<User>, ClientError> {
+        self.replace(id, request, if_match).await
+    }
+
+    pub async fn patch_user(
+        &self,
+        id: &str,
+        request: &PatchRequest,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<User>, ClientError> {
+        self.patch(id, request, if_match).await
+    }
+
+    pub async fn delete_user(
+        &self,
+        id: &str,
+        if_match: Option<&str>,
+    ) -> Result<(), ClientError> {
+        self.delete::<User>(id, if_match).await
+    }
+
+    /// One page of the users matching `query`
+    pub async fn list_users(
+        &self,
+        query: &QueryParams,
+    ) -> Result<Page<User>, ClientError> {
+        self.list(query).await
+    }
+
+    /// Every user matching `filter`, a page at a time
+    pub async fn list_all_users(
+        &self,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<StoredParts<User>>, ClientError> {
+        self.list_all(filter).await
+    }
+
+    pub async fn get_group(
+        &self,
+        id: &str,
+    ) -> Result<StoredParts<Group>, ClientError> {
+        self.get(id).await
+    }
+
+    pub async fn create_group(
+        &self,
+        request: &CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ClientError> {
+        self.create(request).await
+    }
+
+    pub async fn replace_group(
+        &self,
+        id: &str,
+        request: &CreateGroupRequest,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<Group>, ClientError> {
+        self.replace(id, request, if_match).await
+    }
+
+    pub async fn patch_group(
+        &self,
+        id: &str,
+        request: &PatchRequest,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<Group>, ClientError> {
+        self.patch(id, request, if_match).await
+    }
+
+    pub async fn delete_group(
+        &self,
+        id: &str,
+        if_match: Option<&str>,
+    ) -> Result<(), ClientError> {
+        self.delete::<Group>(id, if_match).await
+    }
+
+    /// One page of the groups matching `query`
+    pub async fn list_groups(
+        &self,
+        query: &QueryParams,
+    ) -> Result<Page<Group>, ClientError> {
+        self.list(query).await
+    }
+
+    /// Every group matching `filter`, a page at a time
+    pub async fn list_all_groups(
+        &self,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<StoredParts<Group>>, ClientError> {
+        self.list_all(filter).await
+    }
+
+    fn collection_url<R: Resource>(&self) -> String {
+        format!("{}/{}s", self.base_url, R::resource_type())
+    }
+
+    fn resource_url<R: Resource>(&self, id: &str) -> String {
+        format!(
+            "{}/{}",
+            self.collection_url::<R>(),
+            utf8_percent_encode(id, PATH_SEGMENT)
+        )
+    }
+
+    fn request(&self, method: Method, url: &str) -> RequestBuilder {
+        let request = self
+            .client
+            .request(method, url)
+            .header(header::ACCEPT, CONTENT_TYPE_SCIM_JSON);
+
+        match &self.bearer_token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    /// A request that changes the resource at `url`, with `body` if there is
+    /// one
+    fn write_request<T: Serialize>(
+        &self,
+        method: Method,
+        url: &str,
+        body: Option<&T>,
+        if_match: Option<&str>,
+    ) -> RequestBuilder {
+        let mut request = self.request(method, url);
+
+        if let Some(body) = body {
+            // RFC 7644, section 3.1: the media type is application/scim+json
+            request = request
+                .header(header::CONTENT_TYPE, CONTENT_TYPE_SCIM_JSON)
+                .json(body);
+        }
+
+        // RFC 7644, section 3.14: the version of the resource is its ETag
+        if let Some(version) = if_match {
+            request = request.header(header::IF_MATCH, version);
+        }
+
+        request
+    }
+
+    async fn get<R>(&self, id: &str) -> Result<StoredParts<R>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+    {
+        let request = self.request(Method::GET, &self.resource_url::<R>(id));
+        resource(send(request).await?).await
+    }
+
+    async fn create<R, T>(
+        &self,
+        body: &T,
+    ) -> Result<StoredParts<R>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+        T: Serialize,
+    {
+        let request = self.write_request(
+            Method::POST,
+            &self.collection_url::<R>(),
+            Some(body),
+            None,
+        );
+        resource(send(request).await?).await
+    }
+
+    async fn replace<R, T>(
+        &self,
+        id: &str,
+        body: &T,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<R>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+        T: Serialize,
+    {
+        let request = self.write_request(
+            Method::PUT,
+            &self.resource_url::<R>(id),
+            Some(body),
+            if_match,
+        );
+        resource(send(request).await?).await
+    }
+
+    async fn patch<R>(
+        &self,
+        id: &str,
+        body: &PatchRequest,
+        if_match: Option<&str>,
+    ) -> Result<StoredParts<R>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+    {
+        let request = self.write_request(
+            Method::PATCH,
+            &self.resource_url::<R>(id),
+            Some(body),
+            if_match,
+        );
+        let response = send(request).await?;
+
+        // RFC 7644, section 3.5.2: the service provider may leave out the
+        // resource, in which case it has to be read back
+        if response.status() == StatusCode::NO_CONTENT {
+            return self.get(id).await;
+        }
+
+        resource(response).await
+    }
+
+    async fn delete<R: Resource>(
+        &self,
+        id: &str,
+        if_match: Option<&str>,
+    ) -> Result<(), ClientError> {
+        let request = self.write_request::<()>(
+            Method::DELETE,
+            &self.resource_url::<R>(id),
+            None,
+            if_match,
+        );
+        send(request).await?;
+        Ok(())
+    }
+
+    async fn list<R>(&self, query: &QueryParams) -> Result<Page<R>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+    {
+        let request =
+            self.request(Method::GET, &self.collection_url::<R>()).query(query);
+        let response: ListResponse = json(send(request).await?).await?;
+        let response = TypedListResponse::<R>::try_from(response)?;
+
+        Ok(Page {
+            total_results: response.total_results,
+            start_index: response
+                .start_index
+                .unwrap_or(query.pagination().start_index),
+            resources: response.resources,
+        })
+    }
+
+    async fn list_all<R>(
+        &self,
+        filter: Option<&Filter>,
+    ) -> Result<Vec<StoredParts<R>>, ClientError>
+    where
+        R: Resource + DeserializeOwned,
+    {
+        let mut resources = Vec::new();
+
+        loop {
+            let query = QueryParams {
+                filter: filter.map(Filter::to_string),
+                start_index: Some(resources.len() + 1),
+                count: Some(self.page_size),
+            };
+            let page = self.list::<R>(&query).await?;
+
+            // RFC 7644, section 3.4.2.4: a page may hold fewer resources than
+            // were asked for, so only stop once there are none left
+            let done = page.resources.is_empty()
+                || resources.len() + page.resources.len() >= page.total_results;
+            resources.extend(page.resources);
+
+            if done {
+                return Ok(resources);
+            }
+        }
+    }
+}
+
+/// Send `request`, turning an error status into a `ClientError`
+async fn send(request: RequestBuilder) -> Result<Response, ClientError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let body = response.text().await?;
+    match serde_json::from_str::<scim2_rs::Error>(&body) {
+        Ok(error) => Err(ClientError::Scim(error)),
+        Err(_) => Err(ClientError::Status { status, body }),
+    }
+}
+
+async fn json<T: DeserializeOwned>(
+    response: Response,
+) -> Result<T, ClientError> {
+    let body = response.bytes().await?;
+    serde_json::from_slice(&body).map_err(|error| {
+        ClientError::InvalidResponse(format!("invalid response body: {error}"))
+    })
+}
+
+async fn resource<R>(response: Response) -> Result<StoredParts<R>, ClientError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let response: SingleResourceResponse = json(response).await?;
+    Ok(response.try_into()?)
+}
+
+#[cfg(test)]
+mod test {
+    use scim2_rs::ErrorType;
+    use scim2_test_provider_server::ServerConfig;
+
+    use super::*;
+
+    fn setup(config: ServerConfig) -> ScimClient {
+        let server =
+            scim2_test_provider_server::create_http_server(None, config)
+                .unwrap();
+        let base_url = format!("http://{}/v2", server.local_addr());
+        tokio::spawn(server);
+
+        ScimClient::new(base_url)
+    }
+
+    fn create_user_request(name: &str) -> CreateUserRequest {
+        CreateUserRequest {
+            name: name.to_string(),
+            active: Some(true),
+            external_id: Some(format!("{name}@dundermifflin.com")),
+            groups: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        let client = setup(ServerConfig::default());
+
+        let jim =
+            client.create_user(&create_user_request("jhalpert")).await.unwrap();
+        assert_eq!(jim.resource.name, "jhalpert");
+
+        let error = client
+            .create_user(&create_user_request("jhalpert"))
+            .await
+            .unwrap_err();
+        assert!(error.is_conflict(), "{error}");
+        assert_eq!(error.error_type(), Some(&ErrorType::Uniqueness));
+
+        let fetched = client.get_user(&jim.resource.id).await.unwrap();
+        assert_eq!(fetched.resource, jim.resource);
+
+        let mut request = create_user_request("jhalpert");
+        request.external_id = Some("jim@athlead.com".to_string());
+        let replaced = client
+            .replace_user(&jim.resource.id, &request, Some(&jim.meta.version))
+            .await
+            .unwrap();
+        assert_eq!(
+            replaced.resource.external_id.as_deref(),
+            Some("jim@athlead.com")
+        );
+
+        let patched = client
+            .patch_user(
+                &jim.resource.id,
+                &PatchRequest::new()
+                    .replace_attributes(serde_json::json!({ "active": false })),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(patched.resource.active, Some(false));
+
+        for name in ["dschrute", "pbeesly"] {
+            client.create_user(&create_user_request(name)).await.unwrap();
+        }
+
+        // Listing everything has to follow the pages
+        let users = client
+            .clone()
+            .with_page_size(1)
+            .list_all_users(None)
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 3);
+
+        let page = client
+            .list_users(&QueryParams {
+                start_index: Some(2),
+                count: Some(1),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(page.total_results, 3);
+        assert_eq!(page.start_index, 2);
+        assert_eq!(page.resources.len(), 1);
+
+        let users = client
+            .list_all_users(Some(&Filter::attr("userName").eq("dschrute")))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].resource.name, "dschrute");
+
+        // Values are escaped in the filter, and found as they were written
+        let name = r#"c"bratton\"#;
+        let creed =
+            client.create_user(&create_user_request(name)).await.unwrap();
+        let users = client
+            .list_all_users(Some(&Filter::attr("userName").eq(name)))
+            .await
+            .unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].resource.id, creed.resource.id);
+        client.delete_user(&creed.resource.id, None).await.unwrap();
+
+        client.delete_user(&jim.resource.id, None).await.unwrap();
+        let error = client.get_user(&jim.resource.id).await.unwrap_err();
+        assert!(error.is_not_found(), "{error}");
+        assert!(matches!(error, ClientError::Scim(_)));
+
+        // An id can't escape its resource's URL
+        let error = client.get_user("../Groups").await.unwrap_err();
+        assert!(error.is_not_found(), "{error}");
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let client = setup(ServerConfig::default());
+
+        let jim =
+            client.create_user(&create_user_request("jhalpert")).await.unwrap();
+        let dwight =
+            client.create_user(&create_user_request("dschrute")).await.unwrap();
+
+        let sales = client
+            .create_group(&CreateGroupRequest {
+                display_name: "Sales".to_string(),
+                external_id: None,
+                members: None,
+            })
+            .await
+            .unwrap();
+
+        let sales = client
+            .patch_group(
+                &sales.resource.id,
+                &PatchRequest::new()
+                    .add_members([&jim.resource.id, &dwight.resource.id]),
+                Some(&sales.meta.version),
+            )
+            .await
+            .unwrap();
+        assert_eq!(sales.resource.members.as_ref().unwrap().len(), 2);
+
+        let sales = client
+            .patch_group(
+                &sales.resource.id,
+                &PatchRequest::new().remove_member(&dwight.resource.id),
+                None,
+            )
+            .await
+            .unwrap();
+        let members = sales.resource.members.as_ref().unwrap();
+        assert_eq!(members.len(), 1);
+        assert!(
+            members
+                .iter()
+                .all(|m| m.value.as_deref() == Some(&jim.resource.id))
+        );
+
+        let groups = client.list_all_groups(None).await.unwrap();
+        assert_eq!(groups.len(), 1);
+
+        client.delete_group(&sales.resource.id, None).await.unwrap();
+        let error = client.get_group(&sales.resource.id).await.unwrap_err();
+        assert!(error.is_not_found(), "{error}");
+    }
+
+    /// A service provider whose one user, `jhalpert`, has always changed since
+    /// the version a request names
+    #[derive(Default)]
+    struct ChangedProvider {
+        if_match: std::sync::Mutex<Vec<Option<String>>>,
+    }
+
+    async fn precondition_failed(
+        rqctx: dropshot::RequestContext<std::sync::Arc<ChangedProvider>>,
+    ) -> Result<http::Response<dropshot::Body>, dropshot::HttpError> {
+        let if_match = rqctx
+            .request
+            .headers()
+            .get(header::IF_MATCH)
+            .map(|value| value.to_str().unwrap().to_string());
+        rqctx.context().if_match.lock().unwrap().push(if_match);
+
+        let error = scim2_rs::Error {
+            schemas: vec![
+                "urn:ietf:params:scim:api:messages:2.0:Error".to_string(),
+            ],
+            status: StatusCode::PRECONDITION_FAILED,
+            error_type: None,
+            detail: "Resource jhalpert changed".to_string(),
+        };
+        error.to_http_response().map_err(|error| {
+            dropshot::HttpError::for_internal_error(error.to_string())
+        })
+    }
+
+    #[dropshot::endpoint {
+        method = PUT,
+        path = "/v2/Users/jhalpert"
+    }]
+    async fn put_changed_user(
+        rqctx: dropshot::RequestContext<std::sync::Arc<ChangedProvider>>,
+    ) -> Result<http::Response<dropshot::Body>, dropshot::HttpError> {
+        precondition_failed(rqctx).await
+    }
+
+    #[dropshot::endpoint {
+        method = PATCH,
+        path = "/v2/Users/jhalpert"
+    }]
+    async fn patch_changed_user(
+        rqctx: dropshot::RequestContext<std::sync::Arc<ChangedProvider>>,
+    ) -> Result<http::Response<dropshot::Body>, dropshot::HttpError> {
+        precondition_failed(rqctx).await
+    }
+
+    #[dropshot::endpoint {
+        method = DELETE,
+        path = "/v2/Users/jhalpert"
+    }]
+    async fn delete_changed_user(
+        rqctx: dropshot::RequestContext<std::sync::Arc<ChangedProvider>>,
+    ) -> Result<http::Response<dropshot::Body>, dropshot::HttpError> {
+        precondition_failed(rqctx).await
+    }
+
+    #[tokio::test]
+    async fn test_if_match() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut api = dropshot::ApiDescription::new();
+        api.register(put_changed_user).unwrap();
+        api.register(patch_changed_user).unwrap();
+        api.register(delete_changed_user).unwrap();
+
+        let provider = std::sync::Arc::new(ChangedProvider::default());
+        let config = dropshot::ConfigDropshot {
+            bind_address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+        let server = dropshot::HttpServerStarter::new(
+            &config,
+            api,
+            std::sync::Arc::clone(&provider),
+            &log,
+        )
+        .unwrap()
+        .start();
+        let client =
+            ScimClient::new(format!("http://{}/v2", server.local_addr()));
+
+        let version = r#"W/"1""#;
+        let errors = [
+            client
+                .replace_user(
+                    "jhalpert",
+                    &create_user_request("jhalpert"),
+                    Some(version),
+                )
+                .await
+                .unwrap_err(),
+            client
+                .patch_user(
+                    "jhalpert",
+                    &PatchRequest::new().replace_attributes(
+                        serde_json::json!({ "active": false }),
+                    ),
+                    Some(version),
+                )
+                .await
+                .unwrap_err(),
+            client.delete_user("jhalpert", Some(version)).await.unwrap_err(),
+            client.delete_user("jhalpert", None).await.unwrap_err(),
+        ];
+        for error in errors {
+            assert!(error.is_precondition_failed(), "{error}");
+            assert!(matches!(error, ClientError::Scim(_)));
+        }
+
+        // The version is only sent when there is one
+        assert_eq!(
+            *provider.if_match.lock().unwrap(),
+            [
+                Some(version.to_string()),
+                Some(version.to_string()),
+                Some(version.to_string()),
+                None,
+            ]
+        );
+
+        server.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token() {
+        let client = setup(ServerConfig {
+            bearer_tokens: vec!["secret".to_string()],
+            ..Default::default()
+        });
+
+        let error =
+            client.list_users(&QueryParams::default()).await.unwrap_err();
+        assert!(error.is_unauthorized(), "{error}");
+
+        let client = client.with_bearer_token("secret");
+        let page = client.list_users(&QueryParams::default()).await.unwrap();
+        assert_eq!(page.total_results, 0);
+    }
+}
@@ -0,0 +1,114 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use reqwest::StatusCode;
+use scim2_rs::ErrorType;
+use scim2_rs::ResourceConversionError;
+
+/// Why a request to a service provider failed
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be sent, or its response could not be read
+    Request(reqwest::Error),
+
+    /// The service provider answered with a SCIM error (RFC 7644, section
+    /// 3.12)
+    Scim(scim2_rs::Error),
+
+    /// The service provider answered with an error status, but not with a
+    /// SCIM error
+    Status { status: StatusCode, body: String },
+
+    /// The service provider answered with something other than what SCIM
+    /// says it should have
+    InvalidResponse(String),
+}
+
+impl ClientError {
+    /// The status the service provider answered with, if it answered
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            ClientError::Request(error) => error.status(),
+            ClientError::Scim(error) => Some(error.status()),
+            ClientError::Status { status, .. } => Some(*status),
+            ClientError::InvalidResponse(_) => None,
+        }
+    }
+
+    /// The `scimType` of a SCIM error, which says more about a 400 or 409
+    pub fn error_type(&self) -> Option<&ErrorType> {
+        match self {
+            ClientError::Scim(error) => error.error_type.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn is_unauthorized(&self) -> bool {
+        self.status() == Some(StatusCode::UNAUTHORIZED)
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        self.status() == Some(StatusCode::NOT_FOUND)
+    }
+
+    /// A resource with the same unique attributes, like `userName`, exists
+    /// already
+    pub fn is_conflict(&self) -> bool {
+        self.status() == Some(StatusCode::CONFLICT)
+    }
+
+    /// The resource changed since the version given to `If-Match` (RFC 7644,
+    /// section 3.14)
+    pub fn is_precondition_failed(&self) -> bool {
+        self.status() == Some(StatusCode::PRECONDITION_FAILED)
+    }
+}
+
+impl std::fmt::Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Request(error) => write!(f, "request failed: {error}"),
+
+            ClientError::Scim(error) => match &error.error_type {
+                Some(error_type) => write!(
+                    f,
+                    "SCIM error {} ({error_type:?}): {}",
+                    error.status, error.detail
+                ),
+                None => {
+                    write!(f, "SCIM error {}: {}", error.status, error.detail)
+                }
+            },
+
+            ClientError::Status { status, body } => {
+                write!(f, "service provider returned {status}: {body}")
+            }
+
+            ClientError::InvalidResponse(detail) => {
+                write!(f, "invalid response: {detail}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Request(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(error: reqwest::Error) -> Self {
+        ClientError::Request(error)
+    }
+}
+
+impl From<ResourceConversionError> for ClientError {
+    fn from(error: ResourceConversionError) -> Self {
+        ClientError::InvalidResponse(error.to_string())
+    }
+}
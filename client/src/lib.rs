@@ -0,0 +1,42 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A client for SCIM 2 service providers (RFC 7644), for pushing users and
+//! groups into other applications.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), scim2_client::ClientError> {
+//! use scim2_client::ScimClient;
+//! use scim2_rs::{CreateUserRequest, PatchRequest};
+//!
+//! let client = ScimClient::new("https://scim.example.com/v2")
+//!     .with_bearer_token("secret");
+//!
+//! let user = client
+//!     .create_user(&CreateUserRequest {
+//!         name: "jhalpert".to_string(),
+//!         active: Some(true),
+//!         external_id: None,
+//!         groups: None,
+//!     })
+//!     .await?;
+//!
+//! client
+//!     .patch_user(
+//!         &user.resource.id,
+//!         &PatchRequest::new()
+//!             .replace_attributes(serde_json::json!({ "active": false })),
+//!         Some(&user.meta.version),
+//!     )
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+
+mod client;
+mod error;
+
+pub use client::Page;
+pub use client::ScimClient;
+pub use error::ClientError;
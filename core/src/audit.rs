@@ -0,0 +1,404 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::io::AsyncWriteExt;
+use uuid::Uuid;
+
+use crate::Resource;
+use crate::response::serialize_resource_to_object;
+
+/// Who is asking a `Provider` to do something, and as part of which request
+#[derive(Debug, Clone, Default)]
+pub struct OperationContext {
+    /// The authenticated client identity
+    pub actor: Option<String>,
+
+    pub request_id: Option<String>,
+
+    /// The request's `If-Match` header: the versions of the resource a write
+    /// can go ahead on
+    pub if_match: Option<String>,
+}
+
+impl OperationContext {
+    /// This context, with the `If-Match` header from the request's `headers`
+    pub(crate) fn with_if_match(mut self, headers: &http::HeaderMap) -> Self {
+        self.if_match = headers.get(http::header::IF_MATCH).map(|value| {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        });
+        self
+    }
+}
+
+/// What was done
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    CreateUser,
+    ReplaceUser,
+    PatchUser,
+    DeleteUser,
+    RestoreUser,
+    CreateGroup,
+    ReplaceGroup,
+    PatchGroup,
+    DeleteGroup,
+    RestoreGroup,
+}
+
+/// How one attribute changed. `None` means the attribute was not set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AttributeChange {
+    pub before: Option<Value>,
+    pub after: Option<Value>,
+}
+
+/// One operation on a `Provider`, whether it succeeded or not
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct AuditRecord {
+    pub id: String,
+
+    pub timestamp: DateTime<Utc>,
+
+    pub actor: Option<String>,
+
+    pub request_id: Option<String>,
+
+    /// The tenant operated on, if the provider serves one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    pub operation: AuditOperation,
+
+    /// The resource operated on, once it is known: a create that fails has
+    /// none
+    pub resource_id: Option<String>,
+
+    /// The HTTP status of the response
+    pub status: u16,
+
+    /// The error's detail, if the operation failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    /// The attributes that changed, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub changes: BTreeMap<String, AttributeChange>,
+}
+
+/// What a `Provider` learns about an operation as it carries it out, to be
+/// turned into an `AuditRecord` at the end
+#[derive(Debug, Default)]
+pub(crate) struct AuditDraft {
+    pub resource_id: Option<String>,
+    pub changes: BTreeMap<String, AttributeChange>,
+
+    /// Changes the operation made to other resources along the way, such as
+    /// to the groups a deactivated user leaves, each to be recorded as an
+    /// operation of its own
+    pub related: Vec<(AuditOperation, AuditDraft)>,
+}
+
+impl AuditDraft {
+    /// Record a resource going from `before` to `after`, where `None` means
+    /// it does not exist. Changes to the same attribute through several calls
+    /// are merged, keeping the first `before`.
+    pub fn changed<R: Resource + Clone>(
+        &mut self,
+        before: Option<&R>,
+        after: Option<&R>,
+    ) {
+        let attributes = |resource: Option<&R>| {
+            resource
+                .and_then(|r| serialize_resource_to_object(r.clone()).ok())
+                .unwrap_or_default()
+        };
+        let before = attributes(before);
+        let after = attributes(after);
+
+        for name in before.keys().chain(after.keys()) {
+            if before.get(name) == after.get(name) {
+                continue;
+            }
+
+            let change =
+                self.changes.entry(name.clone()).or_insert(AttributeChange {
+                    before: before.get(name).cloned(),
+                    after: None,
+                });
+            change.after = after.get(name).cloned();
+        }
+
+        // Changes that were undone by a later call are not changes
+        self.changes.retain(|_, change| change.before != change.after);
+    }
+
+    /// Record `operation` changing another resource from `before` to
+    /// `after`, as part of this one.
+    pub fn related<R: Resource + Clone>(
+        &mut self,
+        operation: AuditOperation,
+        before: &R,
+        after: &R,
+    ) {
+        let mut draft =
+            AuditDraft { resource_id: Some(after.id()), ..Default::default() };
+        draft.changed(Some(before), Some(after));
+        self.related.push((operation, draft));
+    }
+}
+
+/// Where a `Provider` records what it did. Records are written after the
+/// operation, so failing to write one cannot undo it: the `Provider` logs the
+/// error instead.
+pub trait AuditSink: Sync {
+    fn record(
+        &self,
+        record: &AuditRecord,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+/// Records nothing
+impl AuditSink for () {
+    async fn record(&self, _record: &AuditRecord) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records nothing when `None`, so that auditing can be configured at run
+/// time
+impl<S: AuditSink> AuditSink for Option<S> {
+    async fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        match self {
+            Some(sink) => sink.record(record).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// So that providers scoped with `Provider::for_store` can share the sink
+impl<S: AuditSink> AuditSink for &S {
+    async fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        (**self).record(record).await
+    }
+}
+
+/// Keeps records in memory, mostly for tests
+#[derive(Debug, Default)]
+pub struct InMemoryAuditSink {
+    records: Mutex<Vec<AuditRecord>>,
+}
+
+impl InMemoryAuditSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn records(&self) -> Vec<AuditRecord> {
+        self.records.lock().unwrap().clone()
+    }
+}
+
+impl AuditSink for InMemoryAuditSink {
+    async fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        self.records.lock().unwrap().push(record.clone());
+        Ok(())
+    }
+}
+
+/// Appends records to a file, one JSON object per line, which tools like `jq`
+/// can query
+#[derive(Debug)]
+pub struct JsonLinesAuditSink {
+    path: PathBuf,
+    file: tokio::sync::Mutex<tokio::fs::File>,
+}
+
+impl JsonLinesAuditSink {
+    /// Open `path` for appending, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening audit log {}", path.display()))?;
+
+        Ok(Self {
+            path,
+            file: tokio::sync::Mutex::new(tokio::fs::File::from_std(file)),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Read back every record in the file at `path`
+    pub async fn read_records(
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<Vec<AuditRecord>> {
+        let path = path.as_ref();
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .with_context(|| format!("reading audit log {}", path.display()))?;
+
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).with_context(|| {
+                    format!("parsing line {} of {}", i + 1, path.display())
+                })
+            })
+            .collect()
+    }
+}
+
+impl AuditSink for JsonLinesAuditSink {
+    async fn record(&self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        // One write per record, under the lock, so that lines from
+        // concurrent requests do not interleave
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+}
+
+impl AuditRecord {
+    pub(crate) fn new(
+        context: &OperationContext,
+        operation: AuditOperation,
+        draft: AuditDraft,
+        status: u16,
+        error: Option<String>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            actor: context.actor.clone(),
+            request_id: context.request_id.clone(),
+            tenant: None,
+            operation,
+            resource_id: draft.resource_id,
+            status,
+            error,
+            changes: draft.changes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::User;
+
+    fn user(active: bool, external_id: Option<&str>) -> User {
+        User {
+            id: String::from("jim"),
+            name: String::from("jhalpert"),
+            active: Some(active),
+            external_id: external_id.map(String::from),
+            groups: None,
+        }
+    }
+
+    #[test]
+    fn test_draft_changes() {
+        let mut draft = AuditDraft::default();
+        draft.changed(Some(&user(true, None)), Some(&user(false, Some("j"))));
+
+        assert_eq!(
+            draft.changes,
+            BTreeMap::from([
+                (
+                    String::from("active"),
+                    AttributeChange {
+                        before: Some(Value::Bool(true)),
+                        after: Some(Value::Bool(false)),
+                    },
+                ),
+                (
+                    String::from("externalId"),
+                    AttributeChange {
+                        before: None,
+                        after: Some(Value::from("j")),
+                    },
+                ),
+            ])
+        );
+
+        // Setting active back cancels that change out, and the first before
+        // is kept for the rest
+        draft.changed(Some(&user(false, Some("j"))), Some(&user(true, None)));
+        assert!(draft.changes.is_empty(), "{:?}", draft.changes);
+    }
+
+    #[tokio::test]
+    async fn test_json_lines_sink() {
+        let path = std::env::temp_dir()
+            .join(format!("scim2-rs-audit-{}.jsonl", Uuid::new_v4()));
+
+        let context = OperationContext {
+            actor: Some(String::from("okta")),
+            request_id: Some(String::from("req-1")),
+            if_match: None,
+        };
+
+        let mut draft = AuditDraft {
+            resource_id: Some(String::from("jim")),
+            ..Default::default()
+        };
+        draft.changed(None, Some(&user(true, None)));
+
+        let records = [
+            AuditRecord::new(
+                &context,
+                AuditOperation::CreateUser,
+                draft,
+                201,
+                None,
+            ),
+            AuditRecord::new(
+                &context,
+                AuditOperation::DeleteUser,
+                AuditDraft::default(),
+                404,
+                Some(String::from("Resource dwight not found")),
+            ),
+        ];
+
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        sink.record(&records[0]).await.unwrap();
+        drop(sink);
+
+        // Reopening appends
+        let sink = JsonLinesAuditSink::open(&path).unwrap();
+        sink.record(&records[1]).await.unwrap();
+
+        assert_eq!(
+            JsonLinesAuditSink::read_records(&path).await.unwrap(),
+            records
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,319 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use http::HeaderMap;
+use http::header::AUTHORIZATION;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::Error;
+
+/// The client a request came from, as established by an `Authenticator`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    /// Names the client in logs and audit records
+    pub name: String,
+
+    /// The tenant the client belongs to, if the authenticator knows
+    pub tenant: Option<String>,
+}
+
+/// An entry in the `authenticationSchemes` of the ServiceProviderConfig, as
+/// described in RFC 7643 section 5
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AuthenticationScheme {
+    /// One of "oauth", "oauth2", "oauthbearertoken", "httpbasic" or
+    /// "httpdigest"
+    #[serde(rename = "type")]
+    pub scheme_type: String,
+
+    pub name: String,
+
+    pub description: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spec_uri: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub documentation_uri: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub primary: Option<bool>,
+}
+
+impl AuthenticationScheme {
+    /// RFC 6750 bearer tokens
+    pub fn oauth_bearer_token() -> Self {
+        Self {
+            scheme_type: String::from("oauthbearertoken"),
+            name: String::from("OAuth Bearer Token"),
+            description: String::from(
+                "Authentication scheme using the OAuth Bearer Token Standard",
+            ),
+            spec_uri: Some(String::from(
+                "https://www.rfc-editor.org/info/rfc6750",
+            )),
+            documentation_uri: None,
+            primary: Some(true),
+        }
+    }
+}
+
+/// Decides who a request is from, given its headers.
+///
+/// Return `Error::unauthorized()` if the request has no credentials or they
+/// are not valid, and `Error::forbidden()` if they are valid but do not allow
+/// the client to use the service. Both are sent back to the client as-is.
+pub trait Authenticator: Sync {
+    fn authenticate(
+        &self,
+        headers: &HeaderMap,
+    ) -> impl Future<Output = Result<ClientIdentity, Error>> + Send;
+
+    /// How clients should authenticate, for the ServiceProviderConfig
+    fn authentication_schemes(&self) -> Vec<AuthenticationScheme>;
+}
+
+/// The token from an `Authorization: Bearer <token>` header, if there is one
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+
+    // The scheme is case insensitive (RFC 9110 section 11.1)
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+
+    let token = token.trim();
+    if token.is_empty() { None } else { Some(token) }
+}
+
+/// The hex encoded SHA-256 hash of `token`, as `StaticTokenAuthenticator`
+/// stores it
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[derive(Clone)]
+struct HashedToken {
+    name: String,
+    tenant: Option<String>,
+    hash: [u8; 32],
+}
+
+/// Accepts any of a fixed set of bearer tokens, each of which names the
+/// client that holds it.
+///
+/// Only the SHA-256 hash of each token is kept, so configuration can carry
+/// hashes rather than the tokens themselves. Several tokens can be active at
+/// once, which is how a token is rotated: add the new one, move clients over
+/// to it, then remove the old one.
+#[derive(Clone, Default)]
+pub struct StaticTokenAuthenticator {
+    tokens: Vec<HashedToken>,
+}
+
+impl std::fmt::Debug for StaticTokenAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> =
+            self.tokens.iter().map(|token| token.name.as_str()).collect();
+        f.debug_struct("StaticTokenAuthenticator")
+            .field("tokens", &names)
+            .finish()
+    }
+}
+
+impl StaticTokenAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept `token`, from the client `name`
+    pub fn with_token(mut self, name: impl Into<String>, token: &str) -> Self {
+        self.tokens.push(HashedToken {
+            name: name.into(),
+            tenant: None,
+            hash: Sha256::digest(token.as_bytes()).into(),
+        });
+        self
+    }
+
+    /// Accept `token`, from the client `name` which belongs to `tenant`
+    pub fn with_tenant_token(
+        mut self,
+        name: impl Into<String>,
+        tenant: impl Into<String>,
+        token: &str,
+    ) -> Self {
+        self.tokens.push(HashedToken {
+            name: name.into(),
+            tenant: Some(tenant.into()),
+            hash: Sha256::digest(token.as_bytes()).into(),
+        });
+        self
+    }
+
+    /// Accept the token whose hex encoded SHA-256 hash (see `hash_token`) is
+    /// `hash`, from the client `name`
+    pub fn with_token_hash(
+        mut self,
+        name: impl Into<String>,
+        hash: &str,
+    ) -> anyhow::Result<Self> {
+        let bytes = (0..hash.len())
+            .step_by(2)
+            .map(|i| {
+                hash.get(i..i + 2)
+                    .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+            })
+            .collect::<Option<Vec<u8>>>();
+
+        let Some(hash) = bytes.and_then(|bytes| bytes.try_into().ok()) else {
+            anyhow::bail!("token hash is not 64 hex digits");
+        };
+
+        self.tokens.push(HashedToken { name: name.into(), tenant: None, hash });
+        Ok(self)
+    }
+
+    /// Stop accepting the tokens from the client `name`
+    pub fn without_token(mut self, name: &str) -> Self {
+        self.tokens.retain(|token| token.name != name);
+        self
+    }
+
+    /// The clients with an active token
+    pub fn names(&self) -> Vec<&str> {
+        self.tokens.iter().map(|token| token.name.as_str()).collect()
+    }
+}
+
+impl Authenticator for StaticTokenAuthenticator {
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<ClientIdentity, Error> {
+        let token = bearer_token(headers).ok_or_else(Error::unauthorized)?;
+        let hash: [u8; 32] = Sha256::digest(token.as_bytes()).into();
+
+        // Compare every byte of every hash, so that how long this takes does
+        // not depend on how close the token came
+        let mut found = None;
+        for candidate in &self.tokens {
+            let difference = candidate
+                .hash
+                .iter()
+                .zip(hash.iter())
+                .fold(0, |acc, (a, b)| acc | (a ^ b));
+            if difference == 0 && found.is_none() {
+                found = Some(candidate);
+            }
+        }
+
+        found
+            .map(|token| ClientIdentity {
+                name: token.name.clone(),
+                tenant: token.tenant.clone(),
+            })
+            .ok_or_else(Error::unauthorized)
+    }
+
+    fn authentication_schemes(&self) -> Vec<AuthenticationScheme> {
+        vec![AuthenticationScheme::oauth_bearer_token()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http::HeaderValue;
+    use http::StatusCode;
+
+    fn headers(authorization: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(authorization) = authorization {
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(authorization).unwrap(),
+            );
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_static_tokens() {
+        let authenticator = StaticTokenAuthenticator::new()
+            .with_token("okta", "old-token")
+            .with_token_hash("okta-rotated", &hash_token("new-token"))
+            .unwrap();
+
+        // Both tokens are accepted while the token is rotated
+        for (authorization, name) in
+            [("Bearer old-token", "okta"), ("bearer new-token", "okta-rotated")]
+        {
+            let identity = authenticator
+                .authenticate(&headers(Some(authorization)))
+                .await
+                .unwrap();
+            assert_eq!(identity.name, name);
+        }
+
+        for authorization in [
+            None,
+            Some("Bearer"),
+            Some("Bearer wrong-token"),
+            Some("Basic b2xkLXRva2Vu"),
+        ] {
+            let error = authenticator
+                .authenticate(&headers(authorization))
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        // and once rotation is done the old one is not
+        let authenticator = authenticator.without_token("okta");
+        assert_eq!(authenticator.names(), ["okta-rotated"]);
+        assert!(
+            authenticator
+                .authenticate(&headers(Some("Bearer old-token")))
+                .await
+                .is_err()
+        );
+
+        // Tokens can say which tenant their client belongs to
+        let identity = StaticTokenAuthenticator::new()
+            .with_tenant_token("okta", "dunder-mifflin", "token")
+            .authenticate(&headers(Some("Bearer token")))
+            .await
+            .unwrap();
+        assert_eq!(identity.tenant.as_deref(), Some("dunder-mifflin"));
+    }
+
+    #[test]
+    fn test_token_hash() {
+        assert!(
+            StaticTokenAuthenticator::new()
+                .with_token_hash("okta", "not hex")
+                .is_err()
+        );
+        assert!(
+            StaticTokenAuthenticator::new()
+                .with_token_hash("okta", &hash_token("token")[..62])
+                .is_err()
+        );
+
+        // Debug output does not give the hashes away
+        let authenticator =
+            StaticTokenAuthenticator::new().with_token("okta", "token");
+        assert!(
+            !format!("{authenticator:?}").contains(&hash_token("token")[..8])
+        );
+    }
+}
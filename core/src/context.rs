@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! What a server has to provide to serve SCIM, whether with dropshot (see
+//! `register_scim_endpoints`) or with `ScimService`, and the parts of the
+//! responses that do not depend on how.
+//!
+//! The context decides who a request is from and which `Provider` serves it,
+//! so servers keep authentication and tenancy to themselves while sharing the
+//! HTTP handling.
+
+use std::sync::Arc;
+
+use http::{Response, StatusCode};
+use serde_json::json;
+
+use crate::{
+    AuditSink, AuthenticationScheme, Error, GROUP_URN, LISTRESPONSE_URN,
+    OperationContext, Provider, ProviderStore, ProvisioningHooks,
+    RESOURCETYPE_URN, USER_URN,
+};
+
+/// What the SCIM endpoints need to know about a request
+#[derive(Debug, Clone, Copy)]
+pub struct ScimRequest<'a> {
+    pub headers: &'a http::HeaderMap,
+
+    pub request_id: &'a str,
+
+    /// The tenant from the request's path, if the endpoints were registered
+    /// under a base path with a `{tenant_id}`
+    pub tenant_id: Option<&'a str>,
+}
+
+/// The server context that the SCIM endpoints are served with
+pub trait ScimContext: Send + Sync + 'static {
+    type Store: ProviderStore + Send + Sync;
+    type Hooks: ProvisioningHooks + Send + Sync;
+    type AuditSink: AuditSink + Send + Sync;
+
+    /// Authenticate `request`, and find the provider that serves it (see
+    /// `Provider::for_store`). Errors, like those from an `Authenticator`,
+    /// are sent back to the client as-is.
+    #[allow(clippy::type_complexity)]
+    fn authorize(
+        &self,
+        request: ScimRequest<'_>,
+    ) -> impl Future<
+        Output = Result<
+            (
+                OperationContext,
+                Provider<Self::Store, &Self::Hooks, &Self::AuditSink>,
+            ),
+            Error,
+        >,
+    > + Send;
+
+    /// How clients should authenticate, for the ServiceProviderConfig
+    fn authentication_schemes(&self) -> Vec<AuthenticationScheme> {
+        vec![]
+    }
+}
+
+/// A provider serves every request itself, without authenticating it
+impl<S, H, A> ScimContext for Provider<Arc<S>, H, A>
+where
+    S: ProviderStore + Send + Sync + 'static,
+    H: ProvisioningHooks + Send + Sync + 'static,
+    A: AuditSink + Send + Sync + 'static,
+{
+    type Store = Arc<S>;
+    type Hooks = H;
+    type AuditSink = A;
+
+    async fn authorize(
+        &self,
+        request: ScimRequest<'_>,
+    ) -> Result<(OperationContext, Provider<Arc<S>, &H, &A>), Error> {
+        let context = OperationContext {
+            actor: None,
+            request_id: Some(request.request_id.to_string()),
+            if_match: None,
+        };
+
+        Ok((context, self.for_store(Arc::clone(self.store()))))
+    }
+}
+
+impl<C: ScimContext> ScimContext for Arc<C> {
+    type Store = C::Store;
+    type Hooks = C::Hooks;
+    type AuditSink = C::AuditSink;
+
+    fn authorize(
+        &self,
+        request: ScimRequest<'_>,
+    ) -> impl Future<
+        Output = Result<
+            (
+                OperationContext,
+                Provider<Self::Store, &Self::Hooks, &Self::AuditSink>,
+            ),
+            Error,
+        >,
+    > + Send {
+        (**self).authorize(request)
+    }
+
+    fn authentication_schemes(&self) -> Vec<AuthenticationScheme> {
+        (**self).authentication_schemes()
+    }
+}
+
+/// The response to a request that `ScimContext::authorize` turned away
+pub fn auth_error_response<B: From<String>>(
+    error: Error,
+) -> Result<Response<B>, http::Error> {
+    let mut response = error.to_http_response()?;
+
+    // RFC 6750, section 3: a 401 says which scheme to authenticate with
+    if response.status() == StatusCode::UNAUTHORIZED {
+        response.headers_mut().insert(
+            http::header::WWW_AUTHENTICATE,
+            http::HeaderValue::from_static("Bearer"),
+        );
+    }
+
+    Ok(response)
+}
+
+// RFC 7644, section 4: Service Provider Configuration Endpoints. These need
+// no authentication, so that clients can find out how to authenticate.
+
+pub(crate) fn resource_type_user() -> serde_json::Value {
+    json!({
+        "schemas": [RESOURCETYPE_URN],
+        "id": "User",
+        "name": "User",
+        "description": "User Account",
+        "endpoint": "/Users",
+        "schema": USER_URN,
+    })
+}
+
+pub(crate) fn resource_type_group() -> serde_json::Value {
+    json!({
+        "schemas": [RESOURCETYPE_URN],
+        "id": "Group",
+        "name": "Group",
+        "description": "Group",
+        "endpoint": "/Groups",
+        "schema": GROUP_URN,
+    })
+}
+
+pub(crate) fn resource_types() -> serde_json::Value {
+    json!({
+        "schemas": [LISTRESPONSE_URN],
+        "totalResults": 2,
+        "startIndex": 1,
+        "itemsPerPage": 2,
+        "Resources": [resource_type_user(), resource_type_group()],
+    })
+}
+
+pub(crate) fn schemas() -> serde_json::Value {
+    json!({
+        "schemas": [LISTRESPONSE_URN],
+        "totalResults": 2,
+        "startIndex": 1,
+        "itemsPerPage": 2,
+        "Resources": [
+            {
+                "id": USER_URN,
+                "name": "User",
+                "attributes": [
+                    {
+                        "name": "userName",
+                        "type": "string",
+                        "multiValued": false
+                    },
+                    {
+                        "name": "externalId",
+                        "type": "string",
+                        "multiValued": false
+                    },
+                    {
+                        "name": "active",
+                        "type": "boolean",
+                        "multiValued": false
+                    }
+                ]
+            },
+            {
+                "id": GROUP_URN,
+                "name": "Group",
+                "attributes": [
+                    {
+                        "name": "displayName",
+                        "type": "string",
+                        "multiValued": false
+                    }
+                ]
+            }
+        ]
+    })
+}
+
+/// The ServiceProviderConfig of a provider backed by a store of type `S`
+pub(crate) fn service_provider_config<S: ProviderStore>(
+    authentication_schemes: Vec<AuthenticationScheme>,
+) -> serde_json::Value {
+    json!({
+        "schemas": [
+            "urn:ietf:params:scim:schemas:core:2.0:ServiceProviderConfig"
+        ],
+        "patch": { "supported": true },
+        "bulk": { "supported": false },
+        "filter": { "supported": true },
+        "changePassword": { "supported": false },
+        "sort": { "supported": false },
+        "etag": { "supported": S::VERSIONED },
+        "authenticationSchemes": authentication_schemes,
+    })
+}
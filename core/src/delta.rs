@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A non-standard delta query, for reconciliation jobs that want everything
+//! that changed since they last looked.
+//!
+//! Stores count every change with a counter that only goes up, and remember
+//! the count at which each resource last changed, including the ones that
+//! were deleted. A client starts with a full listing, keeps the watermark
+//! that comes with it, and passes it back to get only what changed since.
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use http::{Response, StatusCode};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::response::value_to_http_response;
+use crate::{ChangeEventKind, Error, ResourceType};
+
+/// The ids of the resources of one type that changed after some value of a
+/// store's change counter
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoreDelta {
+    /// Resources that were created or changed, and still exist
+    pub changed: Vec<String>,
+
+    /// Resources that were deleted
+    pub deleted: Vec<String>,
+
+    /// The store's change counter when the delta was taken
+    pub counter: u64,
+}
+
+/// When a resource last changed, by a store's change counter
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub(crate) struct ChangeMark {
+    pub counter: u64,
+    pub deleted: bool,
+}
+
+/// The resources that `changes` touch, and whether each was deleted.
+///
+/// Membership changes count as changes to both the group and the user (whose
+/// `groups` attribute changed), but not to a user that is being deleted.
+pub(crate) fn touched_resources(
+    changes: &[ChangeEventKind],
+) -> Vec<(ResourceType, String, bool)> {
+    let deleted_user = changes.iter().find_map(|kind| match kind {
+        ChangeEventKind::UserDeleted { user_id } => Some(user_id),
+        _ => None,
+    });
+
+    let mut touched = Vec::new();
+    for kind in changes {
+        match kind {
+            ChangeEventKind::UserCreated { user }
+            | ChangeEventKind::UserUpdated { user } => {
+                touched.push((ResourceType::User, user.id.clone(), false));
+            }
+
+            ChangeEventKind::UserDeactivated { user_id }
+            | ChangeEventKind::UserReactivated { user_id } => {
+                touched.push((ResourceType::User, user_id.clone(), false));
+            }
+
+            ChangeEventKind::UserDeleted { user_id } => {
+                touched.push((ResourceType::User, user_id.clone(), true));
+            }
+
+            ChangeEventKind::GroupCreated { group }
+            | ChangeEventKind::GroupUpdated { group } => {
+                touched.push((ResourceType::Group, group.id.clone(), false));
+            }
+
+            ChangeEventKind::GroupDeleted { group_id } => {
+                touched.push((ResourceType::Group, group_id.clone(), true));
+            }
+
+            ChangeEventKind::GroupMembersAdded { group_id, user_ids }
+            | ChangeEventKind::GroupMembersRemoved { group_id, user_ids } => {
+                // A deleted group reports its members as removed
+                if !touched.contains(&(
+                    ResourceType::Group,
+                    group_id.clone(),
+                    true,
+                )) {
+                    touched.push((
+                        ResourceType::Group,
+                        group_id.clone(),
+                        false,
+                    ));
+                }
+
+                touched.extend(
+                    user_ids
+                        .iter()
+                        .filter(|user_id| Some(*user_id) != deleted_user)
+                        .map(|user_id| {
+                            (ResourceType::User, user_id.clone(), false)
+                        }),
+                );
+            }
+        }
+    }
+
+    touched
+}
+
+/// The delta after `since` from the `marks` of one resource type
+pub(crate) fn delta_since<'a>(
+    marks: impl IntoIterator<Item = (&'a String, &'a ChangeMark)>,
+    since: u64,
+    counter: u64,
+) -> StoreDelta {
+    let mut delta = StoreDelta { counter, ..Default::default() };
+
+    for (id, mark) in marks {
+        if mark.counter <= since {
+            continue;
+        }
+
+        if mark.deleted {
+            delta.deleted.push(id.clone());
+        } else {
+            delta.changed.push(id.clone());
+        }
+    }
+
+    delta
+}
+
+/// The query parameters of a delta query
+#[derive(Debug, Clone, Default, Deserialize, JsonSchema)]
+pub struct DeltaQueryParams {
+    /// From an earlier delta. Without one, every resource is listed.
+    pub watermark: Option<String>,
+}
+
+/// The answer to a delta query
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeltaResponse {
+    /// Resources that were created or changed since the watermark, and still
+    /// exist. Without a watermark, every resource that exists.
+    pub changed: Vec<String>,
+
+    /// Resources that were deleted since the watermark
+    pub deleted: Vec<String>,
+
+    /// Pass this back to get what changes after this response
+    pub watermark: String,
+}
+
+impl DeltaResponse {
+    pub fn to_http_response<B: From<String>>(
+        self,
+    ) -> Result<Response<B>, http::Error> {
+        value_to_http_response(
+            StatusCode::OK,
+            &self,
+            "serializing delta response failed",
+        )
+    }
+}
+
+/// Watermarks are opaque to clients, so that what is behind them can change
+fn encode_watermark(counter: u64) -> String {
+    URL_SAFE_NO_PAD.encode(format!("v1:{counter}"))
+}
+
+pub(crate) fn decode_watermark(watermark: &str) -> Result<u64, Error> {
+    URL_SAFE_NO_PAD
+        .decode(watermark)
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())
+        .and_then(|raw| raw.strip_prefix("v1:")?.parse().ok())
+        .ok_or_else(|| {
+            Error::invalid_value(format!("invalid watermark {watermark}"))
+        })
+}
+
+/// Answer a delta query for the watermark `since` with `delta`
+pub(crate) fn delta_response(
+    since: Option<u64>,
+    delta: StoreDelta,
+) -> Result<DeltaResponse, Error> {
+    // A watermark from before the store was reset (or from another store)
+    // would silently miss changes
+    if since.is_some_and(|since| since > delta.counter) {
+        return Err(Error::invalid_value(String::from(
+            "the watermark is not from this store, start again without one",
+        )));
+    }
+
+    Ok(DeltaResponse {
+        changed: delta.changed,
+        deleted: delta.deleted,
+        watermark: encode_watermark(delta.counter),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Group, GroupMember, User, group_changes, user_changes};
+
+    #[test]
+    fn test_watermark() {
+        assert_eq!(decode_watermark(&encode_watermark(42)), Ok(42));
+        assert!(decode_watermark("42").is_err());
+        assert!(decode_watermark(&URL_SAFE_NO_PAD.encode("v2:42")).is_err());
+    }
+
+    #[test]
+    fn test_touched_resources() {
+        let user = User {
+            id: String::from("jim"),
+            name: String::from("jhalpert"),
+            active: Some(true),
+            external_id: None,
+            groups: Some(vec![crate::UserGroup {
+                member_type: None,
+                value: Some(String::from("sales")),
+                display: None,
+            }]),
+        };
+
+        // Deleting a user changes its groups, but not the user
+        assert_eq!(
+            touched_resources(&user_changes(Some(&user), None)),
+            [
+                (ResourceType::User, String::from("jim"), true),
+                (ResourceType::Group, String::from("sales"), false),
+            ]
+        );
+
+        let group = Group {
+            id: String::from("sales"),
+            display_name: String::from("Sales"),
+            external_id: None,
+            members: Some(
+                [GroupMember {
+                    resource_type: None,
+                    value: Some(String::from("jim")),
+                }]
+                .into_iter()
+                .collect(),
+            ),
+        };
+
+        // Deleting a group changes its members
+        assert_eq!(
+            touched_resources(&group_changes(Some(&group), None)),
+            [
+                (ResourceType::Group, String::from("sales"), true),
+                (ResourceType::User, String::from("jim"), false),
+            ]
+        );
+    }
+}
@@ -0,0 +1,894 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The SCIM endpoints (RFC 7644, section 3.2) for a dropshot server.
+//!
+//! `register_scim_endpoints` adds the User, Group and discovery endpoints
+//! under a base path to an `ApiDescription` whose context implements
+//! `ScimContext`.
+
+use dropshot::{
+    ApiDescription, ApiDescriptionRegisterError, ApiEndpoint,
+    ApiEndpointVersions, Body, CONTENT_TYPE_JSON, ErrorStatusCode, HttpError,
+    HttpResponse, HttpResponseCreated, HttpResponseDeleted, HttpResponseError,
+    HttpResponseOk, Path, Query, RequestContext, TypedBody,
+};
+use http::{Method, Response, StatusCode};
+use schemars::JsonSchema;
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use crate::context;
+use crate::openapi::{
+    GroupListResponse, GroupResource, UserListResponse, UserResource,
+};
+use crate::response::value_to_http_response;
+use crate::urn::ERROR_URN;
+use crate::{
+    CreateGroupRequest, CreateUserRequest, DeltaQueryParams, DeltaResponse,
+    Error, ErrorType, OperationContext, PatchRequest, Provider, QueryParams,
+    ScimContext, ScimRequest, auth_error_response,
+};
+
+/// Add the SCIM endpoints under `base_path`, like `/v2` or
+/// `/tenants/{tenant_id}/v2`. A `{tenant_id}` in the base path is given to
+/// `ScimContext::authorize`.
+pub fn register_scim_endpoints<C: ScimContext>(
+    api: &mut ApiDescription<C>,
+    base_path: &str,
+) -> Result<(), ApiDescriptionRegisterError> {
+    let base_path = base_path.trim_end_matches('/');
+
+    if base_path.contains("{tenant_id}") {
+        register::<C, TenantPathParam, TenantResourcePathParam>(
+            api, base_path, "tenant_",
+        )
+    } else {
+        register::<C, NoPathParam, ResourcePathParam>(api, base_path, "")
+    }
+}
+
+/// The path parameters of the collection and discovery endpoints
+trait ScimPathParam: DeserializeOwned + JsonSchema + Send + Sync + 'static {
+    fn tenant_id(&self) -> Option<&str>;
+}
+
+/// The path parameters of the endpoints for a single resource
+trait ScimResourcePathParam: ScimPathParam {
+    fn id(&self) -> &str;
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct NoPathParam {}
+
+impl ScimPathParam for NoPathParam {
+    fn tenant_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct TenantPathParam {
+    tenant_id: String,
+}
+
+impl ScimPathParam for TenantPathParam {
+    fn tenant_id(&self) -> Option<&str> {
+        Some(&self.tenant_id)
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct ResourcePathParam {
+    id: String,
+}
+
+impl ScimPathParam for ResourcePathParam {
+    fn tenant_id(&self) -> Option<&str> {
+        None
+    }
+}
+
+impl ScimResourcePathParam for ResourcePathParam {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+struct TenantResourcePathParam {
+    tenant_id: String,
+    id: String,
+}
+
+impl ScimPathParam for TenantResourcePathParam {
+    fn tenant_id(&self) -> Option<&str> {
+        Some(&self.tenant_id)
+    }
+}
+
+impl ScimResourcePathParam for TenantResourcePathParam {
+    fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+fn register<C, P, R>(
+    api: &mut ApiDescription<C>,
+    base_path: &str,
+    operation_prefix: &str,
+) -> Result<(), ApiDescriptionRegisterError>
+where
+    C: ScimContext,
+    P: ScimPathParam,
+    R: ScimResourcePathParam,
+{
+    // The handlers build their own responses, to use the SCIM media type, so
+    // what they respond with is given separately for the OpenAPI document.
+    macro_rules! endpoint {
+        (
+            $handler:expr,
+            $method:ident,
+            $name:literal,
+            $path:literal,
+            $response:ty $(,)?
+        ) => {{
+            let mut endpoint = ApiEndpoint::new(
+                format!("{operation_prefix}{}", $name),
+                $handler,
+                Method::$method,
+                CONTENT_TYPE_JSON,
+                &format!("{base_path}{}", $path),
+                ApiEndpointVersions::All,
+            );
+            endpoint.response =
+                <$response as HttpResponse>::response_metadata();
+            endpoint
+        }};
+    }
+
+    // RFC 7644, section 3.2: SCIM Endpoints and HTTP Methods
+
+    api.register(endpoint!(
+        list_users::<C, P>,
+        GET,
+        "list_users",
+        "/Users",
+        HttpResponseOk<UserListResponse>,
+    ))?;
+    api.register(endpoint!(
+        get_user::<C, R>,
+        GET,
+        "get_user",
+        "/Users/{id}",
+        HttpResponseOk<UserResource>,
+    ))?;
+    api.register(endpoint!(
+        create_user::<C, P>,
+        POST,
+        "create_user",
+        "/Users",
+        HttpResponseCreated<UserResource>,
+    ))?;
+    api.register(endpoint!(
+        put_user::<C, R>,
+        PUT,
+        "put_user",
+        "/Users/{id}",
+        HttpResponseOk<UserResource>,
+    ))?;
+    api.register(endpoint!(
+        delete_user::<C, R>,
+        DELETE,
+        "delete_user",
+        "/Users/{id}",
+        HttpResponseDeleted,
+    ))?;
+    api.register(endpoint!(
+        patch_user::<C, R>,
+        PATCH,
+        "patch_user",
+        "/Users/{id}",
+        HttpResponseOk<UserResource>,
+    ))?;
+    api.register(endpoint!(
+        user_delta::<C, P>,
+        GET,
+        "user_delta",
+        "/Delta/Users",
+        HttpResponseOk<DeltaResponse>,
+    ))?;
+
+    api.register(endpoint!(
+        list_groups::<C, P>,
+        GET,
+        "list_groups",
+        "/Groups",
+        HttpResponseOk<GroupListResponse>,
+    ))?;
+    api.register(endpoint!(
+        get_group::<C, R>,
+        GET,
+        "get_group",
+        "/Groups/{id}",
+        HttpResponseOk<GroupResource>,
+    ))?;
+    api.register(endpoint!(
+        create_group::<C, P>,
+        POST,
+        "create_group",
+        "/Groups",
+        HttpResponseCreated<GroupResource>,
+    ))?;
+    api.register(endpoint!(
+        put_group::<C, R>,
+        PUT,
+        "put_group",
+        "/Groups/{id}",
+        HttpResponseOk<GroupResource>,
+    ))?;
+    api.register(endpoint!(
+        delete_group::<C, R>,
+        DELETE,
+        "delete_group",
+        "/Groups/{id}",
+        HttpResponseDeleted,
+    ))?;
+    api.register(endpoint!(
+        patch_group::<C, R>,
+        PATCH,
+        "patch_group",
+        "/Groups/{id}",
+        HttpResponseOk<GroupResource>,
+    ))?;
+    api.register(endpoint!(
+        group_delta::<C, P>,
+        GET,
+        "group_delta",
+        "/Delta/Groups",
+        HttpResponseOk<DeltaResponse>,
+    ))?;
+
+    api.register(endpoint!(
+        get_resource_types::<C, P>,
+        GET,
+        "get_resource_types",
+        "/ResourceTypes",
+        HttpResponseOk<serde_json::Value>,
+    ))?;
+    api.register(endpoint!(
+        get_resource_type_user::<C, P>,
+        GET,
+        "get_resource_type_user",
+        "/ResourceTypes/User",
+        HttpResponseOk<serde_json::Value>,
+    ))?;
+    api.register(endpoint!(
+        get_resource_type_group::<C, P>,
+        GET,
+        "get_resource_type_group",
+        "/ResourceTypes/Group",
+        HttpResponseOk<serde_json::Value>,
+    ))?;
+    api.register(endpoint!(
+        get_schemas::<C, P>,
+        GET,
+        "get_schemas",
+        "/Schemas",
+        HttpResponseOk<serde_json::Value>,
+    ))?;
+    api.register(endpoint!(
+        get_service_provider_config::<C, P>,
+        GET,
+        "get_service_provider_config",
+        "/ServiceProviderConfig",
+        HttpResponseOk<serde_json::Value>,
+    ))?;
+
+    Ok(())
+}
+
+// Dropshot turns away requests whose path, query string or body it can't
+// parse before a handler sees them. With these, those are SCIM errors too, and
+// the OpenAPI document gives the SCIM error for every failure.
+
+impl From<HttpError> for Error {
+    fn from(error: HttpError) -> Self {
+        let status = error.status_code.as_status();
+        Error {
+            schemas: vec![ERROR_URN.to_string()],
+            status,
+            error_type: (status == StatusCode::BAD_REQUEST)
+                .then_some(ErrorType::InvalidSyntax),
+            detail: error.external_message,
+        }
+    }
+}
+
+impl HttpResponseError for Error {
+    fn status_code(&self) -> ErrorStatusCode {
+        ErrorStatusCode::from_status(self.status)
+            .unwrap_or(ErrorStatusCode::INTERNAL_SERVER_ERROR)
+    }
+}
+
+async fn authorize<'a, C: ScimContext>(
+    rqctx: &'a RequestContext<C>,
+    tenant_id: Option<&str>,
+) -> Result<
+    (OperationContext, Provider<C::Store, &'a C::Hooks, &'a C::AuditSink>),
+    Error,
+> {
+    let request = ScimRequest {
+        headers: rqctx.request.headers(),
+        request_id: &rqctx.request_id,
+        tenant_id,
+    };
+
+    let (context, provider) = rqctx.context().authorize(request).await?;
+
+    Ok((context.with_if_match(rqctx.request.headers()), provider))
+}
+
+async fn list_users<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    query_params: Query<QueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result = match provider.list_users(query_params.into_inner()).await {
+        Ok(response) => response.to_http_response(),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn get_user<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    query_params: Query<QueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result =
+        match provider.get_user_by_id(query_params, path_param.id()).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+async fn create_user<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    body: TypedBody<CreateUserRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result = match provider.create_user(&context, request).await {
+        Ok(response) => response.to_http_response(StatusCode::CREATED),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn put_user<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    body: TypedBody<CreateUserRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result =
+        match provider.replace_user(&context, path_param.id(), request).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+async fn delete_user<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result = match provider.delete_user(&context, path_param.id()).await {
+        Ok(response) => Ok(response.map(Body::from)),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn patch_user<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    body: TypedBody<PatchRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result =
+        match provider.patch_user(&context, path_param.id(), request).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+/// The users that changed since a watermark. Not part of SCIM.
+async fn user_delta<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    query_params: Query<DeltaQueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result =
+        match provider.user_delta(query_params.watermark.as_deref()).await {
+            Ok(response) => response.to_http_response(),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+async fn list_groups<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    query_params: Query<QueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result = match provider.list_groups(query_params).await {
+        Ok(response) => response.to_http_response(),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn get_group<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    query_params: Query<QueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result =
+        match provider.get_group_by_id(query_params, path_param.id()).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+async fn create_group<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    body: TypedBody<CreateGroupRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result = match provider.create_group(&context, request).await {
+        Ok(response) => response.to_http_response(StatusCode::CREATED),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn put_group<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    body: TypedBody<CreateGroupRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result = match provider
+        .replace_group(&context, path_param.id(), request)
+        .await
+    {
+        Ok(response) => response.to_http_response(StatusCode::OK),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn delete_group<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result = match provider.delete_group(&context, path_param.id()).await {
+        Ok(response) => Ok(response.map(Body::from)),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(Error::from)
+}
+
+async fn patch_group<C: ScimContext, R: ScimResourcePathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<R>,
+    body: TypedBody<PatchRequest>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let request = body.into_inner();
+
+    let (context, provider) =
+        match authorize(&rqctx, path_param.tenant_id()).await {
+            Ok(authorized) => authorized,
+            Err(error) => {
+                return auth_error_response(error).map_err(Error::from);
+            }
+        };
+
+    let result =
+        match provider.patch_group(&context, path_param.id(), request).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+/// The groups that changed since a watermark. Not part of SCIM.
+async fn group_delta<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    path_param: Path<P>,
+    query_params: Query<DeltaQueryParams>,
+) -> Result<Response<Body>, Error> {
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let (_, provider) = match authorize(&rqctx, path_param.tenant_id()).await {
+        Ok(authorized) => authorized,
+        Err(error) => {
+            return auth_error_response(error).map_err(Error::from);
+        }
+    };
+
+    let result =
+        match provider.group_delta(query_params.watermark.as_deref()).await {
+            Ok(response) => response.to_http_response(),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(Error::from)
+}
+
+async fn get_resource_types<C: ScimContext, P: ScimPathParam>(
+    _rqctx: RequestContext<C>,
+    _path_param: Path<P>,
+) -> Result<Response<Body>, Error> {
+    value_to_http_response(
+        StatusCode::OK,
+        &context::resource_types(),
+        "serializing resource types failed",
+    )
+    .map_err(Error::from)
+}
+
+async fn get_resource_type_user<C: ScimContext, P: ScimPathParam>(
+    _rqctx: RequestContext<C>,
+    _path_param: Path<P>,
+) -> Result<Response<Body>, Error> {
+    value_to_http_response(
+        StatusCode::OK,
+        &context::resource_type_user(),
+        "serializing resource type failed",
+    )
+    .map_err(Error::from)
+}
+
+async fn get_resource_type_group<C: ScimContext, P: ScimPathParam>(
+    _rqctx: RequestContext<C>,
+    _path_param: Path<P>,
+) -> Result<Response<Body>, Error> {
+    value_to_http_response(
+        StatusCode::OK,
+        &context::resource_type_group(),
+        "serializing resource type failed",
+    )
+    .map_err(Error::from)
+}
+
+async fn get_schemas<C: ScimContext, P: ScimPathParam>(
+    _rqctx: RequestContext<C>,
+    _path_param: Path<P>,
+) -> Result<Response<Body>, Error> {
+    value_to_http_response(
+        StatusCode::OK,
+        &context::schemas(),
+        "serializing schemas failed",
+    )
+    .map_err(Error::from)
+}
+
+async fn get_service_provider_config<C: ScimContext, P: ScimPathParam>(
+    rqctx: RequestContext<C>,
+    _path_param: Path<P>,
+) -> Result<Response<Body>, Error> {
+    let config = context::service_provider_config::<C::Store>(
+        rqctx.context().authentication_schemes(),
+    );
+
+    value_to_http_response(
+        StatusCode::OK,
+        &config,
+        "serializing service provider config failed",
+    )
+    .map_err(Error::from)
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::{
+        InMemoryProviderStore, ProviderStore, Tenant, TenantStoreMap,
+        TenantStores,
+    };
+
+    /// Serves every request from the store of the tenant in its path
+    struct TenantContext {
+        provider: Provider<Arc<InMemoryProviderStore>>,
+        tenants: TenantStoreMap<InMemoryProviderStore>,
+        base_url: String,
+    }
+
+    impl ScimContext for TenantContext {
+        type Store = Arc<InMemoryProviderStore>;
+        type Hooks = ();
+        type AuditSink = ();
+
+        async fn authorize(
+            &self,
+            request: ScimRequest<'_>,
+        ) -> Result<(OperationContext, Provider<Self::Store, &(), &()>), Error>
+        {
+            let tenant_id = request.tenant_id.expect("no tenant in the path");
+            let tenant = Tenant {
+                id: tenant_id.to_string(),
+                base_url: format!("{}/tenants/{tenant_id}/v2", self.base_url),
+            };
+            let store = self.tenants.store(tenant_id).await?;
+
+            Ok((
+                OperationContext::default(),
+                self.provider.for_store(store).with_tenant(tenant),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_scim_endpoints() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider =
+            Provider::new(log.clone(), Arc::new(InMemoryProviderStore::new()));
+
+        let mut api = ApiDescription::new();
+        register_scim_endpoints(&mut api, "/scim/v2/").unwrap();
+
+        let server = dropshot::ServerBuilder::new(api, provider, log.clone())
+            .config(dropshot::ConfigDropshot {
+                bind_address: "127.0.0.1:0".parse().unwrap(),
+                ..Default::default()
+            })
+            .start()
+            .unwrap();
+        let base_url = format!("http://{}/scim/v2", server.local_addr());
+        let client = reqwest::Client::new();
+
+        let result = client
+            .post(format!("{base_url}/Users"))
+            .json(&json!({ "userName": "jhalpert" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+        let user: Value = result.json().await.unwrap();
+
+        let result = client
+            .get(format!("{base_url}/Users/{}", user["id"].as_str().unwrap()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+
+        let result = client
+            .get(format!("{base_url}/ServiceProviderConfig"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        assert_eq!(
+            result.headers()[http::header::CONTENT_TYPE],
+            "application/scim+json"
+        );
+
+        server.close().await.unwrap();
+
+        // A tenant in the base path has to be taken from every endpoint's path
+        let mut api = ApiDescription::<Arc<TenantContext>>::new();
+        register_scim_endpoints(&mut api, "/tenants/{tenant_id}/v2").unwrap();
+
+        let context = Arc::new(TenantContext {
+            provider: Provider::new(
+                log.clone(),
+                Arc::new(InMemoryProviderStore::new()),
+            ),
+            tenants: TenantStoreMap::new(|_| Ok(InMemoryProviderStore::new())),
+            base_url: String::from("http://scim.example.com"),
+        });
+        let server =
+            dropshot::ServerBuilder::new(api, Arc::clone(&context), log)
+                .config(dropshot::ConfigDropshot {
+                    bind_address: "127.0.0.1:0".parse().unwrap(),
+                    ..Default::default()
+                })
+                .start()
+                .unwrap();
+        let tenant_url = |tenant_id: &str| {
+            format!("http://{}/tenants/{tenant_id}/v2", server.local_addr())
+        };
+
+        let result = client
+            .post(format!("{}/Users", tenant_url("sabre")))
+            .json(&json!({ "userName": "jhalpert" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+        let user: Value = result.json().await.unwrap();
+        let user_id = user["id"].as_str().unwrap();
+        assert!(
+            user["meta"]["location"]
+                .as_str()
+                .unwrap()
+                .starts_with("http://scim.example.com/tenants/sabre/v2/Users/"),
+            "{user}"
+        );
+
+        // The user is in the tenant's store, and no other
+        let sabre = context.tenants.store("sabre").await.unwrap();
+        assert!(sabre.get_user_by_id(user_id).await.unwrap().is_some());
+        assert!(
+            context
+                .provider
+                .store()
+                .get_user_by_id(user_id)
+                .await
+                .unwrap()
+                .is_none()
+        );
+
+        for (tenant_id, expected) in [
+            ("sabre", StatusCode::OK),
+            ("dunder-mifflin", StatusCode::NOT_FOUND),
+        ] {
+            let result = client
+                .get(format!("{}/Users/{user_id}", tenant_url(tenant_id)))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(result.status(), expected, "{tenant_id}");
+        }
+        assert_eq!(context.tenants.tenant_ids(), ["dunder-mifflin", "sabre"]);
+
+        server.close().await.unwrap();
+    }
+}
@@ -0,0 +1,240 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{Group, User};
+
+/// Something that changed about the users and groups in a store
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ChangeEventKind {
+    UserCreated {
+        user: User,
+    },
+
+    /// The user was replaced or patched. Changes to `active` are also reported
+    /// as `UserDeactivated` or `UserReactivated`.
+    UserUpdated {
+        user: User,
+    },
+
+    UserDeactivated {
+        user_id: String,
+    },
+
+    UserReactivated {
+        user_id: String,
+    },
+
+    /// The user was deleted, or soft deleted. Its group memberships are
+    /// reported as removed.
+    UserDeleted {
+        user_id: String,
+    },
+
+    GroupCreated {
+        group: Group,
+    },
+
+    /// The group was replaced or patched. Changes to its members are
+    /// reported separately.
+    GroupUpdated {
+        group: Group,
+    },
+
+    /// The group was deleted, or soft deleted. Its members are reported as
+    /// removed.
+    GroupDeleted {
+        group_id: String,
+    },
+
+    GroupMembersAdded {
+        group_id: String,
+        user_ids: Vec<String>,
+    },
+
+    GroupMembersRemoved {
+        group_id: String,
+        user_ids: Vec<String>,
+    },
+}
+
+/// A change, as delivered to subscribers and recorded in an outbox
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ChangeEvent {
+    /// Unique to this event, so that consumers can drop duplicates
+    pub id: String,
+
+    pub occurred_at: DateTime<Utc>,
+
+    /// The tenant whose resources changed, if the provider serves one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+
+    #[serde(flatten)]
+    pub kind: ChangeEventKind,
+}
+
+impl ChangeEvent {
+    pub fn new(kind: ChangeEventKind) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            occurred_at: Utc::now(),
+            tenant: None,
+            kind,
+        }
+    }
+}
+
+/// An event waiting in a store's outbox to be relayed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct OutboxEntry {
+    /// Increases with every event recorded, and is never reused
+    pub sequence: u64,
+
+    pub event: ChangeEvent,
+}
+
+/// An outbox entry that could not be delivered to a consumer, such as a
+/// webhook endpoint, even after retrying
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DeadLetter {
+    /// The consumer that gave up on the entry
+    pub endpoint: String,
+
+    /// The entry's sequence number in the outbox
+    pub sequence: u64,
+
+    pub event: ChangeEvent,
+
+    pub attempts: u32,
+
+    pub last_error: String,
+
+    pub failed_at: DateTime<Utc>,
+}
+
+fn user_group_ids(user: &User) -> Vec<String> {
+    user.groups.iter().flatten().filter_map(|g| g.value.clone()).collect()
+}
+
+fn member_ids(group: &Group) -> BTreeSet<String> {
+    group.members.iter().flatten().filter_map(|m| m.value.clone()).collect()
+}
+
+fn is_active(user: &User) -> bool {
+    // RFC 7643 does not give `active` a default, but treating a missing value
+    // as inactive would turn every IdP that leaves it out into a stream of
+    // deactivations.
+    user.active != Some(false)
+}
+
+/// The events that describe a user going from `before` to `after`, where
+/// `None` means the user does not exist. Restoring a soft deleted user counts
+/// as creating it.
+pub fn user_changes(
+    before: Option<&User>,
+    after: Option<&User>,
+) -> Vec<ChangeEventKind> {
+    match (before, after) {
+        (None, None) => vec![],
+
+        (None, Some(user)) => {
+            let mut changes =
+                vec![ChangeEventKind::UserCreated { user: user.clone() }];
+            changes.extend(user_group_ids(user).into_iter().map(|group_id| {
+                ChangeEventKind::GroupMembersAdded {
+                    group_id,
+                    user_ids: vec![user.id.clone()],
+                }
+            }));
+            changes
+        }
+
+        (Some(before), Some(user)) => {
+            let mut changes =
+                vec![ChangeEventKind::UserUpdated { user: user.clone() }];
+            match (is_active(before), is_active(user)) {
+                (true, false) => {
+                    changes.push(ChangeEventKind::UserDeactivated {
+                        user_id: user.id.clone(),
+                    });
+                }
+                (false, true) => {
+                    changes.push(ChangeEventKind::UserReactivated {
+                        user_id: user.id.clone(),
+                    });
+                }
+                _ => {}
+            }
+            changes
+        }
+
+        (Some(user), None) => {
+            let mut changes =
+                vec![ChangeEventKind::UserDeleted { user_id: user.id.clone() }];
+            changes.extend(user_group_ids(user).into_iter().map(|group_id| {
+                ChangeEventKind::GroupMembersRemoved {
+                    group_id,
+                    user_ids: vec![user.id.clone()],
+                }
+            }));
+            changes
+        }
+    }
+}
+
+/// The events that describe a group going from `before` to `after`, where
+/// `None` means the group does not exist. Restoring a soft deleted group
+/// counts as creating it.
+pub fn group_changes(
+    before: Option<&Group>,
+    after: Option<&Group>,
+) -> Vec<ChangeEventKind> {
+    let group_id = match (before, after) {
+        (None, None) => return vec![],
+        (Some(group), _) | (None, Some(group)) => group.id.clone(),
+    };
+
+    let before_members = before.map(member_ids).unwrap_or_default();
+    let after_members = after.map(member_ids).unwrap_or_default();
+
+    let mut changes = vec![match (before, after) {
+        (None, Some(group)) => {
+            ChangeEventKind::GroupCreated { group: group.clone() }
+        }
+        (Some(_), Some(group)) => {
+            ChangeEventKind::GroupUpdated { group: group.clone() }
+        }
+        (_, None) => {
+            ChangeEventKind::GroupDeleted { group_id: group_id.clone() }
+        }
+    }];
+
+    let added: Vec<String> =
+        after_members.difference(&before_members).cloned().collect();
+    if !added.is_empty() {
+        changes.push(ChangeEventKind::GroupMembersAdded {
+            group_id: group_id.clone(),
+            user_ids: added,
+        });
+    }
+
+    let removed: Vec<String> =
+        before_members.difference(&after_members).cloned().collect();
+    if !removed.is_empty() {
+        changes.push(ChangeEventKind::GroupMembersRemoved {
+            group_id,
+            user_ids: removed,
+        });
+    }
+
+    changes
+}
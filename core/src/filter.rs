@@ -0,0 +1,891 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A parser for the full filter grammar in RFC 7644 section 3.4.2.2.
+//!
+//! `QueryParams::filter` parses the `filter` parameter of a request into a
+//! [`Filter`], which is handed to the store as it is. Stores evaluate it in
+//! memory or by translating it to SQL, and reject the attributes and
+//! comparisons they cannot evaluate with an `invalidFilter` error.
+//!
+//! Filters can also be built in code, and are rendered by their `Display`
+//! impl with string values escaped, so that a value holding quotes cannot
+//! change the meaning of the filter:
+//!
+//! ```
+//! use scim2_rs::Filter;
+//!
+//! let filter = Filter::attr("userName")
+//!     .eq("dwight \"the\" schrute")
+//!     .and(Filter::attr("active").eq(true));
+//!
+//! assert_eq!(
+//!     filter.to_string(),
+//!     r#"userName eq "dwight \"the\" schrute" and active eq true"#,
+//! );
+//! assert_eq!(Filter::parse(&filter.to_string()).unwrap(), filter);
+//! ```
+
+use chrono::{DateTime, Utc};
+
+use crate::Error;
+
+/// An attribute path such as `userName`, `meta.lastModified`, or
+/// `urn:ietf:params:scim:schemas:core:2.0:User:name.familyName`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttrPath {
+    /// The schema URN that qualifies the attribute, if one was given.
+    pub urn: Option<String>,
+
+    pub attr: String,
+
+    pub sub_attr: Option<String>,
+}
+
+impl AttrPath {
+    pub fn new(attr: &str) -> Self {
+        Self { urn: None, attr: attr.to_string(), sub_attr: None }
+    }
+
+    /// The path without any schema URN, e.g. `name.familyName`.
+    pub fn dotted(&self) -> String {
+        match &self.sub_attr {
+            Some(sub_attr) => format!("{}.{}", self.attr, sub_attr),
+            None => self.attr.clone(),
+        }
+    }
+
+    /// Parse an attribute path, such as one from a request, failing with an
+    /// `invalidFilter` error if it is not valid.
+    pub fn parse(raw: &str) -> Result<Self, Error> {
+        // A URN-qualified path puts the attribute after the last colon.
+        let (urn, rest) =
+            if raw.get(..4).is_some_and(|p| p.eq_ignore_ascii_case("urn:")) {
+                match raw.rsplit_once(':') {
+                    Some((urn, rest)) => (Some(urn.to_string()), rest),
+                    None => (None, raw),
+                }
+            } else {
+                (None, raw)
+            };
+
+        let (attr, sub_attr) = match rest.split_once('.') {
+            Some((attr, sub_attr)) => (attr, Some(sub_attr)),
+            None => (rest, None),
+        };
+
+        // ATTRNAME = ALPHA *(nameChar), nameChar = "-" / "_" / DIGIT / ALPHA
+        let is_attr_name = |name: &str| {
+            let mut chars = name.chars();
+            // `$ref` is the one attribute name that breaks the rules
+            name == "$ref"
+                || (chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+                    && chars.all(|c| {
+                        c.is_ascii_alphanumeric() || c == '-' || c == '_'
+                    }))
+        };
+
+        if !is_attr_name(attr) || !sub_attr.is_none_or(is_attr_name) {
+            return Err(Error::invalid_filter(format!(
+                "invalid attribute path {raw}"
+            )));
+        }
+
+        Ok(Self {
+            urn,
+            attr: attr.to_string(),
+            sub_attr: sub_attr.map(str::to_string),
+        })
+    }
+
+    pub fn compare(self, op: CompareOp, value: impl Into<CompValue>) -> Filter {
+        Filter::Compare { path: self, op, value: value.into() }
+    }
+
+    pub fn eq(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Eq, value)
+    }
+
+    pub fn ne(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Ne, value)
+    }
+
+    pub fn co(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Co, value)
+    }
+
+    pub fn sw(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Sw, value)
+    }
+
+    pub fn ew(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Ew, value)
+    }
+
+    pub fn gt(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Gt, value)
+    }
+
+    pub fn ge(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Ge, value)
+    }
+
+    pub fn lt(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Lt, value)
+    }
+
+    pub fn le(self, value: impl Into<CompValue>) -> Filter {
+        self.compare(CompareOp::Le, value)
+    }
+
+    pub fn pr(self) -> Filter {
+        Filter::Present(self)
+    }
+}
+
+impl std::fmt::Display for AttrPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.urn {
+            Some(urn) => write!(f, "{urn}:{}", self.dotted()),
+            None => write!(f, "{}", self.dotted()),
+        }
+    }
+}
+
+/// The attribute operators from RFC 7644 section 3.4.2.2, other than `pr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Co,
+    Sw,
+    Ew,
+    Gt,
+    Ge,
+    Lt,
+    Le,
+}
+
+impl CompareOp {
+    fn parse(raw: &str) -> Option<Self> {
+        // Attribute operators are case insensitive.
+        let op = match raw.to_ascii_lowercase().as_str() {
+            "eq" => CompareOp::Eq,
+            "ne" => CompareOp::Ne,
+            "co" => CompareOp::Co,
+            "sw" => CompareOp::Sw,
+            "ew" => CompareOp::Ew,
+            "gt" => CompareOp::Gt,
+            "ge" => CompareOp::Ge,
+            "lt" => CompareOp::Lt,
+            "le" => CompareOp::Le,
+            _ => return None,
+        };
+
+        Some(op)
+    }
+}
+
+impl std::fmt::Display for CompareOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let op = match self {
+            CompareOp::Eq => "eq",
+            CompareOp::Ne => "ne",
+            CompareOp::Co => "co",
+            CompareOp::Sw => "sw",
+            CompareOp::Ew => "ew",
+            CompareOp::Gt => "gt",
+            CompareOp::Ge => "ge",
+            CompareOp::Lt => "lt",
+            CompareOp::Le => "le",
+        };
+
+        write!(f, "{op}")
+    }
+}
+
+/// The value on the right-hand side of a comparison, which is a JSON literal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompValue {
+    Null,
+    Bool(bool),
+    Number(serde_json::Number),
+    String(String),
+}
+
+impl std::fmt::Display for CompValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            CompValue::Null => write!(f, "null"),
+            CompValue::Bool(value) => write!(f, "{value}"),
+            CompValue::Number(value) => write!(f, "{value}"),
+            // Strings are JSON strings, so serde_json does the escaping
+            CompValue::String(value) => {
+                write!(f, "{}", serde_json::Value::from(value.as_str()))
+            }
+        }
+    }
+}
+
+impl From<&str> for CompValue {
+    fn from(value: &str) -> Self {
+        CompValue::String(value.to_string())
+    }
+}
+
+impl From<String> for CompValue {
+    fn from(value: String) -> Self {
+        CompValue::String(value)
+    }
+}
+
+impl From<&String> for CompValue {
+    fn from(value: &String) -> Self {
+        CompValue::String(value.clone())
+    }
+}
+
+impl From<bool> for CompValue {
+    fn from(value: bool) -> Self {
+        CompValue::Bool(value)
+    }
+}
+
+impl From<i64> for CompValue {
+    fn from(value: i64) -> Self {
+        CompValue::Number(value.into())
+    }
+}
+
+impl From<u64> for CompValue {
+    fn from(value: u64) -> Self {
+        CompValue::Number(value.into())
+    }
+}
+
+impl From<serde_json::Number> for CompValue {
+    fn from(value: serde_json::Number) -> Self {
+        CompValue::Number(value)
+    }
+}
+
+/// Dates are compared as RFC 3339 strings (RFC 7643, section 2.3.5)
+impl From<DateTime<Utc>> for CompValue {
+    fn from(value: DateTime<Utc>) -> Self {
+        CompValue::String(value.to_rfc3339())
+    }
+}
+
+/// A parsed filter expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    /// `attrPath compareOp compValue`
+    Compare {
+        path: AttrPath,
+        op: CompareOp,
+        value: CompValue,
+    },
+
+    /// `attrPath pr`
+    Present(AttrPath),
+
+    And(Box<Filter>, Box<Filter>),
+
+    Or(Box<Filter>, Box<Filter>),
+
+    Not(Box<Filter>),
+}
+
+impl Filter {
+    pub fn parse(raw: &str) -> Result<Self, Error> {
+        let tokens = tokenize(raw)?;
+        let mut parser = Parser { tokens: &tokens, position: 0, depth: 0 };
+
+        let filter = parser.parse_or()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(Error::invalid_filter(format!(
+                "unexpected {token} in filter {raw}"
+            )));
+        }
+
+        Ok(filter)
+    }
+
+    /// Start building an expression on the attribute at `path`, e.g.
+    /// `Filter::attr("name.familyName").eq("Schrute")`.
+    ///
+    /// This is for paths written as literals in code. Use `try_attr` for one
+    /// that comes from anywhere else.
+    ///
+    /// # Panics
+    ///
+    /// If `path` is not a valid attribute path, which for a literal is a bug,
+    /// unlike a value that does not need escaping.
+    pub fn attr(path: &str) -> AttrPath {
+        match Self::try_attr(path) {
+            Ok(path) => path,
+            Err(e) => panic!("{}", e.detail),
+        }
+    }
+
+    /// Start building an expression on the attribute at `path`, failing with
+    /// an `invalidFilter` error if it is not a valid attribute path.
+    pub fn try_attr(path: &str) -> Result<AttrPath, Error> {
+        AttrPath::parse(path)
+    }
+
+    pub fn and(self, other: Filter) -> Filter {
+        Filter::And(Box::new(self), Box::new(other))
+    }
+
+    pub fn or(self, other: Filter) -> Filter {
+        Filter::Or(Box::new(self), Box::new(other))
+    }
+
+    /// How tightly the expression binds, for deciding where `Display` needs
+    /// parentheses
+    fn precedence(&self) -> u8 {
+        match self {
+            Filter::Or(..) => 0,
+            Filter::And(..) => 1,
+            Filter::Compare { .. } | Filter::Present(_) | Filter::Not(_) => 2,
+        }
+    }
+
+    /// Write `self` as an operand of an operator with `precedence`. The
+    /// parser groups "and" and "or" from the left, so an operand on the right
+    /// needs parentheses at the same precedence too.
+    fn fmt_operand(
+        &self,
+        f: &mut std::fmt::Formatter,
+        precedence: u8,
+        right: bool,
+    ) -> std::fmt::Result {
+        let own = self.precedence();
+        if own < precedence || (right && own == precedence) {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+}
+
+/// Renders the filter so that `Filter::parse` gives back the same tree
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Filter::Compare { path, op, value } => {
+                write!(f, "{path} {op} {value}")
+            }
+
+            Filter::Present(path) => write!(f, "{path} pr"),
+
+            Filter::And(lhs, rhs) | Filter::Or(lhs, rhs) => {
+                let precedence = self.precedence();
+                let keyword = if precedence == 0 { "or" } else { "and" };
+                lhs.fmt_operand(f, precedence, false)?;
+                write!(f, " {keyword} ")?;
+                rhs.fmt_operand(f, precedence, true)
+            }
+
+            Filter::Not(filter) => write!(f, "not ({filter})"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+    /// An unquoted run of characters: an attribute path, operator, keyword,
+    /// or non-string literal.
+    Word(String),
+    /// A decoded JSON string
+    String(String),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Token::OpenParen => write!(f, "'('"),
+            Token::CloseParen => write!(f, "')'"),
+            Token::OpenBracket => write!(f, "'['"),
+            Token::CloseBracket => write!(f, "']'"),
+            Token::Word(word) => write!(f, "'{word}'"),
+            Token::String(string) => write!(f, "{string:?}"),
+        }
+    }
+}
+
+fn tokenize(raw: &str) -> Result<Vec<Token>, Error> {
+    let mut tokens = Vec::new();
+    let mut chars = raw.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {}
+            '(' => tokens.push(Token::OpenParen),
+            ')' => tokens.push(Token::CloseParen),
+            '[' => tokens.push(Token::OpenBracket),
+            ']' => tokens.push(Token::CloseBracket),
+
+            '"' => {
+                // Find the closing quote, skipping over escaped characters,
+                // and let serde_json deal with decoding the escapes.
+                let mut end = None;
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => {
+                            end = Some(i);
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+
+                let Some(end) = end else {
+                    return Err(Error::invalid_filter(format!(
+                        "unterminated string in filter {raw}"
+                    )));
+                };
+
+                let value: String = serde_json::from_str(&raw[start..=end])
+                    .map_err(|e| {
+                        Error::invalid_filter(format!(
+                            "invalid string in filter {raw}: {e}"
+                        ))
+                    })?;
+
+                tokens.push(Token::String(value));
+            }
+
+            _ => {
+                let mut end = start + c.len_utf8();
+                while let Some(&(i, c)) = chars.peek() {
+                    if c.is_whitespace() || "()[]\"".contains(c) {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+
+                tokens.push(Token::Word(raw[start..end].to_string()));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// How deeply parentheses and `not` can nest, so that a filter from a client
+/// cannot overflow the stack of the recursive descent.
+const MAX_NESTING_DEPTH: usize = 32;
+
+struct Parser<'a> {
+    tokens: &'a [Token],
+    position: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.position);
+        self.position += 1;
+        token
+    }
+
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Error> {
+        match self.next() {
+            Some(token) if *token == expected => Ok(()),
+            Some(token) => Err(Error::invalid_filter(format!(
+                "expected {expected} but found {token}"
+            ))),
+            None => Err(Error::invalid_filter(format!(
+                "expected {expected} but the filter ended"
+            ))),
+        }
+    }
+
+    // Logical operators have the precedence "not", then "and", then "or".
+
+    fn parse_or(&mut self) -> Result<Filter, Error> {
+        let mut filter = self.parse_and()?;
+        while self.peek_keyword("or") {
+            self.next();
+            let rhs = self.parse_and()?;
+            filter = Filter::Or(Box::new(filter), Box::new(rhs));
+        }
+        Ok(filter)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter, Error> {
+        let mut filter = self.parse_unary()?;
+        while self.peek_keyword("and") {
+            self.next();
+            let rhs = self.parse_unary()?;
+            filter = Filter::And(Box::new(filter), Box::new(rhs));
+        }
+        Ok(filter)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter, Error> {
+        if self.peek_keyword("not") {
+            self.next();
+            self.expect(Token::OpenParen)?;
+            let filter = self.parse_nested()?;
+            self.expect(Token::CloseParen)?;
+            return Ok(Filter::Not(Box::new(filter)));
+        }
+
+        if self.peek() == Some(&Token::OpenParen) {
+            self.next();
+            let filter = self.parse_nested()?;
+            self.expect(Token::CloseParen)?;
+            return Ok(filter);
+        }
+
+        self.parse_attr_expression()
+    }
+
+    /// Parse the expression inside a pair of parentheses
+    fn parse_nested(&mut self) -> Result<Filter, Error> {
+        if self.depth == MAX_NESTING_DEPTH {
+            return Err(Error::invalid_filter(format!(
+                "filter is nested more than {MAX_NESTING_DEPTH} deep"
+            )));
+        }
+
+        self.depth += 1;
+        let filter = self.parse_or();
+        self.depth -= 1;
+        filter
+    }
+
+    fn parse_attr_expression(&mut self) -> Result<Filter, Error> {
+        let path = match self.next() {
+            Some(Token::Word(word)) => AttrPath::parse(word)?,
+            Some(token) => {
+                return Err(Error::invalid_filter(format!(
+                    "expected an attribute path but found {token}"
+                )));
+            }
+            None => {
+                return Err(Error::invalid_filter(
+                    "expected an attribute path but the filter ended"
+                        .to_string(),
+                ));
+            }
+        };
+
+        if self.peek() == Some(&Token::OpenBracket) {
+            return Err(Error::invalid_filter(format!(
+                "complex attribute filters such as {}[...] are not supported",
+                path.dotted()
+            )));
+        }
+
+        let op = match self.next() {
+            Some(Token::Word(word)) if word.eq_ignore_ascii_case("pr") => {
+                return Ok(Filter::Present(path));
+            }
+
+            Some(Token::Word(word)) => {
+                CompareOp::parse(word).ok_or_else(|| {
+                    Error::invalid_filter(format!("unknown operator {word}"))
+                })?
+            }
+
+            _ => {
+                return Err(Error::invalid_filter(format!(
+                    "expected an operator after {}",
+                    path.dotted()
+                )));
+            }
+        };
+
+        let value = match self.next() {
+            Some(Token::String(value)) => CompValue::String(value.clone()),
+            Some(Token::Word(word)) => match word.as_str() {
+                "true" => CompValue::Bool(true),
+                "false" => CompValue::Bool(false),
+                "null" => CompValue::Null,
+                word => CompValue::Number(word.parse().map_err(|_| {
+                    Error::invalid_filter(format!("invalid value {word}"))
+                })?),
+            },
+            _ => {
+                return Err(Error::invalid_filter(format!(
+                    "expected a value after {}",
+                    path.dotted()
+                )));
+            }
+        };
+
+        Ok(Filter::Compare { path, op, value })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn compare(attr: &str, op: CompareOp, value: &str) -> Filter {
+        Filter::Compare {
+            path: AttrPath::parse(attr).unwrap(),
+            op,
+            value: CompValue::String(value.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_parse_attr_expressions() {
+        assert_eq!(
+            Filter::parse("userName Eq \"Mike\"").unwrap(),
+            compare("userName", CompareOp::Eq, "Mike"),
+        );
+
+        assert_eq!(
+            Filter::parse("meta.lastModified gt \"2011-05-13T04:42:34Z\"")
+                .unwrap(),
+            compare("meta.lastModified", CompareOp::Gt, "2011-05-13T04:42:34Z"),
+        );
+
+        assert_eq!(
+            Filter::parse("title pr").unwrap(),
+            Filter::Present(AttrPath::new("title")),
+        );
+
+        assert_eq!(
+            Filter::parse(
+                "urn:ietf:params:scim:schemas:core:2.0:User:name.familyName \
+                co \"O'Malley\""
+            )
+            .unwrap(),
+            Filter::Compare {
+                path: AttrPath {
+                    urn: Some(
+                        "urn:ietf:params:scim:schemas:core:2.0:User"
+                            .to_string()
+                    ),
+                    attr: "name".to_string(),
+                    sub_attr: Some("familyName".to_string()),
+                },
+                op: CompareOp::Co,
+                value: CompValue::String("O'Malley".to_string()),
+            },
+        );
+
+        assert_eq!(
+            Filter::parse("active eq true").unwrap(),
+            Filter::Compare {
+                path: AttrPath::new("active"),
+                op: CompareOp::Eq,
+                value: CompValue::Bool(true),
+            },
+        );
+
+        assert_eq!(
+            Filter::parse("externalId eq null").unwrap(),
+            Filter::Compare {
+                path: AttrPath::new("externalId"),
+                op: CompareOp::Eq,
+                value: CompValue::Null,
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_string_escapes() {
+        assert_eq!(
+            Filter::parse(r#"displayName eq "say \"hi\" \\ é""#).unwrap(),
+            compare("displayName", CompareOp::Eq, "say \"hi\" \\ é"),
+        );
+
+        // Brackets and parentheses inside a string are just characters
+        assert_eq!(
+            Filter::parse(r#"displayName sw "(a) [b]""#).unwrap(),
+            compare("displayName", CompareOp::Sw, "(a) [b]"),
+        );
+    }
+
+    #[test]
+    fn test_parse_logical_expressions() {
+        let a = compare("userName", CompareOp::Eq, "a");
+        let b = compare("userName", CompareOp::Eq, "b");
+        let c = compare("userName", CompareOp::Eq, "c");
+
+        // "and" binds more tightly than "or"
+        assert_eq!(
+            Filter::parse(
+                r#"userName eq "a" or userName eq "b" AND userName eq "c""#
+            )
+            .unwrap(),
+            Filter::Or(
+                Box::new(a.clone()),
+                Box::new(Filter::And(Box::new(b.clone()), Box::new(c.clone()))),
+            ),
+        );
+
+        assert_eq!(
+            Filter::parse(
+                r#"(userName eq "a" or userName eq "b") and userName eq "c""#
+            )
+            .unwrap(),
+            Filter::And(
+                Box::new(Filter::Or(Box::new(a.clone()), Box::new(b.clone()))),
+                Box::new(c.clone()),
+            ),
+        );
+
+        assert_eq!(
+            Filter::parse(r#"not (userName eq "a")"#).unwrap(),
+            Filter::Not(Box::new(a)),
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid_filters() {
+        for raw in [
+            "",
+            "userName",
+            "userName eq",
+            "userName xx \"a\"",
+            "userName eq \"a",
+            "userName eq \"a\" extra",
+            "userName eq bare",
+            "(userName eq \"a\"",
+            "not userName eq \"a\"",
+            "1userName eq \"a\"",
+            "emails[type eq \"work\"]",
+            // Multibyte characters around where a URN prefix would end
+            "abcé eq \"x\"",
+            "é eq \"x\"",
+            "urné:userName eq \"x\"",
+        ] {
+            let error = Filter::parse(raw).unwrap_err();
+            assert_eq!(
+                error.error_type,
+                Some(crate::ErrorType::InvalidFilter),
+                "{raw}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_nesting_limit() {
+        let nested = |depth: usize| {
+            format!(
+                "{}userName eq \"a\"{}",
+                "(".repeat(depth),
+                ")".repeat(depth)
+            )
+        };
+
+        assert!(Filter::parse(&nested(MAX_NESTING_DEPTH)).is_ok());
+
+        for raw in [
+            nested(MAX_NESTING_DEPTH + 1),
+            nested(100_000),
+            "not (".repeat(100_000),
+        ] {
+            let error = Filter::parse(&raw).unwrap_err();
+            assert_eq!(error.error_type, Some(crate::ErrorType::InvalidFilter));
+        }
+    }
+
+    #[test]
+    fn test_build_filters() {
+        assert_eq!(
+            Filter::attr("userName").eq("Mike"),
+            compare("userName", CompareOp::Eq, "Mike"),
+        );
+
+        let filter = Filter::attr("displayName")
+            .eq("a\" or userName pr or \"\\")
+            .and(!Filter::attr("title").pr());
+        assert_eq!(
+            filter.to_string(),
+            r#"displayName eq "a\" or userName pr or \"\\" and not (title pr)"#,
+        );
+
+        let modified = "2011-05-13T04:42:34Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            Filter::attr("meta.lastModified").gt(modified).to_string(),
+            r#"meta.lastModified gt "2011-05-13T04:42:34+00:00""#,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid attribute path")]
+    fn test_build_filter_invalid_attr() {
+        Filter::attr("userName eq \"a\" or userName");
+    }
+
+    #[test]
+    fn test_build_filter_try_attr() {
+        assert_eq!(
+            Filter::try_attr("name.familyName").unwrap().eq("Schrute"),
+            Filter::attr("name.familyName").eq("Schrute"),
+        );
+
+        for path in
+            ["userName eq \"a\" or userName", "", "1st", "name.", "abcé"]
+        {
+            let error = Filter::try_attr(path).unwrap_err();
+            assert_eq!(error.error_type, Some(crate::ErrorType::InvalidFilter));
+        }
+    }
+
+    #[test]
+    fn test_built_filters_round_trip() {
+        let a = Filter::attr("userName").eq("a");
+        let b = Filter::attr("userName").sw("b\"");
+        let c = Filter::attr("active").eq(false);
+        let d = Filter::attr(
+            "urn:ietf:params:scim:schemas:core:2.0:User:name.familyName",
+        )
+        .ne(CompValue::Null);
+
+        for filter in [
+            a.clone().and(b.clone()).or(c.clone()),
+            a.clone().and(b.clone().or(c.clone())),
+            a.clone().or(b.clone().or(c.clone())),
+            a.clone().and(b.clone().and(c.clone())),
+            a.clone().or(b.clone()).and(!c.clone().or(d.clone())),
+            !(a.clone().and(Filter::attr("x").ge(10_u64))),
+            d.clone().or(Filter::attr("x")
+                .lt(serde_json::Number::from_f64(-1.5).unwrap())),
+        ] {
+            let rendered = filter.to_string();
+            assert_eq!(Filter::parse(&rendered).unwrap(), filter, "{rendered}");
+        }
+
+        assert_eq!(
+            a.clone().and(b.clone().or(c.clone())).to_string(),
+            r#"userName eq "a" and (userName sw "b\"" or active eq false)"#,
+        );
+    }
+}
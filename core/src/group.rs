@@ -10,14 +10,16 @@ use unicase::UniCase;
 use crate::utils::skip_serializing_list_map;
 use crate::{GROUP_URN, Resource, ResourceType};
 
-#[derive(Deserialize, JsonSchema, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateGroupRequest {
     pub display_name: String,
 
     /// An identifier for the resource as defined by the provisioning client
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub external_id: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub members: Option<IdOrdMap<GroupMember>>,
 }
 
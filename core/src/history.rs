@@ -0,0 +1,84 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::{Group, Resource, StoredParts, User, UserGroup, UserGroupType};
+
+/// One version of a resource, recorded by a store with history every time
+/// the resource is created, changed, deleted or restored
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Revision<R: Resource> {
+    /// Increases with every revision a store records, across all resources
+    pub revision: u64,
+
+    pub recorded_at: DateTime<Utc>,
+
+    /// The resource as of this revision, or `None` if it was deleted
+    pub resource: Option<StoredParts<R>>,
+}
+
+/// The resource as of the last of `revisions` (oldest first) recorded at or
+/// before `at`, if it existed then
+pub(crate) fn latest_as_of<R: Resource>(
+    revisions: &[Revision<R>],
+    at: DateTime<Utc>,
+) -> Option<&StoredParts<R>> {
+    revisions
+        .iter()
+        .take_while(|revision| revision.recorded_at <= at)
+        .last()
+        .and_then(|revision| revision.resource.as_ref())
+}
+
+// Membership is recorded on the group side: a revision of a group is
+// recorded whenever its members change, including when a member is deleted
+// or restored, but a user's revisions do not show it joining or leaving
+// groups. Point-in-time reads put the user's side back from the groups'
+// history: a user's groups are the groups that existed at the time with the
+// user as a member. A group's members are also checked against the users'
+// history, for histories recorded before member deletes were.
+
+/// Drop the members of `group` for which `user_existed` is false
+pub(crate) fn group_as_of(
+    mut group: StoredParts<Group>,
+    user_existed: impl Fn(&str) -> bool,
+) -> StoredParts<Group> {
+    if let Some(members) = &mut group.resource.members {
+        members.retain(|member| {
+            member.value.as_deref().is_some_and(&user_existed)
+        });
+    }
+
+    group
+}
+
+/// Replace the `groups` of `user` with those of `groups` (as of the same
+/// time) that it is a member of
+pub(crate) fn user_as_of<'a>(
+    mut user: StoredParts<User>,
+    groups: impl IntoIterator<Item = &'a StoredParts<Group>>,
+) -> StoredParts<User> {
+    let user_id = user.resource.id.clone();
+
+    let user_groups: Vec<UserGroup> =
+        groups
+            .into_iter()
+            .filter(|group| {
+                group.resource.members.iter().flatten().any(|member| {
+                    member.value.as_deref() == Some(user_id.as_str())
+                })
+            })
+            .map(|group| UserGroup {
+                member_type: Some(UserGroupType::Direct),
+                value: Some(group.resource.id.clone()),
+                display: Some(group.resource.display_name.clone()),
+            })
+            .collect();
+
+    user.resource.groups = Some(user_groups);
+    user
+}
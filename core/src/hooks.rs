@@ -0,0 +1,308 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::{
+    CreateGroupRequest, CreateUserRequest, Error, Group, StoredParts, User,
+};
+
+/// Application callbacks that a `Provider` invokes around the changes it
+/// makes.
+///
+/// Before-hooks run before the store is touched, and can veto the operation
+/// by returning an `Error`, which is sent back to the client as-is.
+/// After-hooks run once the change has been stored, so they cannot fail the
+/// request: they must deal with their own errors.
+///
+/// Every method does nothing by default, so implementations only need the
+/// ones they care about. `()` is the implementation that does nothing at all.
+///
+/// Group membership can change through several operations (creating,
+/// replacing, patching or deleting a group, and deleting or deactivating a
+/// user), so rather than having every after-hook work it out, the `Provider`
+/// also reports the net change in each group's members through
+/// `after_group_members_added` and `after_group_members_removed`, after the
+/// hook for the operation itself.
+pub trait ProvisioningHooks: Sync {
+    fn before_create_user(
+        &self,
+        _request: &CreateUserRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_create_user(
+        &self,
+        _user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn before_replace_user(
+        &self,
+        _user_id: &str,
+        _request: &CreateUserRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Also called when a user is deactivated by `UserDeletePolicy::Deactivate`
+    fn after_replace_user(
+        &self,
+        _user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called with the user as it is stored, and as it will be once the PATCH
+    /// operations have been applied, so that the hook can see what they
+    /// change.
+    fn before_patch_user(
+        &self,
+        _user: &StoredParts<User>,
+        _patched: &User,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_patch_user(
+        &self,
+        _user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called before a user is deleted, or deactivated by
+    /// `UserDeletePolicy::Deactivate`. Also called under
+    /// `UserDeletePolicy::Reject`, before the policy rejects the delete.
+    fn before_delete_user(
+        &self,
+        _user: &StoredParts<User>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Only called if the user was actually deleted (or soft deleted).
+    fn after_delete_user(
+        &self,
+        _user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn before_create_group(
+        &self,
+        _request: &CreateGroupRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_create_group(
+        &self,
+        _group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn before_replace_group(
+        &self,
+        _group_id: &str,
+        _request: &CreateGroupRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_replace_group(
+        &self,
+        _group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called with the group as it is stored, and as it will be once the
+    /// PATCH operations have been applied.
+    fn before_patch_group(
+        &self,
+        _group: &StoredParts<Group>,
+        _patched: &Group,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_patch_group(
+        &self,
+        _group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn before_delete_group(
+        &self,
+        _group: &StoredParts<Group>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn after_delete_group(
+        &self,
+        _group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Users were added to a group
+    fn after_group_members_added(
+        &self,
+        _group_id: &str,
+        _user_ids: &[String],
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Users were removed from a group, including because they or the group
+    /// were deleted
+    fn after_group_members_removed(
+        &self,
+        _group_id: &str,
+        _user_ids: &[String],
+    ) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+impl ProvisioningHooks for () {}
+
+/// So that providers scoped with `Provider::for_store` can share the hooks
+impl<H: ProvisioningHooks> ProvisioningHooks for &H {
+    fn before_create_user(
+        &self,
+        request: &CreateUserRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_create_user(request)
+    }
+
+    fn after_create_user(
+        &self,
+        user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_create_user(user)
+    }
+
+    fn before_replace_user(
+        &self,
+        user_id: &str,
+        request: &CreateUserRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_replace_user(user_id, request)
+    }
+
+    fn after_replace_user(
+        &self,
+        user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_replace_user(user)
+    }
+
+    fn before_patch_user(
+        &self,
+        user: &StoredParts<User>,
+        patched: &User,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_patch_user(user, patched)
+    }
+
+    fn after_patch_user(
+        &self,
+        user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_patch_user(user)
+    }
+
+    fn before_delete_user(
+        &self,
+        user: &StoredParts<User>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_delete_user(user)
+    }
+
+    fn after_delete_user(
+        &self,
+        user: &StoredParts<User>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_delete_user(user)
+    }
+
+    fn before_create_group(
+        &self,
+        request: &CreateGroupRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_create_group(request)
+    }
+
+    fn after_create_group(
+        &self,
+        group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_create_group(group)
+    }
+
+    fn before_replace_group(
+        &self,
+        group_id: &str,
+        request: &CreateGroupRequest,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_replace_group(group_id, request)
+    }
+
+    fn after_replace_group(
+        &self,
+        group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_replace_group(group)
+    }
+
+    fn before_patch_group(
+        &self,
+        group: &StoredParts<Group>,
+        patched: &Group,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_patch_group(group, patched)
+    }
+
+    fn after_patch_group(
+        &self,
+        group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_patch_group(group)
+    }
+
+    fn before_delete_group(
+        &self,
+        group: &StoredParts<Group>,
+    ) -> impl Future<Output = Result<(), Error>> + Send {
+        (**self).before_delete_group(group)
+    }
+
+    fn after_delete_group(
+        &self,
+        group: &StoredParts<Group>,
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_delete_group(group)
+    }
+
+    fn after_group_members_added(
+        &self,
+        group_id: &str,
+        user_ids: &[String],
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_group_members_added(group_id, user_ids)
+    }
+
+    fn after_group_members_removed(
+        &self,
+        group_id: &str,
+        user_ids: &[String],
+    ) -> impl Future<Output = ()> + Send {
+        (**self).after_group_members_removed(group_id, user_ids)
+    }
+}
@@ -2,29 +2,445 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use crate::delta::{ChangeMark, delta_since, touched_resources};
+use crate::history::{group_as_of, latest_as_of, user_as_of};
+use crate::memory_filter::{FilterAttr, MemoryFilter};
+use crate::provider_store::{history_not_implemented, outbox_not_implemented};
 use crate::response::Error;
+use crate::tracked_map::TrackedMap;
 use crate::utils::ResourceType;
 use crate::{
-    CreateGroupRequest, CreateUserRequest, FilterOp, Group, GroupMember,
-    ProviderStore, ProviderStoreDeleteResult, ProviderStoreError, StoredMeta,
-    StoredParts, User, UserGroup, UserGroupType,
+    ChangeEvent, ChangeEventKind, ChangedGroup, CreateGroupRequest,
+    CreateUserRequest, DeactivatedUser, DeadLetter, DeletedResource, Filter,
+    Group, GroupMember, OutboxEntry, Pagination, ProviderStore,
+    ProviderStoreDeleteResult, ProviderStoreError, ProviderStoreListResult,
+    Resource, Revision, StoreDelta, StoredMeta, StoredParts, User, UserGroup,
+    UserGroupType, group_changes, new_version, user_changes,
 };
 
-use chrono::Utc;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use iddqd::IdOrdMap;
 use schemars::JsonSchema;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{Mutex as AsyncMutex, MutexGuard as AsyncMutexGuard};
+use unicase::UniCase;
 use uuid::Uuid;
 
-#[derive(Clone, Serialize, JsonSchema)]
+// The maps are `TrackedMap`s, so that each change can be journaled to the
+// state file, or rolled back if that fails.
+#[derive(Clone, Default, Serialize, Deserialize, JsonSchema)]
 pub struct InMemoryProviderStoreState {
-    users: BTreeMap<String, StoredParts<User>>,
-    groups: BTreeMap<String, StoredParts<Group>>,
+    users: TrackedMap<String, StoredParts<User>>,
+    groups: TrackedMap<String, StoredParts<Group>>,
+
+    // Soft deleted resources, by id
+    #[serde(default)]
+    deleted_users: TrackedMap<String, DeletedResource<User>>,
+    #[serde(default)]
+    deleted_groups: TrackedMap<String, DeletedResource<Group>>,
+
+    // Change events that have not been acknowledged yet, by sequence number,
+    // and the sequence number of the last one recorded. Only used if the
+    // store has an outbox.
+    #[serde(default)]
+    outbox: TrackedMap<u64, OutboxEntry>,
+    #[serde(default)]
+    outbox_sequence: u64,
+
+    // The cursor of each outbox consumer, and the dead letters of each, by
+    // consumer and then sequence.
+    #[serde(default)]
+    outbox_cursors: TrackedMap<String, u64>,
+    #[serde(default)]
+    dead_letters: TrackedMap<String, BTreeMap<u64, DeadLetter>>,
+
+    // Every revision of every user and group, by id, oldest first, and the
+    // number of the last one recorded. Only used if the store keeps history.
+    #[serde(default)]
+    user_revisions: TrackedMap<String, Vec<Revision<User>>>,
+    #[serde(default)]
+    group_revisions: TrackedMap<String, Vec<Revision<Group>>>,
+    #[serde(default)]
+    revision_sequence: u64,
+
+    // The store's change counter, and the count at which each user and group
+    // last changed, by id. Deleted resources keep their mark, for deltas.
+    #[serde(default)]
+    change_counter: u64,
+    #[serde(default)]
+    user_marks: TrackedMap<String, ChangeMark>,
+    #[serde(default)]
+    group_marks: TrackedMap<String, ChangeMark>,
+
+    // The counters as of the last commit, to roll back to.
+    #[serde(skip)]
+    #[schemars(skip)]
+    committed: Counters,
+
+    // Derived entirely from `users` and `groups`, so there is no need to
+    // expose it.
+    #[serde(skip)]
+    #[schemars(skip)]
+    indexes: Indexes,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+struct Counters {
+    outbox_sequence: u64,
+    revision_sequence: u64,
+    change_counter: u64,
+}
+
+/// One line of the journal: everything that a change to the state touched, as
+/// it is afterwards. Entries hold whole values rather than operations, so
+/// replaying one twice does no harm.
+#[derive(Default, Serialize, Deserialize)]
+struct JournalEntry {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    users: BTreeMap<String, Option<StoredParts<User>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    groups: BTreeMap<String, Option<StoredParts<Group>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    deleted_users: BTreeMap<String, Option<DeletedResource<User>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    deleted_groups: BTreeMap<String, Option<DeletedResource<Group>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    outbox: BTreeMap<u64, Option<OutboxEntry>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    outbox_cursors: BTreeMap<String, Option<u64>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dead_letters: BTreeMap<String, Option<BTreeMap<u64, DeadLetter>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    user_revisions: BTreeMap<String, Option<Vec<Revision<User>>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    group_revisions: BTreeMap<String, Option<Vec<Revision<Group>>>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    user_marks: BTreeMap<String, Option<ChangeMark>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    group_marks: BTreeMap<String, Option<ChangeMark>>,
+    counters: Counters,
+}
+
+/// Case-insensitive secondary indexes for the attributes that IdPs look
+/// resources up by. Okta probes `userName eq` before every create, so without
+/// these every lookup is a scan of the whole store.
+#[derive(Clone, Default)]
+struct Indexes {
+    /// userName -> user id. userName is unique.
+    user_name: BTreeMap<UniCase<String>, String>,
+
+    /// externalId -> user ids. Nothing requires externalId to be unique.
+    user_external_id: BTreeMap<UniCase<String>, BTreeSet<String>>,
+
+    /// displayName -> group id. displayName is unique.
+    group_display_name: BTreeMap<UniCase<String>, String>,
+}
+
+impl Indexes {
+    fn user_id_by_name(&self, name: &str) -> Option<&String> {
+        self.user_name.get(&UniCase::new(name.to_string()))
+    }
+
+    fn user_ids_by_external_id(
+        &self,
+        external_id: &str,
+    ) -> impl Iterator<Item = &String> {
+        self.user_external_id
+            .get(&UniCase::new(external_id.to_string()))
+            .into_iter()
+            .flatten()
+    }
+
+    fn group_id_by_display_name(&self, display_name: &str) -> Option<&String> {
+        self.group_display_name.get(&UniCase::new(display_name.to_string()))
+    }
+
+    fn insert_user(&mut self, user: &User) {
+        self.user_name.insert(UniCase::new(user.name.clone()), user.id.clone());
+
+        if let Some(external_id) = &user.external_id {
+            self.user_external_id
+                .entry(UniCase::new(external_id.clone()))
+                .or_default()
+                .insert(user.id.clone());
+        }
+    }
+
+    fn remove_user(&mut self, user: &User) {
+        self.user_name.remove(&UniCase::new(user.name.clone()));
+
+        if let Some(external_id) = &user.external_id {
+            let key = UniCase::new(external_id.clone());
+            if let Some(ids) = self.user_external_id.get_mut(&key) {
+                ids.remove(&user.id);
+                if ids.is_empty() {
+                    self.user_external_id.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn insert_group(&mut self, group: &Group) {
+        self.group_display_name
+            .insert(UniCase::new(group.display_name.clone()), group.id.clone());
+    }
+
+    fn remove_group(&mut self, group: &Group) {
+        self.group_display_name
+            .remove(&UniCase::new(group.display_name.clone()));
+    }
 }
 
 impl InMemoryProviderStoreState {
+    /// Read a snapshot written by [`write_snapshot`]. The indexes are not
+    /// part of it, so they have to be rebuilt.
+    fn read_snapshot(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+
+        let mut state: Self = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))?;
+        state.committed = state.counters();
+
+        Ok(state)
+    }
+
+    fn rebuild_indexes(&mut self) {
+        self.indexes = Indexes::default();
+
+        for user in self.users.values() {
+            self.indexes.insert_user(&user.resource);
+        }
+
+        for group in self.groups.values() {
+            self.indexes.insert_group(&group.resource);
+        }
+    }
+
+    fn counters(&self) -> Counters {
+        Counters {
+            outbox_sequence: self.outbox_sequence,
+            revision_sequence: self.revision_sequence,
+            change_counter: self.change_counter,
+        }
+    }
+
+    /// What changed since the last commit
+    fn journal_entry(&self) -> JournalEntry {
+        JournalEntry {
+            users: self.users.changes(),
+            groups: self.groups.changes(),
+            deleted_users: self.deleted_users.changes(),
+            deleted_groups: self.deleted_groups.changes(),
+            outbox: self.outbox.changes(),
+            outbox_cursors: self.outbox_cursors.changes(),
+            dead_letters: self.dead_letters.changes(),
+            user_revisions: self.user_revisions.changes(),
+            group_revisions: self.group_revisions.changes(),
+            user_marks: self.user_marks.changes(),
+            group_marks: self.group_marks.changes(),
+            counters: self.counters(),
+        }
+    }
+
+    /// Apply a journal entry read back from the state file. The indexes have
+    /// to be rebuilt afterwards.
+    fn apply(&mut self, entry: JournalEntry) {
+        self.users.apply(entry.users);
+        self.groups.apply(entry.groups);
+        self.deleted_users.apply(entry.deleted_users);
+        self.deleted_groups.apply(entry.deleted_groups);
+        self.outbox.apply(entry.outbox);
+        self.outbox_cursors.apply(entry.outbox_cursors);
+        self.dead_letters.apply(entry.dead_letters);
+        self.user_revisions.apply(entry.user_revisions);
+        self.group_revisions.apply(entry.group_revisions);
+        self.user_marks.apply(entry.user_marks);
+        self.group_marks.apply(entry.group_marks);
+
+        self.outbox_sequence = entry.counters.outbox_sequence;
+        self.revision_sequence = entry.counters.revision_sequence;
+        self.change_counter = entry.counters.change_counter;
+        self.committed = entry.counters;
+    }
+
+    fn commit(&mut self) {
+        self.users.commit();
+        self.groups.commit();
+        self.deleted_users.commit();
+        self.deleted_groups.commit();
+        self.outbox.commit();
+        self.outbox_cursors.commit();
+        self.dead_letters.commit();
+        self.user_revisions.commit();
+        self.group_revisions.commit();
+        self.user_marks.commit();
+        self.group_marks.commit();
+        self.committed = self.counters();
+    }
+
+    /// Whether anything changed since the last commit
+    fn has_changes(&self) -> bool {
+        self.users.has_changes()
+            || self.groups.has_changes()
+            || self.deleted_users.has_changes()
+            || self.deleted_groups.has_changes()
+            || self.outbox.has_changes()
+            || self.outbox_cursors.has_changes()
+            || self.dead_letters.has_changes()
+            || self.user_revisions.has_changes()
+            || self.group_revisions.has_changes()
+            || self.user_marks.has_changes()
+            || self.group_marks.has_changes()
+            || self.counters() != self.committed
+    }
+
+    /// Undo everything since the last commit
+    fn rollback(&mut self) {
+        self.users.rollback();
+        self.groups.rollback();
+        self.deleted_users.rollback();
+        self.deleted_groups.rollback();
+        self.outbox.rollback();
+        self.outbox_cursors.rollback();
+        self.dead_letters.rollback();
+        self.user_revisions.rollback();
+        self.group_revisions.rollback();
+        self.user_marks.rollback();
+        self.group_marks.rollback();
+
+        self.outbox_sequence = self.committed.outbox_sequence;
+        self.revision_sequence = self.committed.revision_sequence;
+        self.change_counter = self.committed.change_counter;
+
+        self.rebuild_indexes();
+    }
+
+    /// Atomically replace the snapshot at `path` with this state: it is
+    /// written to a temporary file next to `path` and renamed over it, so a
+    /// crash never leaves a partially written snapshot behind.
+    fn write_snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let mut temp_path = path.as_os_str().to_owned();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+
+        let file = File::create(&temp_path)
+            .with_context(|| format!("creating {}", temp_path.display()))?;
+
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        std::fs::rename(&temp_path, path).with_context(|| {
+            format!("renaming {} to {}", temp_path.display(), path.display())
+        })?;
+
+        Ok(())
+    }
+
+    /// Remove a user and its group memberships, returning the user as it was
+    /// beforehand.
+    fn remove_user(&mut self, user_id: &str) -> Option<StoredParts<User>> {
+        let user = self.users.remove(user_id)?;
+        self.indexes.remove_user(&user.resource);
+
+        // Remove the user from any group they were a member of, which
+        // modifies the group
+        let is_member =
+            |member: &GroupMember| member.value.as_deref() == Some(user_id);
+        self.groups.update_where(
+            |group| group.resource.members.iter().flatten().any(is_member),
+            |group| {
+                if let Some(members) = &mut group.resource.members {
+                    members.retain(|member| !is_member(&member));
+                }
+                group.meta.touch();
+            },
+        );
+
+        Some(user)
+    }
+
+    /// Remove a group and all of its memberships, returning the group as it
+    /// was beforehand.
+    fn remove_group(&mut self, group_id: &str) -> Option<StoredParts<Group>> {
+        let group = self.groups.remove(group_id)?;
+        self.indexes.remove_group(&group.resource);
+
+        let user_ids = self.remove_group_memberships(group_id);
+        self.touch_users(&user_ids);
+
+        Some(group)
+    }
+
+    /// Take every user out of the group `group_id`, returning the ids of the
+    /// users that were members.
+    fn remove_group_memberships(&mut self, group_id: &str) -> BTreeSet<String> {
+        let is_group = |user_group: &UserGroup| {
+            user_group.value.as_deref() == Some(group_id)
+        };
+        let mut user_ids = BTreeSet::new();
+        self.users.update_where(
+            |user| user.resource.groups.iter().flatten().any(is_group),
+            |user| {
+                if let Some(groups) = &mut user.resource.groups {
+                    groups.retain(|user_group| !is_group(user_group));
+                }
+                user_ids.insert(user.resource.id.clone());
+            },
+        );
+        user_ids
+    }
+
+    /// Bump `meta.lastModified` and the version of users whose group
+    /// memberships changed, so that filtering on `meta.lastModified` agrees
+    /// with the delta endpoints, and `If-Match` sees the change.
+    fn touch_users<'a>(
+        &mut self,
+        user_ids: impl IntoIterator<Item = &'a String>,
+    ) {
+        for user_id in user_ids {
+            if let Some(user) = self.users.get_mut(user_id) {
+                user.meta.touch();
+            }
+        }
+    }
+
+    /// Add the user `user_id` to the group `group_id`
+    fn add_user_group(
+        &mut self,
+        user_id: &str,
+        group_id: &str,
+        display_name: &str,
+    ) {
+        let user = self.users.get_mut(user_id).expect(
+            "get_group_member would returned 404 if the user didn't exist",
+        );
+
+        user.resource.groups.get_or_insert_default().push(UserGroup {
+            member_type: Some(UserGroupType::Direct),
+            value: Some(group_id.to_string()),
+            display: Some(display_name.to_string()),
+        });
+    }
+
+    fn get_indexed_user(&self, user_id: &str) -> &StoredParts<User> {
+        self.users.get(user_id).expect("user index out of sync")
+    }
+
+    fn get_indexed_group(&self, group_id: &str) -> &StoredParts<Group> {
+        self.groups.get(group_id).expect("group index out of sync")
+    }
+
     fn get_group_member(
         &self,
         member: &GroupMember,
@@ -93,9 +509,208 @@ impl InMemoryProviderStoreState {
     }
 }
 
-/// A non-optimized provider store implementation for use with tests
+/// The user ids of a group's `members`, which have been validated
+fn member_ids(
+    members: Option<&IdOrdMap<GroupMember>>,
+) -> impl Iterator<Item = &String> {
+    members.into_iter().flatten().filter_map(|member| member.value.as_ref())
+}
+
+/// Return the requested page of `matches`.
+fn paginate<R>(
+    matches: Vec<&StoredParts<R>>,
+    pagination: Pagination,
+) -> ProviderStoreListResult<R>
+where
+    R: Resource + Clone,
+{
+    let total_results = matches.len();
+
+    let resources = matches
+        .into_iter()
+        .skip(pagination.offset())
+        .take(pagination.count.unwrap_or(usize::MAX))
+        .cloned()
+        .collect();
+
+    ProviderStoreListResult { resources, total_results }
+}
+
+/// How many changes the journal holds before they are folded into a new
+/// snapshot
+const COMPACT_AFTER: usize = 1024;
+
+/// The files that keep an InMemoryProviderStore's state across restarts: a
+/// snapshot of the whole state, and a journal of the changes made since it
+/// was written, one JSON `JournalEntry` per line.
+struct StateFile {
+    path: PathBuf,
+    journal: File,
+
+    /// The length of the journal, to cut a partly written entry off again
+    journal_len: u64,
+
+    journal_entries: usize,
+}
+
+impl StateFile {
+    fn journal_path(path: &Path) -> PathBuf {
+        let mut journal_path = path.as_os_str().to_owned();
+        journal_path.push(".journal");
+        PathBuf::from(journal_path)
+    }
+
+    /// Read the state at `path`: the snapshot, if there is one, with the
+    /// journal replayed over it.
+    fn read(path: &Path) -> anyhow::Result<InMemoryProviderStoreState> {
+        let mut state = if path.try_exists()? {
+            InMemoryProviderStoreState::read_snapshot(path)?
+        } else {
+            InMemoryProviderStoreState::default()
+        };
+
+        let journal_path = Self::journal_path(path);
+        if journal_path.try_exists()? {
+            let file = File::open(&journal_path).with_context(|| {
+                format!("opening {}", journal_path.display())
+            })?;
+
+            let mut lines = BufReader::new(file).lines().peekable();
+            while let Some(line) = lines.next() {
+                let line = line?;
+                match serde_json::from_str(&line) {
+                    Ok(entry) => state.apply(entry),
+
+                    // A crash while an entry was being written leaves it
+                    // cut short. It was never acknowledged, so skip it.
+                    Err(_) if lines.peek().is_none() => break,
+
+                    Err(e) => {
+                        return Err(e).with_context(|| {
+                            format!("parsing {}", journal_path.display())
+                        });
+                    }
+                }
+            }
+        }
+
+        state.rebuild_indexes();
+        Ok(state)
+    }
+
+    /// Write a snapshot of `state` and start an empty journal
+    fn create(
+        path: PathBuf,
+        state: &InMemoryProviderStoreState,
+    ) -> anyhow::Result<Self> {
+        let journal_path = Self::journal_path(&path);
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)
+            .with_context(|| format!("opening {}", journal_path.display()))?;
+
+        let mut state_file =
+            Self { path, journal, journal_len: 0, journal_entries: 0 };
+        state_file.compact(state)?;
+
+        Ok(state_file)
+    }
+
+    /// Durably append `entry` to the journal
+    fn append(&mut self, entry: &JournalEntry) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let result = self
+            .journal
+            .write_all(&line)
+            .and_then(|()| self.journal.sync_data());
+
+        if let Err(e) = result {
+            // Don't leave part of the entry behind for the next one to be
+            // appended to. If this fails too, reading the journal back will
+            // fail rather than skip entries.
+            let _ = self.journal.set_len(self.journal_len);
+            return Err(e.into());
+        }
+
+        self.journal_len += line.len() as u64;
+        self.journal_entries += 1;
+        Ok(())
+    }
+
+    /// Replace the snapshot with `state`, and empty the journal. Replaying
+    /// an entry is idempotent, so a crash in between does no harm.
+    fn compact(
+        &mut self,
+        state: &InMemoryProviderStoreState,
+    ) -> anyhow::Result<()> {
+        state.write_snapshot(&self.path)?;
+
+        self.journal.set_len(0)?;
+        self.journal.sync_all()?;
+        self.journal_len = 0;
+        self.journal_entries = 0;
+
+        Ok(())
+    }
+}
+
+/// The locked state of an `InMemoryProviderStore`. An operation that fails
+/// part way through returns before it saves, so whatever it changed is rolled
+/// back when the lock is released, rather than committed by the next
+/// operation that saves.
+struct StateGuard<'a>(AsyncMutexGuard<'a, InMemoryProviderStoreState>);
+
+impl std::ops::Deref for StateGuard<'_> {
+    type Target = InMemoryProviderStoreState;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for StateGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl Drop for StateGuard<'_> {
+    fn drop(&mut self) {
+        if self.0.has_changes() {
+            self.0.rollback();
+        }
+    }
+}
+
+/// A provider store that holds all of its state in memory, behind one lock
+/// that every operation takes in turn.
+///
+/// Without a state file (`InMemoryProviderStore::new`), everything is lost
+/// when the process exits. With one (`InMemoryProviderStore::with_state_file`),
+/// every change is fsynced to a journal before the operation that made it
+/// returns, so a change that was acknowledged survives a crash, and one that
+/// could not be written is rolled back. Every 1024 changes, the journal is
+/// folded into a new snapshot, which rewrites the whole state.
+///
+/// This suits tests, development and small deployments. Use
+/// `SqliteProviderStore` when the data should not all have to fit in memory,
+/// when more than one process shares it, or when operations should not wait
+/// on each other and on snapshots of the whole state.
 pub struct InMemoryProviderStore {
-    state: Mutex<InMemoryProviderStoreState>,
+    state: AsyncMutex<InMemoryProviderStoreState>,
+
+    /// Where the state is kept across restarts, if anywhere. Only ever
+    /// locked while `state` is, by a blocking task.
+    state_file: Option<Arc<Mutex<StateFile>>>,
+
+    /// Whether change events are recorded in the outbox
+    outbox: bool,
+
+    /// Whether revisions of users and groups are recorded
+    history: bool,
 }
 
 impl Default for InMemoryProviderStore {
@@ -107,24 +722,214 @@ impl Default for InMemoryProviderStore {
 impl InMemoryProviderStore {
     pub fn new() -> Self {
         Self {
-            state: Mutex::new(InMemoryProviderStoreState {
-                users: BTreeMap::new(),
-                groups: BTreeMap::new(),
-            }),
+            state: AsyncMutex::new(InMemoryProviderStoreState::default()),
+            state_file: None,
+            outbox: false,
+            history: false,
         }
     }
 
-    pub fn state(&self) -> InMemoryProviderStoreState {
-        self.state.lock().unwrap().clone()
+    /// Create a store that survives restarts: the state is loaded from
+    /// `path` if it exists. Every change is appended to a journal next to
+    /// `path` (with `.journal` added to the name) before the operation that
+    /// made it returns, and the journal is folded into a new snapshot at
+    /// `path` once it has grown.
+    ///
+    /// If a change cannot be written to the journal, it is undone, and the
+    /// operation that made it fails with a store error.
+    pub fn with_state_file(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let path = path.into();
+        let state = StateFile::read(&path)?;
+
+        // Write the files straight away so that an unwritable path is
+        // reported at startup rather than on the first change.
+        let state_file = StateFile::create(path, &state)?;
+
+        Ok(Self {
+            state: AsyncMutex::new(state),
+            state_file: Some(Arc::new(Mutex::new(state_file))),
+            outbox: false,
+            history: false,
+        })
+    }
+
+    /// Record change events in an outbox, which is part of the state (and so
+    /// of the state file, if there is one).
+    pub fn with_outbox(mut self) -> Self {
+        self.outbox = true;
+        self
+    }
+
+    /// Record a revision of every user and group on every change, which is
+    /// part of the state (and so of the state file, if there is one).
+    pub fn with_history(mut self) -> Self {
+        self.history = true;
+        self
+    }
+
+    pub async fn state(&self) -> InMemoryProviderStoreState {
+        self.state.lock().await.clone()
+    }
+
+    async fn lock(&self) -> StateGuard<'_> {
+        StateGuard(self.state.lock().await)
+    }
+
+    /// Count `changes`, and record them in the outbox if there is one.
+    fn record(
+        &self,
+        state: &mut InMemoryProviderStoreState,
+        changes: Vec<ChangeEventKind>,
+    ) {
+        state.change_counter += 1;
+        for (resource_type, id, deleted) in touched_resources(&changes) {
+            let marks = match resource_type {
+                ResourceType::User => &mut state.user_marks,
+                ResourceType::Group => &mut state.group_marks,
+            };
+            marks.insert(
+                id,
+                ChangeMark { counter: state.change_counter, deleted },
+            );
+        }
+
+        if !self.outbox {
+            return;
+        }
+
+        for kind in changes {
+            state.outbox_sequence += 1;
+            state.outbox.insert(
+                state.outbox_sequence,
+                OutboxEntry {
+                    sequence: state.outbox_sequence,
+                    event: ChangeEvent::new(kind),
+                },
+            );
+        }
+    }
+
+    /// Record a revision of a user, if the store keeps history.
+    fn record_user_revision(
+        &self,
+        state: &mut InMemoryProviderStoreState,
+        user_id: &str,
+        user: Option<&StoredParts<User>>,
+    ) {
+        if !self.history {
+            return;
+        }
+
+        state.revision_sequence += 1;
+        let revision = Revision {
+            revision: state.revision_sequence,
+            recorded_at: Utc::now(),
+            resource: user.cloned(),
+        };
+        state
+            .user_revisions
+            .get_or_insert_default(user_id.to_string())
+            .push(revision);
+    }
+
+    /// Record a revision of a group, if the store keeps history.
+    fn record_group_revision(
+        &self,
+        state: &mut InMemoryProviderStoreState,
+        group_id: &str,
+        group: Option<&StoredParts<Group>>,
+    ) {
+        if !self.history {
+            return;
+        }
+
+        state.revision_sequence += 1;
+        let revision = Revision {
+            revision: state.revision_sequence,
+            recorded_at: Utc::now(),
+            resource: group.cloned(),
+        };
+        state
+            .group_revisions
+            .get_or_insert_default(group_id.to_string())
+            .push(revision);
+    }
+
+    /// Record a revision of each of the groups `user` is a member of, whose
+    /// members changed along with it.
+    fn record_member_group_revisions(
+        &self,
+        state: &mut InMemoryProviderStoreState,
+        user: &User,
+    ) {
+        for group_id in
+            user.groups.iter().flatten().filter_map(|g| g.value.as_ref())
+        {
+            let group = state.groups.get(group_id).cloned();
+            if group.is_some() {
+                self.record_group_revision(state, group_id, group.as_ref());
+            }
+        }
+    }
+
+    /// Commit the changes made to `state`, journaling them to the state file
+    /// if there is one. If they cannot be journaled, they are rolled back.
+    ///
+    /// The file is written and synced by a blocking task, so that the async
+    /// runtime is not held up. `state` stays locked meanwhile, so entries are
+    /// journaled in the order the changes were made.
+    async fn save(
+        &self,
+        state: &mut InMemoryProviderStoreState,
+    ) -> Result<(), ProviderStoreError> {
+        let Some(state_file) = &self.state_file else {
+            state.commit();
+            return Ok(());
+        };
+
+        let entry = state.journal_entry();
+        let journal = Arc::clone(state_file);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut state_file = journal.lock().unwrap();
+            state_file.append(&entry)?;
+            Ok(state_file.journal_entries >= COMPACT_AFTER)
+        })
+        .await
+        .context("journaling changes")
+        .and_then(|result| result);
+
+        let compact = match result {
+            Ok(compact) => compact,
+            Err(e) => {
+                state.rollback();
+                return Err(ProviderStoreError::StoreError(e));
+            }
+        };
+        state.commit();
+
+        if compact {
+            // The change is in the journal already, so if the snapshot
+            // cannot be written it is simply tried again after the next one.
+            let snapshot = state.clone();
+            let state_file = Arc::clone(state_file);
+            let _ = tokio::task::spawn_blocking(move || {
+                state_file.lock().unwrap().compact(&snapshot)
+            })
+            .await;
+        }
+
+        Ok(())
     }
 }
 
 impl ProviderStore for InMemoryProviderStore {
+    const VERSIONED: bool = true;
+
     async fn get_user_by_id(
         &self,
         user_id: &str,
     ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
-        let state = self.state.lock().unwrap();
+        let state = self.state.lock().await;
         Ok(state.users.get(user_id).cloned())
     }
 
@@ -132,13 +937,9 @@ impl ProviderStore for InMemoryProviderStore {
         &self,
         user_request: CreateUserRequest,
     ) -> Result<StoredParts<User>, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
+        let mut state = self.lock().await;
 
-        if state
-            .users
-            .values()
-            .any(|stored_part| stored_part.resource.name == user_request.name)
-        {
+        if state.indexes.user_id_by_name(&user_request.name).is_some() {
             return Err(Error::conflict(user_request.name).into());
         }
 
@@ -153,46 +954,59 @@ impl ProviderStore for InMemoryProviderStore {
                 groups: None,
             },
 
-            meta: StoredMeta {
-                created: Utc::now(),
-                last_modified: Utc::now(),
-                version: String::from("W/unimplemented"),
-            },
+            meta: StoredMeta::now(),
         };
 
+        state.indexes.insert_user(&new_user.resource);
         let existing = state.users.insert(id, new_user.clone());
         assert!(existing.is_none());
 
+        self.record(&mut state, user_changes(None, Some(&new_user.resource)));
+        self.record_user_revision(
+            &mut state,
+            &new_user.resource.id,
+            Some(&new_user),
+        );
+        self.save(&mut state).await?;
+
         Ok(new_user)
     }
 
     async fn list_users(
         &self,
-        filter: Option<FilterOp>,
-    ) -> Result<Vec<StoredParts<User>>, ProviderStoreError> {
-        let state = self.state.lock().unwrap();
-        let mut users = state.users.clone();
-
-        match filter {
-            Some(FilterOp::UserNameEq(username)) => {
-                users.retain(|_, stored_part| {
-                    stored_part.resource.name.eq_ignore_ascii_case(&username)
-                })
-            }
-
-            None => {
-                // ok
-            }
-
-            Some(_) => {
-                return Err(Error::invalid_filter(
-                    "invalid or unsupported filter".to_string(),
-                )
-                .into());
-            }
-        }
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+        let filter = filter.map(|f| MemoryFilter::new::<StoredParts<User>>(&f));
+        let filter = filter.transpose()?;
+        let state = self.state.lock().await;
+
+        // An eq on an indexed attribute is answered from the index
+        let users: Vec<&StoredParts<User>> = match filter
+            .as_ref()
+            .and_then(MemoryFilter::string_eq)
+        {
+            Some((FilterAttr::UserName, username)) => state
+                .indexes
+                .user_id_by_name(username)
+                .map(|id| state.get_indexed_user(id))
+                .into_iter()
+                .collect(),
+
+            Some((FilterAttr::ExternalId, external_id)) => state
+                .indexes
+                .user_ids_by_external_id(external_id)
+                .map(|id| state.get_indexed_user(id))
+                .collect(),
+
+            _ => state
+                .users
+                .values()
+                .filter(|user| filter.as_ref().is_none_or(|f| f.matches(*user)))
+                .collect(),
+        };
 
-        Ok(users.values().cloned().collect())
+        Ok(paginate(users, pagination))
     }
 
     async fn replace_user(
@@ -200,16 +1014,22 @@ impl ProviderStore for InMemoryProviderStore {
         user_id: &str,
         user_request: CreateUserRequest,
     ) -> Result<StoredParts<User>, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
-        let users = &mut state.users;
+        let mut state = self.lock().await;
+        let InMemoryProviderStoreState { users, indexes, .. } = &mut *state;
+
+        // Can't replace a user that does not exist, so return 404 if it's not
+        // found
+        let existing_user = users
+            .get_mut(user_id)
+            .ok_or(Error::not_found(user_id.to_string()))?;
 
         // userName is meant to be unique. If the user request is changing the
         // username to one that already exists, then reject it.
 
-        if users.values().any(|stored_part| {
-            stored_part.resource.name == user_request.name
-                && stored_part.resource.id != user_id
-        }) {
+        if indexes
+            .user_id_by_name(&user_request.name)
+            .is_some_and(|id| id != user_id)
+        {
             return Err(Error::conflict(format!(
                 "username {}",
                 user_request.name
@@ -217,11 +1037,8 @@ impl ProviderStore for InMemoryProviderStore {
             .into());
         }
 
-        // Can't replace a user that does not exist, so return 404 if it's not
-        // found
-        let existing_user = users
-            .get_mut(user_id)
-            .ok_or(Error::not_found(user_id.to_string()))?;
+        indexes.remove_user(&existing_user.resource);
+        let before = existing_user.resource.clone();
 
         // RFC 7664 § 3.5.1:
         // Attributes whose mutability is "readWrite" that are omitted from the
@@ -246,265 +1063,790 @@ impl ProviderStore for InMemoryProviderStore {
             meta: StoredMeta {
                 // Keep creation time
                 created: existing_user.meta.created,
-                // Update the modification time
+                // Update the modification time and the version
                 last_modified: Utc::now(),
-                // Don't touch the version, this impl does not support that!
-                version: existing_user.meta.version.clone(),
+                version: new_version(),
             },
         };
 
-        Ok(existing_user.clone())
+        indexes.insert_user(&existing_user.resource);
+        let existing_user = existing_user.clone();
+
+        self.record(
+            &mut state,
+            user_changes(Some(&before), Some(&existing_user.resource)),
+        );
+        self.record_user_revision(&mut state, user_id, Some(&existing_user));
+        self.save(&mut state).await?;
+
+        Ok(existing_user)
+    }
+
+    async fn delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let result = if let Some(user) = state.remove_user(user_id) {
+            self.record(&mut state, user_changes(Some(&user.resource), None));
+            self.record_user_revision(&mut state, user_id, None);
+            self.record_member_group_revisions(&mut state, &user.resource);
+            self.save(&mut state).await?;
+            ProviderStoreDeleteResult::Deleted
+        } else {
+            ProviderStoreDeleteResult::NotFound
+        };
+
+        Ok(result)
+    }
+
+    async fn get_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        let state = self.state.lock().await;
+        Ok(state.groups.get(group_id).cloned())
+    }
+
+    async fn get_group_display_names(
+        &self,
+        group_ids: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, String>, ProviderStoreError> {
+        let state = self.state.lock().await;
+        Ok(group_ids
+            .iter()
+            .filter_map(|id| {
+                let group = state.groups.get(id)?;
+                Some((id.clone(), group.resource.display_name.clone()))
+            })
+            .collect())
+    }
+
+    async fn deactivate_user(
+        &self,
+        user_id: &str,
+    ) -> Result<DeactivatedUser, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let Some(user) = state.users.get_mut(user_id) else {
+            return Err(Error::not_found(user_id.to_string()).into());
+        };
+        let before = user.clone();
+
+        let group_ids: Vec<String> = user
+            .resource
+            .groups
+            .take()
+            .into_iter()
+            .flatten()
+            .filter_map(|group| group.value)
+            .collect();
+        user.resource.active = Some(false);
+        user.meta.touch();
+        let after = user.clone();
+
+        let mut groups = Vec::new();
+        for group_id in group_ids {
+            let Some(group) = state.groups.get_mut(&group_id) else {
+                continue;
+            };
+
+            let before = group.clone();
+            if let Some(members) = &mut group.resource.members {
+                members
+                    .retain(|member| member.value.as_deref() != Some(user_id));
+            }
+            group.meta.touch();
+            let after = group.clone();
+
+            self.record(
+                &mut state,
+                group_changes(Some(&before.resource), Some(&after.resource)),
+            );
+            self.record_group_revision(&mut state, &group_id, Some(&after));
+            groups.push(ChangedGroup { before, after });
+        }
+
+        self.record(
+            &mut state,
+            user_changes(Some(&before.resource), Some(&after.resource)),
+        );
+        self.record_user_revision(&mut state, user_id, Some(&after));
+        self.save(&mut state).await?;
+
+        Ok(DeactivatedUser { before, after, groups })
+    }
+
+    async fn create_group(
+        &self,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        // Make sure that display name is unique
+        if state
+            .indexes
+            .group_id_by_display_name(&group_request.display_name)
+            .is_some()
+        {
+            return Err(Error::conflict(format!(
+                "displayName {}",
+                group_request.display_name
+            ))
+            .into());
+        }
+
+        let CreateGroupRequest { display_name, external_id, mut members } =
+            group_request;
+
+        let id = Uuid::new_v4().to_string();
+
+        // Validate the members arg and fill in its fields before touching
+        // anything, so that a bad member does not leave users in a group that
+        // was never created.
+        if let Some(members) = &mut members {
+            for mut member in members {
+                *member = state.get_group_member(&member)?;
+            }
+        }
+
+        // Fill in the appropriate User's groups field.
+        if let Some(members) = &members {
+            for member in members {
+                // value will be filled in, so we can unwrap here
+                let user_id: &String = member
+                    .value
+                    .as_ref()
+                    .expect("get_group_member should have filled this in");
+
+                // Add to the User's groups field
+                state.add_user_group(user_id, &id, &display_name);
+            }
+        }
+        state.touch_users(member_ids(members.as_ref()));
+
+        let new_group = StoredParts {
+            resource: Group {
+                id: id.clone(),
+                display_name,
+                external_id,
+                members,
+            },
+            meta: StoredMeta::now(),
+        };
+
+        state.indexes.insert_group(&new_group.resource);
+        let existing = state.groups.insert(id, new_group.clone());
+        assert!(existing.is_none());
+
+        self.record(&mut state, group_changes(None, Some(&new_group.resource)));
+        self.record_group_revision(
+            &mut state,
+            &new_group.resource.id,
+            Some(&new_group),
+        );
+        self.save(&mut state).await?;
+
+        Ok(new_group)
+    }
+
+    async fn list_groups(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError> {
+        let filter =
+            filter.map(|f| MemoryFilter::new::<StoredParts<Group>>(&f));
+        let filter = filter.transpose()?;
+        let state = self.state.lock().await;
+
+        let groups: Vec<&StoredParts<Group>> =
+            match filter.as_ref().and_then(MemoryFilter::string_eq) {
+                Some((FilterAttr::DisplayName, display_name)) => state
+                    .indexes
+                    .group_id_by_display_name(display_name)
+                    .map(|id| state.get_indexed_group(id))
+                    .into_iter()
+                    .collect(),
+
+                _ => state
+                    .groups
+                    .values()
+                    .filter(|group| {
+                        filter.as_ref().is_none_or(|f| f.matches(*group))
+                    })
+                    .collect(),
+            };
+
+        Ok(paginate(groups, pagination))
+    }
+
+    async fn replace_group(
+        &self,
+        group_id: &str,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let CreateGroupRequest { display_name, external_id, mut members } =
+            group_request;
+
+        // Can't replace a group that does not exist, so return 404 if it's not
+        // found
+        let Some(before) = state.groups.get(group_id) else {
+            return Err(Error::not_found(group_id.to_string()).into());
+        };
+        let before = before.resource.clone();
+
+        // Make sure that display name is unique
+        if state
+            .indexes
+            .group_id_by_display_name(&display_name)
+            .is_some_and(|id| id != group_id)
+        {
+            return Err(
+                Error::conflict(format!("displayName {display_name}")).into()
+            );
+        }
+
+        // Validate the members arg and fill in its fields before touching
+        // anything, so that a bad member does not leave a partial update
+        // behind.
+        if let Some(members) = &mut members {
+            for mut member in members {
+                *member = state.get_group_member(&member)?;
+            }
+        }
+
+        // Delete all existing group membership for this group id
+        let removed = state.remove_group_memberships(group_id);
+
+        // Fill in the appropriate User's groups field.
+        if let Some(members) = &members {
+            for member in members {
+                // value will be filled in, so we can unwrap here
+                let user_id: &String = member
+                    .value
+                    .as_ref()
+                    .expect("get_group_member should have filled this in");
+
+                // Add to the User's groups field
+                state.add_user_group(user_id, group_id, &display_name);
+            }
+        }
+
+        // Only the users who joined or left have a changed membership
+        let added = member_ids(members.as_ref()).cloned().collect();
+        state.touch_users(removed.symmetric_difference(&added));
+
+        let InMemoryProviderStoreState { groups, indexes, .. } = &mut *state;
+        let existing_group =
+            groups.get_mut(group_id).expect("checked that the group exists");
+
+        indexes.remove_group(&existing_group.resource);
+
+        // RFC 7664 § 3.5.1:
+        // Attributes whose mutability is "readWrite" that are omitted from the
+        // request body MAY be assumed to be not asserted by the client. The
+        // service provider MAY assume that any existing values are to be
+        // cleared, or the service provider MAY assign a default value to the
+        // final resource representation.
+
+        // This code takes the stance: if a provisioning client doesn't assert a
+        // field, leave it alone: the IdP is the source of truth. We simply
+        // accept the request and write the fields in.
+
+        *existing_group = StoredParts {
+            resource: Group {
+                id: group_id.to_string(),
+                display_name,
+                external_id,
+                members,
+            },
+
+            meta: StoredMeta {
+                // Keep creation time
+                created: existing_group.meta.created,
+                // Update the modification time and the version
+                last_modified: Utc::now(),
+                version: new_version(),
+            },
+        };
+
+        indexes.insert_group(&existing_group.resource);
+        let existing_group = existing_group.clone();
+
+        self.record(
+            &mut state,
+            group_changes(Some(&before), Some(&existing_group.resource)),
+        );
+        self.record_group_revision(&mut state, group_id, Some(&existing_group));
+        self.save(&mut state).await?;
+
+        Ok(existing_group)
+    }
+
+    async fn delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let result = if let Some(group) = state.remove_group(group_id) {
+            self.record(&mut state, group_changes(Some(&group.resource), None));
+            self.record_group_revision(&mut state, group_id, None);
+            self.save(&mut state).await?;
+            ProviderStoreDeleteResult::Deleted
+        } else {
+            ProviderStoreDeleteResult::NotFound
+        };
+
+        Ok(result)
+    }
+
+    async fn soft_delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let Some(user) = state.remove_user(user_id) else {
+            return Ok(ProviderStoreDeleteResult::NotFound);
+        };
+
+        self.record(&mut state, user_changes(Some(&user.resource), None));
+        self.record_user_revision(&mut state, user_id, None);
+        self.record_member_group_revisions(&mut state, &user.resource);
+
+        state.deleted_users.insert(
+            user_id.to_string(),
+            DeletedResource { resource: user, deleted_at: Utc::now() },
+        );
+
+        self.save(&mut state).await?;
+        Ok(ProviderStoreDeleteResult::Deleted)
+    }
+
+    async fn soft_delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let mut state = self.lock().await;
+
+        let Some(group) = state.remove_group(group_id) else {
+            return Ok(ProviderStoreDeleteResult::NotFound);
+        };
+
+        self.record(&mut state, group_changes(Some(&group.resource), None));
+        self.record_group_revision(&mut state, group_id, None);
+
+        state.deleted_groups.insert(
+            group_id.to_string(),
+            DeletedResource { resource: group, deleted_at: Utc::now() },
+        );
+
+        self.save(&mut state).await?;
+        Ok(ProviderStoreDeleteResult::Deleted)
+    }
+
+    async fn list_deleted_users(
+        &self,
+    ) -> Result<Vec<DeletedResource<User>>, ProviderStoreError> {
+        let state = self.state.lock().await;
+        Ok(state.deleted_users.values().cloned().collect())
+    }
+
+    async fn list_deleted_groups(
+        &self,
+    ) -> Result<Vec<DeletedResource<Group>>, ProviderStoreError> {
+        let state = self.state.lock().await;
+        Ok(state.deleted_groups.values().cloned().collect())
     }
 
-    async fn delete_user_by_id(
+    async fn restore_user_by_id(
         &self,
         user_id: &str,
-    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
-        let maybe_user = state.users.remove(user_id);
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        let mut state = self.lock().await;
 
-        let result = if maybe_user.is_some() {
-            ProviderStoreDeleteResult::Deleted
-        } else {
-            ProviderStoreDeleteResult::NotFound
+        let Some(deleted) = state.deleted_users.get(user_id) else {
+            return Ok(None);
         };
 
-        Ok(result)
+        let mut user = deleted.resource.clone();
+        if state.indexes.user_id_by_name(&user.resource.name).is_some() {
+            return Err(Error::conflict(format!(
+                "username {}",
+                user.resource.name
+            ))
+            .into());
+        }
+
+        state.deleted_users.remove(user_id);
+
+        // Rejoin the groups that still exist, under their current names.
+        let mut groups = user.resource.groups.take().unwrap_or_default();
+        groups.retain_mut(|user_group| {
+            let Some(group) = user_group
+                .value
+                .as_ref()
+                .and_then(|group_id| state.groups.get_mut(group_id))
+            else {
+                return false;
+            };
+
+            group.resource.members.get_or_insert_default().insert_overwrite(
+                GroupMember {
+                    resource_type: Some(ResourceType::User.to_string()),
+                    value: Some(user_id.to_string()),
+                },
+            );
+            group.meta.touch();
+            user_group.display = Some(group.resource.display_name.clone());
+            true
+        });
+
+        user.resource.groups = Some(groups);
+        user.meta.touch();
+
+        state.indexes.insert_user(&user.resource);
+        state.users.insert(user_id.to_string(), user.clone());
+
+        self.record(&mut state, user_changes(None, Some(&user.resource)));
+        self.record_user_revision(&mut state, user_id, Some(&user));
+        self.record_member_group_revisions(&mut state, &user.resource);
+        self.save(&mut state).await?;
+        Ok(Some(user))
     }
 
-    async fn get_group_by_id(
+    async fn restore_group_by_id(
         &self,
         group_id: &str,
     ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
-        let state = self.state.lock().unwrap();
-        Ok(state.groups.get(group_id).cloned())
+        let mut state = self.lock().await;
+
+        let Some(deleted) = state.deleted_groups.get(group_id) else {
+            return Ok(None);
+        };
+
+        let mut group = deleted.resource.clone();
+        let display_name = group.resource.display_name.clone();
+        if state.indexes.group_id_by_display_name(&display_name).is_some() {
+            return Err(
+                Error::conflict(format!("displayName {display_name}")).into()
+            );
+        }
+
+        state.deleted_groups.remove(group_id);
+
+        // Bring back the members that still exist.
+        if let Some(members) = &mut group.resource.members {
+            members.retain(|member| {
+                let Some(user) = member
+                    .value
+                    .as_ref()
+                    .and_then(|user_id| state.users.get_mut(user_id))
+                else {
+                    return false;
+                };
+
+                user.resource.groups.get_or_insert_default().push(UserGroup {
+                    member_type: Some(UserGroupType::Direct),
+                    value: Some(group_id.to_string()),
+                    display: Some(display_name.clone()),
+                });
+                user.meta.touch();
+                true
+            });
+        }
+
+        group.meta.touch();
+
+        state.indexes.insert_group(&group.resource);
+        state.groups.insert(group_id.to_string(), group.clone());
+
+        self.record(&mut state, group_changes(None, Some(&group.resource)));
+        self.record_group_revision(&mut state, group_id, Some(&group));
+        self.save(&mut state).await?;
+        Ok(Some(group))
     }
 
-    async fn create_group(
+    async fn purge_deleted(
         &self,
-        group_request: CreateGroupRequest,
-    ) -> Result<StoredParts<Group>, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
-
-        // Make sure that display name is unique
-        if state.groups.values().any(|stored_part| {
-            stored_part.resource.display_name == group_request.display_name
-        }) {
-            return Err(Error::conflict(format!(
-                "displayName {}",
-                group_request.display_name
-            ))
-            .into());
+        deleted_before: DateTime<Utc>,
+    ) -> Result<usize, ProviderStoreError> {
+        let mut state = self.lock().await;
+        let before = state.deleted_users.len() + state.deleted_groups.len();
+
+        state.deleted_users.retain(|_, user| user.deleted_at >= deleted_before);
+        state
+            .deleted_groups
+            .retain(|_, group| group.deleted_at >= deleted_before);
+
+        let purged =
+            before - state.deleted_users.len() - state.deleted_groups.len();
+        if purged > 0 {
+            self.save(&mut state).await?;
         }
 
-        let CreateGroupRequest { display_name, external_id, mut members } =
-            group_request;
+        Ok(purged)
+    }
 
-        let id = Uuid::new_v4().to_string();
+    async fn read_outbox(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
 
-        // Validate the members arg, and return filled in fields. Then fill in
-        // the appropriate User's groups field.
-        if let Some(members) = &mut members {
-            for mut member in members {
-                *member = state.get_group_member(&member)?;
+        let state = self.state.lock().await;
+        Ok(state.outbox.values().take(limit).cloned().collect())
+    }
 
-                // value will be filled in, so we can unwrap here
-                let user_id: &String = member
-                    .value
-                    .as_ref()
-                    .expect("get_group_member should have filled this in");
+    async fn ack_outbox(
+        &self,
+        sequence: u64,
+    ) -> Result<usize, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
 
-                // Add to the User's groups field
-                state
-                    .users
-                    .get_mut(user_id)
-                    .expect("get_group_member would returned 404 if the user didn't exist")
-                    .resource
-                    .groups
-                    .get_or_insert_default()
-                    .push(
-                        UserGroup {
-                            member_type: Some(UserGroupType::Direct),
-                            value: Some(id.clone()),
-                            display: Some(display_name.clone()),
-                        }
-                    );
-            }
+        let mut state = self.lock().await;
+        let before = state.outbox.len();
+        state.outbox.retain(|_, entry| entry.sequence > sequence);
+
+        let acked = before - state.outbox.len();
+        if acked > 0 {
+            self.save(&mut state).await?;
         }
 
-        let new_group = StoredParts {
-            resource: Group {
-                id: id.clone(),
-                display_name,
-                external_id,
-                members,
-            },
-            meta: StoredMeta {
-                created: Utc::now(),
-                last_modified: Utc::now(),
-                version: String::from("W/unimplemented"),
-            },
-        };
+        Ok(acked)
+    }
 
-        let existing = state.groups.insert(id, new_group.clone());
-        assert!(existing.is_none());
+    async fn read_outbox_after(
+        &self,
+        sequence: u64,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
 
-        Ok(new_group)
+        let state = self.state.lock().await;
+        Ok(state
+            .outbox
+            .range(sequence.saturating_add(1)..)
+            .take(limit)
+            .map(|(_, entry)| entry.clone())
+            .collect())
     }
 
-    async fn list_groups(
+    async fn outbox_cursor(
         &self,
-        filter: Option<FilterOp>,
-    ) -> Result<Vec<StoredParts<Group>>, ProviderStoreError> {
-        let state = self.state.lock().unwrap();
-        let mut groups = state.groups.clone();
-
-        match filter {
-            Some(FilterOp::DisplayNameEq(display_name)) => {
-                groups.retain(|_, stored_part| {
-                    stored_part
-                        .resource
-                        .display_name
-                        .eq_ignore_ascii_case(&display_name)
-                })
-            }
+        consumer: &str,
+    ) -> Result<u64, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
 
-            None => {
-                // ok
-            }
+        let state = self.state.lock().await;
+        Ok(state.outbox_cursors.get(consumer).copied().unwrap_or(0))
+    }
 
-            Some(_) => {
-                return Err(Error::invalid_filter(
-                    "invalid or unsupported filter".to_string(),
-                )
-                .into());
-            }
+    async fn set_outbox_cursor(
+        &self,
+        consumer: &str,
+        sequence: u64,
+    ) -> Result<(), ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        let mut state = self.lock().await;
+        if state.outbox_cursors.get(consumer) != Some(&sequence) {
+            state.outbox_cursors.insert(consumer.to_string(), sequence);
+            self.save(&mut state).await?;
         }
 
-        Ok(groups.values().cloned().collect())
+        Ok(())
     }
 
-    async fn replace_group(
+    async fn put_dead_letter(
         &self,
-        group_id: &str,
-        group_request: CreateGroupRequest,
-    ) -> Result<StoredParts<Group>, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
+        dead_letter: DeadLetter,
+    ) -> Result<(), ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
+        }
 
-        let CreateGroupRequest { display_name, external_id, mut members } =
-            group_request;
+        let mut state = self.lock().await;
+        state
+            .dead_letters
+            .get_or_insert_default(dead_letter.endpoint.clone())
+            .insert(dead_letter.sequence, dead_letter);
+        self.save(&mut state).await?;
 
-        // Make sure that display name is unique
-        if state.groups.values().any(|stored_part| {
-            stored_part.resource.display_name == display_name
-                && stored_part.resource.id != group_id
-        }) {
-            return Err(
-                Error::conflict(format!("displayName {display_name}")).into()
-            );
+        Ok(())
+    }
+
+    async fn list_dead_letters(
+        &self,
+    ) -> Result<Vec<DeadLetter>, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
         }
 
-        // Delete all existing group membership for this group id
-        for stored_part in state.users.values_mut() {
-            if let Some(groups) = &mut stored_part.resource.groups {
-                groups.retain(|user_group| {
-                    user_group.value.as_deref() != Some(group_id)
-                });
-            }
+        let state = self.state.lock().await;
+        Ok(state
+            .dead_letters
+            .values()
+            .flat_map(|d| d.values().cloned())
+            .collect())
+    }
+
+    async fn remove_dead_letter(
+        &self,
+        endpoint: &str,
+        sequence: u64,
+    ) -> Result<bool, ProviderStoreError> {
+        if !self.outbox {
+            return Err(outbox_not_implemented());
         }
 
-        // Validate the members arg, and return filled in fields. Then fill in
-        // the appropriate User's groups field.
+        let mut state = self.lock().await;
+        let found = state
+            .dead_letters
+            .get(endpoint)
+            .is_some_and(|dead_letters| dead_letters.contains_key(&sequence));
+        if !found {
+            return Ok(false);
+        }
 
-        if let Some(members) = &mut members {
-            for mut member in members {
-                *member = state.get_group_member(&member)?;
+        let dead_letters = state.dead_letters.get_mut(endpoint).unwrap();
+        dead_letters.remove(&sequence);
+        if dead_letters.is_empty() {
+            state.dead_letters.remove(endpoint);
+        }
+        self.save(&mut state).await?;
 
-                // value will be filled in, so we can unwrap here
-                let user_id: &String = member
-                    .value
-                    .as_ref()
-                    .expect("get_group_member should have filled this in");
+        Ok(true)
+    }
 
-                // Add to the User's groups field
-                state
-                    .users
-                    .get_mut(user_id)
-                    .expect("get_group_member would returned 404 if the user didn't exist")
-                    .resource
-                    .groups
-                    .get_or_insert_default()
-                    .push(
-                        UserGroup {
-                            member_type: Some(UserGroupType::Direct),
-                            value: Some(group_id.to_string()),
-                            display: Some(display_name.clone()),
-                        }
-                    );
-            }
+    async fn list_user_revisions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Revision<User>>, ProviderStoreError> {
+        if !self.history {
+            return Err(history_not_implemented());
         }
 
-        // Can't replace a group that does not exist, so return 404 if it's not
-        // found
-        let existing_group = state
-            .groups
-            .get_mut(group_id)
-            .ok_or(Error::not_found(group_id.to_string()))?;
+        let state = self.state.lock().await;
+        Ok(state.user_revisions.get(user_id).cloned().unwrap_or_default())
+    }
 
-        // RFC 7664 § 3.5.1:
-        // Attributes whose mutability is "readWrite" that are omitted from the
-        // request body MAY be assumed to be not asserted by the client. The
-        // service provider MAY assume that any existing values are to be
-        // cleared, or the service provider MAY assign a default value to the
-        // final resource representation.
+    async fn list_group_revisions(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<Revision<Group>>, ProviderStoreError> {
+        if !self.history {
+            return Err(history_not_implemented());
+        }
 
-        // This code takes the stance: if a provisioning client doesn't assert a
-        // field, leave it alone: the IdP is the source of truth. We simply
-        // accept the request and write the fields in.
+        let state = self.state.lock().await;
+        Ok(state.group_revisions.get(group_id).cloned().unwrap_or_default())
+    }
 
-        *existing_group = StoredParts {
-            resource: Group {
-                id: group_id.to_string(),
-                display_name,
-                external_id,
-                members,
-            },
+    async fn get_user_at(
+        &self,
+        user_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        if !self.history {
+            return Err(history_not_implemented());
+        }
 
-            meta: StoredMeta {
-                // Keep creation time
-                created: existing_group.meta.created,
-                // Update the modification time
-                last_modified: Utc::now(),
-                // Don't touch the version, this impl does not support that!
-                version: existing_group.meta.version.clone(),
-            },
+        let state = self.state.lock().await;
+
+        let Some(user) = state
+            .user_revisions
+            .get(user_id)
+            .and_then(|revisions| latest_as_of(revisions, at))
+        else {
+            return Ok(None);
         };
 
-        Ok(existing_group.clone())
+        let groups = state
+            .group_revisions
+            .values()
+            .filter_map(|revisions| latest_as_of(revisions, at));
+
+        Ok(Some(user_as_of(user.clone(), groups)))
     }
 
-    async fn delete_group_by_id(
+    async fn get_group_at(
         &self,
         group_id: &str,
-    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
-        let mut state = self.state.lock().unwrap();
-
-        let result = if state.groups.remove(group_id).is_some() {
-            // Delete all existing group membership for this group id
-            for stored_part in state.users.values_mut() {
-                if let Some(groups) = &mut stored_part.resource.groups {
-                    groups.retain(|user_group| {
-                        user_group.value.as_deref() != Some(group_id)
-                    });
-                }
-            }
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        if !self.history {
+            return Err(history_not_implemented());
+        }
 
-            ProviderStoreDeleteResult::Deleted
-        } else {
-            ProviderStoreDeleteResult::NotFound
+        let state = self.state.lock().await;
+
+        let Some(group) = state
+            .group_revisions
+            .get(group_id)
+            .and_then(|revisions| latest_as_of(revisions, at))
+        else {
+            return Ok(None);
         };
 
-        Ok(result)
+        Ok(Some(group_as_of(group.clone(), |user_id| {
+            state
+                .user_revisions
+                .get(user_id)
+                .and_then(|revisions| latest_as_of(revisions, at))
+                .is_some()
+        })))
+    }
+
+    async fn user_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        let state = self.state.lock().await;
+
+        Ok(match since {
+            Some(since) => {
+                delta_since(&*state.user_marks, since, state.change_counter)
+            }
+            None => StoreDelta {
+                changed: state.users.keys().cloned().collect(),
+                deleted: vec![],
+                counter: state.change_counter,
+            },
+        })
+    }
+
+    async fn group_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        let state = self.state.lock().await;
+
+        Ok(match since {
+            Some(since) => {
+                delta_since(&*state.group_marks, since, state.change_counter)
+            }
+            None => StoreDelta {
+                changed: state.groups.keys().cloned().collect(),
+                deleted: vec![],
+                counter: state.change_counter,
+            },
+        })
     }
 }
 
@@ -513,24 +1855,82 @@ mod test {
     use anyhow::bail;
     use http::StatusCode;
     use reqwest::{Response, Url};
+    use scim2_test_provider_server::ServerConfig;
+    use scim2_test_provider_server::StoreConfig;
     use serde::{Serialize, de::DeserializeOwned};
     use serde_json::json;
     use uuid::Uuid;
 
     use crate::{
-        Group, ListResponse, PATCHOP_URN, Resource, ResourceType,
-        SingleResourceResponse, StoredMeta, StoredParts, User,
+        DeletedResource, Group, ListResponse, PATCHOP_URN, Resource,
+        ResourceType, SingleResourceResponse, StoredMeta, StoredParts,
+        TypedListResponse, User,
     };
 
+    // These tests exercise the provider store through the test provider
+    // server. Every store that the server supports must pass all of them, so
+    // instantiate each test once per store.
+    macro_rules! store_tests {
+        ($($test:ident),* $(,)?) => {
+            mod in_memory {
+                use scim2_test_provider_server::StoreConfig;
+                $(
+                    #[tokio::test]
+                    async fn $test() {
+                        super::$test(StoreConfig::InMemory(None)).await
+                    }
+                )*
+            }
+
+            mod sqlite {
+                use scim2_test_provider_server::StoreConfig;
+                $(
+                    #[tokio::test]
+                    async fn $test() {
+                        super::$test(StoreConfig::Sqlite(None)).await
+                    }
+                )*
+            }
+        };
+    }
+
+    store_tests!(
+        test_create_user,
+        test_create_user_with_group_membership,
+        test_list_users,
+        test_filters,
+        test_escaped_filter,
+        test_replace_user,
+        test_user_indexes,
+        test_patch_user,
+        test_create_group,
+        test_list_groups,
+        test_group_display_name_index,
+        test_replace_group,
+        test_delete_group,
+        test_patch_group,
+        test_pagination,
+        test_soft_delete,
+        test_history,
+        test_incremental_sync,
+    );
+
     struct ServerCtx {
         base_url: Url,
         client: reqwest::Client,
         _server_handle: tokio::task::JoinHandle<Result<(), String>>,
     }
 
-    async fn setup() -> anyhow::Result<ServerCtx> {
+    async fn setup(store: StoreConfig) -> anyhow::Result<ServerCtx> {
+        setup_with_config(ServerConfig { store, ..Default::default() }).await
+    }
+
+    async fn setup_with_config(
+        config: ServerConfig,
+    ) -> anyhow::Result<ServerCtx> {
         let server =
-            scim2_test_provider_server::create_http_server(None).unwrap();
+            scim2_test_provider_server::create_http_server(None, config)
+                .unwrap();
         let addr = server.local_addr();
         let base_url = format!("http://{addr}/v2").parse().unwrap();
         let server_handle = tokio::spawn(server);
@@ -546,15 +1946,7 @@ mod test {
         R: Resource + DeserializeOwned + Serialize,
     {
         let response: SingleResourceResponse = result.json().await?;
-
-        if !response.resource.schemas.contains(&R::schema()) {
-            bail!("response does not contain {} schema", R::resource_type());
-        }
-
-        let resource: R =
-            serde_json::from_value(serde_json::to_value(&response.resource)?)?;
-
-        Ok(StoredParts { resource, meta: response.meta.into() })
+        Ok(response.try_into()?)
     }
 
     async fn result_as_resource_list<R>(
@@ -564,9 +1956,7 @@ mod test {
         R: Resource + DeserializeOwned + Serialize,
     {
         let response: ListResponse = result.json().await?;
-
-        let resources: Vec<R> =
-            serde_json::from_value(serde_json::to_value(&response.resources)?)?;
+        let response: TypedListResponse<R> = response.try_into()?;
 
         if response.total_results != response.resources.len() {
             // TODO: totalResults may be larger than the returned resources when
@@ -578,7 +1968,7 @@ mod test {
             );
         }
 
-        Ok(resources)
+        Ok(response.resources.into_iter().map(|r| r.resource).collect())
     }
 
     async fn create_user(
@@ -719,9 +2109,18 @@ mod test {
         result_as_resource(result).await.unwrap().resource
     }
 
-    #[tokio::test]
-    async fn test_create_user() {
-        let ctx = setup().await.unwrap();
+    async fn get_group(ctx: &ServerCtx, group_id: &str) -> Group {
+        let result = ctx
+            .client
+            .get(format!("{}/Groups/{}", ctx.base_url, group_id))
+            .send()
+            .await
+            .unwrap();
+        result_as_resource(result).await.unwrap().resource
+    }
+
+    async fn test_create_user(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, _meta) = create_jim_user(&ctx).await.unwrap();
 
         let conflict_result = create_user(
@@ -738,9 +2137,8 @@ mod test {
         assert_eq!(error.error_type.unwrap(), crate::ErrorType::Uniqueness)
     }
 
-    #[tokio::test]
-    async fn test_create_user_with_group_membership() {
-        let ctx = setup().await.unwrap();
+    async fn test_create_user_with_group_membership(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (group, _) = create_sales_group(&ctx).await.unwrap();
 
         let user_name = "cbratton";
@@ -776,9 +2174,8 @@ mod test {
         assert_eq!(user.groups, None);
     }
 
-    #[tokio::test]
-    async fn test_list_users() {
-        let ctx = setup().await.unwrap();
+    async fn test_list_users(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, _meta) = create_jim_user(&ctx).await.unwrap();
         let (dwight, _meta) = create_dwight_user(&ctx).await.unwrap();
         let result = ctx
@@ -808,9 +2205,142 @@ mod test {
         assert!(filtered_users.contains(&jim));
     }
 
-    #[tokio::test]
-    async fn test_replace_user() {
-        let ctx = setup().await.unwrap();
+    /// The sorted names of the resources in `collection` that match `filter`,
+    /// or the SCIM error the request failed with
+    async fn filter_names(
+        ctx: &ServerCtx,
+        collection: &str,
+        filter: &str,
+    ) -> Result<Vec<String>, crate::Error> {
+        let mut url: Url =
+            format!("{}/{collection}", ctx.base_url).parse().unwrap();
+        url.query_pairs_mut().append_pair("filter", filter);
+
+        let result = ctx.client.get(url).send().await.unwrap();
+        if result.status() != StatusCode::OK {
+            return Err(result.json().await.unwrap());
+        }
+
+        let response: ListResponse = result.json().await.unwrap();
+        let mut names: Vec<String> = response
+            .resources
+            .iter()
+            .map(|resource| {
+                let name = resource
+                    .get("userName")
+                    .or_else(|| resource.get("displayName"))
+                    .unwrap();
+                name.as_str().unwrap().to_string()
+            })
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    async fn test_filters(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        create_jim_user(&ctx).await.unwrap();
+        create_dwight_user(&ctx).await.unwrap();
+        create_sales_group(&ctx).await.unwrap();
+        create_management_group(&ctx).await.unwrap();
+
+        let result = ctx
+            .client
+            .post(format!("{}/Users", ctx.base_url))
+            .json(&json!({ "userName": "pbeesly", "active": false }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+
+        for (filter, expected) in [
+            (r#"userName sw "j""#, vec!["jhalpert"]),
+            (r#"userName co "SCHR""#, vec!["dschrute"]),
+            (
+                r#"externalId ew "@DunderMifflin.com""#,
+                vec!["dschrute", "jhalpert"],
+            ),
+            ("externalId pr", vec!["dschrute", "jhalpert"]),
+            ("not (externalId pr)", vec!["pbeesly"]),
+            (
+                r#"userName eq "JHalpert" or active eq false"#,
+                vec!["jhalpert", "pbeesly"],
+            ),
+            (
+                r#"(userName sw "d" or userName sw "p") and not (active eq false)"#,
+                vec!["dschrute"],
+            ),
+            (
+                r#"urn:ietf:params:scim:schemas:core:2.0:User:userName ne "jhalpert""#,
+                vec!["dschrute", "pbeesly"],
+            ),
+            (r#"meta.created lt "2000-01-01T00:00:00Z""#, vec![]),
+        ] {
+            assert_eq!(
+                filter_names(&ctx, "Users", filter).await.unwrap(),
+                expected,
+                "{filter}"
+            );
+        }
+
+        assert_eq!(
+            filter_names(
+                &ctx,
+                "Groups",
+                r#"displayName sw "sales" or externalId eq "UPPER_management""#
+            )
+            .await
+            .unwrap(),
+            vec!["Management", "Sales Reps"],
+        );
+
+        // Filters that don't parse, or that the store can't evaluate, are
+        // rejected
+        for filter in [
+            "userName eq",
+            r#"displayName eq "Sales Reps""#,
+            "active gt true",
+            r#"meta.lastModified gt "yesterday""#,
+            r#"emails[type eq "work"]"#,
+        ] {
+            let error = filter_names(&ctx, "Users", filter).await.unwrap_err();
+            assert_eq!(error.status, StatusCode::BAD_REQUEST, "{filter}");
+            assert_eq!(
+                error.error_type,
+                Some(crate::ErrorType::InvalidFilter),
+                "{filter}"
+            );
+        }
+    }
+
+    async fn test_escaped_filter(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        create_jim_user(&ctx).await.unwrap();
+
+        // A value with a quote and a backslash, which the filter builder
+        // escapes, has to be looked up as it was written
+        let user_name = r#"d"schrute\beets"#;
+        let result = create_user(&ctx, user_name, "dschrute").await.unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+
+        let filter = crate::Filter::attr("userName").eq(user_name);
+        assert_eq!(filter.to_string(), r#"userName eq "d\"schrute\\beets""#);
+        assert_eq!(
+            filter_names(&ctx, "Users", &filter.to_string()).await.unwrap(),
+            vec![user_name],
+        );
+
+        // and the escapes can't be used to break out of the string
+        let filter =
+            crate::Filter::attr("userName").eq(r#"x" or userName pr or "x"#);
+        assert_eq!(
+            filter_names(&ctx, "Users", &filter.to_string()).await.unwrap(),
+            Vec::<String>::new(),
+        );
+    }
+
+    async fn test_replace_user(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, jim_meta) = create_jim_user(&ctx).await.unwrap();
         let _dwight_parts = create_dwight_user(&ctx).await.unwrap();
 
@@ -894,9 +2424,115 @@ mod test {
         assert!(check_user.external_id.is_none());
     }
 
-    #[tokio::test]
-    async fn test_patch_user() {
-        let ctx = setup().await.unwrap();
+    async fn list_users_with_filter(
+        ctx: &ServerCtx,
+        filter: &str,
+    ) -> Vec<User> {
+        let mut url: Url = format!("{}/Users", ctx.base_url).parse().unwrap();
+        url.set_query(Some(&format!("filter={filter}")));
+
+        let result = ctx.client.get(url).send().await.unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        result_as_resource_list(result).await.unwrap()
+    }
+
+    async fn test_user_indexes(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        let (jim, _) = create_jim_user(&ctx).await.unwrap();
+        let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
+
+        // Lookups by externalId
+
+        let users = list_users_with_filter(
+            &ctx,
+            "externalId eq \"JHALPERT@dundermifflin.com\"",
+        )
+        .await;
+        assert_eq!(users, vec![jim.clone()]);
+
+        // userName uniqueness is case-insensitive
+
+        let result = create_user(&ctx, "JHalpert", "other").await.unwrap();
+        assert_eq!(result.status(), StatusCode::CONFLICT);
+
+        // Rename Jim, and make sure the old values no longer resolve to him
+
+        let body = json!({
+            "userName": "bigtuna",
+            "externalId": "bigtuna@dundermifflin.com",
+        });
+        let result = ctx
+            .client
+            .put(format!("{}/Users/{}", ctx.base_url, jim.id))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        let jim: User = result_as_resource(result).await.unwrap().resource;
+
+        assert!(
+            list_users_with_filter(&ctx, "userName eq \"jhalpert\"")
+                .await
+                .is_empty()
+        );
+        assert!(
+            list_users_with_filter(
+                &ctx,
+                "externalId eq \"jhalpert@dundermifflin.com\""
+            )
+            .await
+            .is_empty()
+        );
+        assert_eq!(
+            list_users_with_filter(&ctx, "userName eq \"BigTuna\"").await,
+            vec![jim.clone()],
+        );
+
+        // A delete removes the user from the indexes too
+
+        let result = ctx
+            .client
+            .delete(format!("{}/Users/{}", ctx.base_url, jim.id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NO_CONTENT);
+
+        assert!(
+            list_users_with_filter(&ctx, "userName eq \"bigtuna\"")
+                .await
+                .is_empty()
+        );
+        assert_eq!(
+            list_users_with_filter(&ctx, "userName eq \"dschrute\"").await,
+            vec![dwight],
+        );
+
+        // Both of Jim's old names are free to be used again
+
+        let result = create_user(&ctx, "bigtuna", "jhalpert@dundermifflin.com")
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+        let result =
+            create_user(&ctx, "jhalpert", "jhalpert@dundermifflin.com")
+                .await
+                .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+
+        // externalId is not unique, so both show up
+
+        let users = list_users_with_filter(
+            &ctx,
+            "externalId eq \"jhalpert@dundermifflin.com\"",
+        )
+        .await;
+        assert_eq!(users.len(), 2);
+    }
+
+    async fn test_patch_user(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, _) = create_jim_user(&ctx).await.unwrap();
 
         // Verify we are starting with no value
@@ -933,9 +2569,57 @@ mod test {
         user_is_durably_stored(&ctx, &user).await;
     }
 
-    #[tokio::test]
-    async fn test_create_group() {
-        let ctx = setup().await.unwrap();
+    async fn test_pagination(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        for i in 0..5 {
+            let result =
+                create_user(&ctx, &format!("temp{i}"), "temp").await.unwrap();
+            assert_eq!(result.status(), StatusCode::CREATED);
+        }
+
+        let mut url: Url = format!("{}/Users", ctx.base_url).parse().unwrap();
+        let mut seen = Vec::new();
+
+        for start_index in [1, 3, 5] {
+            url.set_query(Some(&format!("startIndex={start_index}&count=2")));
+            let result = ctx.client.get(url.clone()).send().await.unwrap();
+            assert_eq!(result.status(), StatusCode::OK);
+
+            let response: ListResponse = result.json().await.unwrap();
+            assert_eq!(response.total_results, 5);
+            assert_eq!(response.start_index, Some(start_index));
+            assert_eq!(response.items_per_page, Some(response.resources.len()));
+            assert!(response.resources.len() <= 2);
+
+            seen.extend(
+                response.resources.into_iter().map(|r| r["id"].clone()),
+            );
+        }
+
+        // Every user shows up exactly once across the pages
+        assert_eq!(seen.len(), 5);
+        seen.sort_by_key(|id| id.to_string());
+        seen.dedup();
+        assert_eq!(seen.len(), 5);
+
+        // Paging composes with a filter, and a count of 0 still reports the
+        // total
+        url.set_query(Some("filter=externalId eq \"temp\"&count=0"));
+        let result = ctx.client.get(url.clone()).send().await.unwrap();
+        let response: ListResponse = result.json().await.unwrap();
+        assert_eq!(response.total_results, 5);
+        assert!(response.resources.is_empty());
+
+        // Past the end is an empty page
+        url.set_query(Some("startIndex=10"));
+        let result = ctx.client.get(url).send().await.unwrap();
+        let response: ListResponse = result.json().await.unwrap();
+        assert_eq!(response.total_results, 5);
+        assert!(response.resources.is_empty());
+    }
+
+    async fn test_create_group(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (sales, _sales_meta) = create_sales_group(&ctx).await.unwrap();
 
         let conflict_result = create_group(
@@ -952,9 +2636,8 @@ mod test {
         assert_eq!(error.error_type.unwrap(), crate::ErrorType::Uniqueness)
     }
 
-    #[tokio::test]
-    async fn test_list_groups() {
-        let ctx = setup().await.unwrap();
+    async fn test_list_groups(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (sales, _sales_meta) = create_sales_group(&ctx).await.unwrap();
         let (mgmt, _mgmt_meta) = create_management_group(&ctx).await.unwrap();
 
@@ -990,9 +2673,66 @@ mod test {
         assert!(filtered_users.contains(&sales));
     }
 
-    #[tokio::test]
-    async fn test_replace_group() {
-        let ctx = setup().await.unwrap();
+    async fn test_group_display_name_index(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        let (sales, _) = create_sales_group(&ctx).await.unwrap();
+
+        // displayName uniqueness is case-insensitive
+
+        let result = create_group(&ctx, "SALES REPS", "other").await.unwrap();
+        assert_eq!(result.status(), StatusCode::CONFLICT);
+
+        // Rename the group
+
+        let body = json!({
+            "schemas": [Group::schema()],
+            "displayName": "Sales Associates",
+        });
+        let result = ctx
+            .client
+            .put(format!("{}/Groups/{}", ctx.base_url, sales.id))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+
+        // The old name is free, and the new one is taken
+
+        let result =
+            create_group(&ctx, "sales associates", "other").await.unwrap();
+        assert_eq!(result.status(), StatusCode::CONFLICT);
+
+        let result = create_group(&ctx, "Sales Reps", "other").await.unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+        let new_sales: Group =
+            result_as_resource(result).await.unwrap().resource;
+
+        // Deleting the renamed group removes it from the index
+
+        let result = ctx
+            .client
+            .delete(format!("{}/Groups/{}", ctx.base_url, sales.id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NO_CONTENT);
+
+        let mut url: Url = format!("{}/Groups", ctx.base_url).parse().unwrap();
+        url.set_query(Some("filter=displayName eq \"Sales Associates\""));
+        let result = ctx.client.get(url).send().await.unwrap();
+        let groups: Vec<Group> = result_as_resource_list(result).await.unwrap();
+        assert!(groups.is_empty());
+
+        let mut url: Url = format!("{}/Groups", ctx.base_url).parse().unwrap();
+        url.set_query(Some("filter=displayName eq \"sales reps\""));
+        let result = ctx.client.get(url).send().await.unwrap();
+        let groups: Vec<Group> = result_as_resource_list(result).await.unwrap();
+        assert_eq!(groups, vec![new_sales]);
+    }
+
+    async fn test_replace_group(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, _) = create_jim_user(&ctx).await.unwrap();
         let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
         let (sales, sales_meta) = create_sales_group(&ctx).await.unwrap();
@@ -1124,57 +2864,356 @@ mod test {
         );
     }
 
-    #[tokio::test]
-    async fn test_delete_group() {
-        let ctx = setup().await.unwrap();
+    async fn test_delete_group(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        let (jim, _) = create_jim_user(&ctx).await.unwrap();
+        let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
+        let (sales, _sales_meta) = create_sales_group(&ctx).await.unwrap();
+
+        // Add the users to the group with PUT
+
+        let body = json!(
+            {
+              "schemas": [Group::schema()],
+              "displayName": "Sales Reps",
+              "members": [
+                {
+                  "value": jim.id,
+                },
+                {
+                  "value": dwight.id,
+                }
+              ]
+            }
+        );
+
+        let result = ctx
+            .client
+            .put(format!("{}/Groups/{}", ctx.base_url, sales.id))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+
+        let group: Group = result_as_resource(result).await.unwrap().resource;
+        group_is_durably_stored(&ctx, &group).await;
+
+        // Delete the group
+
+        ctx.client
+            .delete(format!("{}/Groups/{}", ctx.base_url, sales.id))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+
+        let dwight = get_user(&ctx, &dwight.id).await;
+        assert!(dwight.groups.is_none());
+    }
+
+    async fn test_soft_delete(store: StoreConfig) {
+        let ctx = setup_with_config(ServerConfig {
+            store,
+            soft_delete_retention: Some(chrono::TimeDelta::days(30)),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let admin_url = ctx.base_url.join("/admin/deleted/").unwrap();
+
+        let (jim, _) = create_jim_user(&ctx).await.unwrap();
+        let result = ctx
+            .client
+            .post(format!("{}/Groups", ctx.base_url))
+            .json(&json!({
+                "displayName": "Sales",
+                "members": [{ "value": jim.id }],
+            }))
+            .send()
+            .await
+            .unwrap();
+        let sales: Group = result_as_resource(result).await.unwrap().resource;
+
+        // Deleting the user looks like any other delete
+        let result = ctx
+            .client
+            .delete(format!("{}/Users/{}", ctx.base_url, jim.id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NO_CONTENT);
+
+        let result = ctx
+            .client
+            .get(format!("{}/Users/{}", ctx.base_url, jim.id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+
+        let sales_now = get_group(&ctx, &sales.id).await;
+        assert!(sales_now.members.unwrap_or_default().is_empty());
+
+        // but the user is listed by the admin API
+        let deleted: Vec<DeletedResource<User>> = ctx
+            .client
+            .get(admin_url.join("Users").unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(deleted.len(), 1);
+        assert_eq!(deleted[0].resource.resource.id, jim.id);
+
+        // and can be restored, memberships included
+        let result = ctx
+            .client
+            .post(admin_url.join(&format!("Users/{}/restore", jim.id)).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        let restored: User = result_as_resource(result).await.unwrap().resource;
+        assert_eq!(restored.id, jim.id);
+        assert_eq!(restored.groups.unwrap().len(), 1);
+
+        let sales_now = get_group(&ctx, &sales.id).await;
+        assert_eq!(sales_now.members.unwrap().len(), 1);
+
+        // Restoring something that is not deleted is a 404
+        let result = ctx
+            .client
+            .post(admin_url.join(&format!("Users/{}/restore", jim.id)).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+
+        // Nothing is old enough to purge
+        let result: serde_json::Value = ctx
+            .client
+            .post(admin_url.join("purge").unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(result, json!({ "purged": 0 }));
+    }
+
+    async fn test_history(store: StoreConfig) {
+        // Without history, the admin endpoints say so
+        let ctx = setup(store.clone()).await.unwrap();
+        let (jim, _) = create_jim_user(&ctx).await.unwrap();
+        let result = ctx
+            .client
+            .get(
+                ctx.base_url
+                    .join(&format!("/admin/history/Users/{}", jim.id))
+                    .unwrap(),
+            )
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_IMPLEMENTED);
+
+        let ctx = setup_with_config(ServerConfig {
+            store,
+            history: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let admin_url = ctx.base_url.join("/admin/history/").unwrap();
+
+        let (jim, _) = create_jim_user(&ctx).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let before_replace = chrono::Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+
+        let result = ctx
+            .client
+            .put(format!("{}/Users/{}", ctx.base_url, jim.id))
+            .json(&json!({
+                "userName": "jhalpert",
+                "externalId": "rpark@dundermifflin.com",
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+
+        let revisions: Vec<crate::Revision<User>> = ctx
+            .client
+            .get(admin_url.join(&format!("Users/{}", jim.id)).unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(revisions.len(), 2);
+        assert_eq!(
+            revisions[1].resource.as_ref().unwrap().resource.external_id,
+            Some(String::from("rpark@dundermifflin.com"))
+        );
+
+        let result = ctx
+            .client
+            .get(admin_url.join(&format!("Users/{}/as-of", jim.id)).unwrap())
+            .query(&[("at", before_replace.to_rfc3339())])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        let old_jim: User = result_as_resource(result).await.unwrap().resource;
+        assert_eq!(old_jim.external_id, jim.external_id);
+
+        // Before the user existed, there is nothing to see
+        let result = ctx
+            .client
+            .get(admin_url.join(&format!("Users/{}/as-of", jim.id)).unwrap())
+            .query(&[("at", "2000-01-01T00:00:00Z")])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+    }
+
+    async fn test_incremental_sync(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
+        let delta_url = ctx.base_url.join("/v2/Delta/Users").unwrap();
+        let group_delta_url = ctx.base_url.join("/v2/Delta/Groups").unwrap();
+
         let (jim, _) = create_jim_user(&ctx).await.unwrap();
-        let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
-        let (sales, _sales_meta) = create_sales_group(&ctx).await.unwrap();
+        let result = ctx
+            .client
+            .post(format!("{}/Groups", ctx.base_url))
+            .json(&json!({
+                "schemas": [Group::schema()],
+                "displayName": "Sales Reps",
+                "members": [{ "value": jim.id }],
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+        let sales: StoredParts<Group> =
+            result_as_resource(result).await.unwrap();
 
-        // Add the users to the group with PUT
+        // A full listing comes with a watermark
+        let full: crate::DeltaResponse = ctx
+            .client
+            .get(delta_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(full.changed, std::slice::from_ref(&jim.id));
+        let full_groups: crate::DeltaResponse = ctx
+            .client
+            .get(group_delta_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
 
-        let body = json!(
-            {
-              "schemas": [Group::schema()],
-              "displayName": "Sales Reps",
-              "members": [
-                {
-                  "value": jim.id,
-                },
-                {
-                  "value": dwight.id,
-                }
-              ]
-            }
-        );
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+        let since = chrono::Utc::now();
+        tokio::time::sleep(std::time::Duration::from_millis(2)).await;
 
+        let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
         let result = ctx
             .client
-            .put(format!("{}/Groups/{}", ctx.base_url, sales.id))
-            .json(&body)
+            .delete(format!("{}/Users/{}", ctx.base_url, jim.id))
             .send()
             .await
             .unwrap();
+        assert_eq!(result.status(), StatusCode::NO_CONTENT);
 
-        let group: Group = result_as_resource(result).await.unwrap().resource;
-        group_is_durably_stored(&ctx, &group).await;
+        // Filtering on lastModified finds what was created or changed since
+        let result = ctx
+            .client
+            .get(format!("{}/Users", ctx.base_url))
+            .query(&[(
+                "filter",
+                format!("meta.lastModified gt \"{}\"", since.to_rfc3339()),
+            )])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        let users: Vec<User> = result_as_resource_list(result).await.unwrap();
+        assert_eq!(users, std::slice::from_ref(&dwight));
 
-        // Delete the group
+        // and the delta has the deletes as well
+        let delta: crate::DeltaResponse = ctx
+            .client
+            .get(delta_url.clone())
+            .query(&[("watermark", &full.watermark)])
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(delta.changed, [dwight.id]);
+        assert_eq!(delta.deleted, [jim.id]);
+        assert_ne!(delta.watermark, full.watermark);
 
-        ctx.client
-            .delete(format!("{}/Groups/{}", ctx.base_url, sales.id))
-            .json(&body)
+        // Deleting Jim took him out of the group, so both mechanisms report
+        // the group as changed
+        let result = ctx
+            .client
+            .get(format!("{}/Groups", ctx.base_url))
+            .query(&[(
+                "filter",
+                format!("meta.lastModified gt \"{}\"", since.to_rfc3339()),
+            )])
             .send()
             .await
             .unwrap();
+        assert_eq!(result.status(), StatusCode::OK);
+        let groups: Vec<Group> = result_as_resource_list(result).await.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].id, sales.resource.id);
+        assert!(groups[0].members.is_none());
 
-        let dwight = get_user(&ctx, &dwight.id).await;
-        assert!(dwight.groups.is_none());
+        let delta: crate::DeltaResponse = ctx
+            .client
+            .get(group_delta_url.clone())
+            .query(&[("watermark", &full_groups.watermark)])
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(delta.changed, [sales.resource.id]);
+        assert!(delta.deleted.is_empty());
+
+        // Watermarks are checked
+        for watermark in ["nonsense", "djE6MTAwMA"] {
+            let result = ctx
+                .client
+                .get(delta_url.clone())
+                .query(&[("watermark", watermark)])
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(result.status(), StatusCode::BAD_REQUEST, "{watermark}");
+        }
     }
 
-    #[tokio::test]
-    async fn test_patch_group() {
-        let ctx = setup().await.unwrap();
+    async fn test_patch_group(store: StoreConfig) {
+        let ctx = setup(store).await.unwrap();
         let (jim, _) = create_jim_user(&ctx).await.unwrap();
         let (dwight, _) = create_dwight_user(&ctx).await.unwrap();
         let (sales, _) = create_sales_group(&ctx).await.unwrap();
@@ -1464,4 +3503,559 @@ mod test {
                 .contains(&(dwight.id.clone(), ResourceType::User.to_string()))
         );
     }
+
+    #[tokio::test]
+    async fn test_state_file() {
+        use crate::{
+            CreateGroupRequest, CreateUserRequest, GroupMember,
+            InMemoryProviderStore, Pagination, ProviderStore,
+        };
+
+        let path = std::env::temp_dir()
+            .join(format!("scim2-rs-{}.json", Uuid::new_v4()));
+
+        let store = InMemoryProviderStore::with_state_file(&path).unwrap();
+        let user = store
+            .create_user(CreateUserRequest {
+                name: String::from("kmalone"),
+                active: Some(true),
+                external_id: Some(String::from("kevin")),
+                groups: None,
+            })
+            .await
+            .unwrap();
+        let group = store
+            .create_group(CreateGroupRequest {
+                display_name: String::from("Accounting"),
+                external_id: None,
+                members: Some(
+                    [GroupMember {
+                        resource_type: None,
+                        value: Some(user.resource.id.clone()),
+                    }]
+                    .into_iter()
+                    .collect(),
+                ),
+            })
+            .await
+            .unwrap();
+        let before = serde_json::to_value(store.state().await).unwrap();
+        drop(store);
+
+        // Everything comes back after a restart, including the indexes that
+        // are not part of the snapshot.
+        let store = InMemoryProviderStore::with_state_file(&path).unwrap();
+        assert_eq!(serde_json::to_value(store.state().await).unwrap(), before);
+
+        let users = store
+            .list_users(
+                Some(crate::Filter::attr("externalId").eq("KEVIN")),
+                Pagination::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(users.resources[0].resource.id, user.resource.id);
+
+        store
+            .create_group(CreateGroupRequest {
+                display_name: String::from("accounting"),
+                external_id: None,
+                members: None,
+            })
+            .await
+            .unwrap_err();
+
+        // Deletes are persisted too
+        store.delete_group_by_id(&group.resource.id).await.unwrap();
+        drop(store);
+
+        let store = InMemoryProviderStore::with_state_file(&path).unwrap();
+        assert!(
+            store.get_group_by_id(&group.resource.id).await.unwrap().is_none()
+        );
+        let user = store.get_user_by_id(&user.resource.id).await.unwrap();
+        assert!(user.unwrap().resource.groups.unwrap_or_default().is_empty());
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(super::StateFile::journal_path(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_state_file_journal() {
+        use std::io::Write;
+
+        use crate::{CreateUserRequest, InMemoryProviderStore, ProviderStore};
+
+        let path = std::env::temp_dir()
+            .join(format!("scim2-rs-{}.json", Uuid::new_v4()));
+        let journal_path = super::StateFile::journal_path(&path);
+        let snapshot = || std::fs::read_to_string(&path).unwrap();
+        let journal_lines =
+            || std::fs::read_to_string(&journal_path).unwrap().lines().count();
+
+        let create_user = |name: &str| CreateUserRequest {
+            name: name.to_string(),
+            active: Some(true),
+            external_id: None,
+            groups: None,
+        };
+
+        let store = InMemoryProviderStore::with_state_file(&path)
+            .unwrap()
+            .with_history();
+        let empty = snapshot();
+
+        // Changes go to the journal, and leave the snapshot alone
+        let kevin = store.create_user(create_user("kmalone")).await.unwrap();
+        store.create_user(create_user("omartinez")).await.unwrap();
+        store.delete_user_by_id(&kevin.resource.id).await.unwrap();
+        assert_eq!(snapshot(), empty);
+        assert_eq!(journal_lines(), 3);
+
+        let before = serde_json::to_value(store.state().await).unwrap();
+        drop(store);
+
+        // A crash part way through writing an entry leaves it cut short
+        let mut journal = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&journal_path)
+            .unwrap();
+        journal.write_all(br#"{"users":{"#).unwrap();
+        drop(journal);
+
+        // Reopening replays the journal, skipping the cut short entry, and
+        // folds it into a new snapshot.
+        let store = InMemoryProviderStore::with_state_file(&path)
+            .unwrap()
+            .with_history();
+        assert_eq!(serde_json::to_value(store.state().await).unwrap(), before);
+        assert_ne!(snapshot(), empty);
+        assert_eq!(journal_lines(), 0);
+
+        // If a change cannot be journaled, it is undone. Swapping the journal
+        // for a read only handle makes appending fail.
+        store.state_file.as_ref().unwrap().lock().unwrap().journal =
+            std::fs::File::open(&journal_path).unwrap();
+
+        store.create_user(create_user("amartin")).await.unwrap_err();
+        assert_eq!(serde_json::to_value(store.state().await).unwrap(), before);
+
+        // Including the indexes
+        let state = store.state.lock().await;
+        assert!(state.indexes.user_id_by_name("amartin").is_none());
+        assert!(state.indexes.user_id_by_name("omartinez").is_some());
+        drop(state);
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&journal_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_create_group() {
+        use crate::{
+            CreateGroupRequest, CreateUserRequest, GroupMember,
+            InMemoryProviderStore, ProviderStore,
+        };
+
+        let path = std::env::temp_dir()
+            .join(format!("scim2-rs-{}.json", Uuid::new_v4()));
+        let create_user = |name: &str| CreateUserRequest {
+            name: name.to_string(),
+            active: Some(true),
+            external_id: None,
+            groups: None,
+        };
+        let member = |id: &str| GroupMember {
+            resource_type: None,
+            value: Some(id.to_string()),
+        };
+
+        let store = InMemoryProviderStore::with_state_file(&path).unwrap();
+        let kevin = store.create_user(create_user("kmalone")).await.unwrap();
+
+        // The second member is missing, so the group is not created
+        store
+            .create_group(CreateGroupRequest {
+                display_name: String::from("Accounting"),
+                external_id: None,
+                members: Some(
+                    [member(&kevin.resource.id), member("missing")]
+                        .into_iter()
+                        .collect(),
+                ),
+            })
+            .await
+            .unwrap_err();
+
+        // and the next change to be saved does not put kevin in it
+        store.create_user(create_user("omartinez")).await.unwrap();
+        let groups = async |store: &InMemoryProviderStore| {
+            let state = store.state().await;
+            state.users.get(&kevin.resource.id).unwrap().resource.groups.clone()
+        };
+        assert_eq!(groups(&store).await, None);
+        drop(store);
+
+        let store = InMemoryProviderStore::with_state_file(&path).unwrap();
+        assert_eq!(groups(&store).await, None);
+        assert!(store.state().await.groups.is_empty());
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(super::StateFile::journal_path(&path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::store_conformance::run(|| async {
+            crate::InMemoryProviderStore::new()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_conformance() {
+        crate::store_conformance::run_soft_delete(|| async {
+            crate::InMemoryProviderStore::new()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox_conformance() {
+        crate::store_conformance::run_outbox(|| async {
+            crate::InMemoryProviderStore::new().with_outbox()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_conformance() {
+        crate::store_conformance::run_history(|| async {
+            crate::InMemoryProviderStore::new().with_history()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_soft_delete_conformance() {
+        crate::store_conformance::run_history_soft_delete(|| async {
+            crate::InMemoryProviderStore::new().with_history()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delta_conformance() {
+        crate::store_conformance::run_delta(|| async {
+            crate::InMemoryProviderStore::new()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_webhook_dead_letters() {
+        // Nothing listens on this port once the listener is dropped
+        let unused = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", unused.local_addr().unwrap());
+        drop(unused);
+
+        let ctx = setup_with_config(ServerConfig {
+            webhooks: vec![scim2_test_provider_server::WebhookEndpoint {
+                url: url.clone(),
+                secret: b"secret".to_vec(),
+            }],
+            webhook_retry: scim2_test_provider_server::WebhookRetryPolicy {
+                max_attempts: 2,
+                initial_backoff: std::time::Duration::from_millis(10),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let admin_url = ctx.base_url.join("/admin/webhooks/").unwrap();
+
+        create_jim_user(&ctx).await.unwrap();
+
+        let mut dead_letters = vec![];
+        for _ in 0..500 {
+            dead_letters = ctx
+                .client
+                .get(admin_url.join("dead-letters").unwrap())
+                .send()
+                .await
+                .unwrap()
+                .json::<Vec<crate::DeadLetter>>()
+                .await
+                .unwrap();
+            if !dead_letters.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].endpoint, url);
+        assert_eq!(dead_letters[0].attempts, 2);
+        assert!(matches!(
+            dead_letters[0].event.kind,
+            crate::ChangeEventKind::UserCreated { .. }
+        ));
+
+        let result: serde_json::Value = ctx
+            .client
+            .post(admin_url.join("dead-letters/redeliver").unwrap())
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(result, json!({ "redelivered": 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_auth() {
+        let ctx = setup_with_config(ServerConfig {
+            bearer_tokens: vec![
+                String::from("old-token"),
+                String::from("new-token"),
+            ],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let users_url = format!("{}/Users", ctx.base_url);
+
+        // Without a valid token, requests are turned away
+        for token in [None, Some("this-is-invalid")] {
+            let mut request = ctx.client.get(&users_url);
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            let result = request.send().await.unwrap();
+            assert_eq!(result.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(
+                result.headers()[http::header::WWW_AUTHENTICATE],
+                "Bearer"
+            );
+            let error: crate::Error = result.json().await.unwrap();
+            assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let result = ctx
+            .client
+            .post(&users_url)
+            .json(&json!({ "userName": "jhalpert" }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::UNAUTHORIZED);
+
+        // Both tokens are accepted, so that clients can move between them
+        for token in ["old-token", "new-token"] {
+            let result = ctx
+                .client
+                .get(&users_url)
+                .bearer_auth(token)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(result.status(), StatusCode::OK);
+        }
+
+        // Discovery needs no token, and says how to authenticate
+        let config: serde_json::Value = ctx
+            .client
+            .get(format!("{}/ServiceProviderConfig", ctx.base_url))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(
+            config["authenticationSchemes"][0]["type"],
+            "oauthbearertoken"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let ctx = setup_with_config(ServerConfig {
+            tenants: true,
+            bearer_tokens: vec![String::from("admin-token")],
+            tenant_bearer_tokens: vec![(
+                String::from("sabre"),
+                String::from("sabre-token"),
+            )],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let tenant_url = |tenant_id: &str| {
+            ctx.base_url
+                .join(&format!("/tenants/{tenant_id}/v2/Users"))
+                .unwrap()
+        };
+
+        // The same user name can be used once in every tenant
+        for tenant_id in ["dunder-mifflin", "sabre"] {
+            for expected in [StatusCode::CREATED, StatusCode::CONFLICT] {
+                let result = ctx
+                    .client
+                    .post(tenant_url(tenant_id))
+                    .bearer_auth("admin-token")
+                    .json(&json!({ "userName": "jhalpert" }))
+                    .send()
+                    .await
+                    .unwrap();
+                assert_eq!(result.status(), expected);
+
+                if expected == StatusCode::CREATED {
+                    let response: SingleResourceResponse =
+                        result.json().await.unwrap();
+                    // Under the address the server is bound to
+                    let location = response.meta.location;
+                    assert!(
+                        location.starts_with(&format!(
+                            "{}/",
+                            tenant_url(tenant_id)
+                        )),
+                        "{location}"
+                    );
+                }
+            }
+        }
+
+        // Tenants are not seen outside of their own path
+        let users: Vec<User> = result_as_resource_list(
+            ctx.client
+                .get(format!("{}/Users", ctx.base_url))
+                .bearer_auth("admin-token")
+                .send()
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert!(users.is_empty());
+
+        // A tenant's client is routed to its tenant, and cannot reach any
+        // other
+        let users: Vec<User> = result_as_resource_list(
+            ctx.client
+                .get(format!("{}/Users", ctx.base_url))
+                .bearer_auth("sabre-token")
+                .send()
+                .await
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(users.len(), 1);
+
+        let result = ctx
+            .client
+            .get(tenant_url("dunder-mifflin"))
+            .bearer_auth("sabre-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::FORBIDDEN);
+
+        // Tenant ids are checked
+        let result = ctx
+            .client
+            .get(tenant_url("bad%20tenant"))
+            .bearer_auth("admin-token")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_tenant_creation() {
+        let create_user = |ctx: &ServerCtx, url: Url| {
+            ctx.client.post(url).json(&json!({ "userName": "jhalpert" })).send()
+        };
+
+        // Anonymous clients cannot create tenants
+        let ctx = setup_with_config(ServerConfig {
+            tenants: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let tenant_url = |ctx: &ServerCtx, tenant_id: &str| {
+            ctx.base_url
+                .join(&format!("/tenants/{tenant_id}/v2/Users"))
+                .unwrap()
+        };
+
+        let result =
+            create_user(&ctx, tenant_url(&ctx, "sabre")).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+
+        // but can use the allowed ones
+        let ctx = setup_with_config(ServerConfig {
+            tenants: true,
+            allowed_tenants: vec![String::from("dunder-mifflin")],
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        let result = create_user(&ctx, tenant_url(&ctx, "dunder-mifflin"))
+            .await
+            .unwrap();
+        assert_eq!(result.status(), StatusCode::CREATED);
+
+        let result =
+            create_user(&ctx, tenant_url(&ctx, "sabre")).await.unwrap();
+        assert_eq!(result.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_public_url() {
+        let ctx = setup_with_config(ServerConfig {
+            tenants: true,
+            allowed_tenants: vec![String::from("sabre")],
+            public_url: Some(String::from("https://scim.example.com/")),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        for (path, expected) in [
+            ("/v2/Users", "https://scim.example.com/v2/Users/"),
+            (
+                "/tenants/sabre/v2/Users",
+                "https://scim.example.com/tenants/sabre/v2/Users/",
+            ),
+        ] {
+            let result = ctx
+                .client
+                .post(ctx.base_url.join(path).unwrap())
+                .json(&json!({ "userName": "jhalpert" }))
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(result.status(), StatusCode::CREATED);
+
+            let response: SingleResourceResponse = result.json().await.unwrap();
+            let location = response.meta.location;
+            assert!(location.starts_with(expected), "{location}");
+        }
+    }
 }
@@ -0,0 +1,723 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+#[cfg(feature = "jwks-url")]
+use std::{sync::Mutex, time::Instant};
+
+use anyhow::Context;
+use http::HeaderMap;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, EllipticCurve, Jwk, JwkSet, PublicKeyUse,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+
+use crate::{
+    AuthenticationScheme, Authenticator, ClientIdentity, Error, bearer_token,
+};
+
+/// Read a JSON Web Key Set (RFC 7517 section 5) from a file
+pub fn read_jwks_file(path: impl AsRef<Path>) -> anyhow::Result<JwkSet> {
+    let path = path.as_ref();
+    let contents = std::fs::read(path)
+        .with_context(|| format!("reading {}", path.display()))?;
+    serde_json::from_slice(&contents)
+        .with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Fetch a JSON Web Key Set from a URL, like an identity provider's
+/// `jwks_uri`
+#[cfg(feature = "jwks-url")]
+pub async fn fetch_jwks(url: &str) -> anyhow::Result<JwkSet> {
+    reqwest::get(url)
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .with_context(|| format!("fetching {url}"))?
+        .json()
+        .await
+        .with_context(|| format!("parsing JWKS from {url}"))
+}
+
+#[derive(Clone)]
+struct VerificationKey {
+    key_id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+/// The keys in `jwks` for checking signatures. Keys for encryption, and keys
+/// whose algorithm is not supported, are left out.
+fn verification_keys(jwks: &JwkSet) -> anyhow::Result<Vec<VerificationKey>> {
+    let mut keys = Vec::new();
+    for jwk in &jwks.keys {
+        let Some(algorithm) = signing_algorithm(jwk) else {
+            continue;
+        };
+
+        keys.push(VerificationKey {
+            key_id: jwk.common.key_id.clone(),
+            algorithm,
+            key: DecodingKey::from_jwk(jwk)
+                .with_context(|| format!("key {:?}", jwk.common.key_id))?,
+        });
+    }
+
+    if keys.is_empty() {
+        anyhow::bail!("the JWKS has no keys for checking signatures");
+    }
+
+    Ok(keys)
+}
+
+/// Where an authenticator's keys came from, when they can be fetched again.
+/// Identity providers rotate their signing keys, publishing the new key in
+/// the JWKS shortly before they start signing with it.
+#[cfg(feature = "jwks-url")]
+struct JwksSource {
+    url: String,
+
+    /// Fetch the keys again once they are this old
+    refresh_interval: Duration,
+
+    /// Never fetch the keys more often than this, however many tokens name a
+    /// key that is not in the set
+    min_refresh_interval: Duration,
+
+    /// When the keys were last fetched, or that was last tried. The request
+    /// that moves it does the fetch, while the others carry on with the keys
+    /// they have.
+    last_fetch: Mutex<Instant>,
+}
+
+/// How long to wait for the JWKS when refreshing it
+#[cfg(feature = "jwks-url")]
+const JWKS_FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The algorithm a key signs with, if it is a key for checking signatures
+fn signing_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    if matches!(
+        jwk.common.public_key_use,
+        Some(PublicKeyUse::Encryption | PublicKeyUse::Other(_))
+    ) {
+        return None;
+    }
+
+    if let Some(algorithm) = jwk.common.key_algorithm {
+        // Key algorithms include ones for encryption, which do not parse
+        return Algorithm::from_str(&algorithm.to_string()).ok();
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Some(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Some(Algorithm::ES256),
+            EllipticCurve::P384 => Some(Algorithm::ES384),
+            _ => None,
+        },
+        AlgorithmParameters::OctetKeyPair(_) => Some(Algorithm::EdDSA),
+
+        // A JWKS is published, so it should never hold a shared secret
+        AlgorithmParameters::OctetKey(_) => None,
+    }
+}
+
+/// Accepts OAuth 2 access tokens that are JWTs (RFC 9068), like the ones
+/// Entra ID and Okta issue to SCIM clients.
+///
+/// A token must be signed by one of the keys from the identity provider's
+/// JWKS, come from the expected issuer, be meant for one of the expected
+/// audiences, not have expired, and grant every required scope. Scopes are
+/// read from the `scope` and `scp` claims, either as a space separated string
+/// or as an array.
+///
+/// The client is named by the `sub` claim unless `with_client_claim` says
+/// otherwise, and `with_tenant_claim` names a claim (like Entra's `tid`) that
+/// says which tenant the client belongs to.
+///
+/// An authenticator made with `from_jwks_url` fetches the JWKS again every
+/// `refresh_interval`, and as soon as a token names a key it does not have
+/// (but no more often than `min_refresh_interval`), so that it follows the
+/// identity provider's key rotation. Clones share their keys.
+#[derive(Clone)]
+pub struct JwtAuthenticator {
+    keys: Arc<RwLock<Vec<VerificationKey>>>,
+    #[cfg(feature = "jwks-url")]
+    source: Option<Arc<JwksSource>>,
+    issuer: String,
+    audience: Vec<String>,
+    required_scopes: Vec<String>,
+    client_claim: String,
+    tenant_claim: Option<String>,
+    leeway: Duration,
+}
+
+impl std::fmt::Debug for JwtAuthenticator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let keys = self.keys.read().unwrap();
+        let key_ids: Vec<Option<&str>> =
+            keys.iter().map(|key| key.key_id.as_deref()).collect();
+        f.debug_struct("JwtAuthenticator")
+            .field("key_ids", &key_ids)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .field("required_scopes", &self.required_scopes)
+            .field("client_claim", &self.client_claim)
+            .field("tenant_claim", &self.tenant_claim)
+            .field("leeway", &self.leeway)
+            .finish()
+    }
+}
+
+impl JwtAuthenticator {
+    /// Check tokens against the signing keys in `jwks`. Keys for encryption,
+    /// and keys whose algorithm is not supported, are left out.
+    pub fn new(
+        jwks: &JwkSet,
+        issuer: impl Into<String>,
+        audience: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let keys = verification_keys(jwks)?;
+
+        if audience.is_empty() {
+            anyhow::bail!("tokens must be checked against an audience");
+        }
+
+        Ok(Self {
+            keys: Arc::new(RwLock::new(keys)),
+            #[cfg(feature = "jwks-url")]
+            source: None,
+            issuer: issuer.into(),
+            audience,
+            required_scopes: Vec::new(),
+            client_claim: String::from("sub"),
+            tenant_claim: None,
+            leeway: Duration::from_secs(60),
+        })
+    }
+
+    /// Check tokens against the signing keys in the JWKS at `url`, like an
+    /// identity provider's `jwks_uri`, which is fetched now and then again
+    /// whenever the keys may have changed. By default that is every hour, or
+    /// when a token names an unknown key, at most once every five minutes.
+    #[cfg(feature = "jwks-url")]
+    pub async fn from_jwks_url(
+        url: impl Into<String>,
+        issuer: impl Into<String>,
+        audience: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let url = url.into();
+        let mut authenticator =
+            Self::new(&fetch_jwks(&url).await?, issuer, audience)?;
+
+        authenticator.source = Some(Arc::new(JwksSource {
+            url,
+            refresh_interval: Duration::from_secs(60 * 60),
+            min_refresh_interval: Duration::from_secs(5 * 60),
+            last_fetch: Mutex::new(Instant::now()),
+        }));
+
+        Ok(authenticator)
+    }
+
+    /// Fetch the JWKS again once the keys are this old. Only applies to an
+    /// authenticator made with `from_jwks_url`.
+    #[cfg(feature = "jwks-url")]
+    pub fn with_refresh_interval(mut self, interval: Duration) -> Self {
+        self.update_source(|source| source.refresh_interval = interval);
+        self
+    }
+
+    /// Fetch the JWKS at most this often, when tokens name a key that is not
+    /// in it. Only applies to an authenticator made with `from_jwks_url`.
+    #[cfg(feature = "jwks-url")]
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.update_source(|source| source.min_refresh_interval = interval);
+        self
+    }
+
+    #[cfg(feature = "jwks-url")]
+    fn update_source(&mut self, update: impl FnOnce(&mut JwksSource)) {
+        // Only called while building, before the source can be shared
+        if let Some(source) = self.source.as_mut().and_then(Arc::get_mut) {
+            update(source);
+        }
+    }
+
+    /// Fetch the JWKS again if the keys are due a refresh, or if `key_id` is
+    /// not among them, unless that was tried too recently. The old keys are
+    /// kept if the fetch fails.
+    #[cfg(feature = "jwks-url")]
+    async fn refresh(&self, key_id: Option<&str>) {
+        let Some(source) = &self.source else {
+            return;
+        };
+
+        let unknown_key = key_id.is_some_and(|key_id| {
+            !self
+                .keys
+                .read()
+                .unwrap()
+                .iter()
+                .any(|key| key.key_id.as_deref() == Some(key_id))
+        });
+
+        {
+            let mut last_fetch = source.last_fetch.lock().unwrap();
+            let since = last_fetch.elapsed();
+            let due = since >= source.refresh_interval
+                || (unknown_key && since >= source.min_refresh_interval);
+            if !due {
+                return;
+            }
+            *last_fetch = Instant::now();
+        }
+
+        let fetched =
+            tokio::time::timeout(JWKS_FETCH_TIMEOUT, fetch_jwks(&source.url))
+                .await;
+        if let Ok(Ok(jwks)) = fetched
+            && let Ok(keys) = verification_keys(&jwks)
+        {
+            *self.keys.write().unwrap() = keys;
+        }
+    }
+
+    /// Only accept tokens that grant `scope`
+    pub fn with_required_scope(mut self, scope: impl Into<String>) -> Self {
+        self.required_scopes.push(scope.into());
+        self
+    }
+
+    /// Name clients by the claim `claim` instead of `sub`
+    pub fn with_client_claim(mut self, claim: impl Into<String>) -> Self {
+        self.client_claim = claim.into();
+        self
+    }
+
+    /// Take the client's tenant from the claim `claim`. Tokens without it are
+    /// refused.
+    pub fn with_tenant_claim(mut self, claim: impl Into<String>) -> Self {
+        self.tenant_claim = Some(claim.into());
+        self
+    }
+
+    /// Allow for this much clock skew when checking `exp` and `nbf`. The
+    /// default is a minute.
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The claims of `token`, if it is signed by one of the keys and passes
+    /// the standard checks
+    fn validate(
+        &self,
+        header: &jsonwebtoken::Header,
+        token: &str,
+    ) -> Option<Map<String, Value>> {
+        let keys = self.keys.read().unwrap();
+
+        // Without a `kid`, a token can only be checked if there is just one
+        // key to check it against
+        let key = match &header.kid {
+            Some(kid) => {
+                keys.iter().find(|key| key.key_id.as_ref() == Some(kid))?
+            }
+            None if keys.len() == 1 => &keys[0],
+            None => return None,
+        };
+
+        // Only the key's own algorithm is accepted, whatever the token's
+        // header says
+        let mut validation = Validation::new(key.algorithm);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&self.audience);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+
+        jsonwebtoken::decode(token, &key.key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+}
+
+/// The scopes a token grants
+fn scopes(claims: &Map<String, Value>) -> Vec<&str> {
+    let mut scopes = Vec::new();
+
+    for claim in ["scope", "scp"] {
+        match claims.get(claim) {
+            Some(Value::String(scope)) => {
+                scopes.extend(scope.split_whitespace());
+            }
+
+            Some(Value::Array(values)) => {
+                scopes.extend(values.iter().filter_map(Value::as_str));
+            }
+
+            _ => {}
+        }
+    }
+
+    scopes
+}
+
+impl Authenticator for JwtAuthenticator {
+    async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+    ) -> Result<ClientIdentity, Error> {
+        let token = bearer_token(headers).ok_or_else(Error::unauthorized)?;
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| Error::unauthorized())?;
+
+        #[cfg(feature = "jwks-url")]
+        self.refresh(header.kid.as_deref()).await;
+
+        let claims =
+            self.validate(&header, token).ok_or_else(Error::unauthorized)?;
+
+        let granted = scopes(&claims);
+        if !self
+            .required_scopes
+            .iter()
+            .all(|scope| granted.contains(&scope.as_str()))
+        {
+            return Err(Error::forbidden());
+        }
+
+        let claim = |name: &str| {
+            claims.get(name).and_then(Value::as_str).map(String::from)
+        };
+
+        let name = claim(&self.client_claim).ok_or_else(Error::forbidden)?;
+        let tenant = match &self.tenant_claim {
+            Some(tenant_claim) => {
+                Some(claim(tenant_claim).ok_or_else(Error::forbidden)?)
+            }
+            None => None,
+        };
+
+        Ok(ClientIdentity { name, tenant })
+    }
+
+    fn authentication_schemes(&self) -> Vec<AuthenticationScheme> {
+        vec![AuthenticationScheme::oauth_bearer_token()]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use http::header::AUTHORIZATION;
+    use http::{HeaderValue, StatusCode};
+    use jsonwebtoken::{EncodingKey, Header};
+    use rsa::RsaPrivateKey;
+    use rsa::pkcs8::{EncodePrivateKey, LineEnding};
+    use rsa::rand_core::OsRng;
+    use rsa::traits::PublicKeyParts;
+    use serde_json::json;
+    use std::sync::OnceLock;
+
+    const ISSUER: &str = "https://login.example.com/tenant";
+    const AUDIENCE: &str = "api://scim";
+
+    struct Signer {
+        key_id: String,
+        key: EncodingKey,
+        jwk: Value,
+    }
+
+    impl Signer {
+        fn generate(key_id: &str) -> Self {
+            let key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+            let pem = key.to_pkcs8_pem(LineEnding::LF).unwrap();
+
+            let encode = |bytes: Vec<u8>| URL_SAFE_NO_PAD.encode(bytes);
+
+            Self {
+                key_id: key_id.to_string(),
+                key: EncodingKey::from_rsa_pem(pem.as_bytes()).unwrap(),
+                jwk: json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "kid": key_id,
+                    "n": encode(key.n().to_bytes_be()),
+                    "e": encode(key.e().to_bytes_be()),
+                }),
+            }
+        }
+
+        fn sign(&self, claims: Value) -> HeaderMap {
+            let mut header = Header::new(Algorithm::RS256);
+            header.kid = Some(self.key_id.clone());
+            let token =
+                jsonwebtoken::encode(&header, &claims, &self.key).unwrap();
+
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+            );
+            headers
+        }
+    }
+
+    fn claims() -> Value {
+        json!({
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "exp": chrono::Utc::now().timestamp() + 300,
+            "sub": "okta-scim-client",
+            "tid": "dunder-mifflin",
+            "scp": "scim.read scim.write",
+        })
+    }
+
+    /// Generating RSA keys is slow, so the tests share one
+    fn signer() -> &'static Signer {
+        static SIGNER: OnceLock<Signer> = OnceLock::new();
+        SIGNER.get_or_init(|| Signer::generate("2026-10"))
+    }
+
+    fn authenticator(signer: &Signer) -> JwtAuthenticator {
+        let jwks: JwkSet =
+            serde_json::from_value(json!({ "keys": [signer.jwk] })).unwrap();
+        JwtAuthenticator::new(&jwks, ISSUER, vec![AUDIENCE.to_string()])
+            .unwrap()
+            .with_required_scope("scim.write")
+            .with_tenant_claim("tid")
+    }
+
+    #[tokio::test]
+    async fn test_jwt_claims() {
+        let signer = signer();
+        let authenticator = authenticator(signer);
+
+        let identity =
+            authenticator.authenticate(&signer.sign(claims())).await.unwrap();
+        assert_eq!(
+            identity,
+            ClientIdentity {
+                name: String::from("okta-scim-client"),
+                tenant: Some(String::from("dunder-mifflin")),
+            }
+        );
+
+        // Scopes can be an array too
+        let mut granted = claims();
+        granted["scp"] = json!(["scim.write"]);
+        assert!(
+            authenticator.authenticate(&signer.sign(granted)).await.is_ok()
+        );
+
+        // Tokens that are not for us, or no longer good, are unauthorized
+        for (claim, value) in [
+            ("iss", json!("https://evil.example.com")),
+            ("aud", json!("api://something-else")),
+            ("exp", json!(chrono::Utc::now().timestamp() - 3600)),
+        ] {
+            let mut claims = claims();
+            claims[claim] = value;
+            let error = authenticator
+                .authenticate(&signer.sign(claims))
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), StatusCode::UNAUTHORIZED, "{claim}");
+        }
+
+        // while valid tokens that do not let the client in are forbidden
+        for (claim, value) in [
+            ("scp", json!("scim.read")),
+            ("tid", Value::Null),
+            ("sub", json!(42)),
+        ] {
+            let mut claims = claims();
+            claims[claim] = value;
+            let error = authenticator
+                .authenticate(&signer.sign(claims))
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), StatusCode::FORBIDDEN, "{claim}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jwt_signature() {
+        let signer = signer();
+        let authenticator = authenticator(signer);
+
+        // A token signed by a key that is not in the JWKS
+        let impostor = Signer::generate("2026-10");
+        let error = authenticator
+            .authenticate(&impostor.sign(claims()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        // A token naming a key that is not in the JWKS
+        let unknown = Signer {
+            key_id: String::from("2026-11"),
+            key: signer.key.clone(),
+            jwk: signer.jwk.clone(),
+        };
+        let error = authenticator
+            .authenticate(&unknown.sign(claims()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        // An HMAC token using the public key as its secret
+        let mut headers = HeaderMap::new();
+        let token = jsonwebtoken::encode(
+            &Header {
+                kid: Some(String::from("2026-10")),
+                ..Header::new(Algorithm::HS256)
+            },
+            &claims(),
+            &EncodingKey::from_secret(
+                unknown.jwk["n"].as_str().unwrap().as_ref(),
+            ),
+        )
+        .unwrap();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        let error = authenticator.authenticate(&headers).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// Serves a JWKS, counting how often it is fetched
+    #[cfg(feature = "jwks-url")]
+    struct JwksServer {
+        jwks: std::sync::Mutex<Value>,
+        fetches: std::sync::atomic::AtomicUsize,
+    }
+
+    #[cfg(feature = "jwks-url")]
+    #[dropshot::endpoint {
+        method = GET,
+        path = "/jwks"
+    }]
+    async fn serve_jwks(
+        rqctx: dropshot::RequestContext<Arc<JwksServer>>,
+    ) -> Result<dropshot::HttpResponseOk<Value>, dropshot::HttpError> {
+        let server = rqctx.context();
+        server.fetches.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        Ok(dropshot::HttpResponseOk(server.jwks.lock().unwrap().clone()))
+    }
+
+    #[cfg(feature = "jwks-url")]
+    #[tokio::test]
+    async fn test_jwks_rotation() {
+        use std::sync::atomic::Ordering;
+
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut api = dropshot::ApiDescription::new();
+        api.register(serve_jwks).unwrap();
+
+        let jwks_server = Arc::new(JwksServer {
+            jwks: std::sync::Mutex::new(json!({ "keys": [signer().jwk] })),
+            fetches: Default::default(),
+        });
+        let config = dropshot::ConfigDropshot {
+            bind_address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+        let server = dropshot::HttpServerStarter::new(
+            &config,
+            api,
+            Arc::clone(&jwks_server),
+            &log,
+        )
+        .unwrap()
+        .start();
+
+        let authenticator = JwtAuthenticator::from_jwks_url(
+            format!("http://{}/jwks", server.local_addr()),
+            ISSUER,
+            vec![AUDIENCE.to_string()],
+        )
+        .await
+        .unwrap()
+        .with_min_refresh_interval(Duration::ZERO);
+        assert_eq!(jwks_server.fetches.load(Ordering::SeqCst), 1);
+
+        // Known keys do not cause a fetch
+        authenticator.authenticate(&signer().sign(claims())).await.unwrap();
+        assert_eq!(jwks_server.fetches.load(Ordering::SeqCst), 1);
+
+        // The identity provider rotates to a new key, which is fetched when
+        // a token first names it
+        let rotated = Signer::generate("2026-11");
+        *jwks_server.jwks.lock().unwrap() = json!({ "keys": [rotated.jwk] });
+        authenticator.authenticate(&rotated.sign(claims())).await.unwrap();
+        assert_eq!(jwks_server.fetches.load(Ordering::SeqCst), 2);
+
+        // and the old key is gone with it
+        let error = authenticator
+            .authenticate(&signer().sign(claims()))
+            .await
+            .unwrap_err();
+        assert_eq!(error.status(), StatusCode::UNAUTHORIZED);
+
+        // Unknown keys are only fetched for once per min_refresh_interval
+        let authenticator =
+            authenticator.with_min_refresh_interval(Duration::from_secs(3600));
+        let unknown = Signer {
+            key_id: String::from("2026-12"),
+            key: rotated.key.clone(),
+            jwk: rotated.jwk.clone(),
+        };
+        let fetches = jwks_server.fetches.load(Ordering::SeqCst);
+        for _ in 0..3 {
+            assert!(
+                authenticator
+                    .authenticate(&unknown.sign(claims()))
+                    .await
+                    .is_err()
+            );
+        }
+        assert_eq!(jwks_server.fetches.load(Ordering::SeqCst), fetches);
+    }
+
+    #[test]
+    fn test_jwks_keys() {
+        let signer = signer();
+        let mut encryption = signer.jwk.clone();
+        encryption["use"] = json!("enc");
+        encryption["kid"] = json!("encryption");
+
+        let jwks: JwkSet = serde_json::from_value(json!({
+            "keys": [
+                encryption,
+                { "kty": "oct", "k": "c2VjcmV0" },
+                signer.jwk.clone(),
+            ],
+        }))
+        .unwrap();
+
+        // Only the signing key is kept
+        let authenticator =
+            JwtAuthenticator::new(&jwks, ISSUER, vec![AUDIENCE.to_string()])
+                .unwrap();
+        let keys = authenticator.keys.read().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].key_id.as_deref(), Some("2026-10"));
+
+        let jwks = JwkSet { keys: jwks.keys[..2].to_vec() };
+        assert!(
+            JwtAuthenticator::new(&jwks, ISSUER, vec![AUDIENCE.to_string()])
+                .is_err()
+        );
+    }
+}
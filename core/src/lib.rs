@@ -6,40 +6,143 @@
 //! Management version 2.0 (SCIM) or RFC 7643 (schema) and RFC 7644 (protocol).
 //! At the moment it is known to work specifically with Okta serving as an IdP.
 
+mod audit;
+mod auth;
+mod context;
+mod delta;
+#[cfg(feature = "endpoints")]
+mod endpoints;
+mod events;
+mod filter;
 mod group;
+mod history;
+mod hooks;
 mod in_memory_provider_store;
+mod jwt_auth;
+mod memory_filter;
 mod meta;
+#[cfg(feature = "endpoints")]
+mod openapi;
 mod patch;
 mod provider;
 mod provider_store;
 mod query_params;
 mod resource;
 mod response;
+mod security_event;
+mod service;
+mod sql_filter;
+#[cfg(feature = "sqlite")]
+mod sqlite_provider_store;
+pub mod store_conformance;
+#[cfg(any(test, feature = "testing"))]
+pub mod store_proptest;
+mod tenant;
+mod tracked_map;
 mod urn;
 mod user;
 mod utils;
+#[cfg(feature = "webhooks")]
+mod webhook;
 
+pub use audit::AttributeChange;
+pub use audit::AuditOperation;
+pub use audit::AuditRecord;
+pub use audit::AuditSink;
+pub use audit::InMemoryAuditSink;
+pub use audit::JsonLinesAuditSink;
+pub use audit::OperationContext;
+pub use auth::AuthenticationScheme;
+pub use auth::Authenticator;
+pub use auth::ClientIdentity;
+pub use auth::StaticTokenAuthenticator;
+pub use auth::bearer_token;
+pub use auth::hash_token;
+pub use context::ScimContext;
+pub use context::ScimRequest;
+pub use context::auth_error_response;
+pub use delta::DeltaQueryParams;
+pub use delta::DeltaResponse;
+pub use delta::StoreDelta;
+#[cfg(feature = "endpoints")]
+pub use endpoints::register_scim_endpoints;
+pub use events::ChangeEvent;
+pub use events::ChangeEventKind;
+pub use events::DeadLetter;
+pub use events::OutboxEntry;
+pub use events::group_changes;
+pub use events::user_changes;
+pub use filter::AttrPath;
+pub use filter::CompValue;
+pub use filter::CompareOp;
+pub use filter::Filter;
 pub use group::CreateGroupRequest;
 pub use group::Group;
 pub use group::GroupMember;
+pub use history::Revision;
+pub use hooks::ProvisioningHooks;
 pub use in_memory_provider_store::InMemoryProviderStore;
 pub use in_memory_provider_store::InMemoryProviderStoreState;
+pub use jwt_auth::JwtAuthenticator;
+#[cfg(feature = "jwks-url")]
+pub use jwt_auth::fetch_jwks;
+pub use jwt_auth::read_jwks_file;
 pub use meta::Meta;
 pub use meta::StoredMeta;
 pub use meta::StoredParts;
+pub use meta::new_version;
+#[cfg(feature = "endpoints")]
+pub use openapi::scim_openapi;
 pub use patch::PatchRequest;
 pub use patch::PatchRequestError;
 pub use provider::Provider;
+pub use provider::UserDeletePolicy;
+pub use provider_store::ChangedGroup;
+pub use provider_store::DeactivatedUser;
+pub use provider_store::DeletedResource;
 pub use provider_store::ProviderStore;
 pub use provider_store::ProviderStoreDeleteResult;
 pub use provider_store::ProviderStoreError;
-pub use query_params::FilterOp;
+pub use provider_store::ProviderStoreListResult;
+pub use query_params::Pagination;
 pub use query_params::QueryParams;
 pub use resource::Resource;
 pub use response::Error;
 pub use response::ErrorType;
 pub use response::ListResponse;
+pub use response::ResourceConversionError;
 pub use response::SingleResourceResponse;
+pub use response::TypedListResponse;
+pub use security_event::HmacSigner;
+pub use security_event::ProvisioningOperation;
+pub use security_event::RsaSigner;
+pub use security_event::SecurityEventIssuer;
+pub use security_event::SecurityEventSigner;
+pub use security_event::SecurityEventToken;
+pub use security_event::SubjectId;
+pub use security_event::UnsignedSigner;
+pub use service::ScimService;
+pub use sql_filter::SqlColumn;
+pub use sql_filter::SqlColumnMap;
+pub use sql_filter::SqlColumnType;
+pub use sql_filter::SqlFragment;
+pub use sql_filter::SqlPlaceholder;
+pub use sql_filter::SqlValue;
+#[cfg(feature = "sqlite")]
+pub use sqlite_provider_store::SqliteProviderStore;
+pub use tenant::Tenant;
+pub use tenant::TenantStoreMap;
+pub use tenant::TenantStores;
+pub use tenant::validate_tenant_id;
+pub use urn::EVENT_ACTIVATE_URN;
+pub use urn::EVENT_CREATE_FULL_URN;
+pub use urn::EVENT_CREATE_NOTICE_URN;
+pub use urn::EVENT_DEACTIVATE_URN;
+pub use urn::EVENT_DELETE_URN;
+pub use urn::EVENT_PATCH_FULL_URN;
+pub use urn::EVENT_PATCH_NOTICE_URN;
+pub use urn::EVENT_PUT_FULL_URN;
+pub use urn::EVENT_PUT_NOTICE_URN;
 pub use urn::GROUP_URN;
 pub use urn::LISTRESPONSE_URN;
 pub use urn::PATCHOP_URN;
@@ -50,3 +153,13 @@ pub use user::User;
 pub use user::UserGroup;
 pub use user::UserGroupType;
 pub use utils::ResourceType;
+#[cfg(feature = "webhooks")]
+pub use webhook::WEBHOOK_SIGNATURE_HEADER;
+#[cfg(feature = "webhooks")]
+pub use webhook::WebhookDispatcher;
+#[cfg(feature = "webhooks")]
+pub use webhook::WebhookEndpoint;
+#[cfg(feature = "webhooks")]
+pub use webhook::WebhookRetryPolicy;
+#[cfg(feature = "webhooks")]
+pub use webhook::webhook_signature;
@@ -0,0 +1,348 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Evaluation of a parsed [`Filter`] against resources held in memory, for
+//! the `InMemoryProviderStore`.
+//!
+//! The attributes that can be filtered on, and the comparisons allowed on
+//! them, are the ones the `SqliteProviderStore` maps to columns, so that both
+//! stores accept and reject the same filters.
+
+use std::cmp::Ordering;
+
+use chrono::{DateTime, Utc};
+use unicase::UniCase;
+
+use crate::filter::{AttrPath, CompValue, CompareOp, Filter};
+use crate::{Error, Group, StoredParts, User};
+use crate::{GROUP_URN, USER_URN};
+
+/// An attribute that a filter can refer to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FilterAttr {
+    Id,
+    UserName,
+    DisplayName,
+    ExternalId,
+    Active,
+    Created,
+    LastModified,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AttrType {
+    String { case_exact: bool },
+    Boolean,
+    DateTime,
+}
+
+impl FilterAttr {
+    fn attr_type(self) -> AttrType {
+        match self {
+            FilterAttr::Id => AttrType::String { case_exact: true },
+            FilterAttr::UserName
+            | FilterAttr::DisplayName
+            | FilterAttr::ExternalId => AttrType::String { case_exact: false },
+            FilterAttr::Active => AttrType::Boolean,
+            FilterAttr::Created | FilterAttr::LastModified => {
+                AttrType::DateTime
+            }
+        }
+    }
+}
+
+/// The value of an attribute of a resource
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AttrValue<'a> {
+    String(&'a str),
+    Boolean(bool),
+    DateTime(DateTime<Utc>),
+}
+
+/// A resource type whose stored resources can be filtered in memory
+pub(crate) trait Filterable {
+    /// The schema a URN-qualified attribute path has to name
+    const SCHEMA: &'static str;
+
+    /// The attribute paths that can be filtered on
+    const ATTRIBUTES: &'static [(&'static str, FilterAttr)];
+
+    /// The value of `attr`, or `None` if the resource does not have it
+    fn value(&self, attr: FilterAttr) -> Option<AttrValue<'_>>;
+}
+
+impl Filterable for StoredParts<User> {
+    const SCHEMA: &'static str = USER_URN;
+
+    const ATTRIBUTES: &'static [(&'static str, FilterAttr)] = &[
+        ("id", FilterAttr::Id),
+        ("userName", FilterAttr::UserName),
+        ("externalId", FilterAttr::ExternalId),
+        ("active", FilterAttr::Active),
+        ("meta.created", FilterAttr::Created),
+        ("meta.lastModified", FilterAttr::LastModified),
+    ];
+
+    fn value(&self, attr: FilterAttr) -> Option<AttrValue<'_>> {
+        match attr {
+            FilterAttr::Id => Some(AttrValue::String(&self.resource.id)),
+            FilterAttr::UserName => {
+                Some(AttrValue::String(&self.resource.name))
+            }
+            FilterAttr::ExternalId => {
+                self.resource.external_id.as_deref().map(AttrValue::String)
+            }
+            FilterAttr::Active => self.resource.active.map(AttrValue::Boolean),
+            FilterAttr::Created => Some(AttrValue::DateTime(self.meta.created)),
+            FilterAttr::LastModified => {
+                Some(AttrValue::DateTime(self.meta.last_modified))
+            }
+            FilterAttr::DisplayName => None,
+        }
+    }
+}
+
+impl Filterable for StoredParts<Group> {
+    const SCHEMA: &'static str = GROUP_URN;
+
+    const ATTRIBUTES: &'static [(&'static str, FilterAttr)] = &[
+        ("id", FilterAttr::Id),
+        ("displayName", FilterAttr::DisplayName),
+        ("externalId", FilterAttr::ExternalId),
+        ("meta.created", FilterAttr::Created),
+        ("meta.lastModified", FilterAttr::LastModified),
+    ];
+
+    fn value(&self, attr: FilterAttr) -> Option<AttrValue<'_>> {
+        match attr {
+            FilterAttr::Id => Some(AttrValue::String(&self.resource.id)),
+            FilterAttr::DisplayName => {
+                Some(AttrValue::String(&self.resource.display_name))
+            }
+            FilterAttr::ExternalId => {
+                self.resource.external_id.as_deref().map(AttrValue::String)
+            }
+            FilterAttr::Created => Some(AttrValue::DateTime(self.meta.created)),
+            FilterAttr::LastModified => {
+                Some(AttrValue::DateTime(self.meta.last_modified))
+            }
+            FilterAttr::UserName | FilterAttr::Active => None,
+        }
+    }
+}
+
+/// The value on the right-hand side of a comparison, checked against the
+/// type of the attribute
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Null,
+    String(String),
+    Boolean(bool),
+    DateTime(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Compare { attr: FilterAttr, op: CompareOp, value: Operand },
+    Present(FilterAttr),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+}
+
+/// A filter whose attributes and values have been checked against a resource
+/// type
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MemoryFilter(Node);
+
+impl MemoryFilter {
+    /// Check `filter` against the attributes of `R`, rejecting filters on
+    /// attributes it does not have, and comparisons that RFC 7644 § 3.4.2.2
+    /// does not allow for an attribute's type, with an `invalidFilter` error.
+    pub fn new<R: Filterable>(filter: &Filter) -> Result<Self, Error> {
+        Ok(Self(node::<R>(filter)?))
+    }
+
+    /// The attribute and value, if this filter is a single `eq` on a string,
+    /// which a store may answer from an index
+    pub fn string_eq(&self) -> Option<(FilterAttr, &str)> {
+        match &self.0 {
+            Node::Compare {
+                attr,
+                op: CompareOp::Eq,
+                value: Operand::String(value),
+            } => Some((*attr, value)),
+            _ => None,
+        }
+    }
+
+    pub fn matches<R: Filterable>(&self, resource: &R) -> bool {
+        evaluate(&self.0, resource)
+    }
+}
+
+fn lookup<R: Filterable>(path: &AttrPath) -> Result<FilterAttr, Error> {
+    let schema_matches =
+        path.urn.as_ref().is_none_or(|urn| urn.eq_ignore_ascii_case(R::SCHEMA));
+    let dotted = path.dotted();
+
+    R::ATTRIBUTES
+        .iter()
+        .find(|(name, _)| schema_matches && name.eq_ignore_ascii_case(&dotted))
+        .map(|(_, attr)| *attr)
+        .ok_or_else(|| {
+            Error::invalid_filter(format!(
+                "filtering on {dotted} is not supported"
+            ))
+        })
+}
+
+fn node<R: Filterable>(filter: &Filter) -> Result<Node, Error> {
+    match filter {
+        Filter::And(lhs, rhs) => {
+            Ok(Node::And(Box::new(node::<R>(lhs)?), Box::new(node::<R>(rhs)?)))
+        }
+
+        Filter::Or(lhs, rhs) => {
+            Ok(Node::Or(Box::new(node::<R>(lhs)?), Box::new(node::<R>(rhs)?)))
+        }
+
+        Filter::Not(inner) => Ok(Node::Not(Box::new(node::<R>(inner)?))),
+
+        Filter::Present(path) => Ok(Node::Present(lookup::<R>(path)?)),
+
+        Filter::Compare { path, op, value } => {
+            let attr = lookup::<R>(path)?;
+            let invalid = |reason: &str| {
+                Error::invalid_filter(format!(
+                    "invalid filter on {}: {reason}",
+                    path.dotted()
+                ))
+            };
+
+            let value = match (attr.attr_type(), value) {
+                (_, CompValue::Null) => {
+                    if !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+                        return Err(invalid("null only supports eq and ne"));
+                    }
+                    Operand::Null
+                }
+
+                (AttrType::String { .. }, CompValue::String(value)) => {
+                    Operand::String(value.clone())
+                }
+
+                (AttrType::Boolean, CompValue::Bool(value)) => {
+                    if !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+                        return Err(invalid("booleans cannot be ordered"));
+                    }
+                    Operand::Boolean(*value)
+                }
+
+                (AttrType::DateTime, CompValue::String(value)) => {
+                    if matches!(
+                        op,
+                        CompareOp::Co | CompareOp::Sw | CompareOp::Ew
+                    ) {
+                        return Err(invalid("co, sw and ew require a string"));
+                    }
+                    let value = DateTime::parse_from_rfc3339(value)
+                        .map_err(|e| invalid(&format!("bad dateTime: {e}")))?;
+                    Operand::DateTime(value.with_timezone(&Utc))
+                }
+
+                _ => return Err(invalid("value has the wrong type")),
+            };
+
+            Ok(Node::Compare { attr, op: *op, value })
+        }
+    }
+}
+
+fn evaluate<R: Filterable>(node: &Node, resource: &R) -> bool {
+    match node {
+        Node::And(lhs, rhs) => {
+            evaluate(lhs, resource) && evaluate(rhs, resource)
+        }
+
+        Node::Or(lhs, rhs) => {
+            evaluate(lhs, resource) || evaluate(rhs, resource)
+        }
+
+        Node::Not(inner) => !evaluate(inner, resource),
+
+        Node::Present(attr) => resource.value(*attr).is_some(),
+
+        Node::Compare { attr, op, value } => {
+            let actual = resource.value(*attr);
+
+            match (actual, value) {
+                (actual, Operand::Null) => match op {
+                    CompareOp::Eq => actual.is_none(),
+                    _ => actual.is_some(),
+                },
+
+                // Resources without the attribute are not equal to any value,
+                // and match no other comparison.
+                (None, _) => *op == CompareOp::Ne,
+
+                (Some(AttrValue::String(actual)), Operand::String(value)) => {
+                    let case_exact = attr.attr_type()
+                        == AttrType::String { case_exact: true };
+                    compare_strings(actual, *op, value, case_exact)
+                }
+
+                (Some(AttrValue::Boolean(actual)), Operand::Boolean(value)) => {
+                    compare_ordering(*op, actual.cmp(value))
+                }
+
+                (
+                    Some(AttrValue::DateTime(actual)),
+                    Operand::DateTime(value),
+                ) => compare_ordering(*op, actual.cmp(value)),
+
+                // Ruled out when the filter was checked
+                _ => false,
+            }
+        }
+    }
+}
+
+fn compare_strings(
+    actual: &str,
+    op: CompareOp,
+    value: &str,
+    case_exact: bool,
+) -> bool {
+    let substring = |actual: &str, value: &str| match op {
+        CompareOp::Co => actual.contains(value),
+        CompareOp::Sw => actual.starts_with(value),
+        _ => actual.ends_with(value),
+    };
+
+    match op {
+        CompareOp::Co | CompareOp::Sw | CompareOp::Ew if case_exact => {
+            substring(actual, value)
+        }
+        CompareOp::Co | CompareOp::Sw | CompareOp::Ew => {
+            substring(&actual.to_lowercase(), &value.to_lowercase())
+        }
+        _ if case_exact => compare_ordering(op, actual.cmp(value)),
+        _ => {
+            compare_ordering(op, UniCase::new(actual).cmp(&UniCase::new(value)))
+        }
+    }
+}
+
+fn compare_ordering(op: CompareOp, ordering: Ordering) -> bool {
+    match op {
+        CompareOp::Eq => ordering.is_eq(),
+        CompareOp::Ne => ordering.is_ne(),
+        CompareOp::Gt => ordering.is_gt(),
+        CompareOp::Ge => ordering.is_ge(),
+        CompareOp::Lt => ordering.is_lt(),
+        CompareOp::Le => ordering.is_le(),
+        CompareOp::Co | CompareOp::Sw | CompareOp::Ew => false,
+    }
+}
@@ -5,6 +5,7 @@
 use chrono::{DateTime, Utc};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
 use crate::Resource;
 
@@ -22,7 +23,7 @@ pub struct Meta {
     pub location: String,
 }
 
-#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct StoredMeta {
     pub created: DateTime<Utc>,
 
@@ -31,12 +32,32 @@ pub struct StoredMeta {
     pub version: String,
 }
 
-#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct StoredParts<R: Resource> {
     pub resource: R,
     pub meta: StoredMeta,
 }
 
+impl StoredMeta {
+    /// The metadata of a resource created just now
+    pub fn now() -> StoredMeta {
+        let now = Utc::now();
+        StoredMeta { created: now, last_modified: now, version: new_version() }
+    }
+
+    /// Record that the resource changed just now, giving it a new version
+    pub fn touch(&mut self) {
+        self.last_modified = Utc::now();
+        self.version = new_version();
+    }
+}
+
+/// A new weak entity tag (RFC 7232, section 2.3), for a resource that changed.
+/// RFC 7644, section 3.14: a resource's version is its ETag.
+pub fn new_version() -> String {
+    format!("W/\"{}\"", Uuid::new_v4().simple())
+}
+
 impl From<Meta> for StoredMeta {
     fn from(m: Meta) -> StoredMeta {
         StoredMeta {
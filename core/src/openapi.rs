@@ -0,0 +1,255 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The OpenAPI document for the SCIM endpoints, for generating clients.
+//!
+//! Resources are put into responses as `serde_json::Map`s, so that what is
+//! returned can be picked per request, which leaves `SingleResourceResponse`
+//! and `ListResponse` without a useful schema. The types here describe what
+//! the endpoints send, and are only used for their schemas.
+
+use std::sync::Arc;
+
+use dropshot::ApiDescription;
+use schemars::JsonSchema;
+use schemars::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::Serialize;
+
+use crate::utils::urn_list_schema;
+use crate::{
+    GROUP_URN, Group, InMemoryProviderStore, LISTRESPONSE_URN, Meta, Provider,
+    USER_URN, User, register_scim_endpoints,
+};
+
+/// The OpenAPI document for the SCIM endpoints under `base_path`, as they are
+/// added by `register_scim_endpoints`
+pub fn scim_openapi(base_path: &str) -> anyhow::Result<serde_json::Value> {
+    // The context is only needed to name the type of the API
+    let mut api = ApiDescription::<Provider<Arc<InMemoryProviderStore>>>::new();
+    register_scim_endpoints(&mut api, base_path)?;
+
+    let version = semver::Version::parse(env!("CARGO_PKG_VERSION"))?;
+    let mut openapi = api.openapi("SCIM", version);
+    openapi.description(
+        "System for Cross-domain Identity Management, as specified in RFC \
+            7643 and RFC 7644",
+    );
+
+    let mut document = openapi.json()?;
+    scim_media_types(&mut document);
+    Ok(document)
+}
+
+const JSON: &str = "application/json";
+const SCIM_JSON: &str = "application/scim+json";
+
+/// Dropshot describes every body as `application/json`. RFC 7644 section 3.1
+/// makes it `application/scim+json`, which is what the endpoints send, and
+/// request bodies may be either. The errors Dropshot sends itself, for
+/// requests it cannot parse, are still `application/json`.
+fn scim_media_types(document: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    fn rename_json(content: &mut Value, keep_json: bool) {
+        let Some(content) = content.as_object_mut() else {
+            return;
+        };
+        let Some(media_type) = content.get(JSON).cloned() else {
+            return;
+        };
+
+        if !keep_json {
+            content.remove(JSON);
+        }
+        content.insert(SCIM_JSON.to_string(), media_type);
+    }
+
+    let operations = document["paths"]
+        .as_object_mut()
+        .into_iter()
+        .flat_map(|paths| paths.values_mut())
+        .filter_map(Value::as_object_mut)
+        .flat_map(|path| path.values_mut());
+    for operation in operations {
+        rename_json(&mut operation["requestBody"]["content"], true);
+
+        if let Some(responses) = operation["responses"].as_object_mut() {
+            for response in responses.values_mut() {
+                rename_json(&mut response["content"], false);
+            }
+        }
+    }
+
+    rename_json(
+        &mut document["components"]["responses"]["Error"]["content"],
+        true,
+    );
+}
+
+/// A User, as returned by the User endpoints
+#[derive(Serialize, JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct UserResource {
+    #[schemars(schema_with = "user_schemas_schema")]
+    schemas: Vec<String>,
+
+    #[serde(flatten)]
+    user: User,
+
+    meta: Meta,
+}
+
+/// A Group, as returned by the Group endpoints
+#[derive(Serialize, JsonSchema)]
+#[allow(dead_code)]
+pub(crate) struct GroupResource {
+    #[schemars(schema_with = "group_schemas_schema")]
+    schemas: Vec<String>,
+
+    #[serde(flatten)]
+    group: Group,
+
+    meta: Meta,
+}
+
+/// A page of Users (RFC 7644, section 3.4.2)
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+pub(crate) struct UserListResponse {
+    #[schemars(schema_with = "list_response_schemas_schema")]
+    schemas: Vec<String>,
+
+    total_results: usize,
+
+    start_index: Option<usize>,
+
+    items_per_page: Option<usize>,
+
+    #[serde(rename = "Resources")]
+    resources: Vec<UserResource>,
+}
+
+/// A page of Groups (RFC 7644, section 3.4.2)
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+#[allow(dead_code)]
+pub(crate) struct GroupListResponse {
+    #[schemars(schema_with = "list_response_schemas_schema")]
+    schemas: Vec<String>,
+
+    total_results: usize,
+
+    start_index: Option<usize>,
+
+    items_per_page: Option<usize>,
+
+    #[serde(rename = "Resources")]
+    resources: Vec<GroupResource>,
+}
+
+fn user_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(USER_URN)
+}
+
+fn group_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(GROUP_URN)
+}
+
+fn list_response_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(LISTRESPONSE_URN)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const OPENAPI_PATH: &str =
+        concat!(env!("CARGO_MANIFEST_DIR"), "/openapi/scim.json");
+
+    /// The checked in document has to match the endpoints, so that clients
+    /// generated from it work. Run with `EXPECTORATE=overwrite` to update it.
+    #[test]
+    fn test_openapi_document_is_current() {
+        let document = scim_openapi("/v2").unwrap();
+        let document = serde_json::to_string_pretty(&document).unwrap() + "\n";
+
+        if std::env::var("EXPECTORATE").as_deref() == Ok("overwrite") {
+            std::fs::write(OPENAPI_PATH, &document).unwrap();
+            return;
+        }
+
+        let checked_in = std::fs::read_to_string(OPENAPI_PATH).unwrap();
+        assert!(
+            checked_in == document,
+            "{OPENAPI_PATH} is out of date, run the tests with \
+                EXPECTORATE=overwrite to update it"
+        );
+    }
+
+    #[test]
+    fn test_openapi_schemas() {
+        let document = scim_openapi("/v2").unwrap();
+        let schemas = &document["components"]["schemas"];
+
+        assert_eq!(
+            schemas["UserResource"]["properties"]["userName"]["type"],
+            "string"
+        );
+        assert_eq!(
+            schemas["GroupResource"]["properties"]["schemas"]["items"]["enum"]
+                [0],
+            GROUP_URN
+        );
+        let user_list = &schemas["UserListResponse"]["properties"];
+        assert_eq!(
+            user_list["Resources"]["items"]["$ref"],
+            "#/components/schemas/UserResource"
+        );
+        assert_eq!(schemas["Error"]["properties"]["status"]["type"], "string");
+
+        // Requests can be either JSON media type
+        let post_users = &document["paths"]["/v2/Users"]["post"];
+        for media_type in [SCIM_JSON, JSON] {
+            assert_eq!(
+                post_users["requestBody"]["content"][media_type]["schema"]["$ref"],
+                "#/components/schemas/CreateUserRequest"
+            );
+        }
+
+        // but the endpoints only send application/scim+json
+        let created = &post_users["responses"]["201"]["content"];
+        assert_eq!(
+            created[SCIM_JSON]["schema"]["$ref"],
+            "#/components/schemas/UserResource"
+        );
+        assert!(created.get(JSON).is_none());
+        for path in document["paths"].as_object().unwrap().values() {
+            for operation in path.as_object().unwrap().values() {
+                for response in
+                    operation["responses"].as_object().unwrap().values()
+                {
+                    if let Some(content) = response["content"].as_object() {
+                        assert_eq!(
+                            content.keys().collect::<Vec<_>>(),
+                            [SCIM_JSON],
+                            "{operation}"
+                        );
+                    }
+                }
+            }
+        }
+        assert_eq!(
+            post_users["responses"]["4XX"]["$ref"],
+            "#/components/responses/Error"
+        );
+
+        let patch_user = &document["paths"]["/v2/Users/{id}"]["patch"];
+        assert_eq!(
+            patch_user["requestBody"]["content"][SCIM_JSON]["schema"]["$ref"],
+            "#/components/schemas/PatchRequest"
+        );
+    }
+}
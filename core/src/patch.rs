@@ -4,17 +4,21 @@
 
 use iddqd::IdOrdMap;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use schemars::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::{Deserialize, Serialize};
 use slog::Logger;
 use slog::info;
 use unicase::UniCase;
 
+use crate::Filter;
 use crate::Group;
 use crate::GroupMember;
 use crate::PATCHOP_URN;
 use crate::StoredParts;
 use crate::User;
 use crate::utils::ResourceType;
+use crate::utils::urn_list_schema;
 
 #[derive(Debug)]
 pub enum PatchRequestError {
@@ -22,22 +26,121 @@ pub enum PatchRequestError {
     Unsupported(String),
 }
 
-#[derive(Debug, Deserialize, JsonSchema, Clone)]
+/// One PATCH operation, as described in RFC 7644 section 3.5.2
+#[derive(Debug, Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(tag = "op", rename_all = "camelCase")]
 enum PatchOp {
-    Replace { path: Option<String>, value: serde_json::Value },
-    Add { path: Option<String>, value: serde_json::Value },
-    Remove { path: String },
+    /// Replace the value of the attribute at `path`, or, without a path, the
+    /// attributes given in `value`
+    Replace {
+        /// An attribute path, like `active` or `members[value eq "..."]`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        value: serde_json::Value,
+    },
+
+    /// Add `value` to the attribute at `path`, or, without a path, add the
+    /// attributes given in `value`
+    Add {
+        /// An attribute path, like `members`
+        #[serde(skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        value: serde_json::Value,
+    },
+
+    /// Remove the attribute, or the values of a multi-valued attribute, at
+    /// `path`
+    Remove {
+        /// An attribute path, like `members[value eq "..."]`
+        path: String,
+    },
 }
 
-#[derive(Debug, Deserialize, JsonSchema)]
+/// A PATCH request body (RFC 7644, section 3.5.2)
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
 pub struct PatchRequest {
+    #[schemars(schema_with = "patch_schemas_schema")]
     schemas: Vec<String>,
     #[serde(rename = "Operations")]
     operations: Vec<PatchOp>,
 }
 
+fn patch_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(PATCHOP_URN)
+}
+
+impl Default for PatchRequest {
+    fn default() -> Self {
+        Self { schemas: vec![PATCHOP_URN.to_string()], operations: vec![] }
+    }
+}
+
 impl PatchRequest {
+    /// A request without operations, for clients to add them to:
+    ///
+    /// ```
+    /// # use scim2_rs::PatchRequest;
+    /// let request = PatchRequest::new()
+    ///     .replace("displayName", "Sales".into())
+    ///     .add_members(["2819c223-7f76-453a-919d-413861904646"]);
+    /// ```
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the value of the attribute at `path`
+    pub fn replace(
+        mut self,
+        path: impl Into<String>,
+        value: serde_json::Value,
+    ) -> Self {
+        self.operations
+            .push(PatchOp::Replace { path: Some(path.into()), value });
+        self
+    }
+
+    /// Replace the attributes in `value`, an object of attribute names to
+    /// their new values
+    pub fn replace_attributes(mut self, value: serde_json::Value) -> Self {
+        self.operations.push(PatchOp::Replace { path: None, value });
+        self
+    }
+
+    /// Add `value` to the attribute at `path`
+    pub fn add(
+        mut self,
+        path: impl Into<String>,
+        value: serde_json::Value,
+    ) -> Self {
+        self.operations.push(PatchOp::Add { path: Some(path.into()), value });
+        self
+    }
+
+    /// Remove the attribute at `path`, which may pick values of a
+    /// multi-valued attribute with a filter
+    pub fn remove(mut self, path: impl Into<String>) -> Self {
+        self.operations.push(PatchOp::Remove { path: path.into() });
+        self
+    }
+
+    /// Add the users with these ids to a group
+    pub fn add_members<I, S>(self, user_ids: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        let members: Vec<serde_json::Value> = user_ids
+            .into_iter()
+            .map(|id| serde_json::json!({ "value": id.into() }))
+            .collect();
+        self.add("members", serde_json::Value::Array(members))
+    }
+
+    /// Remove the user with this id from a group
+    pub fn remove_member(self, user_id: &str) -> Self {
+        self.remove(format!("members[{}]", Filter::attr("value").eq(user_id)))
+    }
+
     /// Ensure that the parsed `PatchRequest` contians the expected schema
     /// field.
     fn validate_schema(&self) -> Result<(), PatchRequestError> {
@@ -391,7 +494,7 @@ mod test {
 
     use crate::{
         PatchRequest,
-        patch::{GroupRemoveOp, PATCHOP_URN, parse_remove_path},
+        patch::{GroupRemoveOp, PATCHOP_URN, PatchOp, parse_remove_path},
     };
 
     #[test]
@@ -413,6 +516,50 @@ mod test {
         serde_json::from_value::<PatchRequest>(json).unwrap();
     }
 
+    #[test]
+    fn test_build_patch_request() {
+        let request = PatchRequest::new()
+            .replace("displayName", json!("Sales"))
+            .add_members(["jim", "dwight"])
+            .remove_member("andy");
+
+        assert_eq!(
+            serde_json::to_value(&request).unwrap(),
+            json!({
+              "schemas": [
+                PATCHOP_URN
+              ],
+              "Operations": [
+                {
+                  "op": "replace",
+                  "path": "displayName",
+                  "value": "Sales"
+                },
+                {
+                  "op": "add",
+                  "path": "members",
+                  "value": [{ "value": "jim" }, { "value": "dwight" }]
+                },
+                {
+                  "op": "remove",
+                  "path": "members[value eq \"andy\"]"
+                }
+              ]
+            })
+        );
+
+        let request: PatchRequest =
+            serde_json::from_value(serde_json::to_value(&request).unwrap())
+                .unwrap();
+        let PatchOp::Remove { path } = &request.operations[2] else {
+            panic!("expected a remove op, got {:?}", request.operations[2]);
+        };
+        assert!(matches!(
+            parse_remove_path(path),
+            Ok(GroupRemoveOp::Indvidual(value)) if value == "andy"
+        ));
+    }
+
     #[test]
     fn test_parse_group_displayname_replace_op() {
         let json = json!({
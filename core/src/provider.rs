@@ -2,18 +2,29 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use dropshot::Body;
-use http::Response;
-use slog::{Logger, debug, error, info};
+use std::collections::BTreeSet;
 
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use http::{Response, StatusCode};
+use slog::{Logger, debug, error, info, warn};
+use tokio::sync::broadcast;
+
+use crate::audit::AuditDraft;
+use crate::delta::{decode_watermark, delta_response};
 use crate::in_memory_provider_store::{
     InMemoryProviderStore, InMemoryProviderStoreState,
 };
-use crate::response::{Error, deleted_http_response};
+use crate::response::{DEFAULT_BASE_URL, Error, deleted_http_response};
 use crate::{
-    CreateGroupRequest, CreateUserRequest, Group, ListResponse, PatchRequest,
-    ProviderStore, ProviderStoreDeleteResult, ProviderStoreError, QueryParams,
-    SingleResourceResponse, StoredParts,
+    AuditOperation, AuditRecord, AuditSink, ChangeEvent, ChangeEventKind,
+    ChangedGroup, CreateGroupRequest, CreateUserRequest, DeactivatedUser,
+    DeletedResource, DeltaResponse, Group, ListResponse, OperationContext,
+    OutboxEntry, PatchRequest, ProviderStore, ProviderStoreDeleteResult,
+    ProviderStoreError, ProvisioningHooks, ProvisioningOperation, QueryParams,
+    Resource, Revision, SecurityEventIssuer, SecurityEventToken,
+    SingleResourceResponse, StoredParts, Tenant, User, group_changes,
+    user_changes,
 };
 
 fn provider_error_to_error(
@@ -38,16 +49,424 @@ fn provider_error_to_error(
     }
 }
 
+/// How many change events a subscriber can fall behind by before it starts
+/// missing them
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// The ids of a group's members
+fn member_ids(group: &StoredParts<Group>) -> BTreeSet<String> {
+    group
+        .resource
+        .members
+        .iter()
+        .flatten()
+        .filter_map(|member| member.value.clone())
+        .collect()
+}
+
+/// RFC 7644, section 3.14: a write with `If-Match` only goes ahead if the
+/// resource is still at one of the versions it names, or it names any version
+/// with `*`
+fn check_if_match<R: Resource>(
+    if_match: Option<&str>,
+    stored: &StoredParts<R>,
+) -> Result<(), Error> {
+    let Some(if_match) = if_match else {
+        return Ok(());
+    };
+
+    let matches = if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == stored.meta.version);
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::precondition_failed(stored.resource.id()))
+    }
+}
+
+/// What a `Provider` does when asked to DELETE a User
+///
+/// Some identity providers DELETE users when they are unassigned, while others
+/// PATCH them to `active: false`. Applications that must not lose users can
+/// use this to treat both the same way.
+///
+/// A DELETE of a user that does not exist is a 404 whatever the policy.
+/// Otherwise the `before_delete_user` hook runs first, and the policy is only
+/// applied if it allows the delete. The outcome, including a rejection, is
+/// audited as a `DeleteUser`.
+#[derive(Debug, Clone, Default)]
+pub enum UserDeletePolicy {
+    /// Delete the user (or soft delete it, if soft delete is configured)
+    #[default]
+    Delete,
+
+    /// Set `active` to false and remove the user from all of its groups, but
+    /// keep it. The DELETE still succeeds, as the identity provider expects.
+    Deactivate,
+
+    /// Refuse the DELETE with this error
+    Reject(Error),
+}
+
 /// Provider implements SCIM CRUD over some provider store, transforming the
 /// Rust types returned by that store into the generic SCIM response types.
-pub struct Provider<T: ProviderStore> {
+/// The application can follow along, and veto changes, with
+/// `ProvisioningHooks`, and every change (or attempt at one) is recorded in an
+/// `AuditSink`.
+pub struct Provider<
+    T: ProviderStore,
+    H: ProvisioningHooks = (),
+    A: AuditSink = (),
+> {
     log: Logger,
     store: T,
+    hooks: H,
+    audit_sink: A,
+    events: broadcast::Sender<ChangeEvent>,
+
+    /// If set, a Security Event Token is issued for every change
+    security_events: Option<SecurityEventIssuer>,
+    security_event_tokens: broadcast::Sender<SecurityEventToken>,
+
+    /// If set, deletes are soft deletes, and soft deleted resources are kept
+    /// for this long before `purge_expired` removes them.
+    soft_delete_retention: Option<TimeDelta>,
+
+    user_delete_policy: UserDeletePolicy,
+
+    /// The SCIM base URL, for `meta.location`
+    base_url: String,
+
+    /// The tenant this provider serves, if it was scoped to one with
+    /// `with_tenant`
+    tenant: Option<String>,
 }
 
 impl<T: ProviderStore> Provider<T> {
     pub fn new(log: Logger, store: T) -> Self {
-        Self { log, store }
+        Self {
+            log,
+            store,
+            hooks: (),
+            audit_sink: (),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            security_events: None,
+            security_event_tokens: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            soft_delete_retention: None,
+            user_delete_policy: UserDeletePolicy::default(),
+            base_url: DEFAULT_BASE_URL.to_string(),
+            tenant: None,
+        }
+    }
+}
+
+impl<T: ProviderStore, H: ProvisioningHooks, A: AuditSink> Provider<T, H, A> {
+    pub fn with_hooks<H2: ProvisioningHooks>(
+        self,
+        hooks: H2,
+    ) -> Provider<T, H2, A> {
+        let Provider {
+            log,
+            store,
+            hooks: _,
+            audit_sink,
+            events,
+            security_events,
+            security_event_tokens,
+            soft_delete_retention,
+            user_delete_policy,
+            base_url,
+            tenant,
+        } = self;
+
+        Provider {
+            log,
+            store,
+            hooks,
+            audit_sink,
+            events,
+            security_events,
+            security_event_tokens,
+            soft_delete_retention,
+            user_delete_policy,
+            base_url,
+            tenant,
+        }
+    }
+
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    pub fn with_audit_sink<A2: AuditSink>(
+        self,
+        audit_sink: A2,
+    ) -> Provider<T, H, A2> {
+        let Provider {
+            log,
+            store,
+            hooks,
+            audit_sink: _,
+            events,
+            security_events,
+            security_event_tokens,
+            soft_delete_retention,
+            user_delete_policy,
+            base_url,
+            tenant,
+        } = self;
+
+        Provider {
+            log,
+            store,
+            hooks,
+            audit_sink,
+            events,
+            security_events,
+            security_event_tokens,
+            soft_delete_retention,
+            user_delete_policy,
+            base_url,
+            tenant,
+        }
+    }
+
+    pub fn audit_sink(&self) -> &A {
+        &self.audit_sink
+    }
+
+    /// The SCIM base URL that clients use, like `https://example.com/scim/v2`,
+    /// for the `meta.location` of resources
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self.security_events = self
+            .security_events
+            .map(|issuer| issuer.with_base_url(self.base_url.clone()));
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// A provider for `store` that shares everything else with this one: its
+    /// hooks, audit sink, event channels and settings. This is cheap, so a
+    /// provider can be made for every request, such as with the store for
+    /// the tenant the request is for (see `TenantStores`).
+    pub fn for_store<S: ProviderStore>(&self, store: S) -> Provider<S, &H, &A> {
+        Provider {
+            log: self.log.clone(),
+            store,
+            hooks: &self.hooks,
+            audit_sink: &self.audit_sink,
+            events: self.events.clone(),
+            security_events: self.security_events.clone(),
+            security_event_tokens: self.security_event_tokens.clone(),
+            soft_delete_retention: self.soft_delete_retention,
+            user_delete_policy: self.user_delete_policy.clone(),
+            base_url: self.base_url.clone(),
+            tenant: self.tenant.clone(),
+        }
+    }
+
+    /// Serve `tenant`: resources are located at its base URL, and its id is
+    /// given in change events and audit records
+    pub fn with_tenant(self, tenant: Tenant) -> Self {
+        let mut provider = self.with_base_url(tenant.base_url);
+        provider.log =
+            provider.log.new(slog::o!("tenant" => tenant.id.clone()));
+        provider.tenant = Some(tenant.id);
+        provider
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// Record the outcome of an operation in the audit sink
+    async fn audit<R>(
+        &self,
+        context: &OperationContext,
+        operation: AuditOperation,
+        mut draft: AuditDraft,
+        result: &Result<R, Error>,
+        success: StatusCode,
+    ) {
+        let (status, error) = match result {
+            Ok(_) => (success, None),
+            Err(error) => (error.status, Some(error.detail.clone())),
+        };
+
+        // The related changes were made, whatever became of the operation
+        // itself.
+        let related = std::mem::take(&mut draft.related);
+        let records = std::iter::once(AuditRecord::new(
+            context,
+            operation,
+            draft,
+            status.as_u16(),
+            error,
+        ))
+        .chain(related.into_iter().map(|(operation, draft)| {
+            AuditRecord::new(
+                context,
+                operation,
+                draft,
+                StatusCode::OK.as_u16(),
+                None,
+            )
+        }));
+
+        for mut record in records {
+            record.tenant = self.tenant.clone();
+
+            if let Err(error) = self.audit_sink.record(&record).await {
+                error!(self.log, "recording audit record failed";
+                    "record" => ?record,
+                    "error" => ?error,
+                );
+            }
+        }
+    }
+
+    /// Receive a `ChangeEvent` for every change this provider makes, from now
+    /// on.
+    ///
+    /// Delivery is best effort: events are not kept for subscribers that fall
+    /// too far behind (they see `RecvError::Lagged`), nor across restarts.
+    /// Consumers that cannot miss events should relay them from a store with
+    /// an outbox instead.
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+        self.events.subscribe()
+    }
+
+    fn publish(&self, changes: Vec<ChangeEventKind>) {
+        for kind in changes {
+            // An error only means there are no subscribers
+            let event = ChangeEvent {
+                tenant: self.tenant.clone(),
+                ..ChangeEvent::new(kind)
+            };
+            let _ = self.events.send(event);
+        }
+    }
+
+    /// Issue a Security Event Token (RFC 8417) for every change this provider
+    /// makes, to be received through `subscribe_security_events`.
+    pub fn with_security_events(mut self, issuer: SecurityEventIssuer) -> Self {
+        self.security_events =
+            Some(issuer.with_base_url(self.base_url.clone()));
+        self
+    }
+
+    /// Receive a `SecurityEventToken` for every change this provider makes,
+    /// from now on, if it was built `with_security_events`. Tokens are
+    /// unsigned claims: sign them with a `SecurityEventSigner` before sending
+    /// them on. Delivery is best effort, as for `subscribe`.
+    pub fn subscribe_security_events(
+        &self,
+    ) -> broadcast::Receiver<SecurityEventToken> {
+        self.security_event_tokens.subscribe()
+    }
+
+    fn issue_security_event<R: Resource + Clone>(
+        &self,
+        operation: ProvisioningOperation,
+        before: Option<&StoredParts<R>>,
+        after: Option<&StoredParts<R>>,
+    ) {
+        let Some(issuer) = &self.security_events else {
+            return;
+        };
+
+        // The change has been made, so failing to describe it must not fail
+        // the request
+        match issuer.issue(operation, before, after) {
+            Ok(token) => {
+                let _ = self.security_event_tokens.send(token);
+            }
+            Err(error) => {
+                error!(self.log, "issuing security event failed";
+                    "operation" => ?operation,
+                    "error" => ?error,
+                );
+            }
+        }
+    }
+
+    /// Make user and group deletes soft deletes, which can be restored until
+    /// `retention` has passed and they are purged. The store must support
+    /// soft delete.
+    pub fn with_soft_delete(mut self, retention: TimeDelta) -> Self {
+        self.soft_delete_retention = Some(retention);
+        self
+    }
+
+    pub fn soft_delete_retention(&self) -> Option<TimeDelta> {
+        self.soft_delete_retention
+    }
+
+    pub fn with_user_delete_policy(mut self, policy: UserDeletePolicy) -> Self {
+        self.user_delete_policy = policy;
+        self
+    }
+
+    pub fn store(&self) -> &T {
+        &self.store
+    }
+
+    /// RFC 7643 § 4.1.2 makes a user's `groups` attribute a read-only
+    /// reflection of group membership, so its `display` values must be what
+    /// the groups are called now. Stores keep a denormalised copy, but rather
+    /// than trust every store to have followed every rename, rewrite it from
+    /// the groups themselves before users leave the provider. Memberships of
+    /// groups that no longer exist are dropped.
+    async fn refresh_user_groups(
+        &self,
+        users: &mut [StoredParts<User>],
+    ) -> Result<(), Error> {
+        let group_ids: BTreeSet<String> = users
+            .iter()
+            .flat_map(|user| user.resource.groups.iter().flatten())
+            .filter_map(|group| group.value.clone())
+            .collect();
+
+        if group_ids.is_empty() {
+            return Ok(());
+        }
+
+        // group id -> current displayName, for the groups that still exist
+        let display_names =
+            self.store.get_group_display_names(&group_ids).await.map_err(
+                provider_error_to_error(
+                    &self.log,
+                    "get group display names failed!".to_string(),
+                ),
+            )?;
+
+        for user in users {
+            let Some(groups) = &mut user.resource.groups else {
+                continue;
+            };
+
+            groups.retain_mut(|group| {
+                let Some(group_id) = &group.value else {
+                    return true;
+                };
+
+                match display_names.get(group_id) {
+                    Some(display_name) => {
+                        group.display = Some(display_name.clone());
+                        true
+                    }
+                    None => false,
+                }
+            });
+        }
+
+        Ok(())
     }
 
     pub async fn list_users(
@@ -57,14 +476,18 @@ impl<T: ProviderStore> Provider<T> {
         let filter = query_params.filter()?;
         debug!(self.log, "filter value"; "filter" => ?filter);
 
-        let stored_users = self.store.list_users(filter).await.map_err(
-            provider_error_to_error(
-                &self.log,
-                "list users failed!".to_string(),
-            ),
-        )?;
+        let pagination = query_params.pagination();
+        let mut stored_users =
+            self.store.list_users(filter, pagination).await.map_err(
+                provider_error_to_error(
+                    &self.log,
+                    "list users failed!".to_string(),
+                ),
+            )?;
 
-        ListResponse::from_resources(stored_users, query_params)
+        self.refresh_user_groups(&mut stored_users.resources).await?;
+
+        ListResponse::from_resources(stored_users, query_params, &self.base_url)
     }
 
     pub async fn get_user_by_id(
@@ -72,7 +495,7 @@ impl<T: ProviderStore> Provider<T> {
         query_params: QueryParams,
         user_id: &str,
     ) -> Result<SingleResourceResponse, Error> {
-        let StoredParts { resource, meta } = self
+        let mut stored_user = self
             .store
             .get_user_by_id(user_id)
             .await
@@ -82,15 +505,87 @@ impl<T: ProviderStore> Provider<T> {
             ))?
             .ok_or(Error::not_found(user_id.to_string()))?;
 
+        self.refresh_user_groups(std::slice::from_mut(&mut stored_user))
+            .await?;
+        let StoredParts { resource, meta } = stored_user;
+
         SingleResourceResponse::from_resource(
             resource,
             meta,
             Some(query_params),
+            &self.base_url,
         )
     }
 
+    /// Fetch a user, or fail with a 404
+    async fn get_stored_user(
+        &self,
+        user_id: &str,
+    ) -> Result<StoredParts<User>, Error> {
+        self.store
+            .get_user_by_id(user_id)
+            .await
+            .map_err(provider_error_to_error(
+                &self.log,
+                format!("get user by id {user_id} failed!"),
+            ))?
+            .ok_or(Error::not_found(user_id.to_string()))
+    }
+
+    /// Fetch a group, or fail with a 404
+    async fn get_stored_group(
+        &self,
+        group_id: &str,
+    ) -> Result<StoredParts<Group>, Error> {
+        self.store
+            .get_group_by_id(group_id)
+            .await
+            .map_err(provider_error_to_error(
+                &self.log,
+                format!("get group by id {group_id} failed!"),
+            ))?
+            .ok_or(Error::not_found(group_id.to_string()))
+    }
+
+    /// Tell the hooks how a group's members changed
+    async fn report_membership_change(
+        &self,
+        group_id: &str,
+        before: &BTreeSet<String>,
+        after: &BTreeSet<String>,
+    ) {
+        let added: Vec<String> = after.difference(before).cloned().collect();
+        if !added.is_empty() {
+            self.hooks.after_group_members_added(group_id, &added).await;
+        }
+
+        let removed: Vec<String> = before.difference(after).cloned().collect();
+        if !removed.is_empty() {
+            self.hooks.after_group_members_removed(group_id, &removed).await;
+        }
+    }
+
     pub async fn create_user(
         &self,
+        context: &OperationContext,
+        request: CreateUserRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self.create_user_inner(&mut audit, request).await;
+        self.audit(
+            context,
+            AuditOperation::CreateUser,
+            audit,
+            &result,
+            StatusCode::CREATED,
+        )
+        .await;
+        result
+    }
+
+    async fn create_user_inner(
+        &self,
+        audit: &mut AuditDraft,
         mut request: CreateUserRequest,
     ) -> Result<SingleResourceResponse, Error> {
         // RFC 7643 4.1.1.  Singular Attributes
@@ -117,23 +612,42 @@ impl<T: ProviderStore> Provider<T> {
             );
         }
 
-        let StoredParts { resource, meta } =
-            self.store.create_user(request).await.map_err(
-                provider_error_to_error(
-                    &self.log,
-                    "create user failed!".to_string(),
-                ),
-            )?;
+        self.hooks.before_create_user(&request).await?;
+
+        let stored_user = self.store.create_user(request).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                "create user failed!".to_string(),
+            ),
+        )?;
+
+        audit.resource_id = Some(stored_user.resource.id.clone());
+        audit.changed(None, Some(&stored_user.resource));
 
-        SingleResourceResponse::from_resource(resource, meta, None)
+        self.hooks.after_create_user(&stored_user).await;
+        self.publish(user_changes(None, Some(&stored_user.resource)));
+        self.issue_security_event(
+            ProvisioningOperation::Create,
+            None,
+            Some(&stored_user),
+        );
+
+        let StoredParts { resource, meta } = stored_user;
+        SingleResourceResponse::from_resource(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
     }
 
-    pub async fn replace_user(
+    /// Replace a user in the store, without invoking any hooks
+    async fn replace_stored_user(
         &self,
         user_id: &str,
         request: CreateUserRequest,
-    ) -> Result<SingleResourceResponse, Error> {
-        let StoredParts { resource, meta } =
+    ) -> Result<StoredParts<User>, Error> {
+        let mut stored_user =
             self.store.replace_user(user_id, request).await.map_err(
                 provider_error_to_error(
                     &self.log,
@@ -141,27 +655,119 @@ impl<T: ProviderStore> Provider<T> {
                 ),
             )?;
 
-        SingleResourceResponse::from_resource(resource, meta, None)
+        self.refresh_user_groups(std::slice::from_mut(&mut stored_user))
+            .await?;
+
+        Ok(stored_user)
+    }
+
+    pub async fn replace_user(
+        &self,
+        context: &OperationContext,
+        user_id: &str,
+        request: CreateUserRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self
+            .replace_user_inner(
+                &mut audit,
+                context.if_match.as_deref(),
+                user_id,
+                request,
+            )
+            .await;
+        self.audit(
+            context,
+            AuditOperation::ReplaceUser,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn replace_user_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
+        user_id: &str,
+        request: CreateUserRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(user_id.to_string());
+
+        let before = self.get_stored_user(user_id).await?;
+        check_if_match(if_match, &before)?;
+
+        self.hooks.before_replace_user(user_id, &request).await?;
+
+        let stored_user = self.replace_stored_user(user_id, request).await?;
+
+        audit.changed(Some(&before.resource), Some(&stored_user.resource));
+
+        self.hooks.after_replace_user(&stored_user).await;
+        self.publish(user_changes(
+            Some(&before.resource),
+            Some(&stored_user.resource),
+        ));
+        self.issue_security_event(
+            ProvisioningOperation::Put,
+            Some(&before),
+            Some(&stored_user),
+        );
+
+        let StoredParts { resource, meta } = stored_user;
+        SingleResourceResponse::from_resource(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
     }
 
     pub async fn patch_user(
         &self,
+        context: &OperationContext,
         user_id: &str,
         request: PatchRequest,
     ) -> Result<SingleResourceResponse, Error> {
-        let stored_user = self
-            .store
-            .get_user_by_id(user_id)
-            .await
-            .map_err(provider_error_to_error(
-                &self.log,
-                format!("patch user by id {user_id} failed!"),
-            ))?
-            .ok_or(Error::not_found(user_id.to_string()))?;
+        let mut audit = AuditDraft::default();
+        let result = self
+            .patch_user_inner(
+                &mut audit,
+                context.if_match.as_deref(),
+                user_id,
+                request,
+            )
+            .await;
+        self.audit(
+            context,
+            AuditOperation::PatchUser,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn patch_user_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
+        user_id: &str,
+        request: PatchRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(user_id.to_string());
+
+        let stored_user = self.get_stored_user(user_id).await?;
+        check_if_match(if_match, &stored_user)?;
 
         let StoredParts { resource: user, meta: _ } =
             request.apply_user_ops(&self.log, &stored_user)?;
 
+        self.hooks.before_patch_user(&stored_user, &user).await?;
+
         let request = CreateUserRequest {
             name: user.name,
             active: user.active,
@@ -169,153 +775,1858 @@ impl<T: ProviderStore> Provider<T> {
             groups: user.groups,
         };
 
-        let StoredParts { resource, meta } =
-            self.store.replace_user(user_id, request).await.map_err(
-                provider_error_to_error(
-                    &self.log,
-                    format!("replace user by id {user_id} failed!"),
-                ),
-            )?;
+        let patched_user = self.replace_stored_user(user_id, request).await?;
 
-        SingleResourceResponse::from_resource(resource, meta, None)
+        audit
+            .changed(Some(&stored_user.resource), Some(&patched_user.resource));
+
+        self.hooks.after_patch_user(&patched_user).await;
+        self.publish(user_changes(
+            Some(&stored_user.resource),
+            Some(&patched_user.resource),
+        ));
+        self.issue_security_event(
+            ProvisioningOperation::Patch,
+            Some(&stored_user),
+            Some(&patched_user),
+        );
+
+        let StoredParts { resource, meta } = patched_user;
+        SingleResourceResponse::from_resource(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
     }
 
     pub async fn delete_user(
         &self,
+        context: &OperationContext,
         user_id: &str,
-    ) -> Result<Response<Body>, Error> {
-        match self.store.delete_user_by_id(user_id).await.map_err(
-            provider_error_to_error(
-                &self.log,
-                format!("delete user by id {user_id} failed!"),
-            ),
-        )? {
-            ProviderStoreDeleteResult::Deleted => deleted_http_response(),
+    ) -> Result<Response<Bytes>, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self
+            .delete_user_inner(&mut audit, context.if_match.as_deref(), user_id)
+            .await;
+        self.audit(
+            context,
+            AuditOperation::DeleteUser,
+            audit,
+            &result,
+            StatusCode::NO_CONTENT,
+        )
+        .await;
+        result
+    }
+
+    async fn delete_user_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
+        user_id: &str,
+    ) -> Result<Response<Bytes>, Error> {
+        audit.resource_id = Some(user_id.to_string());
+
+        // Deleting a user that does not exist is a 404 whatever the policy
+        let stored_user = self.get_stored_user(user_id).await?;
+        check_if_match(if_match, &stored_user)?;
+
+        self.hooks.before_delete_user(&stored_user).await?;
+
+        if let UserDeletePolicy::Reject(error) = &self.user_delete_policy {
+            warn!(self.log, "rejected user delete";
+                "policy" => "reject",
+                "user_id" => user_id,
+                "user_name" => &stored_user.resource.name,
+                "status" => error.status.as_u16(),
+            );
+
+            return Err(error.clone());
+        }
+
+        if let UserDeletePolicy::Deactivate = &self.user_delete_policy {
+            return self.deactivate_user(audit, user_id).await;
+        }
+
+        let result = if self.soft_delete_retention.is_some() {
+            self.store.soft_delete_user_by_id(user_id).await
+        } else {
+            self.store.delete_user_by_id(user_id).await
+        };
+
+        match result.map_err(provider_error_to_error(
+            &self.log,
+            format!("delete user by id {user_id} failed!"),
+        ))? {
+            ProviderStoreDeleteResult::Deleted => {}
 
             ProviderStoreDeleteResult::NotFound => {
-                Err(Error::not_found(user_id.to_string()))
+                return Err(Error::not_found(user_id.to_string()));
+            }
+        }
+
+        audit.changed(Some(&stored_user.resource), None);
+
+        self.hooks.after_delete_user(&stored_user).await;
+        self.publish(user_changes(Some(&stored_user.resource), None));
+        self.issue_security_event(
+            ProvisioningOperation::Delete,
+            Some(&stored_user),
+            None,
+        );
+
+        let removed = [user_id.to_string()];
+        for group in stored_user.resource.groups.iter().flatten() {
+            if let Some(group_id) = &group.value {
+                self.hooks
+                    .after_group_members_removed(group_id, &removed)
+                    .await;
             }
         }
+
+        deleted_http_response()
     }
 
-    pub async fn list_groups(
+    /// Carry out a DELETE of a user under `UserDeletePolicy::Deactivate`
+    async fn deactivate_user(
         &self,
-        query_params: QueryParams,
-    ) -> Result<ListResponse, Error> {
-        let filter = query_params.filter()?;
-        debug!(self.log, "filter value"; "filter" => ?filter);
+        audit: &mut AuditDraft,
+        user_id: &str,
+    ) -> Result<Response<Bytes>, Error> {
+        // `groups` is read-only on the user, so have the store take it out of
+        // each group and deactivate it in one go, so that no concurrent change
+        // to a group is lost and a failure doesn't leave the user half
+        // deactivated.
+        let DeactivatedUser { before, after, groups } =
+            self.store.deactivate_user(user_id).await.map_err(
+                provider_error_to_error(
+                    &self.log,
+                    format!("deactivate user {user_id} failed!"),
+                ),
+            )?;
 
-        let stored_groups = self.store.list_groups(filter).await.map_err(
-            provider_error_to_error(
-                &self.log,
-                "list groups failed!".to_string(),
-            ),
-        )?;
+        for ChangedGroup { before, after } in &groups {
+            audit.related(
+                AuditOperation::PatchGroup,
+                &before.resource,
+                &after.resource,
+            );
+            self.publish(group_changes(
+                Some(&before.resource),
+                Some(&after.resource),
+            ));
+            self.issue_security_event(
+                ProvisioningOperation::Patch,
+                Some(before),
+                Some(after),
+            );
+        }
 
-        ListResponse::from_resources(stored_groups, query_params)
+        audit.changed(Some(&before.resource), Some(&after.resource));
+        self.publish(user_changes(
+            Some(&before.resource),
+            Some(&after.resource),
+        ));
+        self.issue_security_event(
+            ProvisioningOperation::Patch,
+            Some(&before),
+            Some(&after),
+        );
+
+        info!(self.log, "deactivated user instead of deleting it";
+            "policy" => "deactivate",
+            "user_id" => user_id,
+            "user_name" => &before.resource.name,
+            "groups_left" => groups.len(),
+        );
+
+        self.hooks.after_replace_user(&after).await;
+
+        let removed = [user_id.to_string()];
+        for ChangedGroup { after, .. } in &groups {
+            self.hooks
+                .after_group_members_removed(&after.resource.id, &removed)
+                .await;
+        }
+
+        deleted_http_response()
     }
 
-    pub async fn get_group_by_id(
+    pub async fn list_groups(
         &self,
         query_params: QueryParams,
-        group_id: &str,
+    ) -> Result<ListResponse, Error> {
+        let filter = query_params.filter()?;
+        debug!(self.log, "filter value"; "filter" => ?filter);
+
+        let pagination = query_params.pagination();
+        let stored_groups =
+            self.store.list_groups(filter, pagination).await.map_err(
+                provider_error_to_error(
+                    &self.log,
+                    "list groups failed!".to_string(),
+                ),
+            )?;
+
+        ListResponse::from_resources(
+            stored_groups,
+            query_params,
+            &self.base_url,
+        )
+    }
+
+    pub async fn get_group_by_id(
+        &self,
+        query_params: QueryParams,
+        group_id: &str,
     ) -> Result<SingleResourceResponse, Error> {
-        let StoredParts { resource: group, meta } = self
-            .store
-            .get_group_by_id(group_id)
-            .await
-            .map_err(provider_error_to_error(
-                &self.log,
-                format!("get group by id {group_id} failed!"),
-            ))?
-            .ok_or(Error::not_found(group_id.to_string()))?;
+        let StoredParts { resource: group, meta } =
+            self.get_stored_group(group_id).await?;
 
         SingleResourceResponse::from_resource::<Group>(
             group,
             meta,
             Some(query_params),
+            &self.base_url,
         )
     }
 
     pub async fn create_group(
         &self,
+        context: &OperationContext,
         request: CreateGroupRequest,
     ) -> Result<SingleResourceResponse, Error> {
-        let StoredParts { resource: group, meta } =
-            self.store.create_group(request).await.map_err(
-                provider_error_to_error(
-                    &self.log,
-                    "create group failed!".to_string(),
-                ),
-            )?;
+        let mut audit = AuditDraft::default();
+        let result = self.create_group_inner(&mut audit, request).await;
+        self.audit(
+            context,
+            AuditOperation::CreateGroup,
+            audit,
+            &result,
+            StatusCode::CREATED,
+        )
+        .await;
+        result
+    }
+
+    async fn create_group_inner(
+        &self,
+        audit: &mut AuditDraft,
+        request: CreateGroupRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        self.hooks.before_create_group(&request).await?;
+
+        let stored_group = self.store.create_group(request).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                "create group failed!".to_string(),
+            ),
+        )?;
+
+        audit.resource_id = Some(stored_group.resource.id.clone());
+        audit.changed(None, Some(&stored_group.resource));
 
-        SingleResourceResponse::from_resource(group, meta, None)
+        self.hooks.after_create_group(&stored_group).await;
+        self.publish(group_changes(None, Some(&stored_group.resource)));
+        self.issue_security_event(
+            ProvisioningOperation::Create,
+            None,
+            Some(&stored_group),
+        );
+        self.report_membership_change(
+            &stored_group.resource.id,
+            &BTreeSet::new(),
+            &member_ids(&stored_group),
+        )
+        .await;
+
+        let StoredParts { resource: group, meta } = stored_group;
+        SingleResourceResponse::from_resource(group, meta, None, &self.base_url)
+    }
+
+    /// Replace a group in the store, without invoking any hooks
+    async fn replace_stored_group(
+        &self,
+        group_id: &str,
+        request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, Error> {
+        self.store.replace_group(group_id, request).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                format!("replace group by id {group_id} failed!"),
+            ),
+        )
     }
 
     pub async fn replace_group(
         &self,
+        context: &OperationContext,
         group_id: &str,
         request: CreateGroupRequest,
     ) -> Result<SingleResourceResponse, Error> {
-        let StoredParts { resource: group, meta } =
-            self.store.replace_group(group_id, request).await.map_err(
-                provider_error_to_error(
-                    &self.log,
-                    format!("replace group by id {group_id} failed!"),
-                ),
-            )?;
+        let mut audit = AuditDraft::default();
+        let result = self
+            .replace_group_inner(
+                &mut audit,
+                context.if_match.as_deref(),
+                group_id,
+                request,
+            )
+            .await;
+        self.audit(
+            context,
+            AuditOperation::ReplaceGroup,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn replace_group_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
+        group_id: &str,
+        request: CreateGroupRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(group_id.to_string());
+
+        let before = self.get_stored_group(group_id).await?;
+        check_if_match(if_match, &before)?;
+
+        self.hooks.before_replace_group(group_id, &request).await?;
+
+        let stored_group = self.replace_stored_group(group_id, request).await?;
+
+        audit.changed(Some(&before.resource), Some(&stored_group.resource));
+
+        self.hooks.after_replace_group(&stored_group).await;
+        self.publish(group_changes(
+            Some(&before.resource),
+            Some(&stored_group.resource),
+        ));
+        self.issue_security_event(
+            ProvisioningOperation::Put,
+            Some(&before),
+            Some(&stored_group),
+        );
+        self.report_membership_change(
+            group_id,
+            &member_ids(&before),
+            &member_ids(&stored_group),
+        )
+        .await;
 
-        SingleResourceResponse::from_resource(group, meta, None)
+        let StoredParts { resource: group, meta } = stored_group;
+        SingleResourceResponse::from_resource(group, meta, None, &self.base_url)
     }
 
     pub async fn delete_group(
         &self,
+        context: &OperationContext,
         group_id: &str,
-    ) -> Result<Response<Body>, Error> {
-        match self.store.delete_group_by_id(group_id).await.map_err(
-            provider_error_to_error(
-                &self.log,
-                format!("delete group by id {group_id} failed!"),
-            ),
-        )? {
-            ProviderStoreDeleteResult::Deleted => deleted_http_response(),
+    ) -> Result<Response<Bytes>, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self
+            .delete_group_inner(
+                &mut audit,
+                context.if_match.as_deref(),
+                group_id,
+            )
+            .await;
+        self.audit(
+            context,
+            AuditOperation::DeleteGroup,
+            audit,
+            &result,
+            StatusCode::NO_CONTENT,
+        )
+        .await;
+        result
+    }
+
+    async fn delete_group_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
+        group_id: &str,
+    ) -> Result<Response<Bytes>, Error> {
+        audit.resource_id = Some(group_id.to_string());
+
+        let stored_group = self.get_stored_group(group_id).await?;
+        check_if_match(if_match, &stored_group)?;
+
+        self.hooks.before_delete_group(&stored_group).await?;
+
+        let result = if self.soft_delete_retention.is_some() {
+            self.store.soft_delete_group_by_id(group_id).await
+        } else {
+            self.store.delete_group_by_id(group_id).await
+        };
+
+        match result.map_err(provider_error_to_error(
+            &self.log,
+            format!("delete group by id {group_id} failed!"),
+        ))? {
+            ProviderStoreDeleteResult::Deleted => {}
 
             ProviderStoreDeleteResult::NotFound => {
-                Err(Error::not_found(group_id.to_string()))
+                return Err(Error::not_found(group_id.to_string()));
             }
         }
+
+        audit.changed(Some(&stored_group.resource), None);
+
+        self.hooks.after_delete_group(&stored_group).await;
+        self.publish(group_changes(Some(&stored_group.resource), None));
+        self.issue_security_event(
+            ProvisioningOperation::Delete,
+            Some(&stored_group),
+            None,
+        );
+        self.report_membership_change(
+            group_id,
+            &member_ids(&stored_group),
+            &BTreeSet::new(),
+        )
+        .await;
+
+        deleted_http_response()
     }
 
     pub async fn patch_group(
         &self,
+        context: &OperationContext,
+        group_id: &str,
+        request: PatchRequest,
+    ) -> Result<SingleResourceResponse, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self
+            .patch_group_inner(
+                &mut audit,
+                context.if_match.as_deref(),
+                group_id,
+                request,
+            )
+            .await;
+        self.audit(
+            context,
+            AuditOperation::PatchGroup,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn patch_group_inner(
+        &self,
+        audit: &mut AuditDraft,
+        if_match: Option<&str>,
         group_id: &str,
         request: PatchRequest,
     ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(group_id.to_string());
+
+        let stored_group = self.get_stored_group(group_id).await?;
+        check_if_match(if_match, &stored_group)?;
+
+        let StoredParts { resource: group, meta: _ } =
+            request.apply_group_ops(&self.log, &stored_group)?;
+
+        self.hooks.before_patch_group(&stored_group, &group).await?;
+
+        let request = CreateGroupRequest {
+            display_name: group.display_name,
+            external_id: group.external_id,
+            members: group.members,
+        };
+
+        let patched_group =
+            self.replace_stored_group(group_id, request).await?;
+
+        audit.changed(
+            Some(&stored_group.resource),
+            Some(&patched_group.resource),
+        );
+
+        self.hooks.after_patch_group(&patched_group).await;
+        self.publish(group_changes(
+            Some(&stored_group.resource),
+            Some(&patched_group.resource),
+        ));
+        self.issue_security_event(
+            ProvisioningOperation::Patch,
+            Some(&stored_group),
+            Some(&patched_group),
+        );
+        self.report_membership_change(
+            group_id,
+            &member_ids(&stored_group),
+            &member_ids(&patched_group),
+        )
+        .await;
+
+        let StoredParts { resource: group, meta } = patched_group;
+        SingleResourceResponse::from_resource(group, meta, None, &self.base_url)
+    }
+
+    // Administration of soft deleted resources. These are not part of SCIM,
+    // and are meant to be exposed to operators rather than identity
+    // providers.
+
+    pub async fn list_deleted_users(
+        &self,
+    ) -> Result<Vec<DeletedResource<User>>, Error> {
+        self.store.list_deleted_users().await.map_err(provider_error_to_error(
+            &self.log,
+            "list deleted users failed!".to_string(),
+        ))
+    }
+
+    pub async fn list_deleted_groups(
+        &self,
+    ) -> Result<Vec<DeletedResource<Group>>, Error> {
+        self.store.list_deleted_groups().await.map_err(provider_error_to_error(
+            &self.log,
+            "list deleted groups failed!".to_string(),
+        ))
+    }
+
+    pub async fn restore_user(
+        &self,
+        context: &OperationContext,
+        user_id: &str,
+    ) -> Result<SingleResourceResponse, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self.restore_user_inner(&mut audit, user_id).await;
+        self.audit(
+            context,
+            AuditOperation::RestoreUser,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn restore_user_inner(
+        &self,
+        audit: &mut AuditDraft,
+        user_id: &str,
+    ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(user_id.to_string());
+
+        let mut stored_user = self
+            .store
+            .restore_user_by_id(user_id)
+            .await
+            .map_err(provider_error_to_error(
+                &self.log,
+                format!("restore user by id {user_id} failed!"),
+            ))?
+            .ok_or(Error::not_found(user_id.to_string()))?;
+
+        info!(self.log, "restored user"; "user_id" => user_id);
+        audit.changed(None, Some(&stored_user.resource));
+        self.publish(user_changes(None, Some(&stored_user.resource)));
+        self.issue_security_event(
+            ProvisioningOperation::Create,
+            None,
+            Some(&stored_user),
+        );
+
+        self.refresh_user_groups(std::slice::from_mut(&mut stored_user))
+            .await?;
+        let StoredParts { resource, meta } = stored_user;
+
+        SingleResourceResponse::from_resource(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
+    }
+
+    pub async fn restore_group(
+        &self,
+        context: &OperationContext,
+        group_id: &str,
+    ) -> Result<SingleResourceResponse, Error> {
+        let mut audit = AuditDraft::default();
+        let result = self.restore_group_inner(&mut audit, group_id).await;
+        self.audit(
+            context,
+            AuditOperation::RestoreGroup,
+            audit,
+            &result,
+            StatusCode::OK,
+        )
+        .await;
+        result
+    }
+
+    async fn restore_group_inner(
+        &self,
+        audit: &mut AuditDraft,
+        group_id: &str,
+    ) -> Result<SingleResourceResponse, Error> {
+        audit.resource_id = Some(group_id.to_string());
+
         let stored_group = self
             .store
-            .get_group_by_id(group_id)
+            .restore_group_by_id(group_id)
             .await
             .map_err(provider_error_to_error(
                 &self.log,
-                format!("patch group by id {group_id} failed!"),
+                format!("restore group by id {group_id} failed!"),
             ))?
             .ok_or(Error::not_found(group_id.to_string()))?;
 
-        let StoredParts { resource: group, meta: _ } =
-            request.apply_group_ops(&self.log, &stored_group)?;
+        info!(self.log, "restored group"; "group_id" => group_id);
+        audit.changed(None, Some(&stored_group.resource));
+        self.publish(group_changes(None, Some(&stored_group.resource)));
+        self.issue_security_event(
+            ProvisioningOperation::Create,
+            None,
+            Some(&stored_group),
+        );
 
-        let request = CreateGroupRequest {
-            display_name: group.display_name,
-            external_id: group.external_id,
-            members: group.members,
+        let StoredParts { resource: group, meta } = stored_group;
+
+        SingleResourceResponse::from_resource(group, meta, None, &self.base_url)
+    }
+
+    pub async fn read_outbox(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, Error> {
+        self.store.read_outbox(limit).await.map_err(provider_error_to_error(
+            &self.log,
+            "read outbox failed!".to_string(),
+        ))
+    }
+
+    pub async fn ack_outbox(&self, sequence: u64) -> Result<usize, Error> {
+        self.store.ack_outbox(sequence).await.map_err(provider_error_to_error(
+            &self.log,
+            format!("ack outbox through {sequence} failed!"),
+        ))
+    }
+
+    pub async fn list_user_revisions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Revision<User>>, Error> {
+        self.store.list_user_revisions(user_id).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                format!("list revisions of user {user_id} failed!"),
+            ),
+        )
+    }
+
+    pub async fn list_group_revisions(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<Revision<Group>>, Error> {
+        self.store.list_group_revisions(group_id).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                format!("list revisions of group {group_id} failed!"),
+            ),
+        )
+    }
+
+    /// The users that changed since `watermark` (from an earlier delta), or
+    /// all users if there is no watermark. Not part of SCIM.
+    pub async fn user_delta(
+        &self,
+        watermark: Option<&str>,
+    ) -> Result<DeltaResponse, Error> {
+        let since = watermark.map(decode_watermark).transpose()?;
+
+        let delta = self.store.user_delta(since).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                "user delta failed!".to_string(),
+            ),
+        )?;
+
+        delta_response(since, delta)
+    }
+
+    /// The groups that changed since `watermark` (from an earlier delta), or
+    /// all groups if there is no watermark. Not part of SCIM.
+    pub async fn group_delta(
+        &self,
+        watermark: Option<&str>,
+    ) -> Result<DeltaResponse, Error> {
+        let since = watermark.map(decode_watermark).transpose()?;
+
+        let delta = self.store.group_delta(since).await.map_err(
+            provider_error_to_error(
+                &self.log,
+                "group delta failed!".to_string(),
+            ),
+        )?;
+
+        delta_response(since, delta)
+    }
+
+    /// The user as it was at `at`, with the groups it was a member of then
+    pub async fn get_user_at(
+        &self,
+        user_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<SingleResourceResponse, Error> {
+        let StoredParts { resource, meta } = self
+            .store
+            .get_user_at(user_id, at)
+            .await
+            .map_err(provider_error_to_error(
+                &self.log,
+                format!("get user {user_id} at {at} failed!"),
+            ))?
+            .ok_or(Error::not_found(user_id.to_string()))?;
+
+        SingleResourceResponse::from_resource(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
+    }
+
+    /// The group as it was at `at`, with the members it had then
+    pub async fn get_group_at(
+        &self,
+        group_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<SingleResourceResponse, Error> {
+        let StoredParts { resource, meta } = self
+            .store
+            .get_group_at(group_id, at)
+            .await
+            .map_err(provider_error_to_error(
+                &self.log,
+                format!("get group {group_id} at {at} failed!"),
+            ))?
+            .ok_or(Error::not_found(group_id.to_string()))?;
+
+        SingleResourceResponse::from_resource::<Group>(
+            resource,
+            meta,
+            None,
+            &self.base_url,
+        )
+    }
+
+    /// Permanently remove soft deleted resources that are past the retention
+    /// period, returning how many were removed.
+    pub async fn purge_expired(&self) -> Result<usize, Error> {
+        let Some(retention) = self.soft_delete_retention else {
+            return Ok(0);
         };
 
-        self.replace_group(group_id, request).await
+        let purged =
+            self.store.purge_deleted(Utc::now() - retention).await.map_err(
+                provider_error_to_error(
+                    &self.log,
+                    "purge deleted resources failed!".to_string(),
+                ),
+            )?;
+
+        if purged > 0 {
+            info!(self.log, "purged soft deleted resources"; "count" => purged);
+        }
+
+        Ok(purged)
     }
 }
 
-impl Provider<InMemoryProviderStore> {
-    pub fn state(&self) -> InMemoryProviderStoreState {
-        self.store.state()
+impl<H: ProvisioningHooks, A: AuditSink> Provider<InMemoryProviderStore, H, A> {
+    pub async fn state(&self) -> InMemoryProviderStoreState {
+        self.store.state().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ErrorType, Filter, GroupMember, InMemoryProviderStore, Pagination,
+        ProviderStoreListResult, UserGroup, UserGroupType,
+    };
+    use std::collections::BTreeMap;
+
+    /// Wraps the in-memory store, but hands out users whose `groups` are
+    /// stale, like a store that forgot to follow a rename would.
+    struct StaleStore(InMemoryProviderStore);
+
+    fn make_stale(user: &mut StoredParts<User>) {
+        let groups = user.resource.groups.get_or_insert_default();
+        for group in groups.iter_mut() {
+            group.display = Some(String::from("stale"));
+        }
+        groups.push(UserGroup {
+            member_type: Some(UserGroupType::Direct),
+            value: Some(String::from("deleted-group")),
+            display: Some(String::from("Deleted")),
+        });
+    }
+
+    impl ProviderStore for StaleStore {
+        async fn get_user_by_id(
+            &self,
+            user_id: &str,
+        ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+            let mut user = self.0.get_user_by_id(user_id).await?;
+            user.iter_mut().for_each(make_stale);
+            Ok(user)
+        }
+
+        async fn create_user(
+            &self,
+            user_request: CreateUserRequest,
+        ) -> Result<StoredParts<User>, ProviderStoreError> {
+            self.0.create_user(user_request).await
+        }
+
+        async fn list_users(
+            &self,
+            filter: Option<Filter>,
+            pagination: Pagination,
+        ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+            let mut users = self.0.list_users(filter, pagination).await?;
+            users.resources.iter_mut().for_each(make_stale);
+            Ok(users)
+        }
+
+        async fn replace_user(
+            &self,
+            user_id: &str,
+            user_request: CreateUserRequest,
+        ) -> Result<StoredParts<User>, ProviderStoreError> {
+            let mut user = self.0.replace_user(user_id, user_request).await?;
+            make_stale(&mut user);
+            Ok(user)
+        }
+
+        async fn delete_user_by_id(
+            &self,
+            user_id: &str,
+        ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+            self.0.delete_user_by_id(user_id).await
+        }
+
+        async fn get_group_by_id(
+            &self,
+            group_id: &str,
+        ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+            self.0.get_group_by_id(group_id).await
+        }
+
+        async fn create_group(
+            &self,
+            group_request: CreateGroupRequest,
+        ) -> Result<StoredParts<Group>, ProviderStoreError> {
+            self.0.create_group(group_request).await
+        }
+
+        async fn list_groups(
+            &self,
+            filter: Option<Filter>,
+            pagination: Pagination,
+        ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError>
+        {
+            self.0.list_groups(filter, pagination).await
+        }
+
+        async fn replace_group(
+            &self,
+            group_id: &str,
+            group_request: CreateGroupRequest,
+        ) -> Result<StoredParts<Group>, ProviderStoreError> {
+            self.0.replace_group(group_id, group_request).await
+        }
+
+        async fn delete_group_by_id(
+            &self,
+            group_id: &str,
+        ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+            self.0.delete_group_by_id(group_id).await
+        }
+    }
+
+    fn response_groups(response: SingleResourceResponse) -> serde_json::Value {
+        serde_json::to_value(response).unwrap()["groups"].clone()
+    }
+
+    #[tokio::test]
+    async fn test_user_groups_display_is_current() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider =
+            Provider::new(log, StaleStore(InMemoryProviderStore::new()));
+
+        let user = provider
+            .store()
+            .create_user(CreateUserRequest {
+                name: String::from("jhalpert"),
+                active: Some(true),
+                external_id: None,
+                groups: None,
+            })
+            .await
+            .unwrap();
+        let group = provider
+            .store()
+            .create_group(CreateGroupRequest {
+                display_name: String::from("Sales"),
+                external_id: None,
+                members: Some(
+                    [GroupMember {
+                        resource_type: None,
+                        value: Some(user.resource.id.clone()),
+                    }]
+                    .into_iter()
+                    .collect(),
+                ),
+            })
+            .await
+            .unwrap();
+
+        let expected = serde_json::json!([{
+            "type": "Direct",
+            "value": group.resource.id,
+            "display": "Sales",
+        }]);
+
+        let response = provider
+            .get_user_by_id(QueryParams::default(), &user.resource.id)
+            .await
+            .unwrap();
+        assert_eq!(response_groups(response), expected);
+
+        let response = provider
+            .replace_user(
+                &OperationContext::default(),
+                &user.resource.id,
+                CreateUserRequest {
+                    name: String::from("jhalpert"),
+                    active: Some(false),
+                    external_id: None,
+                    groups: None,
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(response_groups(response), expected);
+
+        let response =
+            provider.list_users(QueryParams::default()).await.unwrap();
+        let response = serde_json::to_value(response).unwrap();
+        assert_eq!(response["Resources"][0]["groups"], expected);
+    }
+
+    async fn create_jim_in_sales(
+        store: &InMemoryProviderStore,
+    ) -> (StoredParts<User>, StoredParts<Group>) {
+        let user = store
+            .create_user(CreateUserRequest {
+                name: String::from("jhalpert"),
+                active: Some(true),
+                external_id: None,
+                groups: None,
+            })
+            .await
+            .unwrap();
+        let group = store
+            .create_group(CreateGroupRequest {
+                display_name: String::from("Sales"),
+                external_id: None,
+                members: Some(
+                    [GroupMember {
+                        resource_type: None,
+                        value: Some(user.resource.id.clone()),
+                    }]
+                    .into_iter()
+                    .collect(),
+                ),
+            })
+            .await
+            .unwrap();
+
+        (user, group)
+    }
+
+    #[tokio::test]
+    async fn test_user_delete_policy_deactivate() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_user_delete_policy(UserDeletePolicy::Deactivate)
+            .with_audit_sink(crate::InMemoryAuditSink::new());
+        let (user, group) = create_jim_in_sales(provider.store()).await;
+
+        let response = provider
+            .delete_user(&OperationContext::default(), &user.resource.id)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+
+        // The group the user left is audited along with the user
+        let records = provider.audit_sink().records();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].operation, crate::AuditOperation::DeleteUser);
+        assert_eq!(records[0].resource_id, Some(user.resource.id.clone()));
+        assert_eq!(
+            records[0].changes.keys().collect::<Vec<_>>(),
+            ["active", "groups"]
+        );
+        assert_eq!(records[1].operation, crate::AuditOperation::PatchGroup);
+        assert_eq!(records[1].resource_id, Some(group.resource.id.clone()));
+        assert_eq!(records[1].status, 200);
+        assert_eq!(records[1].changes.keys().collect::<Vec<_>>(), ["members"]);
+
+        let user = provider
+            .store()
+            .get_user_by_id(&user.resource.id)
+            .await
+            .unwrap()
+            .expect("user was kept");
+        assert_eq!(user.resource.active, Some(false));
+        assert!(user.resource.groups.unwrap_or_default().is_empty());
+
+        let group = provider
+            .store()
+            .get_group_by_id(&group.resource.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(group.resource.members.unwrap_or_default().is_empty());
+
+        // Deleting again is fine, as identity providers retry
+        provider
+            .delete_user(&OperationContext::default(), &user.resource.id)
+            .await
+            .unwrap();
+
+        let error = provider
+            .delete_user(&OperationContext::default(), "missing")
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_user_delete_policy_reject() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let rejection =
+            Error::mutability(String::from("deactivate users instead"));
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_user_delete_policy(UserDeletePolicy::Reject(
+                rejection.clone(),
+            ))
+            .with_hooks(RecordingHooks::default())
+            .with_audit_sink(crate::InMemoryAuditSink::new());
+        let (user, group) = create_jim_in_sales(provider.store()).await;
+
+        let error = provider
+            .delete_user(&OperationContext::default(), &user.resource.id)
+            .await
+            .unwrap_err();
+        assert_eq!(error, rejection);
+
+        // The hooks run before the policy, and the rejection is audited
+        assert_eq!(provider.hooks().take(), ["before_delete_user jhalpert"]);
+        let records = provider.audit_sink().records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].operation, crate::AuditOperation::DeleteUser);
+        assert_eq!(records[0].resource_id, Some(user.resource.id.clone()));
+        assert_eq!(records[0].status, rejection.status.as_u16());
+        assert!(records[0].changes.is_empty());
+
+        // Nothing changed
+        let stored_user = provider
+            .store()
+            .get_user_by_id(&user.resource.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_user.resource.active, Some(true));
+        assert_eq!(stored_user.resource.groups.unwrap().len(), 1);
+        assert_eq!(
+            provider
+                .store()
+                .get_group_by_id(&group.resource.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .resource,
+            group.resource,
+        );
+
+        let error = provider
+            .delete_user(&OperationContext::default(), "missing")
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::NOT_FOUND);
+    }
+
+    /// Wraps the in-memory store, but fails to replace users. Deactivating
+    /// uses the trait's default, which has to put back the groups it changed
+    /// before the failure.
+    struct FailingStore(InMemoryProviderStore);
+
+    impl ProviderStore for FailingStore {
+        async fn get_user_by_id(
+            &self,
+            user_id: &str,
+        ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+            self.0.get_user_by_id(user_id).await
+        }
+
+        async fn create_user(
+            &self,
+            user_request: CreateUserRequest,
+        ) -> Result<StoredParts<User>, ProviderStoreError> {
+            self.0.create_user(user_request).await
+        }
+
+        async fn list_users(
+            &self,
+            filter: Option<Filter>,
+            pagination: Pagination,
+        ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+            self.0.list_users(filter, pagination).await
+        }
+
+        async fn replace_user(
+            &self,
+            _user_id: &str,
+            _user_request: CreateUserRequest,
+        ) -> Result<StoredParts<User>, ProviderStoreError> {
+            Err(ProviderStoreError::StoreError(anyhow::anyhow!("disk full")))
+        }
+
+        async fn delete_user_by_id(
+            &self,
+            user_id: &str,
+        ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+            self.0.delete_user_by_id(user_id).await
+        }
+
+        async fn get_group_by_id(
+            &self,
+            group_id: &str,
+        ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+            self.0.get_group_by_id(group_id).await
+        }
+
+        async fn create_group(
+            &self,
+            group_request: CreateGroupRequest,
+        ) -> Result<StoredParts<Group>, ProviderStoreError> {
+            self.0.create_group(group_request).await
+        }
+
+        async fn list_groups(
+            &self,
+            filter: Option<Filter>,
+            pagination: Pagination,
+        ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError>
+        {
+            self.0.list_groups(filter, pagination).await
+        }
+
+        async fn replace_group(
+            &self,
+            group_id: &str,
+            group_request: CreateGroupRequest,
+        ) -> Result<StoredParts<Group>, ProviderStoreError> {
+            self.0.replace_group(group_id, group_request).await
+        }
+
+        async fn delete_group_by_id(
+            &self,
+            group_id: &str,
+        ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+            self.0.delete_group_by_id(group_id).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_user_delete_policy_deactivate_failure() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let store = FailingStore(InMemoryProviderStore::new());
+        let (user, group) = create_jim_in_sales(&store.0).await;
+        let provider = Provider::new(log, store)
+            .with_user_delete_policy(UserDeletePolicy::Deactivate);
+
+        let error = provider
+            .delete_user(&OperationContext::default(), &user.resource.id)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::INTERNAL_SERVER_ERROR);
+
+        // The user is neither deactivated nor out of the group
+        let stored_user = provider
+            .store()
+            .get_user_by_id(&user.resource.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_user.resource.active, Some(true));
+        let groups = stored_user.resource.groups.unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].value, Some(group.resource.id.clone()));
+        let stored_group = provider
+            .store()
+            .get_group_by_id(&group.resource.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored_group.resource, group.resource);
+    }
+
+    #[tokio::test]
+    async fn test_if_match() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new());
+        let (user, group) = create_jim_in_sales(provider.store()).await;
+        let user_id = &user.resource.id;
+
+        let if_match = |version: &str| OperationContext {
+            if_match: Some(version.to_string()),
+            ..Default::default()
+        };
+        let deactivate = || -> PatchRequest {
+            serde_json::from_value(serde_json::json!({
+                "schemas": [crate::PATCHOP_URN],
+                "Operations": [{
+                    "op": "replace",
+                    "value": { "active": false },
+                }],
+            }))
+            .unwrap()
+        };
+
+        // Joining Sales changed jhalpert, so the version it was created at
+        // is stale
+        let stored_user = provider.store().get_user_by_id(user_id).await;
+        let version = stored_user.unwrap().unwrap().meta.version;
+        assert_ne!(version, user.meta.version);
+        let error = provider
+            .patch_user(&if_match(&user.meta.version), user_id, deactivate())
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::PRECONDITION_FAILED);
+        let error = provider
+            .delete_user(&if_match(&user.meta.version), user_id)
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::PRECONDITION_FAILED);
+
+        let stored_user = provider.store().get_user_by_id(user_id).await;
+        let stored_user = stored_user.unwrap().unwrap();
+        assert_eq!(stored_user.resource.active, Some(true));
+        assert_eq!(stored_user.meta.version, version);
+
+        // Any of the versions listed will do
+        let response = provider
+            .patch_user(
+                &if_match(&format!("W/\"other\", {version}")),
+                user_id,
+                deactivate(),
+            )
+            .await
+            .unwrap();
+        assert_ne!(response.meta.version, version);
+
+        let sales = || CreateGroupRequest {
+            display_name: String::from("Sales"),
+            external_id: None,
+            members: None,
+        };
+        provider
+            .replace_group(
+                &if_match(&group.meta.version),
+                &group.resource.id,
+                sales(),
+            )
+            .await
+            .unwrap();
+        let error = provider
+            .replace_group(
+                &if_match(&group.meta.version),
+                &group.resource.id,
+                sales(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(error.status, http::StatusCode::PRECONDITION_FAILED);
+
+        // As will any version at all
+        let response = provider
+            .delete_group(&if_match("*"), &group.resource.id)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), http::StatusCode::NO_CONTENT);
+    }
+
+    /// Records every hook call, and vetoes anything to do with "vetoed", and
+    /// PATCHes that deactivate a user
+    #[derive(Default)]
+    struct RecordingHooks {
+        calls: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl RecordingHooks {
+        fn record(&self, call: String) {
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+    }
+
+    fn veto(name: &str) -> Result<(), Error> {
+        if name == "vetoed" {
+            Err(Error::mutability(format!("{name} is not allowed")))
+        } else {
+            Ok(())
+        }
+    }
+
+    impl ProvisioningHooks for RecordingHooks {
+        async fn before_create_user(
+            &self,
+            request: &CreateUserRequest,
+        ) -> Result<(), Error> {
+            self.record(format!("before_create_user {}", request.name));
+            veto(&request.name)
+        }
+
+        async fn after_create_user(&self, user: &StoredParts<User>) {
+            self.record(format!("after_create_user {}", user.resource.name));
+        }
+
+        async fn before_patch_user(
+            &self,
+            user: &StoredParts<User>,
+            patched: &User,
+        ) -> Result<(), Error> {
+            self.record(format!(
+                "before_patch_user {} active={:?}->{:?}",
+                user.resource.name, user.resource.active, patched.active
+            ));
+            if user.resource.active != Some(false)
+                && patched.active == Some(false)
+            {
+                return Err(Error::mutability(String::from(
+                    "users are deactivated by the HR system",
+                )));
+            }
+            Ok(())
+        }
+
+        async fn after_patch_user(&self, user: &StoredParts<User>) {
+            self.record(format!(
+                "after_patch_user {} active={:?}",
+                user.resource.name, user.resource.active
+            ));
+        }
+
+        async fn before_delete_user(
+            &self,
+            user: &StoredParts<User>,
+        ) -> Result<(), Error> {
+            self.record(format!("before_delete_user {}", user.resource.name));
+            Ok(())
+        }
+
+        async fn after_delete_user(&self, user: &StoredParts<User>) {
+            self.record(format!("after_delete_user {}", user.resource.name));
+        }
+
+        async fn after_create_group(&self, group: &StoredParts<Group>) {
+            self.record(format!(
+                "after_create_group {}",
+                group.resource.display_name
+            ));
+        }
+
+        async fn before_patch_group(
+            &self,
+            group: &StoredParts<Group>,
+            patched: &Group,
+        ) -> Result<(), Error> {
+            let members = |group: &Group| {
+                group
+                    .members
+                    .iter()
+                    .flatten()
+                    .filter_map(|member| member.value.clone())
+                    .collect::<Vec<_>>()
+            };
+            self.record(format!(
+                "before_patch_group {} members={:?}->{:?}",
+                group.resource.display_name,
+                members(&group.resource),
+                members(patched)
+            ));
+            Ok(())
+        }
+
+        async fn after_patch_group(&self, group: &StoredParts<Group>) {
+            self.record(format!(
+                "after_patch_group {}",
+                group.resource.display_name
+            ));
+        }
+
+        async fn before_delete_group(
+            &self,
+            group: &StoredParts<Group>,
+        ) -> Result<(), Error> {
+            self.record(format!(
+                "before_delete_group {}",
+                group.resource.display_name
+            ));
+            veto(&group.resource.display_name)
+        }
+
+        async fn after_group_members_added(
+            &self,
+            _group_id: &str,
+            user_ids: &[String],
+        ) {
+            self.record(format!("members_added {}", user_ids.len()));
+        }
+
+        async fn after_group_members_removed(
+            &self,
+            _group_id: &str,
+            user_ids: &[String],
+        ) {
+            self.record(format!("members_removed {}", user_ids.len()));
+        }
+    }
+
+    fn response_id(response: SingleResourceResponse) -> String {
+        serde_json::to_value(response).unwrap()["id"]
+            .as_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[tokio::test]
+    async fn test_hooks() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_hooks(RecordingHooks::default());
+
+        let user_request = |name: &str| CreateUserRequest {
+            name: name.to_string(),
+            active: Some(true),
+            external_id: None,
+            groups: None,
+        };
+
+        let jim = response_id(
+            provider
+                .create_user(
+                    &OperationContext::default(),
+                    user_request("jhalpert"),
+                )
+                .await
+                .unwrap(),
+        );
+        let dwight = response_id(
+            provider
+                .create_user(
+                    &OperationContext::default(),
+                    user_request("dschrute"),
+                )
+                .await
+                .unwrap(),
+        );
+        assert_eq!(
+            provider.hooks().take(),
+            [
+                "before_create_user jhalpert",
+                "after_create_user jhalpert",
+                "before_create_user dschrute",
+                "after_create_user dschrute",
+            ],
+        );
+
+        // A vetoed create fails with the hook's error, and stores nothing
+        let error = provider
+            .create_user(&OperationContext::default(), user_request("vetoed"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_type, Some(ErrorType::Mutability));
+        assert_eq!(provider.hooks().take(), ["before_create_user vetoed"]);
+        let users = provider
+            .store()
+            .list_users(None, Pagination::default())
+            .await
+            .unwrap();
+        assert_eq!(users.total_results, 2);
+
+        let sales = response_id(
+            provider
+                .create_group(
+                    &OperationContext::default(),
+                    CreateGroupRequest {
+                        display_name: String::from("Sales"),
+                        external_id: None,
+                        members: Some(
+                            [GroupMember {
+                                resource_type: None,
+                                value: Some(jim.clone()),
+                            }]
+                            .into_iter()
+                            .collect(),
+                        ),
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+        assert_eq!(
+            provider.hooks().take(),
+            ["after_create_group Sales", "members_added 1"],
+        );
+
+        // Swapping members reports both sides of the change
+        let patch: PatchRequest = serde_json::from_value(serde_json::json!({
+            "schemas": [crate::PATCHOP_URN],
+            "Operations": [{
+                "op": "replace",
+                "path": "members",
+                "value": [{ "value": dwight }],
+            }],
+        }))
+        .unwrap();
+        provider
+            .patch_group(&OperationContext::default(), &sales, patch)
+            .await
+            .unwrap();
+        assert_eq!(
+            provider.hooks().take(),
+            [
+                format!(
+                    "before_patch_group Sales members=[{jim:?}]->[{dwight:?}]"
+                ),
+                String::from("after_patch_group Sales"),
+                String::from("members_added 1"),
+                String::from("members_removed 1"),
+            ],
+        );
+
+        // Deleting a user reports its memberships going away
+        provider
+            .delete_user(&OperationContext::default(), &dwight)
+            .await
+            .unwrap();
+        assert_eq!(
+            provider.hooks().take(),
+            [
+                "before_delete_user dschrute",
+                "after_delete_user dschrute",
+                "members_removed 1",
+            ],
+        );
+
+        let patch: PatchRequest = serde_json::from_value(serde_json::json!({
+            "schemas": [crate::PATCHOP_URN],
+            "Operations": [{
+                "op": "replace",
+                "value": { "active": false },
+            }],
+        }))
+        .unwrap();
+        // The hook sees what the PATCH would change, and can reject it
+        let error = provider
+            .patch_user(&OperationContext::default(), &jim, patch)
+            .await
+            .unwrap_err();
+        assert_eq!(error.error_type, Some(ErrorType::Mutability));
+        assert_eq!(
+            provider.hooks().take(),
+            ["before_patch_user jhalpert active=Some(true)->Some(false)"],
+        );
+        let stored_jim =
+            provider.store().get_user_by_id(&jim).await.unwrap().unwrap();
+        assert_eq!(stored_jim.resource.active, Some(true));
+
+        let patch: PatchRequest = serde_json::from_value(serde_json::json!({
+            "schemas": [crate::PATCHOP_URN],
+            "Operations": [{
+                "op": "replace",
+                "value": { "active": true },
+            }],
+        }))
+        .unwrap();
+        provider
+            .patch_user(&OperationContext::default(), &jim, patch)
+            .await
+            .unwrap();
+        assert_eq!(
+            provider.hooks().take(),
+            [
+                "before_patch_user jhalpert active=Some(true)->Some(true)",
+                "after_patch_user jhalpert active=Some(true)",
+            ],
+        );
+
+        // A vetoed delete leaves the group alone
+        let vetoed = response_id(
+            provider
+                .create_group(
+                    &OperationContext::default(),
+                    CreateGroupRequest {
+                        display_name: String::from("vetoed"),
+                        external_id: None,
+                        members: None,
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+        provider.hooks().take();
+
+        provider
+            .delete_group(&OperationContext::default(), &vetoed)
+            .await
+            .unwrap_err();
+        assert_eq!(provider.hooks().take(), ["before_delete_group vetoed"]);
+        assert!(
+            provider.store().get_group_by_id(&vetoed).await.unwrap().is_some()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_subscribe() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_user_delete_policy(UserDeletePolicy::Deactivate);
+        let mut events = provider.subscribe();
+
+        let jim = response_id(
+            provider
+                .create_user(
+                    &OperationContext::default(),
+                    CreateUserRequest {
+                        name: String::from("jhalpert"),
+                        active: Some(true),
+                        external_id: None,
+                        groups: None,
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+        let sales = response_id(
+            provider
+                .create_group(
+                    &OperationContext::default(),
+                    CreateGroupRequest {
+                        display_name: String::from("Sales"),
+                        external_id: None,
+                        members: Some(
+                            [GroupMember {
+                                resource_type: None,
+                                value: Some(jim.clone()),
+                            }]
+                            .into_iter()
+                            .collect(),
+                        ),
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+
+        // Deactivating the user takes it out of its groups first
+        provider.delete_user(&OperationContext::default(), &jim).await.unwrap();
+
+        let mut kinds = vec![];
+        while let Ok(event) = events.try_recv() {
+            kinds.push(event.kind);
+        }
+
+        let user =
+            provider.store().get_user_by_id(&jim).await.unwrap().unwrap();
+        let group =
+            provider.store().get_group_by_id(&sales).await.unwrap().unwrap();
+
+        assert_eq!(kinds.len(), 7, "{kinds:#?}");
+        assert!(matches!(
+            &kinds[0],
+            ChangeEventKind::UserCreated { user } if user.name == "jhalpert"
+        ));
+        assert!(matches!(
+            &kinds[1],
+            ChangeEventKind::GroupCreated { group } if group.id == sales
+        ));
+        assert_eq!(
+            kinds[2],
+            ChangeEventKind::GroupMembersAdded {
+                group_id: sales.clone(),
+                user_ids: vec![jim.clone()],
+            }
+        );
+        assert_eq!(
+            kinds[3..],
+            [
+                ChangeEventKind::GroupUpdated { group: group.resource },
+                ChangeEventKind::GroupMembersRemoved {
+                    group_id: sales.clone(),
+                    user_ids: vec![jim.clone()],
+                },
+                ChangeEventKind::UserUpdated { user: user.resource },
+                ChangeEventKind::UserDeactivated { user_id: jim.clone() },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_security_events() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_security_events(SecurityEventIssuer::new(
+                "https://scim.example.com",
+                vec![],
+            ));
+        let mut tokens = provider.subscribe_security_events();
+
+        let jim = response_id(
+            provider
+                .create_user(
+                    &OperationContext::default(),
+                    CreateUserRequest {
+                        name: String::from("jhalpert"),
+                        active: Some(true),
+                        external_id: None,
+                        groups: None,
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+
+        let patch: PatchRequest = serde_json::from_value(serde_json::json!({
+            "schemas": [crate::PATCHOP_URN],
+            "Operations": [{
+                "op": "replace",
+                "value": { "active": false },
+            }],
+        }))
+        .unwrap();
+        provider
+            .patch_user(&OperationContext::default(), &jim, patch)
+            .await
+            .unwrap();
+        provider.delete_user(&OperationContext::default(), &jim).await.unwrap();
+
+        let mut events = vec![];
+        while let Ok(token) = tokens.try_recv() {
+            assert_eq!(token.sub_id.uri, format!("/Users/{jim}"));
+            events.push(token.events.into_keys().collect::<Vec<_>>());
+        }
+
+        assert_eq!(
+            events,
+            [
+                vec![crate::EVENT_CREATE_NOTICE_URN],
+                vec![
+                    crate::EVENT_DEACTIVATE_URN,
+                    crate::EVENT_PATCH_NOTICE_URN
+                ],
+                vec![crate::EVENT_DELETE_URN],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_audit() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new())
+            .with_audit_sink(crate::InMemoryAuditSink::new());
+        let context = OperationContext {
+            actor: Some(String::from("okta")),
+            request_id: Some(String::from("req-1")),
+            if_match: None,
+        };
+
+        let jim = response_id(
+            provider
+                .create_user(
+                    &context,
+                    CreateUserRequest {
+                        name: String::from("jhalpert"),
+                        active: Some(true),
+                        external_id: None,
+                        groups: None,
+                    },
+                )
+                .await
+                .unwrap(),
+        );
+
+        let patch: PatchRequest = serde_json::from_value(serde_json::json!({
+            "schemas": [crate::PATCHOP_URN],
+            "Operations": [{
+                "op": "replace",
+                "value": { "active": false },
+            }],
+        }))
+        .unwrap();
+        provider.patch_user(&context, &jim, patch).await.unwrap();
+        provider.delete_user(&context, "missing").await.unwrap_err();
+
+        let records = provider.audit_sink().records();
+        assert_eq!(records.len(), 3);
+        for record in &records {
+            assert_eq!(record.actor.as_deref(), Some("okta"));
+            assert_eq!(record.request_id.as_deref(), Some("req-1"));
+        }
+
+        assert_eq!(records[0].operation, crate::AuditOperation::CreateUser);
+        assert_eq!(records[0].resource_id.as_deref(), Some(jim.as_str()));
+        assert_eq!(records[0].status, 201);
+        assert_eq!(
+            records[0].changes.keys().collect::<Vec<_>>(),
+            ["active", "id", "userName"]
+        );
+
+        assert_eq!(records[1].operation, crate::AuditOperation::PatchUser);
+        assert_eq!(records[1].status, 200);
+        assert_eq!(
+            records[1].changes,
+            BTreeMap::from([(
+                String::from("active"),
+                crate::AttributeChange {
+                    before: Some(serde_json::Value::Bool(true)),
+                    after: Some(serde_json::Value::Bool(false)),
+                },
+            )])
+        );
+
+        // Failures are recorded too
+        assert_eq!(records[2].operation, crate::AuditOperation::DeleteUser);
+        assert_eq!(records[2].resource_id.as_deref(), Some("missing"));
+        assert_eq!(records[2].status, 404);
+        assert!(records[2].error.is_some());
+        assert!(records[2].changes.is_empty());
     }
 }
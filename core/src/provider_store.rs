@@ -2,14 +2,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
 use crate::response::Error;
 use crate::{
-    CreateGroupRequest, CreateUserRequest, FilterOp, Group, StoredParts, User,
+    CreateGroupRequest, CreateUserRequest, DeadLetter, Filter, Group,
+    OutboxEntry, Pagination, Resource, Revision, StoreDelta, StoredParts, User,
 };
 
 /// The durable store for users and groups
-#[trait_variant::make]
+///
+/// Group membership is recorded twice: in a group's `members` and in each
+/// member's `groups`. Stores must keep the two in step: creating, replacing,
+/// or deleting a group updates its members' `groups` (including the
+/// `display` value, which is the group's current `displayName`), and deleting
+/// a user removes it from every group's `members`. The conformance suite in
+/// [`crate::store_conformance`] checks these rules.
+#[trait_variant::make(Send)]
 pub trait ProviderStore: Sync {
+    /// Whether the store gives a resource a new `meta.version` every time it
+    /// changes, so that the service provider can advertise ETag support (RFC
+    /// 7644, section 3.14)
+    const VERSIONED: bool = false;
+
     async fn get_user_by_id(
         &self,
         user_id: &str,
@@ -20,10 +40,14 @@ pub trait ProviderStore: Sync {
         user_request: CreateUserRequest,
     ) -> Result<StoredParts<User>, ProviderStoreError>;
 
+    /// The page of users that match `filter`, ordered by id. Filters on
+    /// attributes or with comparisons the store cannot evaluate are rejected
+    /// with an `invalidFilter` error.
     async fn list_users(
         &self,
-        filter: Option<FilterOp>,
-    ) -> Result<Vec<StoredParts<User>>, ProviderStoreError>;
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<User>, ProviderStoreError>;
 
     async fn replace_user(
         &self,
@@ -48,10 +72,12 @@ pub trait ProviderStore: Sync {
         group_request: CreateGroupRequest,
     ) -> Result<StoredParts<Group>, ProviderStoreError>;
 
+    /// The page of groups that match `filter`, like `list_users`
     async fn list_groups(
         &self,
-        filter: Option<FilterOp>,
-    ) -> Result<Vec<StoredParts<Group>>, ProviderStoreError>;
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError>;
 
     async fn replace_group(
         &self,
@@ -67,6 +93,595 @@ pub trait ProviderStore: Sync {
         &self,
         group_id: &str,
     ) -> Result<ProviderStoreDeleteResult, ProviderStoreError>;
+
+    // The current displayName of each of `group_ids` that exists, so that
+    // users' `groups` can be refreshed without a lookup per membership. The
+    // default looks the groups up one at a time; stores should override it
+    // with a single lookup.
+    fn get_group_display_names(
+        &self,
+        group_ids: &BTreeSet<String>,
+    ) -> impl Future<Output = Result<BTreeMap<String, String>, ProviderStoreError>>
+    {
+        async move {
+            let mut display_names = BTreeMap::new();
+            for group_id in group_ids {
+                if let Some(group) = self.get_group_by_id(group_id).await? {
+                    display_names
+                        .insert(group_id.clone(), group.resource.display_name);
+                }
+            }
+            Ok(display_names)
+        }
+    }
+
+    // Deactivate a user: set `active` to false and take it out of every group
+    // it is a member of, returning the user and each of those groups as they
+    // were before and after. Stores should do this as one atomic change, so
+    // that a failure part way through leaves nothing behind and a change made
+    // to one of the groups at the same time is neither lost nor overwritten.
+    // The default reads and replaces the groups one at a time, then the user,
+    // and puts the groups back if anything fails.
+    fn deactivate_user(
+        &self,
+        user_id: &str,
+    ) -> impl Future<Output = Result<DeactivatedUser, ProviderStoreError>> {
+        async move {
+            let Some(before) = self.get_user_by_id(user_id).await? else {
+                return Err(Error::not_found(user_id.to_string()).into());
+            };
+
+            let mut groups = Vec::new();
+            let result = async {
+                for group_id in before
+                    .resource
+                    .groups
+                    .iter()
+                    .flatten()
+                    .filter_map(|group| group.value.as_ref())
+                {
+                    let Some(group_before) =
+                        self.get_group_by_id(group_id).await?
+                    else {
+                        continue;
+                    };
+
+                    let mut members = group_before
+                        .resource
+                        .members
+                        .clone()
+                        .unwrap_or_default();
+                    members.retain(|member| {
+                        member.value.as_deref() != Some(user_id)
+                    });
+
+                    let request = CreateGroupRequest {
+                        display_name: group_before
+                            .resource
+                            .display_name
+                            .clone(),
+                        external_id: group_before.resource.external_id.clone(),
+                        members: Some(members),
+                    };
+                    let after = self.replace_group(group_id, request).await?;
+                    groups.push(ChangedGroup { before: group_before, after });
+                }
+
+                let request = CreateUserRequest {
+                    name: before.resource.name.clone(),
+                    active: Some(false),
+                    external_id: before.resource.external_id.clone(),
+                    groups: None,
+                };
+                self.replace_user(user_id, request).await
+            }
+            .await;
+
+            match result {
+                Ok(after) => Ok(DeactivatedUser { before, after, groups }),
+
+                Err(e) => {
+                    // Put back the groups that were changed. If this fails
+                    // too there is nothing more to be done, and the original
+                    // error is the one to report.
+                    for ChangedGroup { before, .. } in groups {
+                        let request = CreateGroupRequest {
+                            display_name: before.resource.display_name,
+                            external_id: before.resource.external_id,
+                            members: before.resource.members,
+                        };
+                        let _ = self
+                            .replace_group(&before.resource.id, request)
+                            .await;
+                    }
+                    Err(e)
+                }
+            }
+        }
+    }
+
+    // Soft delete support. A soft deleted resource behaves exactly as if it
+    // was deleted (including removing its group memberships, and freeing up
+    // its userName or displayName), but the store keeps a `DeletedResource`
+    // copy of it, memberships included, until it is restored or purged.
+    //
+    // These are only used when the `Provider` is configured for soft delete,
+    // so stores that do not support it can leave the defaults in place.
+
+    fn soft_delete_user_by_id(
+        &self,
+        _user_id: &str,
+    ) -> impl Future<Output = Result<ProviderStoreDeleteResult, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    fn soft_delete_group_by_id(
+        &self,
+        _group_id: &str,
+    ) -> impl Future<Output = Result<ProviderStoreDeleteResult, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    fn list_deleted_users(
+        &self,
+    ) -> impl Future<Output = Result<Vec<DeletedResource<User>>, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    fn list_deleted_groups(
+        &self,
+    ) -> impl Future<Output = Result<Vec<DeletedResource<Group>>, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    // Restore a soft deleted user, along with its memberships of groups that
+    // still exist. None is returned if there is no such deleted user, and a
+    // conflict if its userName has been taken in the meantime.
+    fn restore_user_by_id(
+        &self,
+        _user_id: &str,
+    ) -> impl Future<Output = Result<Option<StoredParts<User>>, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    // Restore a soft deleted group, along with those of its members that
+    // still exist. None is returned if there is no such deleted group, and a
+    // conflict if its displayName has been taken in the meantime.
+    fn restore_group_by_id(
+        &self,
+        _group_id: &str,
+    ) -> impl Future<Output = Result<Option<StoredParts<Group>>, ProviderStoreError>>
+    {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    // Permanently remove resources that were soft deleted before
+    // `deleted_before`, returning how many were removed.
+    fn purge_deleted(
+        &self,
+        _deleted_before: DateTime<Utc>,
+    ) -> impl Future<Output = Result<usize, ProviderStoreError>> {
+        async { Err(soft_delete_not_implemented()) }
+    }
+
+    // Outbox support. A store with an outbox records the `ChangeEvent`s for
+    // each change (see `user_changes` and `group_changes`) as part of making
+    // the change, so that they survive a crash straight after it. A relay
+    // reads the oldest entries, delivers them, and then acknowledges them,
+    // giving at-least-once delivery.
+
+    // Return up to `limit` entries, oldest first.
+    fn read_outbox(
+        &self,
+        _limit: usize,
+    ) -> impl Future<Output = Result<Vec<OutboxEntry>, ProviderStoreError>>
+    {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // Remove entries up to and including `sequence`, returning how many were
+    // removed.
+    fn ack_outbox(
+        &self,
+        _sequence: u64,
+    ) -> impl Future<Output = Result<usize, ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // A relay with several consumers, such as one per webhook endpoint, keeps
+    // a cursor for each instead: the sequence of the last entry the consumer
+    // is done with. Entries are only acknowledged once every consumer is done
+    // with them. An entry a consumer gives up on is kept as a dead letter.
+
+    // Return up to `limit` entries after `sequence`, oldest first.
+    fn read_outbox_after(
+        &self,
+        _sequence: u64,
+        _limit: usize,
+    ) -> impl Future<Output = Result<Vec<OutboxEntry>, ProviderStoreError>>
+    {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // The cursor of `consumer`, or 0 if it has none yet.
+    fn outbox_cursor(
+        &self,
+        _consumer: &str,
+    ) -> impl Future<Output = Result<u64, ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    fn set_outbox_cursor(
+        &self,
+        _consumer: &str,
+        _sequence: u64,
+    ) -> impl Future<Output = Result<(), ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // Save a dead letter, replacing any with the same endpoint and sequence.
+    fn put_dead_letter(
+        &self,
+        _dead_letter: DeadLetter,
+    ) -> impl Future<Output = Result<(), ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // Every dead letter, by endpoint and then sequence.
+    fn list_dead_letters(
+        &self,
+    ) -> impl Future<Output = Result<Vec<DeadLetter>, ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // Remove a dead letter, returning whether there was one.
+    fn remove_dead_letter(
+        &self,
+        _endpoint: &str,
+        _sequence: u64,
+    ) -> impl Future<Output = Result<bool, ProviderStoreError>> {
+        async { Err(outbox_not_implemented()) }
+    }
+
+    // History support. A store with history records an immutable `Revision`
+    // of a resource, as part of every change to it, and keeps them after the
+    // resource is deleted.
+
+    // Every revision of a user, oldest first. Empty if there never was such
+    // a user.
+    fn list_user_revisions(
+        &self,
+        _user_id: &str,
+    ) -> impl Future<Output = Result<Vec<Revision<User>>, ProviderStoreError>>
+    {
+        async { Err(history_not_implemented()) }
+    }
+
+    fn list_group_revisions(
+        &self,
+        _group_id: &str,
+    ) -> impl Future<Output = Result<Vec<Revision<Group>>, ProviderStoreError>>
+    {
+        async { Err(history_not_implemented()) }
+    }
+
+    // A user as it was at `at`, with the groups it was a member of then, or
+    // None if it did not exist then.
+    fn get_user_at(
+        &self,
+        _user_id: &str,
+        _at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<Option<StoredParts<User>>, ProviderStoreError>>
+    {
+        async { Err(history_not_implemented()) }
+    }
+
+    // A group as it was at `at`, with the members it had then, or None if it
+    // did not exist then.
+    fn get_group_at(
+        &self,
+        _group_id: &str,
+        _at: DateTime<Utc>,
+    ) -> impl Future<Output = Result<Option<StoredParts<Group>>, ProviderStoreError>>
+    {
+        async { Err(history_not_implemented()) }
+    }
+
+    // Delta support. A store with deltas counts every change with a counter
+    // that never goes down, and remembers the count at which each resource
+    // last changed (see `touched_resources`), deletes included.
+
+    // The users that changed after the counter was at `since`, or all users
+    // that exist if there is no `since`.
+    fn user_delta(
+        &self,
+        _since: Option<u64>,
+    ) -> impl Future<Output = Result<StoreDelta, ProviderStoreError>> {
+        async { Err(delta_not_implemented()) }
+    }
+
+    fn group_delta(
+        &self,
+        _since: Option<u64>,
+    ) -> impl Future<Output = Result<StoreDelta, ProviderStoreError>> {
+        async { Err(delta_not_implemented()) }
+    }
+}
+
+/// So that a store can be shared, such as between the `Provider`s for a
+/// tenant (see `TenantStores`)
+impl<S: ProviderStore + Send> ProviderStore for Arc<S> {
+    const VERSIONED: bool = S::VERSIONED;
+
+    async fn get_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        (**self).get_user_by_id(user_id).await
+    }
+
+    async fn create_user(
+        &self,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        (**self).create_user(user_request).await
+    }
+
+    async fn list_users(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+        (**self).list_users(filter, pagination).await
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        (**self).replace_user(user_id, user_request).await
+    }
+
+    async fn delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        (**self).delete_user_by_id(user_id).await
+    }
+
+    async fn get_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        (**self).get_group_by_id(group_id).await
+    }
+
+    async fn get_group_display_names(
+        &self,
+        group_ids: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, String>, ProviderStoreError> {
+        (**self).get_group_display_names(group_ids).await
+    }
+
+    async fn deactivate_user(
+        &self,
+        user_id: &str,
+    ) -> Result<DeactivatedUser, ProviderStoreError> {
+        (**self).deactivate_user(user_id).await
+    }
+
+    async fn create_group(
+        &self,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        (**self).create_group(group_request).await
+    }
+
+    async fn list_groups(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError> {
+        (**self).list_groups(filter, pagination).await
+    }
+
+    async fn replace_group(
+        &self,
+        group_id: &str,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        (**self).replace_group(group_id, group_request).await
+    }
+
+    async fn delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        (**self).delete_group_by_id(group_id).await
+    }
+
+    async fn soft_delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        (**self).soft_delete_user_by_id(user_id).await
+    }
+
+    async fn soft_delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        (**self).soft_delete_group_by_id(group_id).await
+    }
+
+    async fn list_deleted_users(
+        &self,
+    ) -> Result<Vec<DeletedResource<User>>, ProviderStoreError> {
+        (**self).list_deleted_users().await
+    }
+
+    async fn list_deleted_groups(
+        &self,
+    ) -> Result<Vec<DeletedResource<Group>>, ProviderStoreError> {
+        (**self).list_deleted_groups().await
+    }
+
+    async fn restore_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        (**self).restore_user_by_id(user_id).await
+    }
+
+    async fn restore_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        (**self).restore_group_by_id(group_id).await
+    }
+
+    async fn purge_deleted(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<usize, ProviderStoreError> {
+        (**self).purge_deleted(deleted_before).await
+    }
+
+    async fn read_outbox(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        (**self).read_outbox(limit).await
+    }
+
+    async fn ack_outbox(
+        &self,
+        sequence: u64,
+    ) -> Result<usize, ProviderStoreError> {
+        (**self).ack_outbox(sequence).await
+    }
+
+    async fn read_outbox_after(
+        &self,
+        sequence: u64,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        (**self).read_outbox_after(sequence, limit).await
+    }
+
+    async fn outbox_cursor(
+        &self,
+        consumer: &str,
+    ) -> Result<u64, ProviderStoreError> {
+        (**self).outbox_cursor(consumer).await
+    }
+
+    async fn set_outbox_cursor(
+        &self,
+        consumer: &str,
+        sequence: u64,
+    ) -> Result<(), ProviderStoreError> {
+        (**self).set_outbox_cursor(consumer, sequence).await
+    }
+
+    async fn put_dead_letter(
+        &self,
+        dead_letter: DeadLetter,
+    ) -> Result<(), ProviderStoreError> {
+        (**self).put_dead_letter(dead_letter).await
+    }
+
+    async fn list_dead_letters(
+        &self,
+    ) -> Result<Vec<DeadLetter>, ProviderStoreError> {
+        (**self).list_dead_letters().await
+    }
+
+    async fn remove_dead_letter(
+        &self,
+        endpoint: &str,
+        sequence: u64,
+    ) -> Result<bool, ProviderStoreError> {
+        (**self).remove_dead_letter(endpoint, sequence).await
+    }
+
+    async fn list_user_revisions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Revision<User>>, ProviderStoreError> {
+        (**self).list_user_revisions(user_id).await
+    }
+
+    async fn list_group_revisions(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<Revision<Group>>, ProviderStoreError> {
+        (**self).list_group_revisions(group_id).await
+    }
+
+    async fn get_user_at(
+        &self,
+        user_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        (**self).get_user_at(user_id, at).await
+    }
+
+    async fn get_group_at(
+        &self,
+        group_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        (**self).get_group_at(group_id, at).await
+    }
+
+    async fn user_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        (**self).user_delta(since).await
+    }
+
+    async fn group_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        (**self).group_delta(since).await
+    }
+}
+
+fn soft_delete_not_implemented() -> ProviderStoreError {
+    Error::not_implemented(
+        "this provider store does not support soft delete".to_string(),
+    )
+    .into()
+}
+
+pub(crate) fn outbox_not_implemented() -> ProviderStoreError {
+    Error::not_implemented(
+        "this provider store does not have an outbox".to_string(),
+    )
+    .into()
+}
+
+fn delta_not_implemented() -> ProviderStoreError {
+    Error::not_implemented(
+        "this provider store does not support delta queries".to_string(),
+    )
+    .into()
+}
+
+pub(crate) fn history_not_implemented() -> ProviderStoreError {
+    Error::not_implemented(
+        "this provider store does not keep history".to_string(),
+    )
+    .into()
 }
 
 /// The backing store for users and groups may return its own error or a SCIM
@@ -83,8 +698,40 @@ impl From<Error> for ProviderStoreError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum ProviderStoreDeleteResult {
     NotFound,
     Deleted,
 }
+
+/// A group as it was before and after a change the store made to it
+#[derive(Debug, Clone)]
+pub struct ChangedGroup {
+    pub before: StoredParts<Group>,
+    pub after: StoredParts<Group>,
+}
+
+/// A user as it was before and after being deactivated, and the groups it
+/// was taken out of
+#[derive(Debug, Clone)]
+pub struct DeactivatedUser {
+    pub before: StoredParts<User>,
+    pub after: StoredParts<User>,
+    pub groups: Vec<ChangedGroup>,
+}
+
+/// A soft deleted resource, as it was when it was deleted.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DeletedResource<R: Resource> {
+    pub resource: StoredParts<R>,
+    pub deleted_at: DateTime<Utc>,
+}
+
+/// A single page of the resources that matched a list request.
+#[derive(Debug)]
+pub struct ProviderStoreListResult<R: Resource> {
+    pub resources: Vec<StoredParts<R>>,
+
+    /// The number of resources that matched the filter, across all pages.
+    pub total_results: usize,
+}
@@ -3,140 +3,171 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use crate::Error;
+use crate::Filter;
 
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, JsonSchema, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Default)]
 pub struct QueryParams {
     // TODO: attributes
-
-    // TODO: pagination: start_index and count
+    /// Only return resources that match this filter (RFC 7644, section
+    /// 3.4.2.2)
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub filter: Option<String>,
+
+    /// The 1-based index of the first query result
+    #[serde(rename = "startIndex")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_index: Option<usize>,
+
+    /// The desired maximum number of query results per page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub count: Option<usize>,
 }
 
-impl QueryParams {
-    pub fn filter(&self) -> Result<Option<FilterOp>, Error> {
-        // self.filter.as_ref().map(|f| parse_filter_param(f))
-        match &self.filter {
-            Some(filter) => match parse_filter_param(filter) {
-                Ok(v) => Ok(Some(v)),
-                Err(e) => Err(e),
-            },
-
-            None => Ok(None),
-        }
+/// The page of results requested by a client, as described in RFC 7644
+/// section 3.4.2.4.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Pagination {
+    /// The 1-based index of the first result to return
+    pub start_index: usize,
+
+    /// The maximum number of results to return, or `None` for all of them
+    pub count: Option<usize>,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Self { start_index: 1, count: None }
     }
 }
 
-// These filter operations are exactly what Okta uses rather than implementing
-// the full filtering spec found in RFC 7644 Section 3.4.2.2.
-#[derive(Debug, PartialEq, Clone)]
-pub enum FilterOp {
-    UserNameEq(String),
-    DisplayNameEq(String),
+impl Pagination {
+    /// The number of results to skip before the first result of this page.
+    pub fn offset(&self) -> usize {
+        self.start_index - 1
+    }
 }
 
-fn parse_filter_param(raw: &str) -> Result<FilterOp, Error> {
-    // RFC 7644 - 3.4.2.2.  Filtering
-    //
-    // Attribute names and attribute operators used in filters are case
-    // insensitive.  For example, the following two expressions will
-    // evaluate to the same logical value:
-    //
-    // filter=userName Eq "john"
-    //
-    // filter=Username eq "john"
-    //
-    // The filter parameter MUST contain at least one valid expression.  Each
-    // expression MUST contain an attribute name followed by an attribute
-    // operator and optional value.  Multiple expressions MAY be combined using
-    // logical operators.  Expressions MAY be grouped together
-    // using round brackets "(" and ")"
-    //
-    // NOTE: We are currently only supporting a single `Eq` operator but we
-    // may expand to support more filtering operators and expressions in the
-    // future. Additionally we are explicitly looking for "userName" and
-    // "displayName" at this time. In the future we should consider using the
-    // winnow crate to properly parse these operations.
-
-    let raw = raw.trim().to_lowercase();
-    let parts: Vec<_> = raw.split(" eq ").collect();
-
-    if parts.len() != 2 {
-        return Err(Error::invalid_filter(format!("invalid filter {raw}")));
+impl QueryParams {
+    /// Returns true if the client asked for a specific page of results
+    /// rather than all of them.
+    pub fn is_paginated(&self) -> bool {
+        self.start_index.is_some() || self.count.is_some()
     }
 
-    // The value portion of the expression must be a string wrapped in
-    // quotations
-    let is_quoted_value = |v: &str| -> Option<String> {
-        if v.len() > 2 && v.starts_with('"') && v.ends_with('"') {
-            return Some(v[1..v.len() - 1].to_string());
+    pub fn pagination(&self) -> Pagination {
+        // RFC 7644 - 3.4.2.4.  Pagination
+        //
+        // startIndex: The 1-based index of the first query result.  A value
+        // less than 1 SHALL be interpreted as 1.
+        Pagination {
+            start_index: self.start_index.unwrap_or(1).max(1),
+            count: self.count,
         }
-        None
-    };
+    }
 
-    match (parts[0], is_quoted_value(parts[1])) {
-        ("username", Some(value)) => Ok(FilterOp::UserNameEq(value)),
-        ("displayname", Some(value)) => Ok(FilterOp::DisplayNameEq(value)),
-        _ => Err(Error::invalid_filter(format!("filter {raw} not supported"))),
+    /// Parse the filter with the full grammar of RFC 7644 section 3.4.2.2.
+    /// Whether the store can evaluate it is up to the store.
+    pub fn filter(&self) -> Result<Option<Filter>, Error> {
+        self.filter.as_deref().map(Filter::parse).transpose()
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::parse_filter_param;
-    use crate::FilterOp;
+    use crate::{Filter, Pagination, QueryParams};
+
+    fn filter(raw: &str) -> Result<Option<Filter>, crate::Error> {
+        QueryParams {
+            filter: Some(raw.to_string()),
+            start_index: None,
+            count: None,
+        }
+        .filter()
+    }
 
     #[test]
-    fn test_user_eq_filter() {
+    fn test_pagination() {
+        let params =
+            QueryParams { filter: None, start_index: None, count: None };
+        assert!(!params.is_paginated());
+        assert_eq!(params.pagination(), Pagination::default());
+        assert_eq!(params.filter(), Ok(None));
+
+        let params =
+            QueryParams { filter: None, start_index: Some(0), count: Some(10) };
+        assert!(params.is_paginated());
         assert_eq!(
-            parse_filter_param("userName Eq \"Mike\""),
-            Ok(FilterOp::UserNameEq("mike".to_string()))
+            params.pagination(),
+            Pagination { start_index: 1, count: Some(10) }
         );
 
+        let params =
+            QueryParams { filter: None, start_index: Some(11), count: None };
+        assert_eq!(params.pagination().offset(), 10);
+    }
+
+    #[test]
+    fn test_user_eq_filter() {
+        // Attribute names and operators are case insensitive, values are not
+        // folded
         assert_eq!(
-            parse_filter_param("USERNAME eq \"JAMES\""),
-            Ok(FilterOp::UserNameEq("james".to_string()))
+            filter("USERNAME Eq \"Mike\""),
+            Ok(Some(Filter::attr("USERNAME").eq("Mike")))
         );
 
         assert_eq!(
-            parse_filter_param(
-                "USERNAME eq \"michael+dakota@oxidecomputer.com\""
-            ),
-            Ok(FilterOp::UserNameEq(
-                "michael+dakota@oxidecomputer.com".to_string()
+            filter("userName eq \"michael+dakota@oxidecomputer.com\""),
+            Ok(Some(
+                Filter::attr("userName").eq("michael+dakota@oxidecomputer.com")
             ))
         );
+
+        // Escapes in values are decoded
+        assert_eq!(
+            filter(r#"userName eq "a\"b\\c""#),
+            Ok(Some(Filter::attr("userName").eq("a\"b\\c")))
+        );
     }
 
     #[test]
-    fn test_group_eq_filter() {
+    fn test_last_modified_gt_filter() {
         assert_eq!(
-            parse_filter_param("displayName Eq \"PowerUsers\""),
-            Ok(FilterOp::DisplayNameEq("powerusers".to_string()))
+            filter("meta.lastModified gt \"2011-05-13T04:42:34Z\""),
+            Ok(Some(
+                Filter::attr("meta.lastModified").gt("2011-05-13T04:42:34Z")
+            ))
         );
+    }
 
+    #[test]
+    fn test_logical_filter() {
         assert_eq!(
-            parse_filter_param("dIsPlAyNaMe EQ \"Admins\""),
-            Ok(FilterOp::DisplayNameEq("admins".to_string()))
+            filter("userName sw \"j\" and not (active eq false)"),
+            Ok(Some(
+                Filter::attr("userName")
+                    .sw("j")
+                    .and(!Filter::attr("active").eq(false))
+            ))
         );
     }
 
     #[test]
     fn test_invalid_filter() {
-        assert!(
-            parse_filter_param("displayName Eq \"PowerUsers\" extra values")
-                .is_err()
-        );
-
-        assert!(
-            parse_filter_param("extra value username EQ \"Admins\"").is_err()
-        );
-
-        assert!(
-            parse_filter_param("meta.lastModified gt \"2011-05-13T04:42:34Z\"")
-                .is_err()
-        );
+        for raw in [
+            "displayName Eq \"PowerUsers\" extra values",
+            "extra value username EQ \"Admins\"",
+            "meta.lastModified gt 2011-05-13T04:42:34Z",
+            "userName eq \"unterminated",
+        ] {
+            let error = filter(raw).unwrap_err();
+            assert_eq!(
+                error.error_type,
+                Some(crate::ErrorType::InvalidFilter),
+                "{raw}"
+            );
+        }
     }
 }
@@ -2,24 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use dropshot::Body;
 use http::{Response, StatusCode, header};
 use schemars::{
     JsonSchema, SchemaGenerator,
     schema::{InstanceType, Schema, SchemaObject},
 };
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize};
 
 use crate::{
-    Meta, PatchRequestError, QueryParams, Resource, StoredMeta, StoredParts,
+    Meta, PatchRequestError, ProviderStoreListResult, QueryParams, Resource,
+    StoredMeta, StoredParts,
     urn::{ERROR_URN, LISTRESPONSE_URN},
+    utils::urn_list_schema,
 };
 
 const CONTENT_TYPE_SCIM_JSON: &str = "application/scim+json";
 
+/// Where resources are said to be, in `meta.location`, unless a `Provider` is
+/// told otherwise
+pub(crate) const DEFAULT_BASE_URL: &str = "http://127.0.0.1:4567/v2";
+
 /// The generic response used to return a list of resources
 #[derive(Deserialize, Serialize, JsonSchema)]
 pub struct ListResponse {
+    #[schemars(schema_with = "list_response_schemas_schema")]
     pub schemas: Vec<String>,
 
     #[serde(rename = "totalResults")]
@@ -39,16 +46,20 @@ pub struct ListResponse {
 
 impl ListResponse {
     pub fn from_resources<R>(
-        resources: Vec<StoredParts<R>>,
+        list_result: ProviderStoreListResult<R>,
         query_params: QueryParams,
+        base_url: &str,
     ) -> Result<Self, Error>
     where
         R: Resource,
     {
         let schemas = vec![LISTRESPONSE_URN.to_string()];
 
-        // TODO pagination should have happened before this, but fill in
-        // start_index and items_per_page below based on query_params
+        // Pagination has already happened in the store, so all that is left
+        // is to report which page this is if the client asked for one.
+        let ProviderStoreListResult { resources, total_results } = list_result;
+        let is_paginated = query_params.is_paginated();
+        let start_index = query_params.pagination().start_index;
 
         let resources = resources
             .into_iter()
@@ -58,6 +69,7 @@ impl ListResponse {
                     resource,
                     meta,
                     Some(query_params.clone()),
+                    base_url,
                 )
             })
             .collect::<Result<Vec<_>, Error>>()?
@@ -67,14 +79,16 @@ impl ListResponse {
 
         Ok(ListResponse {
             schemas,
-            total_results: resources.len(),
-            start_index: None,
-            items_per_page: None,
+            total_results,
+            start_index: is_paginated.then_some(start_index),
+            items_per_page: is_paginated.then_some(resources.len()),
             resources,
         })
     }
 
-    pub fn to_http_response(self) -> Result<Response<Body>, http::Error> {
+    pub fn to_http_response<B: From<String>>(
+        self,
+    ) -> Result<Response<B>, http::Error> {
         value_to_http_response(
             StatusCode::OK,
             &self,
@@ -105,6 +119,7 @@ impl SingleResourceResponse {
         resource: R,
         meta: StoredMeta,
         _query_params: Option<QueryParams>,
+        base_url: &str,
     ) -> Result<Self, Error>
     where
         R: Resource + Serialize,
@@ -127,7 +142,8 @@ impl SingleResourceResponse {
                 last_modified: meta.last_modified,
                 version: meta.version,
                 location: format!(
-                    "http://127.0.0.1:4567/v2/{}s/{}",
+                    "{}/{}s/{}",
+                    base_url.trim_end_matches('/'),
                     R::resource_type(),
                     id
                 ),
@@ -135,21 +151,162 @@ impl SingleResourceResponse {
         })
     }
 
-    pub fn to_http_response(
+    pub fn to_http_response<B: From<String>>(
         self,
         status_code: StatusCode,
-    ) -> Result<Response<Body>, http::Error> {
-        value_to_http_response(
+    ) -> Result<Response<B>, http::Error> {
+        let mut response = value_to_http_response(
             status_code,
             &self,
             "serializing resource failed",
-        )
+        )?;
+
+        // RFC 7644, section 3.14: the version is also sent as the ETag
+        if let Ok(etag) = http::HeaderValue::from_str(&self.meta.version) {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+
+        Ok(response)
+    }
+}
+
+/// Why a response does not hold the type of resource it was expected to
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResourceConversionError {
+    /// `schemas` does not have the URN of the expected resource or message
+    MissingSchema { expected: String, found: Vec<String> },
+
+    /// `meta.resourceType` names another type of resource
+    WrongResourceType { expected: String, found: String },
+
+    /// The attributes are not those of the expected type of resource
+    InvalidResource { resource_type: String, detail: String },
+}
+
+impl std::fmt::Display for ResourceConversionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceConversionError::MissingSchema { expected, found } => {
+                write!(f, "expected schema {expected}, found {found:?}")
+            }
+
+            ResourceConversionError::WrongResourceType { expected, found } => {
+                write!(f, "expected resource type {expected}, found {found}")
+            }
+
+            ResourceConversionError::InvalidResource {
+                resource_type,
+                detail,
+            } => write!(f, "invalid {resource_type}: {detail}"),
+        }
+    }
+}
+
+impl std::error::Error for ResourceConversionError {}
+
+/// The typed resource in a response. Its `schemas` may have extension URNs
+/// besides the resource's own.
+impl<R> TryFrom<SingleResourceResponse> for StoredParts<R>
+where
+    R: Resource + DeserializeOwned,
+{
+    type Error = ResourceConversionError;
+
+    fn try_from(response: SingleResourceResponse) -> Result<Self, Self::Error> {
+        let SingleResourceResponse {
+            resource: ResourceInner { resource, schemas },
+            meta,
+        } = response;
+
+        // RFC 7643, section 2.1: URNs are not case sensitive
+        let schema = R::schema();
+        if !schemas.iter().any(|s| s.eq_ignore_ascii_case(&schema)) {
+            return Err(ResourceConversionError::MissingSchema {
+                expected: schema,
+                found: schemas,
+            });
+        }
+
+        let resource_type = R::resource_type().to_string();
+        if meta.resource_type != resource_type {
+            return Err(ResourceConversionError::WrongResourceType {
+                expected: resource_type,
+                found: meta.resource_type,
+            });
+        }
+
+        let resource =
+            serde_json::from_value(resource.into()).map_err(|error| {
+                ResourceConversionError::InvalidResource {
+                    resource_type,
+                    detail: error.to_string(),
+                }
+            })?;
+
+        Ok(StoredParts { resource, meta: meta.into() })
+    }
+}
+
+/// A `ListResponse` of one type of resource
+#[derive(Debug, Clone)]
+pub struct TypedListResponse<R: Resource> {
+    pub total_results: usize,
+
+    pub start_index: Option<usize>,
+
+    pub items_per_page: Option<usize>,
+
+    pub resources: Vec<StoredParts<R>>,
+}
+
+impl<R> TryFrom<ListResponse> for TypedListResponse<R>
+where
+    R: Resource + DeserializeOwned,
+{
+    type Error = ResourceConversionError;
+
+    fn try_from(response: ListResponse) -> Result<Self, Self::Error> {
+        let ListResponse {
+            schemas,
+            total_results,
+            start_index,
+            items_per_page,
+            resources,
+        } = response;
+
+        if !schemas.iter().any(|s| s.eq_ignore_ascii_case(LISTRESPONSE_URN)) {
+            return Err(ResourceConversionError::MissingSchema {
+                expected: LISTRESPONSE_URN.to_string(),
+                found: schemas,
+            });
+        }
+
+        let resources = resources
+            .into_iter()
+            .map(|resource| {
+                let response: SingleResourceResponse = serde_json::from_value(
+                    resource.into(),
+                )
+                .map_err(|error| ResourceConversionError::InvalidResource {
+                    resource_type: R::resource_type().to_string(),
+                    detail: error.to_string(),
+                })?;
+                StoredParts::try_from(response)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(TypedListResponse {
+            total_results,
+            start_index,
+            items_per_page,
+            resources,
+        })
     }
 }
 
 /// The SCIM error types specified in RFC 7644, section 3.12
 // RFC 7644, section 3.12:  HTTP Status and Error Response Handling
-#[derive(Deserialize, Serialize, JsonSchema, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
 pub enum ErrorType {
     #[serde(rename = "invalidFilter")]
     InvalidFilter,
@@ -162,6 +319,9 @@ pub enum ErrorType {
 
     #[serde(rename = "mutability")]
     Mutability,
+
+    #[serde(rename = "invalidValue")]
+    InvalidValue,
 }
 
 fn status_to_string<S>(
@@ -178,8 +338,23 @@ fn string_to_status<'de, D>(deserializer: D) -> Result<StatusCode, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s: &str = Deserialize::deserialize(deserializer)?;
-    s.parse::<StatusCode>().map_err(serde::de::Error::custom)
+    // RFC 7644 makes the status a string, but some service providers send a
+    // number
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Status {
+        String(String),
+        Number(u16),
+    }
+
+    match Status::deserialize(deserializer)? {
+        Status::String(s) => {
+            s.parse::<StatusCode>().map_err(serde::de::Error::custom)
+        }
+        Status::Number(n) => {
+            StatusCode::from_u16(n).map_err(serde::de::Error::custom)
+        }
+    }
 }
 
 fn status_code_schema(_: &mut SchemaGenerator) -> Schema {
@@ -190,9 +365,18 @@ fn status_code_schema(_: &mut SchemaGenerator) -> Schema {
     .into()
 }
 
+fn list_response_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(LISTRESPONSE_URN)
+}
+
+fn error_schemas_schema(_: &mut SchemaGenerator) -> Schema {
+    urn_list_schema(ERROR_URN)
+}
+
 /// The SCIM error format is specified in RFC 7644, section 3.12
-#[derive(Deserialize, Serialize, JsonSchema, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, PartialEq)]
 pub struct Error {
+    #[schemars(schema_with = "error_schemas_schema")]
     pub schemas: Vec<String>,
 
     #[serde(serialize_with = "status_to_string")]
@@ -280,19 +464,70 @@ impl Error {
         Self::new(StatusCode::NOT_IMPLEMENTED, None, detail)
     }
 
+    pub fn method_not_allowed(method: &http::Method) -> Self {
+        Self::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            None,
+            format!("Method {method} is not allowed for this resource"),
+        )
+    }
+
+    pub fn unsupported_media_type(content_type: &str) -> Self {
+        Self::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            None,
+            format!(
+                "Content type {content_type:?} is not supported, expected \
+                    application/scim+json"
+            ),
+        )
+    }
+
+    /// RFC 7644, section 3.14: the resource `id` changed since the version
+    /// given to `If-Match`
+    pub fn precondition_failed(id: String) -> Self {
+        Self::new(
+            StatusCode::PRECONDITION_FAILED,
+            None,
+            format!("Resource {id} has changed since the version requested"),
+        )
+    }
+
     pub fn mutability(detail: String) -> Self {
         Self::new(StatusCode::BAD_REQUEST, Some(ErrorType::Mutability), detail)
     }
 
+    pub fn invalid_value(detail: String) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            Some(ErrorType::InvalidValue),
+            detail,
+        )
+    }
+
     pub fn status(&self) -> StatusCode {
         self.status
     }
 
-    pub fn to_http_response(self) -> Result<Response<Body>, http::Error> {
+    pub fn to_http_response<B: From<String>>(
+        self,
+    ) -> Result<Response<B>, http::Error> {
         value_to_http_response(self.status, &self, "serializing error failed")
     }
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.detail)
+    }
+}
+
+impl From<http::Error> for Error {
+    fn from(error: http::Error) -> Self {
+        Error::internal_error(format!("building response failed: {error}"))
+    }
+}
+
 impl From<PatchRequestError> for Error {
     fn from(value: PatchRequestError) -> Self {
         match value {
@@ -304,11 +539,13 @@ impl From<PatchRequestError> for Error {
     }
 }
 
-pub fn value_to_http_response<S: Serialize>(
+/// Responses are built with whatever body type the server uses, such as
+/// `dropshot::Body` or `bytes::Bytes`
+pub fn value_to_http_response<S: Serialize, B: From<String>>(
     status_code: StatusCode,
     value: &S,
     error_context: &str,
-) -> Result<Response<Body>, http::Error> {
+) -> Result<Response<B>, http::Error> {
     match serde_json::to_string(value) {
         Ok(serialized) => Response::builder()
             .status(status_code)
@@ -332,16 +569,16 @@ pub fn value_to_http_response<S: Serialize>(
     }
 }
 
-pub fn deleted_http_response() -> Result<Response<Body>, Error> {
+pub fn deleted_http_response<B: Default>() -> Result<Response<B>, Error> {
     Response::builder()
         .status(StatusCode::NO_CONTENT)
         .header(header::CONTENT_TYPE, CONTENT_TYPE_SCIM_JSON)
-        .body(Body::empty())
+        .body(B::default())
         .map_err(|e| Error::internal_error(format!("{e}")))
 }
 
 /// Convert a `Resource` to a more dynamic `serde_json::Map`
-fn serialize_resource_to_object<R>(
+pub(crate) fn serialize_resource_to_object<R>(
     resource: R,
 ) -> Result<serde_json::Map<String, serde_json::Value>, Error>
 where
@@ -363,3 +600,161 @@ where
         ))),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{GROUP_URN, Group, ResourceType, USER_URN, User};
+
+    fn meta() -> StoredMeta {
+        StoredMeta {
+            created: Utc::now(),
+            last_modified: Utc::now(),
+            version: "W/unimplemented".to_string(),
+        }
+    }
+
+    /// What a client gets for `resource`
+    fn response_for<R: Resource + Serialize>(
+        resource: R,
+    ) -> SingleResourceResponse {
+        let response = SingleResourceResponse::from_resource(
+            resource,
+            meta(),
+            None,
+            DEFAULT_BASE_URL,
+        )
+        .unwrap();
+        serde_json::from_str(&serde_json::to_string(&response).unwrap())
+            .unwrap()
+    }
+
+    fn jim() -> User {
+        User {
+            id: "2819c223".to_string(),
+            name: "jhalpert".to_string(),
+            active: Some(true),
+            external_id: None,
+            groups: None,
+        }
+    }
+
+    #[test]
+    fn test_single_resource_response_into_stored_parts() {
+        let user: StoredParts<User> = response_for(jim()).try_into().unwrap();
+        assert_eq!(user.resource, jim());
+
+        let result: Result<StoredParts<Group>, _> =
+            response_for(jim()).try_into();
+        assert!(matches!(
+            result,
+            Err(ResourceConversionError::MissingSchema { expected, .. })
+                if expected == GROUP_URN
+        ));
+
+        let mut response = response_for(jim());
+        response.meta.resource_type = "Group".to_string();
+        let result: Result<StoredParts<User>, _> = response.try_into();
+        assert_eq!(
+            result.unwrap_err(),
+            ResourceConversionError::WrongResourceType {
+                expected: "User".to_string(),
+                found: "Group".to_string(),
+            }
+        );
+
+        let mut response = response_for(jim());
+        response.resource.resource.remove("userName");
+        let result: Result<StoredParts<User>, _> = response.try_into();
+        assert!(matches!(
+            result,
+            Err(ResourceConversionError::InvalidResource { .. })
+        ));
+    }
+
+    /// A User with the enterprise extension (RFC 7643, section 4.3)
+    #[derive(Debug, Serialize, Deserialize)]
+    struct EnterpriseUser {
+        id: String,
+
+        #[serde(rename = "userName")]
+        name: String,
+
+        #[serde(
+            rename = "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"
+        )]
+        enterprise: Option<serde_json::Value>,
+    }
+
+    impl Resource for EnterpriseUser {
+        fn id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn schema() -> String {
+            USER_URN.to_string()
+        }
+
+        fn resource_type() -> ResourceType {
+            ResourceType::User
+        }
+    }
+
+    #[test]
+    fn test_extension_resource_into_stored_parts() {
+        let mut response = response_for(jim());
+        response.resource.schemas.push(
+            "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"
+                .to_string(),
+        );
+        response.resource.resource.insert(
+            "urn:ietf:params:scim:schemas:extension:enterprise:2.0:User"
+                .to_string(),
+            json!({ "employeeNumber": "701984" }),
+        );
+
+        let user: StoredParts<EnterpriseUser> = response.try_into().unwrap();
+        assert_eq!(user.resource.name, "jhalpert");
+        assert_eq!(
+            user.resource.enterprise,
+            Some(json!({ "employeeNumber": "701984" }))
+        );
+    }
+
+    #[test]
+    fn test_list_response_into_typed_list_response() {
+        let list_result = ProviderStoreListResult {
+            resources: vec![StoredParts { resource: jim(), meta: meta() }],
+            total_results: 1,
+        };
+        let response = ListResponse::from_resources(
+            list_result,
+            QueryParams::default(),
+            DEFAULT_BASE_URL,
+        )
+        .unwrap();
+        let response: ListResponse =
+            serde_json::from_str(&serde_json::to_string(&response).unwrap())
+                .unwrap();
+
+        let users: TypedListResponse<User> = response.try_into().unwrap();
+        assert_eq!(users.total_results, 1);
+        assert_eq!(users.resources[0].resource, jim());
+
+        let response = ListResponse {
+            schemas: vec![USER_URN.to_string()],
+            total_results: 0,
+            start_index: None,
+            items_per_page: None,
+            resources: vec![],
+        };
+        let result: Result<TypedListResponse<User>, _> = response.try_into();
+        assert!(matches!(
+            result,
+            Err(ResourceConversionError::MissingSchema { .. })
+        ));
+    }
+}
@@ -0,0 +1,590 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value, json};
+use uuid::Uuid;
+
+use crate::response::{DEFAULT_BASE_URL, serialize_resource_to_object};
+use crate::urn::{
+    EVENT_ACTIVATE_URN, EVENT_CREATE_FULL_URN, EVENT_CREATE_NOTICE_URN,
+    EVENT_DEACTIVATE_URN, EVENT_DELETE_URN, EVENT_PATCH_FULL_URN,
+    EVENT_PATCH_NOTICE_URN, EVENT_PUT_FULL_URN, EVENT_PUT_NOTICE_URN,
+};
+use crate::{
+    Error, PATCHOP_URN, Resource, SingleResourceResponse, StoredParts,
+};
+
+/// The JWT `typ` header for Security Event Tokens, from RFC 8417 § 2.3
+const SECEVENT_JWT_TYPE: &str = "secevent+jwt";
+
+/// The SCIM operation that changed a resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvisioningOperation {
+    Create,
+    Put,
+    Patch,
+    Delete,
+}
+
+/// Identifies the resource an event is about, using the "scim" subject
+/// identifier format from the SCIM events draft
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SubjectId {
+    pub format: String,
+
+    /// The resource's path relative to the SCIM base URL, like `/Users/{id}`
+    pub uri: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_id: Option<String>,
+}
+
+/// The claims of a Security Event Token (RFC 8417) describing one change to a
+/// SCIM resource. Sign it with a `SecurityEventSigner` to get a JWT.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct SecurityEventToken {
+    pub jti: String,
+
+    pub iss: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aud: Vec<String>,
+
+    /// When the token was issued, in seconds since the epoch
+    pub iat: i64,
+
+    /// When the change happened, in seconds since the epoch
+    pub toe: i64,
+
+    pub sub_id: SubjectId,
+
+    /// Event type URI -> event payload. A change to a user's `active`
+    /// attribute adds an activate or deactivate event alongside the event
+    /// for the operation.
+    pub events: BTreeMap<String, Value>,
+}
+
+/// Builds Security Event Tokens for the changes a `Provider` makes.
+///
+/// By default tokens carry "notice" events, which only list the attributes
+/// that changed, so that receivers have to come back to the provider to see
+/// the values. `with_full_data` makes them carry "full" events instead, with
+/// the resource (or, for a patch, the changes) in the token.
+#[derive(Debug, Clone)]
+pub struct SecurityEventIssuer {
+    issuer: String,
+    audience: Vec<String>,
+    full_data: bool,
+    base_url: String,
+}
+
+impl SecurityEventIssuer {
+    pub fn new(issuer: impl Into<String>, audience: Vec<String>) -> Self {
+        Self {
+            issuer: issuer.into(),
+            audience,
+            full_data: false,
+            base_url: DEFAULT_BASE_URL.to_string(),
+        }
+    }
+
+    pub fn with_full_data(mut self) -> Self {
+        self.full_data = true;
+        self
+    }
+
+    /// The SCIM base URL, for the `meta.location` of resources in full
+    /// events. A `Provider` sets this to its own base URL.
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Describe `operation` taking a resource from `before` to `after`, where
+    /// `None` means the resource does not exist.
+    pub fn issue<R: Resource + Clone>(
+        &self,
+        operation: ProvisioningOperation,
+        before: Option<&StoredParts<R>>,
+        after: Option<&StoredParts<R>>,
+    ) -> Result<SecurityEventToken, Error> {
+        let Some(subject) = after.or(before) else {
+            return Err(Error::internal_error(format!(
+                "no {} to issue a security event for",
+                R::resource_type()
+            )));
+        };
+
+        let before_attrs =
+            before.map(|b| attributes(&b.resource)).transpose()?;
+        let after_attrs = after.map(|a| attributes(&a.resource)).transpose()?;
+        let subject_attrs =
+            after_attrs.as_ref().or(before_attrs.as_ref()).unwrap();
+
+        let mut events = BTreeMap::new();
+
+        match (operation, after) {
+            (ProvisioningOperation::Delete, _) | (_, None) => {
+                events.insert(EVENT_DELETE_URN.to_string(), json!({}));
+            }
+
+            (ProvisioningOperation::Create, Some(after)) => {
+                let (urn, payload) = if self.full_data {
+                    (
+                        EVENT_CREATE_FULL_URN,
+                        json!({ "data": full(after, &self.base_url)? }),
+                    )
+                } else {
+                    let names: Vec<&String> = subject_attrs.keys().collect();
+                    (EVENT_CREATE_NOTICE_URN, json!({ "attributes": names }))
+                };
+                events.insert(urn.to_string(), payload);
+            }
+
+            (ProvisioningOperation::Put, Some(after)) => {
+                let (urn, payload) = if self.full_data {
+                    (
+                        EVENT_PUT_FULL_URN,
+                        json!({ "data": full(after, &self.base_url)? }),
+                    )
+                } else {
+                    let names: Vec<&String> = subject_attrs.keys().collect();
+                    (EVENT_PUT_NOTICE_URN, json!({ "attributes": names }))
+                };
+                events.insert(urn.to_string(), payload);
+            }
+
+            (ProvisioningOperation::Patch, Some(_)) => {
+                let before_attrs = before_attrs.clone().unwrap_or_default();
+                let changed = changed_attributes(&before_attrs, subject_attrs);
+                let (urn, payload) = if self.full_data {
+                    let data = patch_message(&changed, subject_attrs);
+                    (EVENT_PATCH_FULL_URN, json!({ "data": data }))
+                } else {
+                    (EVENT_PATCH_NOTICE_URN, json!({ "attributes": changed }))
+                };
+                events.insert(urn.to_string(), payload);
+            }
+        }
+
+        if let (Some(before), Some(after)) = (&before_attrs, &after_attrs) {
+            match (is_active(before), is_active(after)) {
+                (true, false) => {
+                    events.insert(EVENT_DEACTIVATE_URN.to_string(), json!({}));
+                }
+                (false, true) => {
+                    events.insert(EVENT_ACTIVATE_URN.to_string(), json!({}));
+                }
+                _ => {}
+            }
+        }
+
+        let now = Utc::now();
+        let toe = match after {
+            Some(after) => after.meta.last_modified,
+            None => now,
+        };
+
+        Ok(SecurityEventToken {
+            jti: Uuid::new_v4().simple().to_string(),
+            iss: self.issuer.clone(),
+            aud: self.audience.clone(),
+            iat: now.timestamp(),
+            toe: toe.timestamp(),
+            sub_id: SubjectId {
+                format: String::from("scim"),
+                uri: format!(
+                    "/{}s/{}",
+                    R::resource_type(),
+                    subject.resource.id()
+                ),
+                external_id: subject_attrs
+                    .get("externalId")
+                    .and_then(Value::as_str)
+                    .map(String::from),
+            },
+            events,
+        })
+    }
+}
+
+/// A resource's attributes, as a client would see them
+fn attributes<R: Resource + Clone>(
+    resource: &R,
+) -> Result<Map<String, Value>, Error> {
+    serialize_resource_to_object(resource.clone())
+}
+
+/// The full SCIM representation of a resource
+fn full<R: Resource + Clone>(
+    stored: &StoredParts<R>,
+    base_url: &str,
+) -> Result<Value, Error> {
+    let response = SingleResourceResponse::from_resource(
+        stored.resource.clone(),
+        stored.meta.clone(),
+        None,
+        base_url,
+    )?;
+
+    serde_json::to_value(response).map_err(|e| {
+        Error::internal_error(format!("failed to serialize resource: {e}"))
+    })
+}
+
+fn changed_attributes(
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) -> Vec<String> {
+    let mut names: Vec<String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|name| before.get(*name) != after.get(*name))
+        .cloned()
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// A PatchOp message that makes the `changed` attributes what they are in
+/// `after`
+fn patch_message(changed: &[String], after: &Map<String, Value>) -> Value {
+    let mut replaced = Map::new();
+    let mut operations = Vec::new();
+
+    for name in changed {
+        match after.get(name) {
+            Some(value) => {
+                replaced.insert(name.clone(), value.clone());
+            }
+            None => operations.push(json!({ "op": "remove", "path": name })),
+        }
+    }
+
+    if !replaced.is_empty() {
+        operations.insert(0, json!({ "op": "replace", "value": replaced }));
+    }
+
+    json!({ "schemas": [PATCHOP_URN], "Operations": operations })
+}
+
+fn is_active(attrs: &Map<String, Value>) -> bool {
+    // As for change events, a missing `active` counts as active. Groups have
+    // no `active`, so they are never activated or deactivated.
+    attrs.get("active") != Some(&Value::Bool(false))
+}
+
+/// Turns Security Event Tokens into JWTs
+pub trait SecurityEventSigner {
+    fn sign(&self, token: &SecurityEventToken) -> anyhow::Result<String>;
+}
+
+/// Produces unsecured JWTs (`"alg": "none"`), for tests and for receivers that
+/// trust the channel instead
+#[derive(Debug, Clone, Default)]
+pub struct UnsignedSigner;
+
+impl SecurityEventSigner for UnsignedSigner {
+    fn sign(&self, token: &SecurityEventToken) -> anyhow::Result<String> {
+        let header = json!({ "alg": "none", "typ": SECEVENT_JWT_TYPE });
+
+        Ok(format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(token)?),
+        ))
+    }
+}
+
+/// Signs with a key through `jsonwebtoken`
+fn sign_with(
+    algorithm: Algorithm,
+    key_id: Option<&String>,
+    key: &EncodingKey,
+    token: &SecurityEventToken,
+) -> anyhow::Result<String> {
+    let mut header = Header::new(algorithm);
+    header.typ = Some(SECEVENT_JWT_TYPE.to_string());
+    header.kid = key_id.cloned();
+
+    Ok(jsonwebtoken::encode(&header, token, key)?)
+}
+
+/// Signs with HMAC SHA-256 and a secret shared with the receiver
+#[derive(Clone)]
+pub struct HmacSigner {
+    key: EncodingKey,
+    key_id: Option<String>,
+}
+
+impl HmacSigner {
+    pub fn new(secret: &[u8]) -> Self {
+        Self { key: EncodingKey::from_secret(secret), key_id: None }
+    }
+
+    /// Name the key in the `kid` header, so that receivers can tell which
+    /// secret to check against while keys are being rotated
+    pub fn with_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.key_id = Some(key_id.into());
+        self
+    }
+}
+
+impl SecurityEventSigner for HmacSigner {
+    fn sign(&self, token: &SecurityEventToken) -> anyhow::Result<String> {
+        sign_with(Algorithm::HS256, self.key_id.as_ref(), &self.key, token)
+    }
+}
+
+/// Signs with RSA SHA-256 (RS256) and a private key
+#[derive(Clone)]
+pub struct RsaSigner {
+    key: EncodingKey,
+    key_id: Option<String>,
+}
+
+impl RsaSigner {
+    /// Use a PEM encoded PKCS#1 or PKCS#8 RSA private key
+    pub fn from_pem(pem: &[u8]) -> anyhow::Result<Self> {
+        Ok(Self { key: EncodingKey::from_rsa_pem(pem)?, key_id: None })
+    }
+
+    /// Name the key in the `kid` header, so that receivers can find the
+    /// public key in a JWKS
+    pub fn with_key_id(mut self, key_id: impl Into<String>) -> Self {
+        self.key_id = Some(key_id.into());
+        self
+    }
+}
+
+impl SecurityEventSigner for RsaSigner {
+    fn sign(&self, token: &SecurityEventToken) -> anyhow::Result<String> {
+        sign_with(Algorithm::RS256, self.key_id.as_ref(), &self.key, token)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{StoredMeta, User};
+
+    use jsonwebtoken::{DecodingKey, Validation};
+    use rsa::RsaPrivateKey;
+    use rsa::pkcs8::{EncodePrivateKey, EncodePublicKey, LineEnding};
+    use rsa::rand_core::OsRng;
+
+    fn stored_user(active: bool) -> StoredParts<User> {
+        StoredParts {
+            resource: User {
+                id: String::from("44f6142df96bd6ab61e7521d9"),
+                name: String::from("jhalpert"),
+                active: Some(active),
+                external_id: Some(String::from("jim")),
+                groups: None,
+            },
+            meta: StoredMeta {
+                created: Utc::now(),
+                last_modified: Utc::now(),
+                version: String::from("W/\"1\""),
+            },
+        }
+    }
+
+    fn issuer() -> SecurityEventIssuer {
+        SecurityEventIssuer::new(
+            "https://scim.example.com",
+            vec![String::from("https://receiver.example.com")],
+        )
+    }
+
+    #[test]
+    fn test_create_notice() {
+        let user = stored_user(true);
+        let token = issuer()
+            .issue(ProvisioningOperation::Create, None, Some(&user))
+            .unwrap();
+
+        assert_eq!(token.iss, "https://scim.example.com");
+        assert_eq!(
+            token.sub_id,
+            SubjectId {
+                format: String::from("scim"),
+                uri: String::from("/Users/44f6142df96bd6ab61e7521d9"),
+                external_id: Some(String::from("jim")),
+            }
+        );
+        assert_eq!(
+            token.events,
+            BTreeMap::from([(
+                EVENT_CREATE_NOTICE_URN.to_string(),
+                json!({
+                    "attributes": ["active", "externalId", "id", "userName"],
+                }),
+            )])
+        );
+    }
+
+    #[test]
+    fn test_patch_deactivates() {
+        let before = stored_user(true);
+        let after = stored_user(false);
+
+        let token = issuer()
+            .issue(ProvisioningOperation::Patch, Some(&before), Some(&after))
+            .unwrap();
+        assert_eq!(
+            token.events,
+            BTreeMap::from([
+                (
+                    EVENT_PATCH_NOTICE_URN.to_string(),
+                    json!({ "attributes": ["active"] }),
+                ),
+                (EVENT_DEACTIVATE_URN.to_string(), json!({})),
+            ])
+        );
+
+        // A full event carries a PatchOp message with the changes
+        let token = issuer()
+            .with_full_data()
+            .issue(ProvisioningOperation::Patch, Some(&after), Some(&before))
+            .unwrap();
+        assert_eq!(
+            token.events,
+            BTreeMap::from([
+                (
+                    EVENT_PATCH_FULL_URN.to_string(),
+                    json!({
+                        "data": {
+                            "schemas": [PATCHOP_URN],
+                            "Operations": [{
+                                "op": "replace",
+                                "value": { "active": true },
+                            }],
+                        },
+                    }),
+                ),
+                (EVENT_ACTIVATE_URN.to_string(), json!({})),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let user = stored_user(true);
+        let token = issuer()
+            .with_full_data()
+            .issue(ProvisioningOperation::Delete, Some(&user), None)
+            .unwrap();
+
+        assert_eq!(token.sub_id.uri, "/Users/44f6142df96bd6ab61e7521d9");
+        assert_eq!(
+            token.events,
+            BTreeMap::from([(EVENT_DELETE_URN.to_string(), json!({}))])
+        );
+    }
+
+    fn decode_unverified(jwt: &str) -> (Value, Value) {
+        let mut parts = jwt.split('.');
+        let mut decode = || {
+            let part = parts.next().unwrap();
+            serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap())
+                .unwrap()
+        };
+        (decode(), decode())
+    }
+
+    #[test]
+    fn test_unsigned_signer() {
+        let user = stored_user(true);
+        let token = issuer()
+            .issue(ProvisioningOperation::Create, None, Some(&user))
+            .unwrap();
+
+        let jwt = UnsignedSigner.sign(&token).unwrap();
+        assert!(jwt.ends_with('.'));
+
+        let (header, claims) = decode_unverified(&jwt);
+        assert_eq!(header, json!({ "alg": "none", "typ": "secevent+jwt" }));
+        assert_eq!(
+            serde_json::from_value::<SecurityEventToken>(claims).unwrap(),
+            token
+        );
+    }
+
+    fn validation(algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        // SETs have no expiry (RFC 8417 § 2.2)
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        validation.set_audience(&["https://receiver.example.com"]);
+        validation.set_issuer(&["https://scim.example.com"]);
+        validation
+    }
+
+    #[test]
+    fn test_hmac_signer() {
+        let user = stored_user(true);
+        let token = issuer()
+            .issue(ProvisioningOperation::Create, None, Some(&user))
+            .unwrap();
+
+        let jwt = HmacSigner::new(b"secret")
+            .with_key_id("2026-10")
+            .sign(&token)
+            .unwrap();
+
+        let decoded = jsonwebtoken::decode::<SecurityEventToken>(
+            &jwt,
+            &DecodingKey::from_secret(b"secret"),
+            &validation(Algorithm::HS256),
+        )
+        .unwrap();
+        assert_eq!(decoded.header.typ.as_deref(), Some("secevent+jwt"));
+        assert_eq!(decoded.header.kid.as_deref(), Some("2026-10"));
+        assert_eq!(decoded.claims, token);
+
+        jsonwebtoken::decode::<SecurityEventToken>(
+            &jwt,
+            &DecodingKey::from_secret(b"wrong"),
+            &validation(Algorithm::HS256),
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn test_rsa_signer() {
+        let user = stored_user(true);
+        let token = issuer()
+            .issue(ProvisioningOperation::Create, None, Some(&user))
+            .unwrap();
+
+        let key = RsaPrivateKey::new(&mut OsRng, 2048).unwrap();
+        let private_pem = key.to_pkcs8_pem(LineEnding::LF).unwrap();
+        let public_pem =
+            key.to_public_key().to_public_key_pem(LineEnding::LF).unwrap();
+
+        let jwt = RsaSigner::from_pem(private_pem.as_bytes())
+            .unwrap()
+            .sign(&token)
+            .unwrap();
+
+        let decoded = jsonwebtoken::decode::<SecurityEventToken>(
+            &jwt,
+            &DecodingKey::from_rsa_pem(public_pem.as_bytes()).unwrap(),
+            &validation(Algorithm::RS256),
+        )
+        .unwrap();
+        assert_eq!(decoded.claims, token);
+    }
+}
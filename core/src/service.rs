@@ -0,0 +1,492 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! SCIM over plain `http` types, for servers that are not built on dropshot.
+//!
+//! A `ScimService` takes an `http::Request<Bytes>` and gives back an
+//! `http::Response<Bytes>`, doing the routing, query string and body parsing
+//! and error mapping that the dropshot endpoints get from dropshot. It is a
+//! `tower::Service`, so it can be mounted in an axum or hyper service once
+//! the request body has been collected.
+
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, Response, StatusCode, Uri, header};
+use percent_encoding::percent_decode_str;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::context;
+use crate::response::value_to_http_response;
+use crate::{
+    DeltaQueryParams, Error, QueryParams, ScimContext, ScimRequest,
+    auth_error_response,
+};
+
+/// Serves the SCIM endpoints under a base path, like `/v2` or
+/// `/tenants/{tenant_id}/v2`, with a `ScimContext`. A `{tenant_id}` in the
+/// base path is given to `ScimContext::authorize`.
+pub struct ScimService<C> {
+    inner: Arc<ServiceInner<C>>,
+}
+
+struct ServiceInner<C> {
+    context: C,
+    base_path: Vec<BaseSegment>,
+}
+
+enum BaseSegment {
+    Literal(String),
+    TenantId,
+}
+
+/// The endpoints, as found from a request's path
+enum Route {
+    Users,
+    User(String),
+    UserDelta,
+    Groups,
+    Group(String),
+    GroupDelta,
+    ResourceTypes,
+    ResourceTypeUser,
+    ResourceTypeGroup,
+    Schemas,
+    ServiceProviderConfig,
+}
+
+impl Route {
+    fn allows(&self, method: &Method) -> bool {
+        match self {
+            Route::Users | Route::Groups => {
+                method == Method::GET || method == Method::POST
+            }
+
+            Route::User(_) | Route::Group(_) => {
+                [Method::GET, Method::PUT, Method::PATCH, Method::DELETE]
+                    .contains(method)
+            }
+
+            Route::UserDelta
+            | Route::GroupDelta
+            | Route::ResourceTypes
+            | Route::ResourceTypeUser
+            | Route::ResourceTypeGroup
+            | Route::Schemas
+            | Route::ServiceProviderConfig => method == Method::GET,
+        }
+    }
+}
+
+impl<C> Clone for ScimService<C> {
+    fn clone(&self) -> Self {
+        Self { inner: Arc::clone(&self.inner) }
+    }
+}
+
+impl<C: ScimContext> ScimService<C> {
+    pub fn new(context: C, base_path: &str) -> Self {
+        let base_path = base_path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| match segment {
+                "{tenant_id}" => BaseSegment::TenantId,
+                _ => BaseSegment::Literal(segment.to_string()),
+            })
+            .collect();
+
+        Self { inner: Arc::new(ServiceInner { context, base_path }) }
+    }
+
+    pub fn context(&self) -> &C {
+        &self.inner.context
+    }
+
+    /// Serve `request`. Every failure, including not finding an endpoint for
+    /// it, is a SCIM error response.
+    pub async fn handle(&self, request: Request<Bytes>) -> Response<Bytes> {
+        let response = match self.serve(request).await {
+            Ok(response) => Ok(response),
+            Err(error) => auth_error_response(error),
+        };
+
+        response.unwrap_or_else(|error| {
+            let mut response =
+                Response::new(Bytes::from(format!("{error}").into_bytes()));
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+    }
+
+    /// The tenant and endpoint for a request path, if it is under the base
+    /// path
+    fn route(&self, path: &str) -> Option<(Option<String>, Route)> {
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| {
+                percent_decode_str(segment)
+                    .decode_utf8()
+                    .ok()
+                    .map(|segment| segment.into_owned())
+            });
+
+        let mut tenant_id = None;
+        for base_segment in &self.inner.base_path {
+            let segment = segments.next()??;
+            match base_segment {
+                BaseSegment::Literal(literal) if *literal == segment => {}
+                BaseSegment::Literal(_) => return None,
+                BaseSegment::TenantId => tenant_id = Some(segment),
+            }
+        }
+
+        let rest = segments.collect::<Option<Vec<String>>>()?;
+        let rest: Vec<&str> = rest.iter().map(String::as_str).collect();
+
+        let route = match rest.as_slice() {
+            ["Users"] => Route::Users,
+            ["Users", id] => Route::User(id.to_string()),
+            ["Delta", "Users"] => Route::UserDelta,
+            ["Groups"] => Route::Groups,
+            ["Groups", id] => Route::Group(id.to_string()),
+            ["Delta", "Groups"] => Route::GroupDelta,
+            ["ResourceTypes"] => Route::ResourceTypes,
+            ["ResourceTypes", "User"] => Route::ResourceTypeUser,
+            ["ResourceTypes", "Group"] => Route::ResourceTypeGroup,
+            ["Schemas"] => Route::Schemas,
+            ["ServiceProviderConfig"] => Route::ServiceProviderConfig,
+            _ => return None,
+        };
+
+        Some((tenant_id, route))
+    }
+
+    async fn serve(
+        &self,
+        request: Request<Bytes>,
+    ) -> Result<Response<Bytes>, Error> {
+        let (parts, body) = request.into_parts();
+
+        let Some((tenant_id, route)) = self.route(parts.uri.path()) else {
+            return Err(Error::not_found(parts.uri.path().to_string()));
+        };
+
+        if !route.allows(&parts.method) {
+            return Err(Error::method_not_allowed(&parts.method));
+        }
+
+        // RFC 7644, section 4: discovery needs no authentication, so that
+        // clients can find out how to authenticate
+        let discovery = match route {
+            Route::ResourceTypes => Some(context::resource_types()),
+            Route::ResourceTypeUser => Some(context::resource_type_user()),
+            Route::ResourceTypeGroup => Some(context::resource_type_group()),
+            Route::Schemas => Some(context::schemas()),
+            Route::ServiceProviderConfig => {
+                Some(context::service_provider_config::<C::Store>(
+                    self.inner.context.authentication_schemes(),
+                ))
+            }
+            _ => None,
+        };
+        if let Some(document) = discovery {
+            return Ok(value_to_http_response(
+                StatusCode::OK,
+                &document,
+                "serializing discovery document failed",
+            )?);
+        }
+
+        let request_id = Uuid::new_v4().to_string();
+        let scim_request = ScimRequest {
+            headers: &parts.headers,
+            request_id: &request_id,
+            tenant_id: tenant_id.as_deref(),
+        };
+        let (context, provider) =
+            self.inner.context.authorize(scim_request).await?;
+        let context = context.with_if_match(&parts.headers);
+
+        let response = match (route, parts.method) {
+            (Route::Users, Method::GET) => {
+                let query_params: QueryParams = query(&parts.uri)?;
+                provider.list_users(query_params).await?.to_http_response()
+            }
+
+            (Route::Users, Method::POST) => provider
+                .create_user(&context, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::CREATED),
+
+            (Route::User(id), Method::GET) => provider
+                .get_user_by_id(query(&parts.uri)?, &id)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::User(id), Method::PUT) => provider
+                .replace_user(&context, &id, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::User(id), Method::PATCH) => provider
+                .patch_user(&context, &id, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::User(id), Method::DELETE) => {
+                Ok(provider.delete_user(&context, &id).await?)
+            }
+
+            (Route::UserDelta, Method::GET) => {
+                let query_params: DeltaQueryParams = query(&parts.uri)?;
+                provider
+                    .user_delta(query_params.watermark.as_deref())
+                    .await?
+                    .to_http_response()
+            }
+
+            (Route::Groups, Method::GET) => {
+                let query_params: QueryParams = query(&parts.uri)?;
+                provider.list_groups(query_params).await?.to_http_response()
+            }
+
+            (Route::Groups, Method::POST) => provider
+                .create_group(&context, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::CREATED),
+
+            (Route::Group(id), Method::GET) => provider
+                .get_group_by_id(query(&parts.uri)?, &id)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::Group(id), Method::PUT) => provider
+                .replace_group(&context, &id, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::Group(id), Method::PATCH) => provider
+                .patch_group(&context, &id, json_body(&parts.headers, &body)?)
+                .await?
+                .to_http_response(StatusCode::OK),
+
+            (Route::Group(id), Method::DELETE) => {
+                Ok(provider.delete_group(&context, &id).await?)
+            }
+
+            (Route::GroupDelta, Method::GET) => {
+                let query_params: DeltaQueryParams = query(&parts.uri)?;
+                provider
+                    .group_delta(query_params.watermark.as_deref())
+                    .await?
+                    .to_http_response()
+            }
+
+            (_, method) => return Err(Error::method_not_allowed(&method)),
+        };
+
+        Ok(response?)
+    }
+}
+
+fn query<T: DeserializeOwned>(uri: &Uri) -> Result<T, Error> {
+    serde_urlencoded::from_str(uri.query().unwrap_or_default()).map_err(
+        |error| Error::invalid_syntax(format!("invalid query string: {error}")),
+    )
+}
+
+/// The JSON request body, which RFC 7644 section 3.1 says is
+/// `application/scim+json`. Plain `application/json` is accepted too.
+fn json_body<T: DeserializeOwned>(
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Result<T, Error> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    if !media_type.eq_ignore_ascii_case("application/scim+json")
+        && !media_type.eq_ignore_ascii_case("application/json")
+    {
+        return Err(Error::unsupported_media_type(content_type));
+    }
+
+    serde_json::from_slice(body).map_err(|error| {
+        Error::invalid_syntax(format!("invalid request body: {error}"))
+    })
+}
+
+impl<C: ScimContext> tower_service::Service<Request<Bytes>> for ScimService<C> {
+    type Response = Response<Bytes>;
+    type Error = Infallible;
+    type Future = Pin<
+        Box<dyn Future<Output = Result<Response<Bytes>, Infallible>> + Send>,
+    >;
+
+    fn poll_ready(
+        &mut self,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Bytes>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move { Ok(service.handle(request).await) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::HeaderValue;
+    use serde_json::{Value, json};
+    use tower_service::Service;
+
+    use super::*;
+    use crate::{InMemoryProviderStore, Provider};
+
+    fn request(
+        method: Method,
+        uri: &str,
+        body: Option<Value>,
+    ) -> Request<Bytes> {
+        let builder = Request::builder().method(method).uri(uri);
+        match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/scim+json")
+                .body(Bytes::from(body.to_string()))
+                .unwrap(),
+            None => builder.body(Bytes::new()).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scim_service() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider =
+            Provider::new(log, Arc::new(InMemoryProviderStore::new()));
+        let mut service = ScimService::new(provider, "/scim/v2");
+
+        let response = service
+            .call(request(
+                Method::POST,
+                "/scim/v2/Users",
+                Some(json!({ "userName": "jhalpert" })),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/scim+json"
+        );
+        let user: Value = serde_json::from_slice(response.body()).unwrap();
+        let user_id = user["id"].as_str().unwrap();
+        let etag = response.headers()[header::ETAG].clone();
+        assert_eq!(etag, user["meta"]["version"].as_str().unwrap());
+
+        let response = service
+            .handle(request(
+                Method::GET,
+                "/scim/v2/Users?filter=userName%20eq%20%22jhalpert%22",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let list: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(list["totalResults"], 1);
+
+        // Writes only go ahead on the version named by If-Match
+        let delete = |if_match: HeaderValue| {
+            let mut request = request(
+                Method::DELETE,
+                &format!("/scim/v2/Users/{user_id}"),
+                None,
+            );
+            request.headers_mut().insert(header::IF_MATCH, if_match);
+            request
+        };
+        let response = service
+            .handle(delete(HeaderValue::from_static("W/\"stale\"")))
+            .await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+        let response = service.handle(delete(etag)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // Bodies have to be JSON
+        let response = service
+            .handle(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/scim/v2/Groups")
+                    .header(header::CONTENT_TYPE, "text/plain")
+                    .body(Bytes::from("Sales"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+        let response = service
+            .handle(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/scim/v2/Groups")
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(Bytes::from("{"))
+                    .unwrap(),
+            )
+            .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        // Everything else is a SCIM error
+        for (method, uri, status) in [
+            (Method::GET, "/scim/v2/Nothing", StatusCode::NOT_FOUND),
+            (Method::GET, "/v2/Users", StatusCode::NOT_FOUND),
+            (Method::DELETE, "/scim/v2/Users", StatusCode::METHOD_NOT_ALLOWED),
+            (Method::GET, "/scim/v2/Users?count=many", StatusCode::BAD_REQUEST),
+        ] {
+            let response = service.handle(request(method, uri, None)).await;
+            assert_eq!(response.status(), status, "{uri}");
+            let error: Error = serde_json::from_slice(response.body()).unwrap();
+            assert_eq!(error.status(), status);
+        }
+
+        let response = service
+            .handle(request(
+                Method::GET,
+                "/scim/v2/ServiceProviderConfig",
+                None,
+            ))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let config: Value = serde_json::from_slice(response.body()).unwrap();
+        assert_eq!(config["etag"], json!({ "supported": true }));
+    }
+
+    #[tokio::test]
+    async fn test_scim_service_tenant_path() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider =
+            Provider::new(log, Arc::new(InMemoryProviderStore::new()));
+        let service = ScimService::new(provider, "/tenants/{tenant_id}/v2");
+
+        let response = service
+            .handle(request(Method::GET, "/tenants/sabre/v2/Groups", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = service
+            .handle(request(Method::GET, "/tenants/v2/Groups", None))
+            .await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
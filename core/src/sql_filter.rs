@@ -0,0 +1,564 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Translation of a parsed [`Filter`] into a parameterised SQL `WHERE`
+//! fragment, for use by database-backed provider stores.
+//!
+//! The generated SQL sticks to portable constructs (`LOWER`, `LIKE ...
+//! ESCAPE`, `COALESCE`, `substr`) so the same fragment works with SQLite and
+//! PostgreSQL. Only the placeholder syntax, and the function that finds a
+//! substring, differ between them.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use chrono::{DateTime, Utc};
+use unicase::UniCase;
+
+use crate::Error;
+use crate::filter::{AttrPath, CompValue, CompareOp, Filter};
+
+/// How a SCIM attribute is stored in its column, which decides how values are
+/// bound and compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlColumnType {
+    /// A string attribute. Comparisons fold case with `LOWER` on both sides
+    /// unless the attribute is caseExact.
+    String {
+        case_exact: bool,
+    },
+    /// A string attribute that is not caseExact, stored in a column whose
+    /// collation already ignores case, like SQLite's `COLLATE NOCASE` or
+    /// PostgreSQL's `citext`. The column is compared as it is, so that an
+    /// index on it can be used.
+    NoCaseString,
+    Boolean,
+    Number,
+    /// A dateTime attribute. Filter values must be RFC 3339 timestamps and
+    /// are bound as [`SqlValue::DateTime`].
+    DateTime,
+}
+
+/// A column expression that a SCIM attribute path maps to.
+#[derive(Debug, Clone)]
+pub struct SqlColumn {
+    pub expression: String,
+    pub column_type: SqlColumnType,
+}
+
+/// The mapping from SCIM attribute paths to columns for one resource type.
+///
+/// Attribute paths are matched without regard to case, as RFC 7643 requires.
+/// A URN-qualified path only matches if the URN is this resource's schema.
+#[derive(Debug, Clone)]
+pub struct SqlColumnMap {
+    schema: String,
+    columns: BTreeMap<UniCase<String>, SqlColumn>,
+}
+
+impl SqlColumnMap {
+    pub fn new(schema: &str) -> Self {
+        Self { schema: schema.to_string(), columns: BTreeMap::new() }
+    }
+
+    /// Map the attribute path `path` (e.g. `userName` or `meta.created`) to
+    /// the SQL `expression`.
+    pub fn column(
+        mut self,
+        path: &str,
+        expression: &str,
+        column_type: SqlColumnType,
+    ) -> Self {
+        self.columns.insert(
+            UniCase::new(path.to_string()),
+            SqlColumn { expression: expression.to_string(), column_type },
+        );
+        self
+    }
+
+    fn lookup(&self, path: &AttrPath) -> Result<&SqlColumn, Error> {
+        let schema_matches = path
+            .urn
+            .as_ref()
+            .is_none_or(|urn| urn.eq_ignore_ascii_case(&self.schema));
+
+        schema_matches
+            .then(|| self.columns.get(&UniCase::new(path.dotted())))
+            .flatten()
+            .ok_or_else(|| {
+                Error::invalid_filter(format!(
+                    "filtering on {} is not supported",
+                    path.dotted()
+                ))
+            })
+    }
+}
+
+/// The placeholder syntax of the target database. Placeholders are numbered,
+/// so a fragment can be combined with other bound values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SqlPlaceholder {
+    /// `?1`, `?2`, ... as used by SQLite
+    Question,
+    /// `$1`, `$2`, ... as used by PostgreSQL
+    Dollar,
+}
+
+/// A value bound to a placeholder in a [`SqlFragment`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Bool(bool),
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    DateTime(DateTime<Utc>),
+}
+
+/// A SQL boolean expression and the values bound to its placeholders, in
+/// placeholder order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlFragment {
+    pub sql: String,
+    pub values: Vec<SqlValue>,
+}
+
+impl SqlFragment {
+    /// Translate `filter` using the columns in `columns`. Placeholders are
+    /// numbered starting at `first_index`.
+    ///
+    /// Filters on attributes that have no column, and comparisons that RFC
+    /// 7644 § 3.4.2.2 does not allow for the attribute's type, are rejected
+    /// with an `invalidFilter` error.
+    pub fn from_filter(
+        filter: &Filter,
+        columns: &SqlColumnMap,
+        placeholder: SqlPlaceholder,
+        first_index: usize,
+    ) -> Result<Self, Error> {
+        let mut translator = Translator {
+            columns,
+            placeholder,
+            first_index,
+            values: Vec::new(),
+        };
+
+        let sql = translator.translate(filter)?;
+
+        Ok(Self { sql, values: translator.values })
+    }
+}
+
+struct Translator<'a> {
+    columns: &'a SqlColumnMap,
+    placeholder: SqlPlaceholder,
+    first_index: usize,
+    values: Vec<SqlValue>,
+}
+
+impl Translator<'_> {
+    fn bind(&mut self, value: SqlValue) -> String {
+        let index = self.first_index + self.values.len();
+        self.values.push(value);
+
+        match self.placeholder {
+            SqlPlaceholder::Question => format!("?{index}"),
+            SqlPlaceholder::Dollar => format!("${index}"),
+        }
+    }
+
+    /// The 1-based position of `needle` in `haystack`, or 0 if it is not
+    /// there. SQLite has no `strpos`, and PostgreSQL has no `instr`.
+    fn strpos(&self, haystack: &str, needle: &str) -> String {
+        match self.placeholder {
+            SqlPlaceholder::Question => format!("instr({haystack}, {needle})"),
+            SqlPlaceholder::Dollar => format!("strpos({haystack}, {needle})"),
+        }
+    }
+
+    fn translate(&mut self, filter: &Filter) -> Result<String, Error> {
+        match filter {
+            Filter::And(lhs, rhs) => Ok(format!(
+                "({} AND {})",
+                self.translate(lhs)?,
+                self.translate(rhs)?
+            )),
+
+            Filter::Or(lhs, rhs) => Ok(format!(
+                "({} OR {})",
+                self.translate(lhs)?,
+                self.translate(rhs)?
+            )),
+
+            // A comparison against a NULL column is NULL rather than false, so
+            // fold that to false before negating it.
+            Filter::Not(inner) => {
+                Ok(format!("(NOT COALESCE({}, FALSE))", self.translate(inner)?))
+            }
+
+            Filter::Present(path) => {
+                let column = self.columns.lookup(path)?;
+                Ok(format!("({} IS NOT NULL)", column.expression))
+            }
+
+            Filter::Compare { path, op, value } => {
+                let column = self.columns.lookup(path)?.clone();
+                self.translate_compare(path, &column, *op, value)
+            }
+        }
+    }
+
+    fn translate_compare(
+        &mut self,
+        path: &AttrPath,
+        column: &SqlColumn,
+        op: CompareOp,
+        value: &CompValue,
+    ) -> Result<String, Error> {
+        let column_expr = &column.expression;
+        let invalid = |reason: &str| {
+            Error::invalid_filter(format!(
+                "invalid filter on {}: {reason}",
+                path.dotted()
+            ))
+        };
+
+        let value = match (column.column_type, value) {
+            (_, CompValue::Null) => {
+                return match op {
+                    CompareOp::Eq => Ok(format!("({column_expr} IS NULL)")),
+                    CompareOp::Ne => Ok(format!("({column_expr} IS NOT NULL)")),
+                    _ => Err(invalid("null only supports eq and ne")),
+                };
+            }
+
+            (
+                SqlColumnType::String { .. } | SqlColumnType::NoCaseString,
+                CompValue::String(value),
+            ) => SqlValue::Text(value.clone()),
+
+            (SqlColumnType::Boolean, CompValue::Bool(value)) => {
+                SqlValue::Bool(*value)
+            }
+
+            (SqlColumnType::Number, CompValue::Number(value)) => {
+                match value.as_i64() {
+                    Some(value) => SqlValue::Integer(value),
+                    None => SqlValue::Real(
+                        value.as_f64().ok_or_else(|| invalid("bad number"))?,
+                    ),
+                }
+            }
+
+            (SqlColumnType::DateTime, CompValue::String(value)) => {
+                let value = DateTime::parse_from_rfc3339(value)
+                    .map_err(|e| invalid(&format!("bad dateTime: {e}")))?;
+                SqlValue::DateTime(value.with_timezone(&Utc))
+            }
+
+            _ => return Err(invalid("value has the wrong type")),
+        };
+
+        let fold_case = matches!(
+            column.column_type,
+            SqlColumnType::String { case_exact: false }
+        );
+        let case_exact = matches!(
+            column.column_type,
+            SqlColumnType::String { case_exact: true }
+        );
+        let lhs = if fold_case {
+            format!("LOWER({column_expr})")
+        } else {
+            column_expr.clone()
+        };
+
+        match op {
+            CompareOp::Co | CompareOp::Sw | CompareOp::Ew => {
+                let SqlValue::Text(value) = value else {
+                    return Err(invalid("co, sw and ew require a string"));
+                };
+
+                // LIKE ignores case in SQLite, so caseExact attributes are
+                // compared by position instead.
+                if case_exact {
+                    let rhs = self.bind(SqlValue::Text(value));
+                    return Ok(match op {
+                        CompareOp::Co => {
+                            format!("({} > 0)", self.strpos(&lhs, &rhs))
+                        }
+                        CompareOp::Sw => {
+                            format!("(substr({lhs}, 1, length({rhs})) = {rhs})")
+                        }
+                        _ => format!(
+                            "(length({lhs}) >= length({rhs}) AND \
+                            substr({lhs}, length({lhs}) - length({rhs}) + 1) \
+                            = {rhs})"
+                        ),
+                    });
+                }
+
+                let escaped = escape_like(&value);
+                let pattern = match op {
+                    CompareOp::Co => format!("%{escaped}%"),
+                    CompareOp::Sw => format!("{escaped}%"),
+                    _ => format!("%{escaped}"),
+                };
+
+                let rhs = self.bind(SqlValue::Text(pattern));
+                let rhs = if fold_case { format!("LOWER({rhs})") } else { rhs };
+
+                Ok(format!("({lhs} LIKE {rhs} ESCAPE '\\')"))
+            }
+
+            CompareOp::Gt | CompareOp::Ge | CompareOp::Lt | CompareOp::Le
+                if column.column_type == SqlColumnType::Boolean =>
+            {
+                Err(invalid("booleans cannot be ordered"))
+            }
+
+            _ => {
+                let rhs = self.bind(value);
+                let rhs = if fold_case { format!("LOWER({rhs})") } else { rhs };
+
+                let mut sql = String::new();
+                match op {
+                    // Resources without the attribute are not equal to any
+                    // value.
+                    CompareOp::Ne => {
+                        write!(sql, "({column_expr} IS NULL OR {lhs} <> {rhs})")
+                    }
+                    CompareOp::Eq => write!(sql, "({lhs} = {rhs})"),
+                    CompareOp::Gt => write!(sql, "({lhs} > {rhs})"),
+                    CompareOp::Ge => write!(sql, "({lhs} >= {rhs})"),
+                    CompareOp::Lt => write!(sql, "({lhs} < {rhs})"),
+                    CompareOp::Le => write!(sql, "({lhs} <= {rhs})"),
+                    CompareOp::Co | CompareOp::Sw | CompareOp::Ew => {
+                        unreachable!()
+                    }
+                }
+                .expect("writing to a String cannot fail");
+
+                Ok(sql)
+            }
+        }
+    }
+}
+
+/// Escape the LIKE wildcards in `value`, using `\` as the escape character.
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(feature = "sqlite")]
+impl rusqlite::ToSql for SqlValue {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        match self {
+            SqlValue::Bool(value) => value.to_sql(),
+            SqlValue::Integer(value) => value.to_sql(),
+            SqlValue::Real(value) => value.to_sql(),
+            SqlValue::Text(value) => value.to_sql(),
+            SqlValue::DateTime(value) => value.to_sql(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::USER_URN;
+
+    fn user_columns() -> SqlColumnMap {
+        SqlColumnMap::new(USER_URN)
+            .column(
+                "userName",
+                "user_name",
+                SqlColumnType::String { case_exact: false },
+            )
+            .column("id", "id", SqlColumnType::String { case_exact: true })
+            .column("externalId", "external_id", SqlColumnType::NoCaseString)
+            .column("active", "active", SqlColumnType::Boolean)
+            .column("meta.version", "version", SqlColumnType::Number)
+            .column(
+                "meta.lastModified",
+                "last_modified",
+                SqlColumnType::DateTime,
+            )
+    }
+
+    fn translate(filter: &str) -> Result<SqlFragment, Error> {
+        SqlFragment::from_filter(
+            &Filter::parse(filter).unwrap(),
+            &user_columns(),
+            SqlPlaceholder::Question,
+            1,
+        )
+    }
+
+    #[test]
+    fn test_case_insensitive_comparison() {
+        assert_eq!(
+            translate(r#"USERNAME eq "Mike""#).unwrap(),
+            SqlFragment {
+                sql: "(LOWER(user_name) = LOWER(?1))".to_string(),
+                values: vec![SqlValue::Text("Mike".to_string())],
+            },
+        );
+
+        assert_eq!(
+            translate(r#"id eq "Mike""#).unwrap(),
+            SqlFragment {
+                sql: "(id = ?1)".to_string(),
+                values: vec![SqlValue::Text("Mike".to_string())],
+            },
+        );
+
+        assert_eq!(
+            translate(r#"id ne "Mike""#).unwrap().sql,
+            "(id IS NULL OR id <> ?1)",
+        );
+
+        // The column's collation does the case folding
+        assert_eq!(
+            translate(r#"externalId eq "Mike""#).unwrap().sql,
+            "(external_id = ?1)",
+        );
+        assert_eq!(
+            translate(r#"externalId sw "M""#).unwrap().sql,
+            "(external_id LIKE ?1 ESCAPE '\\')",
+        );
+    }
+
+    #[test]
+    fn test_case_exact_substrings() {
+        assert_eq!(
+            translate(r#"id co "a%""#).unwrap(),
+            SqlFragment {
+                sql: "(instr(id, ?1) > 0)".to_string(),
+                values: vec![SqlValue::Text("a%".to_string())],
+            },
+        );
+
+        assert_eq!(
+            translate(r#"id sw "a""#).unwrap().sql,
+            "(substr(id, 1, length(?1)) = ?1)",
+        );
+
+        assert_eq!(
+            translate(r#"id ew "a""#).unwrap().sql,
+            "(length(id) >= length(?1) AND \
+            substr(id, length(id) - length(?1) + 1) = ?1)",
+        );
+
+        let fragment = SqlFragment::from_filter(
+            &Filter::parse(r#"id co "a""#).unwrap(),
+            &user_columns(),
+            SqlPlaceholder::Dollar,
+            1,
+        )
+        .unwrap();
+        assert_eq!(fragment.sql, "(strpos(id, $1) > 0)");
+    }
+
+    #[test]
+    fn test_like_escaping() {
+        assert_eq!(
+            translate(r#"userName co "50%_off\\""#).unwrap(),
+            SqlFragment {
+                sql: "(LOWER(user_name) LIKE LOWER(?1) ESCAPE '\\')"
+                    .to_string(),
+                values: vec![SqlValue::Text(r"%50\%\_off\\%".to_string())],
+            },
+        );
+
+        assert_eq!(
+            translate(r#"userName sw "a""#).unwrap().values,
+            vec![SqlValue::Text("a%".to_string())],
+        );
+
+        assert_eq!(
+            translate(r#"userName ew "a""#).unwrap().values,
+            vec![SqlValue::Text("%a".to_string())],
+        );
+    }
+
+    #[test]
+    fn test_present_null_and_logical_operators() {
+        assert_eq!(
+            translate("urn:ietf:params:scim:schemas:core:2.0:User:userName pr")
+                .unwrap()
+                .sql,
+            "(user_name IS NOT NULL)",
+        );
+
+        assert_eq!(translate("id eq null").unwrap().sql, "(id IS NULL)");
+
+        let fragment = translate(
+            r#"not (active eq true) or (meta.version ge 2 and userName pr)"#,
+        )
+        .unwrap();
+        assert_eq!(
+            fragment.sql,
+            "((NOT COALESCE((active = ?1), FALSE)) OR \
+            ((version >= ?2) AND (user_name IS NOT NULL)))",
+        );
+        assert_eq!(
+            fragment.values,
+            vec![SqlValue::Bool(true), SqlValue::Integer(2)]
+        );
+    }
+
+    #[test]
+    fn test_placeholder_styles() {
+        let fragment = SqlFragment::from_filter(
+            &Filter::parse(
+                r#"meta.lastModified gt "2011-05-13T04:42:34+01:00" and id eq "a""#,
+            )
+            .unwrap(),
+            &user_columns(),
+            SqlPlaceholder::Dollar,
+            3,
+        )
+        .unwrap();
+
+        assert_eq!(fragment.sql, "((last_modified > $3) AND (id = $4))");
+        assert_eq!(
+            fragment.values[0],
+            SqlValue::DateTime(
+                "2011-05-13T03:42:34Z".parse::<DateTime<Utc>>().unwrap()
+            ),
+        );
+    }
+
+    #[test]
+    fn test_rejected_filters() {
+        for filter in [
+            // unmapped attributes
+            r#"title eq "a""#,
+            r#"name.familyName eq "a""#,
+            r#"urn:ietf:params:scim:schemas:core:2.0:Group:userName eq "a""#,
+            // type mismatches
+            r#"active eq "true""#,
+            "userName eq 1",
+            r#"meta.lastModified gt "yesterday""#,
+            // operators that do not apply
+            "active gt false",
+            "meta.version co 1",
+            "userName gt null",
+        ] {
+            let error = translate(filter).unwrap_err();
+            assert_eq!(
+                error.error_type,
+                Some(crate::ErrorType::InvalidFilter),
+                "{filter}"
+            );
+        }
+    }
+}
@@ -0,0 +1,2188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::delta::touched_resources;
+use crate::history::{group_as_of, user_as_of};
+use crate::provider_store::{history_not_implemented, outbox_not_implemented};
+use crate::response::Error;
+use crate::utils::ResourceType;
+use crate::{
+    ChangeEvent, ChangeEventKind, ChangedGroup, CreateGroupRequest,
+    CreateUserRequest, DeactivatedUser, DeadLetter, DeletedResource, Filter,
+    GROUP_URN, Group, GroupMember, OutboxEntry, Pagination, ProviderStore,
+    ProviderStoreDeleteResult, ProviderStoreError, ProviderStoreListResult,
+    Resource, Revision, SqlColumnMap, SqlColumnType, SqlFragment,
+    SqlPlaceholder, SqlValue, StoreDelta, StoredMeta, StoredParts, USER_URN,
+    User, UserGroup, UserGroupType, group_changes, new_version, user_changes,
+};
+
+use anyhow::Context;
+use anyhow::bail;
+use chrono::{DateTime, Utc};
+use iddqd::IdOrdMap;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use rusqlite::Row;
+use rusqlite::ToSql;
+use rusqlite::params;
+use rusqlite::params_from_iter;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::LazyLock;
+use std::sync::Mutex;
+use unicase::UniCase;
+use uuid::Uuid;
+
+/// Schema migrations, applied in order. SQLite's `user_version` pragma records
+/// how many of them have been applied to a database.
+const MIGRATIONS: &[&str] = &[
+    // 1: initial schema
+    //
+    // userName and displayName are unique, and are compared without regard to
+    // case just like the filters that Okta sends. Note that NOCASE only folds
+    // ASCII characters.
+    r#"
+    CREATE TABLE scim_users (
+        id TEXT PRIMARY KEY NOT NULL,
+        user_name TEXT NOT NULL COLLATE NOCASE UNIQUE,
+        external_id TEXT COLLATE NOCASE,
+        active INTEGER,
+        created TEXT NOT NULL,
+        last_modified TEXT NOT NULL,
+        version TEXT NOT NULL
+    );
+
+    CREATE INDEX scim_users_external_id ON scim_users (external_id);
+
+    CREATE TABLE scim_groups (
+        id TEXT PRIMARY KEY NOT NULL,
+        display_name TEXT NOT NULL COLLATE NOCASE UNIQUE,
+        external_id TEXT COLLATE NOCASE,
+        created TEXT NOT NULL,
+        last_modified TEXT NOT NULL,
+        version TEXT NOT NULL
+    );
+
+    CREATE TABLE scim_group_members (
+        group_id TEXT NOT NULL REFERENCES scim_groups (id) ON DELETE CASCADE,
+        user_id TEXT NOT NULL REFERENCES scim_users (id) ON DELETE CASCADE,
+        PRIMARY KEY (group_id, user_id)
+    );
+
+    CREATE INDEX scim_group_members_user_id ON scim_group_members (user_id);
+    "#,
+    // 2: soft deleted resources
+    //
+    // A soft deleted resource is removed from the tables above like any
+    // other delete, and kept here as the JSON of its StoredParts (which
+    // includes its memberships) so that it can be restored.
+    r#"
+    CREATE TABLE scim_deleted_resources (
+        resource_type TEXT NOT NULL,
+        id TEXT NOT NULL,
+        deleted_at TEXT NOT NULL,
+        resource TEXT NOT NULL,
+        PRIMARY KEY (resource_type, id)
+    );
+
+    CREATE INDEX scim_deleted_resources_deleted_at
+        ON scim_deleted_resources (deleted_at);
+    "#,
+    // 3: the change event outbox
+    //
+    // AUTOINCREMENT, so that sequence numbers are never reused even once the
+    // newest entries have been acknowledged.
+    r#"
+    CREATE TABLE scim_outbox (
+        sequence INTEGER PRIMARY KEY AUTOINCREMENT,
+        event TEXT NOT NULL
+    );
+    "#,
+    // 4: resource history
+    //
+    // Every version of every resource, as the JSON of its StoredParts, or
+    // NULL for a delete.
+    r#"
+    CREATE TABLE scim_revisions (
+        revision INTEGER PRIMARY KEY AUTOINCREMENT,
+        resource_type TEXT NOT NULL,
+        id TEXT NOT NULL,
+        recorded_at TEXT NOT NULL,
+        resource TEXT
+    );
+
+    CREATE INDEX scim_revisions_id ON scim_revisions (resource_type, id);
+    "#,
+    // 5: change counter, for deltas
+    //
+    // The counter goes up by one for every change, and every resource the
+    // change touched is marked with the new count. Marks are kept for
+    // deleted resources.
+    r#"
+    CREATE TABLE scim_change_counter (
+        counter INTEGER NOT NULL
+    );
+
+    INSERT INTO scim_change_counter (counter) VALUES (0);
+
+    CREATE TABLE scim_changes (
+        resource_type TEXT NOT NULL,
+        id TEXT NOT NULL,
+        counter INTEGER NOT NULL,
+        deleted INTEGER NOT NULL,
+        PRIMARY KEY (resource_type, id)
+    );
+
+    CREATE INDEX scim_changes_counter ON scim_changes (resource_type, counter);
+    "#,
+    // 6: Unicode case folding
+    //
+    // NOCASE only folds ASCII, so userName, displayName and externalId are
+    // also indexed with the UNICASE collation, which folds case the way the
+    // in-memory store does. The filters compare with it. The unique indexes
+    // cannot be created if there are already names that only differ in the
+    // case of a non-ASCII letter.
+    r#"
+    CREATE UNIQUE INDEX scim_users_user_name
+        ON scim_users (user_name COLLATE UNICASE);
+
+    DROP INDEX scim_users_external_id;
+    CREATE INDEX scim_users_external_id
+        ON scim_users (external_id COLLATE UNICASE);
+
+    CREATE UNIQUE INDEX scim_groups_display_name
+        ON scim_groups (display_name COLLATE UNICASE);
+
+    CREATE INDEX scim_groups_external_id
+        ON scim_groups (external_id COLLATE UNICASE);
+    "#,
+    // 7: outbox consumers
+    //
+    // The cursor of each consumer of the outbox, and the entries each gave
+    // up on, as the JSON of their DeadLetter.
+    r#"
+    CREATE TABLE scim_outbox_cursors (
+        consumer TEXT PRIMARY KEY NOT NULL,
+        sequence INTEGER NOT NULL
+    );
+
+    CREATE TABLE scim_dead_letters (
+        endpoint TEXT NOT NULL,
+        sequence INTEGER NOT NULL,
+        dead_letter TEXT NOT NULL,
+        PRIMARY KEY (endpoint, sequence)
+    );
+    "#,
+];
+
+const USER_COLUMNS: &str =
+    "id, user_name, external_id, active, created, last_modified, version";
+
+const GROUP_COLUMNS: &str =
+    "id, display_name, external_id, created, last_modified, version";
+
+// The columns that are not caseExact are compared with the UNICASE collation,
+// which their indexes use.
+static USER_FILTER_COLUMNS: LazyLock<SqlColumnMap> = LazyLock::new(|| {
+    SqlColumnMap::new(USER_URN)
+        .column("id", "id", SqlColumnType::String { case_exact: true })
+        .column(
+            "userName",
+            "user_name COLLATE UNICASE",
+            SqlColumnType::NoCaseString,
+        )
+        .column(
+            "externalId",
+            "external_id COLLATE UNICASE",
+            SqlColumnType::NoCaseString,
+        )
+        .column("active", "active", SqlColumnType::Boolean)
+        .column("meta.created", "created", SqlColumnType::DateTime)
+        .column("meta.lastModified", "last_modified", SqlColumnType::DateTime)
+});
+
+static GROUP_FILTER_COLUMNS: LazyLock<SqlColumnMap> = LazyLock::new(|| {
+    SqlColumnMap::new(GROUP_URN)
+        .column("id", "id", SqlColumnType::String { case_exact: true })
+        .column(
+            "displayName",
+            "display_name COLLATE UNICASE",
+            SqlColumnType::NoCaseString,
+        )
+        .column(
+            "externalId",
+            "external_id COLLATE UNICASE",
+            SqlColumnType::NoCaseString,
+        )
+        .column("meta.created", "created", SqlColumnType::DateTime)
+        .column("meta.lastModified", "last_modified", SqlColumnType::DateTime)
+});
+
+impl From<rusqlite::Error> for ProviderStoreError {
+    fn from(e: rusqlite::Error) -> ProviderStoreError {
+        ProviderStoreError::StoreError(e.into())
+    }
+}
+
+fn is_unique_violation(e: &rusqlite::Error) -> bool {
+    matches!(
+        e,
+        rusqlite::Error::SqliteFailure(error, _)
+            if error.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE
+    )
+}
+
+fn user_from_row(row: &Row<'_>) -> rusqlite::Result<StoredParts<User>> {
+    Ok(StoredParts {
+        resource: User {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            external_id: row.get(2)?,
+            active: row.get(3)?,
+            groups: None,
+        },
+        meta: StoredMeta {
+            created: row.get(4)?,
+            last_modified: row.get(5)?,
+            version: row.get(6)?,
+        },
+    })
+}
+
+fn group_from_row(row: &Row<'_>) -> rusqlite::Result<StoredParts<Group>> {
+    Ok(StoredParts {
+        resource: Group {
+            id: row.get(0)?,
+            display_name: row.get(1)?,
+            external_id: row.get(2)?,
+            members: None,
+        },
+        meta: StoredMeta {
+            created: row.get(3)?,
+            last_modified: row.get(4)?,
+            version: row.get(5)?,
+        },
+    })
+}
+
+/// Fill in the read-only `groups` attribute of a User from the membership
+/// table. The display value always comes from the group itself so it can't go
+/// stale.
+fn load_user_groups(
+    conn: &Connection,
+    user: &mut User,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT g.id, g.display_name
+        FROM scim_group_members m
+        JOIN scim_groups g ON g.id = m.group_id
+        WHERE m.user_id = ?1
+        ORDER BY m.rowid",
+    )?;
+
+    let groups = stmt
+        .query_map([&user.id], |row| {
+            Ok(UserGroup {
+                member_type: Some(UserGroupType::Direct),
+                value: Some(row.get(0)?),
+                display: Some(row.get(1)?),
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    user.groups = (!groups.is_empty()).then_some(groups);
+
+    Ok(())
+}
+
+fn load_group_members(
+    conn: &Connection,
+    group: &mut Group,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT user_id FROM scim_group_members
+        WHERE group_id = ?1
+        ORDER BY user_id",
+    )?;
+
+    let members = stmt
+        .query_map([&group.id], |row| {
+            Ok(GroupMember {
+                resource_type: Some(ResourceType::User.to_string()),
+                value: Some(row.get(0)?),
+            })
+        })?
+        .collect::<rusqlite::Result<IdOrdMap<_>>>()?;
+
+    group.members = (!members.is_empty()).then_some(members);
+
+    Ok(())
+}
+
+fn get_user(
+    conn: &Connection,
+    user_id: &str,
+) -> rusqlite::Result<Option<StoredParts<User>>> {
+    let Some(mut user) = conn
+        .query_row(
+            &format!("SELECT {USER_COLUMNS} FROM scim_users WHERE id = ?1"),
+            [user_id],
+            user_from_row,
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    load_user_groups(conn, &mut user.resource)?;
+
+    Ok(Some(user))
+}
+
+fn get_group(
+    conn: &Connection,
+    group_id: &str,
+) -> rusqlite::Result<Option<StoredParts<Group>>> {
+    let Some(mut group) = conn
+        .query_row(
+            &format!("SELECT {GROUP_COLUMNS} FROM scim_groups WHERE id = ?1"),
+            [group_id],
+            group_from_row,
+        )
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    load_group_members(conn, &mut group.resource)?;
+
+    Ok(Some(group))
+}
+
+fn insert_user(
+    conn: &Connection,
+    stored_user: &StoredParts<User>,
+) -> Result<(), ProviderStoreError> {
+    let StoredParts { resource: user, meta } = stored_user;
+    let result = conn.execute(
+        &format!(
+            "INSERT INTO scim_users ({USER_COLUMNS})
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)"
+        ),
+        params![
+            user.id,
+            user.name,
+            user.external_id,
+            user.active,
+            meta.created,
+            meta.last_modified,
+            meta.version,
+        ],
+    );
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if is_unique_violation(&e) => {
+            Err(Error::conflict(user.name.clone()).into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Insert a group, but not its members
+fn insert_group(
+    conn: &Connection,
+    stored_group: &StoredParts<Group>,
+) -> Result<(), ProviderStoreError> {
+    let StoredParts { resource: group, meta } = stored_group;
+    let result = conn.execute(
+        &format!(
+            "INSERT INTO scim_groups ({GROUP_COLUMNS})
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)"
+        ),
+        params![
+            group.id,
+            group.display_name,
+            group.external_id,
+            meta.created,
+            meta.last_modified,
+            meta.version,
+        ],
+    );
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) if is_unique_violation(&e) => {
+            Err(Error::conflict(format!("displayName {}", group.display_name))
+                .into())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Keep a copy of a resource that is about to be deleted
+fn insert_deleted<R: Resource>(
+    conn: &Connection,
+    stored: &StoredParts<R>,
+) -> Result<(), ProviderStoreError> {
+    let resource = serde_json::to_string(stored)
+        .context("serializing deleted resource")
+        .map_err(ProviderStoreError::StoreError)?;
+
+    conn.execute(
+        "INSERT OR REPLACE INTO scim_deleted_resources
+            (resource_type, id, deleted_at, resource)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![
+            R::resource_type().to_string(),
+            stored.resource.id(),
+            Utc::now(),
+            resource,
+        ],
+    )?;
+
+    Ok(())
+}
+
+fn list_deleted<R>(
+    conn: &Connection,
+) -> Result<Vec<DeletedResource<R>>, ProviderStoreError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let mut stmt = conn.prepare(
+        "SELECT deleted_at, resource FROM scim_deleted_resources
+        WHERE resource_type = ?1
+        ORDER BY deleted_at, id",
+    )?;
+
+    let rows = stmt
+        .query_map([R::resource_type().to_string()], |row| {
+            Ok((row.get(0)?, row.get::<_, String>(1)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    rows.into_iter()
+        .map(|(deleted_at, resource)| {
+            let resource = serde_json::from_str(&resource)
+                .context("parsing deleted resource")
+                .map_err(ProviderStoreError::StoreError)?;
+            Ok(DeletedResource { resource, deleted_at })
+        })
+        .collect()
+}
+
+/// Remove the copy of a deleted resource, returning it
+fn take_deleted<R>(
+    conn: &Connection,
+    id: &str,
+) -> Result<Option<StoredParts<R>>, ProviderStoreError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let resource: Option<String> = conn
+        .query_row(
+            "DELETE FROM scim_deleted_resources
+            WHERE resource_type = ?1 AND id = ?2
+            RETURNING resource",
+            params![R::resource_type().to_string(), id],
+            |row| row.get(0),
+        )
+        .optional()?;
+
+    resource
+        .map(|resource| {
+            serde_json::from_str(&resource)
+                .context("parsing deleted resource")
+                .map_err(ProviderStoreError::StoreError)
+        })
+        .transpose()
+}
+
+/// Count `changes`, and mark the resources they touch with the new count
+fn insert_change_marks(
+    conn: &Connection,
+    changes: &[ChangeEventKind],
+) -> Result<(), ProviderStoreError> {
+    let counter: u64 = conn.query_row(
+        "UPDATE scim_change_counter SET counter = counter + 1
+        RETURNING counter",
+        [],
+        |row| row.get(0),
+    )?;
+
+    for (resource_type, id, deleted) in touched_resources(changes) {
+        conn.execute(
+            "INSERT INTO scim_changes (resource_type, id, counter, deleted)
+            VALUES (?1, ?2, ?3, ?4)
+            ON CONFLICT (resource_type, id)
+            DO UPDATE
+            SET counter = excluded.counter, deleted = excluded.deleted",
+            params![resource_type.to_string(), id, counter, deleted],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// The delta for `resource_type` after `since`, or every resource of the type
+/// in `table` if there is no `since`
+fn query_delta(
+    conn: &Connection,
+    resource_type: ResourceType,
+    table: &str,
+    since: Option<u64>,
+) -> Result<StoreDelta, ProviderStoreError> {
+    let counter: u64 =
+        conn.query_row("SELECT counter FROM scim_change_counter", [], |row| {
+            row.get(0)
+        })?;
+
+    let mut delta = StoreDelta { counter, ..Default::default() };
+
+    let Some(since) = since else {
+        let mut stmt =
+            conn.prepare(&format!("SELECT id FROM {table} ORDER BY id"))?;
+        delta.changed = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        return Ok(delta);
+    };
+
+    let mut stmt = conn.prepare(
+        "SELECT id, deleted FROM scim_changes
+        WHERE resource_type = ?1 AND counter > ?2
+        ORDER BY id",
+    )?;
+
+    let rows = stmt
+        .query_map(params![resource_type.to_string(), since], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?))
+        })?;
+
+    for row in rows {
+        let (id, deleted) = row?;
+        if deleted {
+            delta.deleted.push(id);
+        } else {
+            delta.changed.push(id);
+        }
+    }
+
+    Ok(delta)
+}
+
+/// Record `changes` in the outbox table
+fn insert_outbox(
+    conn: &Connection,
+    changes: Vec<ChangeEventKind>,
+) -> Result<(), ProviderStoreError> {
+    for kind in changes {
+        let event = serde_json::to_string(&ChangeEvent::new(kind))
+            .context("serializing change event")
+            .map_err(ProviderStoreError::StoreError)?;
+
+        conn.execute("INSERT INTO scim_outbox (event) VALUES (?1)", [event])?;
+    }
+
+    Ok(())
+}
+
+/// Record a revision of the resource `id`, where `None` means it was deleted
+fn insert_revision<R: Resource + Serialize>(
+    conn: &Connection,
+    id: &str,
+    stored: Option<&StoredParts<R>>,
+) -> Result<(), ProviderStoreError> {
+    let resource = stored
+        .map(serde_json::to_string)
+        .transpose()
+        .context("serializing revision")
+        .map_err(ProviderStoreError::StoreError)?;
+
+    conn.execute(
+        "INSERT INTO scim_revisions (resource_type, id, recorded_at, resource)
+        VALUES (?1, ?2, ?3, ?4)",
+        params![R::resource_type().to_string(), id, Utc::now(), resource],
+    )?;
+
+    Ok(())
+}
+
+fn parse_revision<R: Resource + DeserializeOwned>(
+    resource: Option<String>,
+) -> Result<Option<StoredParts<R>>, ProviderStoreError> {
+    resource
+        .map(|resource| serde_json::from_str(&resource))
+        .transpose()
+        .context("parsing revision")
+        .map_err(ProviderStoreError::StoreError)
+}
+
+/// Every revision of the resource `id` of type `R`, oldest first
+fn list_revisions<R>(
+    conn: &Connection,
+    id: &str,
+) -> Result<Vec<Revision<R>>, ProviderStoreError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let mut stmt = conn.prepare_cached(
+        "SELECT revision, recorded_at, resource FROM scim_revisions
+        WHERE resource_type = ?1 AND id = ?2
+        ORDER BY revision",
+    )?;
+
+    let rows = stmt
+        .query_map(params![R::resource_type().to_string(), id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    rows.into_iter()
+        .map(|(revision, recorded_at, resource)| {
+            Ok(Revision {
+                revision,
+                recorded_at,
+                resource: parse_revision(resource)?,
+            })
+        })
+        .collect()
+}
+
+/// The resource `id` of type `R` as of its latest revision recorded at or
+/// before `at`, if it existed then
+fn revision_as_of<R>(
+    conn: &Connection,
+    id: &str,
+    at: DateTime<Utc>,
+) -> Result<Option<StoredParts<R>>, ProviderStoreError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let resource: Option<Option<String>> = conn
+        .prepare_cached(
+            "SELECT resource FROM scim_revisions
+            WHERE resource_type = ?1 AND id = ?2 AND recorded_at <= ?3
+            ORDER BY revision DESC
+            LIMIT 1",
+        )?
+        .query_row(params![R::resource_type().to_string(), id, at], |row| {
+            row.get(0)
+        })
+        .optional()?;
+
+    parse_revision(resource.flatten())
+}
+
+/// Every resource of type `R` that existed at `at`, as of its latest
+/// revision recorded at or before then
+fn revisions_as_of<R>(
+    conn: &Connection,
+    at: DateTime<Utc>,
+) -> Result<Vec<StoredParts<R>>, ProviderStoreError>
+where
+    R: Resource + DeserializeOwned,
+{
+    let resources = conn
+        .prepare_cached(
+            "SELECT resource FROM scim_revisions
+            WHERE resource IS NOT NULL AND revision IN (
+                SELECT MAX(revision) FROM scim_revisions
+                WHERE resource_type = ?1 AND recorded_at <= ?2
+                GROUP BY id
+            )",
+        )?
+        .query_map(params![R::resource_type().to_string(), at], |row| {
+            row.get(0)
+        })?
+        .collect::<rusqlite::Result<Vec<String>>>()?;
+
+    resources
+        .into_iter()
+        .filter_map(|resource| parse_revision(Some(resource)).transpose())
+        .collect()
+}
+
+/// Those of the resources `ids` of type `R` that existed at `at`
+fn existed_as_of<R: Resource>(
+    conn: &Connection,
+    ids: &BTreeSet<String>,
+    at: DateTime<Utc>,
+) -> Result<BTreeSet<String>, ProviderStoreError> {
+    let ids = serde_json::to_string(ids)
+        .context("serializing ids")
+        .map_err(ProviderStoreError::StoreError)?;
+
+    let existed = conn
+        .prepare_cached(
+            "SELECT id FROM scim_revisions
+            WHERE resource IS NOT NULL AND revision IN (
+                SELECT MAX(revision) FROM scim_revisions
+                WHERE resource_type = ?1
+                    AND id IN (SELECT value FROM json_each(?2))
+                    AND recorded_at <= ?3
+                GROUP BY id
+            )",
+        )?
+        .query_map(params![R::resource_type().to_string(), ids, at], |row| {
+            row.get(0)
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+
+    Ok(existed)
+}
+
+/// The ids of the groups that `user_id` is a member of
+fn member_group_ids(
+    conn: &Connection,
+    user_id: &str,
+) -> rusqlite::Result<Vec<String>> {
+    conn.prepare_cached(
+        "SELECT group_id FROM scim_group_members WHERE user_id = ?1",
+    )?
+    .query_map([user_id], |row| row.get(0))?
+    .collect()
+}
+
+/// The ids of the users that are members of `group_id`
+fn group_member_ids(
+    conn: &Connection,
+    group_id: &str,
+) -> rusqlite::Result<BTreeSet<String>> {
+    conn.prepare_cached(
+        "SELECT user_id FROM scim_group_members WHERE group_id = ?1",
+    )?
+    .query_map([group_id], |row| row.get(0))?
+    .collect()
+}
+
+/// Bump `last_modified` and the version of the rows of `table` with the ids
+/// `ids`, for resources whose group memberships changed, so that filtering on
+/// `meta.lastModified` agrees with the delta endpoints, and `If-Match` sees
+/// the change.
+fn touch(
+    conn: &Connection,
+    table: &str,
+    ids: impl IntoIterator<Item = impl ToSql>,
+) -> rusqlite::Result<()> {
+    let now = Utc::now();
+    let mut stmt = conn.prepare_cached(&format!(
+        "UPDATE {table} SET last_modified = ?2, version = ?3 WHERE id = ?1"
+    ))?;
+    for id in ids {
+        stmt.execute(params![id, now, new_version()])?;
+    }
+    Ok(())
+}
+
+/// Validate a member from a group request, returning the id of the User it
+/// refers to.
+fn validate_group_member(
+    conn: &Connection,
+    member: &GroupMember,
+) -> Result<String, ProviderStoreError> {
+    let GroupMember { resource_type, value } = member;
+
+    let Some(value) = value else {
+        // The minimum that this code needs is the value field so complain
+        // about that.
+        return Err(Error::invalid_syntax(String::from(
+            "group member missing value field",
+        ))
+        .into());
+    };
+
+    let exists = |table: &str| -> rusqlite::Result<bool> {
+        conn.query_row(
+            &format!("SELECT EXISTS (SELECT 1 FROM {table} WHERE id = ?1)"),
+            [value],
+            |row| row.get(0),
+        )
+    };
+
+    let resource_type = match resource_type {
+        Some(resource_type) => Some(
+            ResourceType::from_str(resource_type)
+                .map_err(Error::invalid_syntax)?,
+        ),
+        None => None,
+    };
+
+    match resource_type {
+        Some(ResourceType::Group) => {
+            // don't support nested groups for now.
+            Err(Error::internal_error(
+                "nested groups not supported".to_string(),
+            )
+            .into())
+        }
+
+        Some(ResourceType::User) | None if exists("scim_users")? => {
+            Ok(value.clone())
+        }
+
+        None if exists("scim_groups")? => Err(Error::internal_error(
+            "nested groups not supported".to_string(),
+        )
+        .into()),
+
+        Some(ResourceType::User) | None => {
+            Err(Error::not_found(value.clone()).into())
+        }
+    }
+}
+
+/// Replace the membership of a group with `members`, bumping the users who
+/// joined or left.
+fn set_group_members(
+    conn: &Connection,
+    group_id: &str,
+    members: Option<&IdOrdMap<GroupMember>>,
+) -> Result<(), ProviderStoreError> {
+    let removed = group_member_ids(conn, group_id)?;
+    conn.execute(
+        "DELETE FROM scim_group_members WHERE group_id = ?1",
+        [group_id],
+    )?;
+
+    let mut added = BTreeSet::new();
+    for member in members.into_iter().flatten() {
+        let user_id = validate_group_member(conn, member)?;
+        conn.execute(
+            "INSERT OR IGNORE INTO scim_group_members (group_id, user_id)
+            VALUES (?1, ?2)",
+            [group_id, &user_id],
+        )?;
+        added.insert(user_id);
+    }
+
+    touch(conn, "scim_users", removed.symmetric_difference(&added))?;
+
+    Ok(())
+}
+
+/// Run a paginated list query against `table`, returning the requested page
+/// of rows that match `filter` and the total number of matches.
+fn list_page<T>(
+    conn: &Connection,
+    table: &str,
+    columns: &str,
+    filter: Option<(Filter, &SqlColumnMap)>,
+    pagination: Pagination,
+    from_row: fn(&Row<'_>) -> rusqlite::Result<T>,
+) -> Result<(Vec<T>, usize), ProviderStoreError> {
+    let (where_clause, mut values) = match filter {
+        Some((filter, filter_columns)) => {
+            let SqlFragment { sql, values } = SqlFragment::from_filter(
+                &filter,
+                filter_columns,
+                SqlPlaceholder::Question,
+                1,
+            )?;
+            (format!("WHERE {sql}"), values)
+        }
+
+        None => (String::new(), vec![]),
+    };
+
+    let total_results: i64 = conn.query_row(
+        &format!("SELECT COUNT(*) FROM {table} {where_clause}"),
+        params_from_iter(&values),
+        |row| row.get(0),
+    )?;
+
+    // A negative LIMIT means no limit in SQLite.
+    let limit = pagination
+        .count
+        .map(|count| i64::try_from(count).unwrap_or(i64::MAX))
+        .unwrap_or(-1);
+    let offset = i64::try_from(pagination.offset()).unwrap_or(i64::MAX);
+
+    values.push(SqlValue::Integer(limit));
+    let limit_index = values.len();
+    values.push(SqlValue::Integer(offset));
+    let offset_index = values.len();
+
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {columns} FROM {table} {where_clause}
+        ORDER BY id
+        LIMIT ?{limit_index} OFFSET ?{offset_index}"
+    ))?;
+
+    let resources = stmt
+        .query_map(params_from_iter(&values), from_row)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok((resources, total_results as usize))
+}
+
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let tx = conn.transaction()?;
+
+    let applied: usize = tx
+        .pragma_query_value(None, "user_version", |row| row.get(0))
+        .context("reading schema version")?;
+
+    if applied > MIGRATIONS.len() {
+        bail!(
+            "database schema version {applied} is newer than the latest \
+            supported version {}",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+        tx.execute_batch(migration)
+            .with_context(|| format!("applying migration {}", index + 1))?;
+    }
+
+    tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// What is recorded along with each change
+#[derive(Debug, Clone, Copy, Default)]
+struct Recorder {
+    /// Whether change events are recorded in the outbox table
+    outbox: bool,
+
+    /// Whether revisions of users and groups are recorded
+    history: bool,
+}
+
+impl Recorder {
+    fn record(
+        &self,
+        conn: &Connection,
+        changes: Vec<ChangeEventKind>,
+    ) -> Result<(), ProviderStoreError> {
+        insert_change_marks(conn, &changes)?;
+        if self.outbox { insert_outbox(conn, changes) } else { Ok(()) }
+    }
+
+    fn record_revision<R: Resource + Serialize>(
+        &self,
+        conn: &Connection,
+        id: &str,
+        stored: Option<&StoredParts<R>>,
+    ) -> Result<(), ProviderStoreError> {
+        if self.history { insert_revision(conn, id, stored) } else { Ok(()) }
+    }
+
+    /// Record a revision of each of the groups `group_ids`, whose members
+    /// changed along with a user
+    fn record_group_revisions(
+        &self,
+        conn: &Connection,
+        group_ids: &[String],
+    ) -> Result<(), ProviderStoreError> {
+        if !self.history {
+            return Ok(());
+        }
+
+        for group_id in group_ids {
+            if let Some(group) = get_group(conn, group_id)? {
+                insert_revision(conn, group_id, Some(&group))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A provider store backed by a SQLite database
+///
+/// The store has a single connection, which one operation uses at a time.
+/// rusqlite's calls block, on the database and on waiting for the
+/// connection, so each operation runs as a whole on tokio's blocking thread
+/// pool rather than on the async runtime's workers. An operation that
+/// changes anything does so in one transaction.
+pub struct SqliteProviderStore {
+    conn: Arc<Mutex<Connection>>,
+    recorder: Recorder,
+}
+
+impl SqliteProviderStore {
+    /// Open (or create) the database at `path`, migrating its schema to the
+    /// latest version.
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let conn = Connection::open(path)
+            .with_context(|| format!("opening {}", path.display()))?;
+        Self::from_connection(conn)
+    }
+
+    /// Create a store with a new, empty, in-memory database.
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    fn from_connection(mut conn: Connection) -> anyhow::Result<Self> {
+        // Group memberships rely on cascading deletes.
+        conn.pragma_update(None, "foreign_keys", true)?;
+
+        // Names are unique, and compared, without regard to case, folded the
+        // same way as by the in-memory store.
+        conn.create_collation("UNICASE", |a, b| {
+            UniCase::new(a).cmp(&UniCase::new(b))
+        })?;
+        migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+            recorder: Recorder::default(),
+        })
+    }
+
+    /// Record change events in the outbox table, in the same transaction as
+    /// the change they describe.
+    pub fn with_outbox(mut self) -> Self {
+        self.recorder.outbox = true;
+        self
+    }
+
+    /// Record a revision of every user and group in the revisions table, in
+    /// the same transaction as the change.
+    pub fn with_history(mut self) -> Self {
+        self.recorder.history = true;
+        self
+    }
+
+    /// Run `f` with the connection on the blocking thread pool
+    async fn run<T, F>(&self, f: F) -> Result<T, ProviderStoreError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection, Recorder) -> Result<T, ProviderStoreError>
+            + Send
+            + 'static,
+    {
+        let conn = Arc::clone(&self.conn);
+        let recorder = self.recorder;
+
+        tokio::task::spawn_blocking(move || {
+            f(&mut conn.lock().unwrap(), recorder)
+        })
+        .await
+        .context("running a database task")
+        .map_err(ProviderStoreError::StoreError)?
+    }
+}
+
+impl ProviderStore for SqliteProviderStore {
+    const VERSIONED: bool = true;
+
+    async fn get_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, _| Ok(get_user(conn, &user_id)?)).await
+    }
+
+    async fn create_user(
+        &self,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let new_user = StoredParts {
+                resource: User {
+                    id: Uuid::new_v4().to_string(),
+                    name: user_request.name,
+                    external_id: user_request.external_id,
+                    active: user_request.active,
+                    groups: None,
+                },
+
+                meta: StoredMeta::now(),
+            };
+
+            insert_user(&tx, &new_user)?;
+            recorder
+                .record(&tx, user_changes(None, Some(&new_user.resource)))?;
+            recorder.record_revision(
+                &tx,
+                &new_user.resource.id,
+                Some(&new_user),
+            )?;
+            tx.commit()?;
+
+            Ok(new_user)
+        })
+        .await
+    }
+
+    async fn list_users(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+        self.run(move |conn, _| {
+            let (mut resources, total_results) = list_page(
+                conn,
+                "scim_users",
+                USER_COLUMNS,
+                filter.map(|filter| (filter, &*USER_FILTER_COLUMNS)),
+                pagination,
+                user_from_row,
+            )?;
+
+            for user in &mut resources {
+                load_user_groups(conn, &mut user.resource)?;
+            }
+
+            Ok(ProviderStoreListResult { resources, total_results })
+        })
+        .await
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(before) = get_user(&tx, &user_id)? else {
+                return Err(Error::not_found(user_id.to_string()).into());
+            };
+
+            // RFC 7664 § 3.5.1: see the InMemoryProviderStore, this store
+            // takes the same stance of writing in exactly the fields that were
+            // asserted.
+            let result = tx.execute(
+                "UPDATE scim_users
+                SET user_name = ?2, external_id = ?3, active = ?4,
+                    last_modified = ?5, version = ?6
+                WHERE id = ?1",
+                params![
+                    &user_id,
+                    user_request.name,
+                    user_request.external_id,
+                    user_request.active,
+                    Utc::now(),
+                    new_version(),
+                ],
+            );
+
+            match result {
+                Ok(0) => {
+                    return Err(Error::not_found(user_id.to_string()).into());
+                }
+                Ok(_) => {}
+                Err(e) if is_unique_violation(&e) => {
+                    return Err(Error::conflict(format!(
+                        "username {}",
+                        user_request.name
+                    ))
+                    .into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            let user = get_user(&tx, &user_id)?
+                .context("user missing after update")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(
+                &tx,
+                user_changes(Some(&before.resource), Some(&user.resource)),
+            )?;
+            recorder.record_revision(&tx, &user_id, Some(&user))?;
+            tx.commit()?;
+
+            Ok(user)
+        })
+        .await
+    }
+
+    async fn delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(user) = get_user(&tx, &user_id)? else {
+                return Ok(ProviderStoreDeleteResult::NotFound);
+            };
+
+            // Group memberships are removed by the cascade, which modifies the
+            // groups.
+            let group_ids = member_group_ids(&tx, &user_id)?;
+            touch(&tx, "scim_groups", &group_ids)?;
+            tx.execute("DELETE FROM scim_users WHERE id = ?1", [&user_id])?;
+            recorder.record(&tx, user_changes(Some(&user.resource), None))?;
+            recorder.record_revision::<User>(&tx, &user_id, None)?;
+            recorder.record_group_revisions(&tx, &group_ids)?;
+            tx.commit()?;
+
+            Ok(ProviderStoreDeleteResult::Deleted)
+        })
+        .await
+    }
+
+    async fn get_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        let group_id = group_id.to_string();
+        self.run(move |conn, _| Ok(get_group(conn, &group_id)?)).await
+    }
+
+    async fn get_group_display_names(
+        &self,
+        group_ids: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, String>, ProviderStoreError> {
+        let group_ids = serde_json::to_string(group_ids)
+            .context("serializing group ids")
+            .map_err(ProviderStoreError::StoreError)?;
+
+        self.run(move |conn, _| {
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, display_name FROM scim_groups
+                WHERE id IN (SELECT value FROM json_each(?1))",
+            )?;
+            let display_names = stmt
+                .query_map([group_ids], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<rusqlite::Result<_>>()?;
+
+            Ok(display_names)
+        })
+        .await
+    }
+
+    async fn deactivate_user(
+        &self,
+        user_id: &str,
+    ) -> Result<DeactivatedUser, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(before) = get_user(&tx, &user_id)? else {
+                return Err(Error::not_found(user_id.to_string()).into());
+            };
+
+            let group_ids = member_group_ids(&tx, &user_id)?;
+
+            let mut groups = Vec::new();
+            for group_id in group_ids {
+                let Some(before) = get_group(&tx, &group_id)? else {
+                    continue;
+                };
+
+                tx.execute(
+                    "DELETE FROM scim_group_members
+                    WHERE group_id = ?1 AND user_id = ?2",
+                    params![group_id, &user_id],
+                )?;
+                touch(&tx, "scim_groups", [&group_id])?;
+
+                let after = get_group(&tx, &group_id)?
+                    .context("group missing after update")
+                    .map_err(ProviderStoreError::StoreError)?;
+
+                recorder.record(
+                    &tx,
+                    group_changes(
+                        Some(&before.resource),
+                        Some(&after.resource),
+                    ),
+                )?;
+                recorder.record_revision(&tx, &group_id, Some(&after))?;
+                groups.push(ChangedGroup { before, after });
+            }
+
+            tx.execute(
+                "UPDATE scim_users
+                SET active = ?2, last_modified = ?3, version = ?4
+                WHERE id = ?1",
+                params![&user_id, false, Utc::now(), new_version()],
+            )?;
+
+            let after = get_user(&tx, &user_id)?
+                .context("user missing after update")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(
+                &tx,
+                user_changes(Some(&before.resource), Some(&after.resource)),
+            )?;
+            recorder.record_revision(&tx, &user_id, Some(&after))?;
+            tx.commit()?;
+
+            Ok(DeactivatedUser { before, after, groups })
+        })
+        .await
+    }
+
+    async fn create_group(
+        &self,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let CreateGroupRequest { display_name, external_id, members } =
+                group_request;
+
+            let id = Uuid::new_v4().to_string();
+
+            insert_group(
+                &tx,
+                &StoredParts {
+                    resource: Group {
+                        id: id.clone(),
+                        display_name,
+                        external_id,
+                        members: None,
+                    },
+                    meta: StoredMeta::now(),
+                },
+            )?;
+
+            set_group_members(&tx, &id, members.as_ref())?;
+
+            let group = get_group(&tx, &id)?
+                .context("group missing after insert")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(&tx, group_changes(None, Some(&group.resource)))?;
+            recorder.record_revision(&tx, &id, Some(&group))?;
+            tx.commit()?;
+
+            Ok(group)
+        })
+        .await
+    }
+
+    async fn list_groups(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError> {
+        self.run(move |conn, _| {
+            let (mut resources, total_results) = list_page(
+                conn,
+                "scim_groups",
+                GROUP_COLUMNS,
+                filter.map(|filter| (filter, &*GROUP_FILTER_COLUMNS)),
+                pagination,
+                group_from_row,
+            )?;
+
+            for group in &mut resources {
+                load_group_members(conn, &mut group.resource)?;
+            }
+
+            Ok(ProviderStoreListResult { resources, total_results })
+        })
+        .await
+    }
+
+    async fn replace_group(
+        &self,
+        group_id: &str,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        let group_id = group_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let CreateGroupRequest { display_name, external_id, members } =
+                group_request;
+
+            let Some(before) = get_group(&tx, &group_id)? else {
+                return Err(Error::not_found(group_id.to_string()).into());
+            };
+
+            let result = tx.execute(
+                "UPDATE scim_groups
+                SET display_name = ?2, external_id = ?3, last_modified = ?4,
+                    version = ?5
+                WHERE id = ?1",
+                params![
+                    &group_id,
+                    display_name,
+                    external_id,
+                    Utc::now(),
+                    new_version(),
+                ],
+            );
+
+            match result {
+                Ok(0) => {
+                    return Err(Error::not_found(group_id.to_string()).into());
+                }
+                Ok(_) => {}
+                Err(e) if is_unique_violation(&e) => {
+                    return Err(Error::conflict(format!(
+                        "displayName {display_name}"
+                    ))
+                    .into());
+                }
+                Err(e) => return Err(e.into()),
+            }
+
+            set_group_members(&tx, &group_id, members.as_ref())?;
+
+            let group = get_group(&tx, &group_id)?
+                .context("group missing after update")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(
+                &tx,
+                group_changes(Some(&before.resource), Some(&group.resource)),
+            )?;
+            recorder.record_revision(&tx, &group_id, Some(&group))?;
+            tx.commit()?;
+
+            Ok(group)
+        })
+        .await
+    }
+
+    async fn delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let group_id = group_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(group) = get_group(&tx, &group_id)? else {
+                return Ok(ProviderStoreDeleteResult::NotFound);
+            };
+
+            // Group memberships are removed by the cascade, which modifies the
+            // users.
+            touch(&tx, "scim_users", &group_member_ids(&tx, &group_id)?)?;
+            tx.execute("DELETE FROM scim_groups WHERE id = ?1", [&group_id])?;
+            recorder.record(&tx, group_changes(Some(&group.resource), None))?;
+            recorder.record_revision::<Group>(&tx, &group_id, None)?;
+            tx.commit()?;
+
+            Ok(ProviderStoreDeleteResult::Deleted)
+        })
+        .await
+    }
+
+    async fn soft_delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(user) = get_user(&tx, &user_id)? else {
+                return Ok(ProviderStoreDeleteResult::NotFound);
+            };
+
+            insert_deleted(&tx, &user)?;
+            let group_ids = member_group_ids(&tx, &user_id)?;
+            touch(&tx, "scim_groups", &group_ids)?;
+            tx.execute("DELETE FROM scim_users WHERE id = ?1", [&user_id])?;
+            recorder.record(&tx, user_changes(Some(&user.resource), None))?;
+            recorder.record_revision::<User>(&tx, &user_id, None)?;
+            recorder.record_group_revisions(&tx, &group_ids)?;
+            tx.commit()?;
+
+            Ok(ProviderStoreDeleteResult::Deleted)
+        })
+        .await
+    }
+
+    async fn soft_delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        let group_id = group_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(group) = get_group(&tx, &group_id)? else {
+                return Ok(ProviderStoreDeleteResult::NotFound);
+            };
+
+            insert_deleted(&tx, &group)?;
+            touch(&tx, "scim_users", &group_member_ids(&tx, &group_id)?)?;
+            tx.execute("DELETE FROM scim_groups WHERE id = ?1", [&group_id])?;
+            recorder.record(&tx, group_changes(Some(&group.resource), None))?;
+            recorder.record_revision::<Group>(&tx, &group_id, None)?;
+            tx.commit()?;
+
+            Ok(ProviderStoreDeleteResult::Deleted)
+        })
+        .await
+    }
+
+    async fn list_deleted_users(
+        &self,
+    ) -> Result<Vec<DeletedResource<User>>, ProviderStoreError> {
+        self.run(move |conn, _| list_deleted(conn)).await
+    }
+
+    async fn list_deleted_groups(
+        &self,
+    ) -> Result<Vec<DeletedResource<Group>>, ProviderStoreError> {
+        self.run(move |conn, _| list_deleted(conn)).await
+    }
+
+    async fn restore_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        let user_id = user_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(mut user) = take_deleted::<User>(&tx, &user_id)? else {
+                return Ok(None);
+            };
+
+            user.meta.touch();
+            insert_user(&tx, &user)?;
+
+            // Rejoin the groups that still exist
+            for group in user.resource.groups.iter().flatten() {
+                tx.execute(
+                "INSERT OR IGNORE INTO scim_group_members (group_id, user_id)
+                    SELECT id, ?2 FROM scim_groups WHERE id = ?1",
+                params![group.value, &user_id],
+            )?;
+            }
+
+            let group_ids = member_group_ids(&tx, &user_id)?;
+            touch(&tx, "scim_groups", &group_ids)?;
+
+            let user = get_user(&tx, &user_id)?
+                .context("user missing after restore")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(&tx, user_changes(None, Some(&user.resource)))?;
+            recorder.record_revision(&tx, &user_id, Some(&user))?;
+            recorder.record_group_revisions(&tx, &group_ids)?;
+            tx.commit()?;
+
+            Ok(Some(user))
+        })
+        .await
+    }
+
+    async fn restore_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        let group_id = group_id.to_string();
+        self.run(move |conn, recorder| {
+            let tx = conn.transaction()?;
+
+            let Some(mut group) = take_deleted::<Group>(&tx, &group_id)? else {
+                return Ok(None);
+            };
+
+            group.meta.touch();
+            insert_group(&tx, &group)?;
+
+            // Bring back the members that still exist
+            for member in group.resource.members.iter().flatten() {
+                tx.execute(
+                "INSERT OR IGNORE INTO scim_group_members (group_id, user_id)
+                    SELECT ?1, id FROM scim_users WHERE id = ?2",
+                params![&group_id, member.value],
+            )?;
+            }
+            touch(&tx, "scim_users", &group_member_ids(&tx, &group_id)?)?;
+
+            let group = get_group(&tx, &group_id)?
+                .context("group missing after restore")
+                .map_err(ProviderStoreError::StoreError)?;
+
+            recorder.record(&tx, group_changes(None, Some(&group.resource)))?;
+            recorder.record_revision(&tx, &group_id, Some(&group))?;
+            tx.commit()?;
+
+            Ok(Some(group))
+        })
+        .await
+    }
+
+    async fn purge_deleted(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<usize, ProviderStoreError> {
+        self.run(move |conn, _| {
+            let purged = conn.execute(
+                "DELETE FROM scim_deleted_resources WHERE deleted_at < ?1",
+                [deleted_before],
+            )?;
+
+            Ok(purged)
+        })
+        .await
+    }
+
+    async fn read_outbox(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        self.run(move |conn, _| {
+            let mut stmt = conn.prepare(
+                "SELECT sequence, event FROM scim_outbox
+                ORDER BY sequence
+                LIMIT ?1",
+            )?;
+
+            let rows = stmt
+                .query_map([limit], |row| {
+                    Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            rows.into_iter()
+                .map(|(sequence, event)| {
+                    let event = serde_json::from_str(&event)
+                        .context("parsing change event")
+                        .map_err(ProviderStoreError::StoreError)?;
+                    Ok(OutboxEntry { sequence, event })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn ack_outbox(
+        &self,
+        sequence: u64,
+    ) -> Result<usize, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        self.run(move |conn, _| {
+            let acked = conn.execute(
+                "DELETE FROM scim_outbox WHERE sequence <= ?1",
+                [sequence],
+            )?;
+
+            Ok(acked)
+        })
+        .await
+    }
+
+    async fn read_outbox_after(
+        &self,
+        sequence: u64,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        self.run(move |conn, _| {
+            let mut stmt = conn.prepare(
+                "SELECT sequence, event FROM scim_outbox
+                WHERE sequence > ?1
+                ORDER BY sequence
+                LIMIT ?2",
+            )?;
+
+            let rows = stmt
+                .query_map(params![sequence, limit], |row| {
+                    Ok((row.get::<_, u64>(0)?, row.get::<_, String>(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            rows.into_iter()
+                .map(|(sequence, event)| {
+                    let event = serde_json::from_str(&event)
+                        .context("parsing change event")
+                        .map_err(ProviderStoreError::StoreError)?;
+                    Ok(OutboxEntry { sequence, event })
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn outbox_cursor(
+        &self,
+        consumer: &str,
+    ) -> Result<u64, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        let consumer = consumer.to_string();
+        self.run(move |conn, _| {
+            let sequence = conn
+            .query_row(
+                "SELECT sequence FROM scim_outbox_cursors WHERE consumer = ?1",
+                [&consumer],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+            Ok(sequence.unwrap_or(0))
+        })
+        .await
+    }
+
+    async fn set_outbox_cursor(
+        &self,
+        consumer: &str,
+        sequence: u64,
+    ) -> Result<(), ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        let consumer = consumer.to_string();
+        self.run(move |conn, _| {
+            conn.execute(
+            "INSERT OR REPLACE INTO scim_outbox_cursors (consumer, sequence)
+                VALUES (?1, ?2)",
+            params![&consumer, sequence],
+        )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn put_dead_letter(
+        &self,
+        dead_letter: DeadLetter,
+    ) -> Result<(), ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        let json = serde_json::to_string(&dead_letter)
+            .context("serializing dead letter")
+            .map_err(ProviderStoreError::StoreError)?;
+
+        self.run(move |conn, _| {
+            conn.execute(
+                "INSERT OR REPLACE INTO scim_dead_letters
+                    (endpoint, sequence, dead_letter)
+                VALUES (?1, ?2, ?3)",
+                params![dead_letter.endpoint, dead_letter.sequence, json],
+            )?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list_dead_letters(
+        &self,
+    ) -> Result<Vec<DeadLetter>, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        self.run(move |conn, _| {
+            let mut stmt = conn.prepare(
+                "SELECT dead_letter FROM scim_dead_letters
+                ORDER BY endpoint, sequence",
+            )?;
+
+            let rows = stmt
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+
+            rows.iter()
+                .map(|json| {
+                    serde_json::from_str(json)
+                        .context("parsing dead letter")
+                        .map_err(ProviderStoreError::StoreError)
+                })
+                .collect()
+        })
+        .await
+    }
+
+    async fn remove_dead_letter(
+        &self,
+        endpoint: &str,
+        sequence: u64,
+    ) -> Result<bool, ProviderStoreError> {
+        if !self.recorder.outbox {
+            return Err(outbox_not_implemented());
+        }
+
+        let endpoint = endpoint.to_string();
+        self.run(move |conn, _| {
+        let removed = conn.execute(
+            "DELETE FROM scim_dead_letters WHERE endpoint = ?1 AND sequence = ?2",
+            params![&endpoint, sequence],
+        )?;
+
+        Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn list_user_revisions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Revision<User>>, ProviderStoreError> {
+        if !self.recorder.history {
+            return Err(history_not_implemented());
+        }
+
+        let user_id = user_id.to_string();
+        self.run(move |conn, _| list_revisions(conn, &user_id)).await
+    }
+
+    async fn list_group_revisions(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<Revision<Group>>, ProviderStoreError> {
+        if !self.recorder.history {
+            return Err(history_not_implemented());
+        }
+
+        let group_id = group_id.to_string();
+        self.run(move |conn, _| list_revisions(conn, &group_id)).await
+    }
+
+    async fn get_user_at(
+        &self,
+        user_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        if !self.recorder.history {
+            return Err(history_not_implemented());
+        }
+
+        let user_id = user_id.to_string();
+        self.run(move |conn, _| {
+            let Some(user) = revision_as_of::<User>(conn, &user_id, at)? else {
+                return Ok(None);
+            };
+
+            let groups = revisions_as_of::<Group>(conn, at)?;
+
+            Ok(Some(user_as_of(user, &groups)))
+        })
+        .await
+    }
+
+    async fn get_group_at(
+        &self,
+        group_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        if !self.recorder.history {
+            return Err(history_not_implemented());
+        }
+
+        let group_id = group_id.to_string();
+        self.run(move |conn, _| {
+            let Some(group) = revision_as_of::<Group>(conn, &group_id, at)?
+            else {
+                return Ok(None);
+            };
+
+            let member_ids = group
+                .resource
+                .members
+                .iter()
+                .flatten()
+                .filter_map(|member| member.value.clone())
+                .collect();
+            let users = existed_as_of::<User>(conn, &member_ids, at)?;
+
+            Ok(Some(group_as_of(group, |user_id| users.contains(user_id))))
+        })
+        .await
+    }
+
+    async fn user_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        self.run(move |conn, _| {
+            query_delta(conn, ResourceType::User, "scim_users", since)
+        })
+        .await
+    }
+
+    async fn group_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        self.run(move |conn, _| {
+            query_delta(conn, ResourceType::Group, "scim_groups", since)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rusqlite::params_from_iter;
+
+    use super::{
+        GROUP_FILTER_COLUMNS, SqliteProviderStore, USER_COLUMNS,
+        USER_FILTER_COLUMNS, list_page, user_from_row,
+    };
+    use crate::{
+        CreateUserRequest, Filter, Pagination, ProviderStore, SqlFragment,
+        SqlPlaceholder,
+    };
+
+    #[tokio::test]
+    async fn test_reopen_database() {
+        let path = std::env::temp_dir()
+            .join(format!("scim2-rs-{}.sqlite", uuid::Uuid::new_v4()));
+
+        let store = SqliteProviderStore::open(&path).unwrap();
+        let user = store
+            .create_user(CreateUserRequest {
+                name: String::from("kmalone"),
+                active: Some(true),
+                external_id: None,
+                groups: None,
+            })
+            .await
+            .unwrap();
+        drop(store);
+
+        // Reopening runs the migrations again, which must not disturb what is
+        // already there.
+        let store = SqliteProviderStore::open(&path).unwrap();
+        let users =
+            store.list_users(None, Pagination::default()).await.unwrap();
+        assert_eq!(users.total_results, 1);
+        assert_eq!(users.resources[0].resource, user.resource);
+
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_filter_translation() {
+        let store = SqliteProviderStore::open_in_memory().unwrap();
+        for name in ["50%off", "50xoff", "jim_halpert", "JIMXHALPERT"] {
+            store
+                .create_user(CreateUserRequest {
+                    name: name.to_string(),
+                    active: Some(name.starts_with("50")),
+                    external_id: None,
+                    groups: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let conn = store.conn.lock().unwrap();
+        let list = |filter: &str| {
+            let (users, total) = list_page(
+                &conn,
+                "scim_users",
+                USER_COLUMNS,
+                Some((Filter::parse(filter).unwrap(), &*USER_FILTER_COLUMNS)),
+                Pagination::default(),
+                user_from_row,
+            )
+            .unwrap();
+            assert_eq!(users.len(), total);
+
+            let mut names: Vec<_> =
+                users.into_iter().map(|user| user.resource.name).collect();
+            names.sort();
+            names
+        };
+
+        // LIKE wildcards in the value are matched literally
+        assert_eq!(list(r#"userName sw "50%""#), ["50%off"]);
+        assert_eq!(list(r#"userName co "_h""#), ["jim_halpert"]);
+        assert_eq!(
+            list(r#"userName ew "HALPERT""#),
+            ["JIMXHALPERT", "jim_halpert"]
+        );
+
+        assert_eq!(list(r#"userName eq "JIM_HALPERT""#), ["jim_halpert"]);
+        assert_eq!(
+            list(r#"not (active eq true) and externalId pr"#),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            list(r#"active eq false or userName eq "50xoff""#),
+            ["50xoff", "JIMXHALPERT", "jim_halpert"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_case_exact_filters() {
+        let store = SqliteProviderStore::open_in_memory().unwrap();
+        let user = store
+            .create_user(CreateUserRequest {
+                name: String::from("jhalpert"),
+                active: Some(true),
+                external_id: None,
+                groups: None,
+            })
+            .await
+            .unwrap();
+        let id = user.resource.id;
+
+        let conn = store.conn.lock().unwrap();
+        let count = |filter: String| {
+            let (_, total) = list_page(
+                &conn,
+                "scim_users",
+                USER_COLUMNS,
+                Some((Filter::parse(&filter).unwrap(), &*USER_FILTER_COLUMNS)),
+                Pagination::default(),
+                user_from_row,
+            )
+            .unwrap();
+            total
+        };
+
+        // ids are caseExact, so a value where only the letter case differs
+        // matches nothing
+        let upper = id.to_uppercase();
+        assert_ne!(upper, id);
+        for op in ["eq", "co", "sw", "ew"] {
+            assert_eq!(count(format!(r#"id {op} "{id}""#)), 1, "{op}");
+            assert_eq!(count(format!(r#"id {op} "{upper}""#)), 0, "{op}");
+        }
+
+        assert_eq!(count(format!(r#"id ew "x{id}""#)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_filters_use_indexes() {
+        let store = SqliteProviderStore::open_in_memory().unwrap();
+        let conn = store.conn.lock().unwrap();
+
+        for (table, filter_columns, filter, index) in [
+            (
+                "scim_users",
+                &*USER_FILTER_COLUMNS,
+                r#"userName eq "Jim""#,
+                "scim_users_user_name",
+            ),
+            (
+                "scim_users",
+                &*USER_FILTER_COLUMNS,
+                r#"externalId eq "Jim""#,
+                "scim_users_external_id",
+            ),
+            (
+                "scim_groups",
+                &*GROUP_FILTER_COLUMNS,
+                r#"displayName eq "Sales""#,
+                "scim_groups_display_name",
+            ),
+            (
+                "scim_groups",
+                &*GROUP_FILTER_COLUMNS,
+                r#"externalId eq "Sales""#,
+                "scim_groups_external_id",
+            ),
+        ] {
+            let SqlFragment { sql, values } = SqlFragment::from_filter(
+                &Filter::parse(filter).unwrap(),
+                filter_columns,
+                SqlPlaceholder::Question,
+                1,
+            )
+            .unwrap();
+
+            let mut stmt = conn
+                .prepare(&format!(
+                    "EXPLAIN QUERY PLAN SELECT * FROM {table} WHERE {sql}"
+                ))
+                .unwrap();
+            let plan = stmt
+                .query_map(params_from_iter(&values), |row| {
+                    row.get::<_, String>(3)
+                })
+                .unwrap()
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap()
+                .join("\n");
+
+            assert!(plan.contains(&format!("USING INDEX {index}")), "{plan}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        crate::store_conformance::run(|| async {
+            SqliteProviderStore::open_in_memory().unwrap()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_soft_delete_conformance() {
+        crate::store_conformance::run_soft_delete(|| async {
+            SqliteProviderStore::open_in_memory().unwrap()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_outbox_conformance() {
+        crate::store_conformance::run_outbox(|| async {
+            SqliteProviderStore::open_in_memory().unwrap().with_outbox()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_conformance() {
+        crate::store_conformance::run_history(|| async {
+            SqliteProviderStore::open_in_memory().unwrap().with_history()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_history_soft_delete_conformance() {
+        crate::store_conformance::run_history_soft_delete(|| async {
+            SqliteProviderStore::open_in_memory().unwrap().with_history()
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_delta_conformance() {
+        crate::store_conformance::run_delta(|| async {
+            SqliteProviderStore::open_in_memory().unwrap()
+        })
+        .await
+        .unwrap();
+    }
+}
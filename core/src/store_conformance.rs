@@ -0,0 +1,2185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A conformance suite for [`ProviderStore`] implementations.
+//!
+//! [`run`] exercises a store directly (no HTTP involved) and checks that it
+//! behaves the way `Provider` expects, using the in-memory store as the
+//! reference. Call it from a test in the crate that implements the store:
+//!
+//! ```ignore
+//! #[tokio::test]
+//! async fn test_conformance() {
+//!     scim2_rs::store_conformance::run(|| async { MyStore::new().await })
+//!         .await
+//!         .unwrap();
+//! }
+//! ```
+//!
+//! Stores that support soft delete should also pass [`run_soft_delete`],
+//! stores with an outbox [`run_outbox`], stores that keep history
+//! [`run_history`] (and [`run_history_soft_delete`] if they support soft
+//! delete too), and stores with deltas [`run_delta`].
+//!
+//! For stores that set [`ProviderStore::VERSIONED`], [`run`] also checks that
+//! every change gives a resource a new version.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+
+use anyhow::{Context, bail, ensure};
+use chrono::{DateTime, TimeDelta, Utc};
+use http::StatusCode;
+
+use crate::{
+    ChangeEventKind, ChangedGroup, CreateGroupRequest, CreateUserRequest,
+    DeactivatedUser, DeadLetter, Error, ErrorType, Filter, Group, GroupMember,
+    Pagination, ProviderStore, ProviderStoreDeleteResult, ProviderStoreError,
+    StoredParts,
+};
+
+/// Run every conformance check, each against a fresh store from
+/// `new_store`, returning an error that describes the first failure.
+pub async fn run<S, F, Fut>(mut new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_user_uniqueness(&new_store().await)
+        .await
+        .context("user uniqueness")?;
+
+    check_group_uniqueness(&new_store().await)
+        .await
+        .context("group uniqueness")?;
+
+    check_not_found(&new_store().await).await.context("not found")?;
+
+    check_group_membership(&new_store().await)
+        .await
+        .context("group membership")?;
+
+    check_user_delete_cascade(&new_store().await)
+        .await
+        .context("user delete cascade")?;
+
+    check_group_rename(&new_store().await).await.context("group rename")?;
+
+    check_group_display_names(&new_store().await)
+        .await
+        .context("group display names")?;
+
+    check_deactivate_user(&new_store().await)
+        .await
+        .context("remove user from groups")?;
+
+    check_filters(&new_store().await).await.context("filters")?;
+
+    check_last_modified_filter(&new_store().await)
+        .await
+        .context("lastModified filter")?;
+
+    check_pagination(&new_store().await).await.context("pagination")?;
+
+    if S::VERSIONED {
+        check_versions(&new_store().await).await.context("versions")?;
+    }
+
+    Ok(())
+}
+
+/// Run the soft delete checks, each against a fresh store from `new_store`.
+pub async fn run_soft_delete<S, F, Fut>(mut new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_soft_delete_user(&new_store().await)
+        .await
+        .context("soft delete user")?;
+
+    check_soft_delete_group(&new_store().await)
+        .await
+        .context("soft delete group")?;
+
+    check_restore_conflict(&new_store().await)
+        .await
+        .context("restore conflict")?;
+
+    check_restore_without_memberships(&new_store().await)
+        .await
+        .context("restore without memberships")?;
+
+    check_purge(&new_store().await).await.context("purge")?;
+
+    Ok(())
+}
+
+/// Run the outbox checks, each against a fresh store with an outbox from
+/// `new_store`.
+pub async fn run_outbox<S, F, Fut>(mut new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_outbox_events(&new_store().await).await.context("outbox events")?;
+
+    check_outbox_ack(&new_store().await).await.context("outbox ack")?;
+
+    check_outbox_cursors(&new_store().await).await.context("outbox cursors")?;
+
+    check_dead_letters(&new_store().await).await.context("dead letters")?;
+
+    Ok(())
+}
+
+/// Run the history checks, each against a fresh store from `new_store`,
+/// which must keep history.
+pub async fn run_history<S, F, Fut>(mut new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_revisions(&new_store().await).await.context("revisions")?;
+
+    check_point_in_time(&new_store().await)
+        .await
+        .context("point in time reads")?;
+
+    Ok(())
+}
+
+/// Run the checks for history across soft deletes and restores, each against
+/// a fresh store from `new_store`, which must keep history and support soft
+/// delete.
+pub async fn run_history_soft_delete<S, F, Fut>(
+    mut new_store: F,
+) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_point_in_time_restore(&new_store().await)
+        .await
+        .context("point in time reads across a restore")?;
+
+    Ok(())
+}
+
+/// Run the delta checks against a fresh store from `new_store`.
+pub async fn run_delta<S, F, Fut>(mut new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = S>,
+{
+    check_delta(&new_store().await).await.context("delta")?;
+
+    Ok(())
+}
+
+fn user_request(name: &str, external_id: Option<&str>) -> CreateUserRequest {
+    CreateUserRequest {
+        name: name.to_string(),
+        active: Some(true),
+        external_id: external_id.map(str::to_string),
+        groups: None,
+    }
+}
+
+fn group_request(display_name: &str, members: &[&str]) -> CreateGroupRequest {
+    CreateGroupRequest {
+        display_name: display_name.to_string(),
+        external_id: None,
+        members: Some(
+            members
+                .iter()
+                .map(|id| GroupMember {
+                    resource_type: None,
+                    value: Some(id.to_string()),
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Expect `result` to have failed with a SCIM error with `status`.
+fn expect_error<T: Debug>(
+    result: Result<T, ProviderStoreError>,
+    status: StatusCode,
+) -> anyhow::Result<Error> {
+    match result {
+        Err(ProviderStoreError::Scim(error)) if error.status == status => {
+            Ok(error)
+        }
+        Err(ProviderStoreError::Scim(error)) => {
+            bail!("expected a {status} error, got {error:?}")
+        }
+        Err(ProviderStoreError::StoreError(error)) => {
+            bail!("expected a {status} error, got store error {error:#}")
+        }
+        Ok(value) => bail!("expected a {status} error, got {value:?}"),
+    }
+}
+
+fn store_error(error: ProviderStoreError) -> anyhow::Error {
+    match error {
+        ProviderStoreError::StoreError(error) => error,
+        ProviderStoreError::Scim(error) => {
+            anyhow::anyhow!("unexpected SCIM error {error:?}")
+        }
+    }
+}
+
+/// The ids of the users that are members of a group, as recorded by the
+/// group
+async fn group_member_ids<S: ProviderStore>(
+    store: &S,
+    group_id: &str,
+) -> anyhow::Result<BTreeSet<String>> {
+    let group = store
+        .get_group_by_id(group_id)
+        .await
+        .map_err(store_error)?
+        .with_context(|| format!("group {group_id} is missing"))?;
+
+    Ok(group
+        .resource
+        .members
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|member| member.value.clone())
+        .collect())
+}
+
+/// The ids and display names of the groups a user is in, as recorded by the
+/// user
+async fn user_group_ids<S: ProviderStore>(
+    store: &S,
+    user_id: &str,
+) -> anyhow::Result<BTreeSet<(String, String)>> {
+    let user = store
+        .get_user_by_id(user_id)
+        .await
+        .map_err(store_error)?
+        .with_context(|| format!("user {user_id} is missing"))?;
+
+    user.resource
+        .groups
+        .unwrap_or_default()
+        .into_iter()
+        .map(|group| match (group.value, group.display) {
+            (Some(value), Some(display)) => Ok((value, display)),
+            (value, display) => {
+                bail!("incomplete group {value:?} {display:?} in user")
+            }
+        })
+        .collect()
+}
+
+fn ids<const N: usize>(ids: [(&str, &str); N]) -> BTreeSet<(String, String)> {
+    ids.into_iter().map(|(a, b)| (a.to_string(), b.to_string())).collect()
+}
+
+async fn check_user_uniqueness<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?;
+
+    // userName is not caseExact, so uniqueness ignores case
+    let error = expect_error(
+        store.create_user(user_request("JHalpert", None)).await,
+        StatusCode::CONFLICT,
+    )?;
+    ensure!(
+        error.error_type == Some(ErrorType::Uniqueness),
+        "expected a uniqueness error, got {error:?}"
+    );
+
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?;
+
+    expect_error(
+        store
+            .replace_user(&dwight.resource.id, user_request("JHALPERT", None))
+            .await,
+        StatusCode::CONFLICT,
+    )?;
+
+    // Replacing a user with its own name is not a conflict
+    let replaced = store
+        .replace_user(&dwight.resource.id, user_request("DSchrute", None))
+        .await
+        .map_err(store_error)?;
+    ensure!(replaced.resource.name == "DSchrute", "user was not renamed");
+
+    Ok(())
+}
+
+async fn check_group_uniqueness<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    store
+        .create_group(group_request("Sales", &[]))
+        .await
+        .map_err(store_error)?;
+
+    let error = expect_error(
+        store.create_group(group_request("SALES", &[])).await,
+        StatusCode::CONFLICT,
+    )?;
+    ensure!(
+        error.error_type == Some(ErrorType::Uniqueness),
+        "expected a uniqueness error, got {error:?}"
+    );
+
+    let accounting = store
+        .create_group(group_request("Accounting", &[]))
+        .await
+        .map_err(store_error)?;
+
+    expect_error(
+        store
+            .replace_group(&accounting.resource.id, group_request("sales", &[]))
+            .await,
+        StatusCode::CONFLICT,
+    )?;
+
+    let replaced = store
+        .replace_group(
+            &accounting.resource.id,
+            group_request("accounting", &[]),
+        )
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        replaced.resource.display_name == "accounting",
+        "group was not renamed"
+    );
+
+    Ok(())
+}
+
+async fn check_not_found<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let missing = uuid::Uuid::new_v4().to_string();
+
+    ensure!(
+        store.get_user_by_id(&missing).await.map_err(store_error)?.is_none(),
+        "found a user that does not exist"
+    );
+    ensure!(
+        store.get_group_by_id(&missing).await.map_err(store_error)?.is_none(),
+        "found a group that does not exist"
+    );
+
+    expect_error(
+        store.replace_user(&missing, user_request("jhalpert", None)).await,
+        StatusCode::NOT_FOUND,
+    )?;
+    expect_error(
+        store.replace_group(&missing, group_request("Sales", &[])).await,
+        StatusCode::NOT_FOUND,
+    )?;
+
+    ensure!(
+        store.delete_user_by_id(&missing).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::NotFound,
+        "deleted a user that does not exist"
+    );
+    ensure!(
+        store.delete_group_by_id(&missing).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::NotFound,
+        "deleted a group that does not exist"
+    );
+
+    // A group cannot have members that do not exist
+    expect_error(
+        store.create_group(group_request("Sales", &[&missing])).await,
+        StatusCode::NOT_FOUND,
+    )?;
+
+    // None of the failures above may have left anything behind
+    let users = store
+        .list_users(None, Pagination::default())
+        .await
+        .map_err(store_error)?;
+    let groups = store
+        .list_groups(None, Pagination::default())
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        users.total_results == 0 && groups.total_results == 0,
+        "failed operations created resources"
+    );
+
+    Ok(())
+}
+
+async fn check_group_membership<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let mut user_ids = Vec::new();
+    for name in ["jhalpert", "dschrute", "pbeesly"] {
+        let user = store
+            .create_user(user_request(name, None))
+            .await
+            .map_err(store_error)?;
+        user_ids.push(user.resource.id);
+    }
+    let [jim, dwight, pam] = [&user_ids[0], &user_ids[1], &user_ids[2]];
+
+    let sales = store
+        .create_group(group_request("Sales", &[jim, dwight]))
+        .await
+        .map_err(store_error)?;
+    let sales_id = &sales.resource.id;
+
+    ensure!(
+        group_member_ids(store, sales_id).await?
+            == BTreeSet::from([jim.clone(), dwight.clone()]),
+        "created group has the wrong members"
+    );
+    ensure!(
+        user_group_ids(store, jim).await? == ids([(sales_id, "Sales")]),
+        "member of a created group does not list it"
+    );
+    ensure!(
+        user_group_ids(store, pam).await?.is_empty(),
+        "non-member lists a group"
+    );
+
+    // Replacing the members moves the group between users
+    store
+        .replace_group(sales_id, group_request("Sales", &[dwight, pam]))
+        .await
+        .map_err(store_error)?;
+
+    ensure!(
+        group_member_ids(store, sales_id).await?
+            == BTreeSet::from([dwight.clone(), pam.clone()]),
+        "replaced group has the wrong members"
+    );
+    ensure!(
+        user_group_ids(store, jim).await?.is_empty(),
+        "removed member still lists the group"
+    );
+    ensure!(
+        user_group_ids(store, pam).await? == ids([(sales_id, "Sales")]),
+        "added member does not list the group"
+    );
+
+    // A replace that fails must not change any memberships
+    let missing = uuid::Uuid::new_v4().to_string();
+    expect_error(
+        store
+            .replace_group(sales_id, group_request("Sales", &[jim, &missing]))
+            .await,
+        StatusCode::NOT_FOUND,
+    )?;
+
+    ensure!(
+        group_member_ids(store, sales_id).await?
+            == BTreeSet::from([dwight.clone(), pam.clone()]),
+        "failed replace changed the group's members"
+    );
+    ensure!(
+        user_group_ids(store, jim).await?.is_empty()
+            && user_group_ids(store, dwight).await?
+                == ids([(sales_id, "Sales")]),
+        "failed replace changed users' groups"
+    );
+
+    // Deleting the group removes it from every member
+    store.delete_group_by_id(sales_id).await.map_err(store_error)?;
+
+    for user_id in [jim, dwight, pam] {
+        ensure!(
+            user_group_ids(store, user_id).await?.is_empty(),
+            "user still lists a deleted group"
+        );
+    }
+
+    Ok(())
+}
+
+async fn check_user_delete_cascade<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let sales = store
+        .create_group(group_request("Sales", &[&jim, &dwight]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    ensure!(
+        store.delete_user_by_id(&jim).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::Deleted,
+        "user was not deleted"
+    );
+
+    ensure!(
+        store.get_user_by_id(&jim).await.map_err(store_error)?.is_none(),
+        "deleted user is still there"
+    );
+    ensure!(
+        group_member_ids(store, &sales).await? == BTreeSet::from([dwight]),
+        "deleted user is still a group member"
+    );
+
+    // The listed group must agree with the fetched one
+    let groups = store
+        .list_groups(None, Pagination::default())
+        .await
+        .map_err(store_error)?;
+    let members: BTreeSet<_> = groups
+        .resources
+        .iter()
+        .flat_map(|group| group.resource.members.iter().flatten())
+        .filter_map(|member| member.value.clone())
+        .collect();
+    ensure!(!members.contains(&jim), "deleted user is still listed in group");
+
+    Ok(())
+}
+
+async fn check_group_rename<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let accounting = store
+        .create_group(group_request("Accounting", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    // A user's groups[].display is the group's current displayName, no
+    // matter how the group was renamed.
+    store
+        .replace_group(&sales, group_request("Paper Sales", &[&jim]))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        user_group_ids(store, &jim).await?
+            == ids([(&sales, "Paper Sales"), (&accounting, "Accounting")]),
+        "user has a stale group display name after a rename"
+    );
+
+    let users = store
+        .list_users(None, Pagination::default())
+        .await
+        .map_err(store_error)?;
+    let displays: BTreeSet<_> = users
+        .resources
+        .iter()
+        .flat_map(|user| user.resource.groups.iter().flatten())
+        .filter_map(|group| group.display.clone())
+        .collect();
+    ensure!(
+        displays
+            == BTreeSet::from([
+                "Paper Sales".to_string(),
+                "Accounting".to_string()
+            ]),
+        "listed user has a stale group display name after a rename"
+    );
+
+    // Renaming one group must not disturb the user's other memberships, and
+    // renaming without changing members must still be seen.
+    store
+        .replace_group(&accounting, group_request("ACCOUNTING", &[&jim]))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        user_group_ids(store, &jim).await?
+            == ids([(&sales, "Paper Sales"), (&accounting, "ACCOUNTING")]),
+        "user has a stale group display name after a case-only rename"
+    );
+
+    Ok(())
+}
+
+async fn check_deactivate_user<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let pam = store
+        .create_user(user_request("pbeesly", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let sales = store
+        .create_group(group_request("Sales", &[&jim, &pam]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let reception = store
+        .create_group(group_request("Reception", &[&pam]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let pranks = store
+        .create_group(group_request("Pranks", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let DeactivatedUser { before, after, groups } =
+        store.deactivate_user(&jim).await.map_err(store_error)?;
+    ensure!(
+        before.resource.active == Some(true)
+            && before.resource.groups.iter().flatten().count() == 2,
+        "deactivated user before is wrong"
+    );
+    ensure!(
+        after.resource.active == Some(false)
+            && after.resource.groups.iter().flatten().count() == 0
+            && after.resource.name == before.resource.name,
+        "deactivated user after is wrong"
+    );
+    ensure!(
+        store
+            .get_user_by_id(&jim)
+            .await
+            .map_err(store_error)?
+            .is_some_and(|user| user.resource == after.resource),
+        "deactivated user was not stored"
+    );
+
+    let changed: BTreeMap<_, _> = groups
+        .into_iter()
+        .map(|ChangedGroup { before, after }| {
+            let members = |group: &StoredParts<Group>| -> BTreeSet<String> {
+                group
+                    .resource
+                    .members
+                    .iter()
+                    .flatten()
+                    .filter_map(|member| member.value.clone())
+                    .collect()
+            };
+            (after.resource.id.clone(), (members(&before), members(&after)))
+        })
+        .collect();
+    ensure!(
+        changed
+            == BTreeMap::from([
+                (
+                    sales.clone(),
+                    (
+                        BTreeSet::from([jim.clone(), pam.clone()]),
+                        BTreeSet::from([pam.clone()])
+                    )
+                ),
+                (
+                    pranks.clone(),
+                    (BTreeSet::from([jim.clone()]), BTreeSet::new())
+                ),
+            ]),
+        "wrong groups changed {changed:?}"
+    );
+
+    ensure!(
+        user_group_ids(store, &jim).await?.is_empty(),
+        "user is still in groups"
+    );
+    ensure!(
+        group_member_ids(store, &sales).await? == BTreeSet::from([pam.clone()]),
+        "group members wrong after removing a user"
+    );
+    ensure!(
+        group_member_ids(store, &pranks).await?.is_empty(),
+        "group members wrong after removing a user"
+    );
+    ensure!(
+        user_group_ids(store, &pam).await?
+            == ids([(&sales, "Sales"), (&reception, "Reception")]),
+        "another user's groups changed"
+    );
+
+    // Deactivating again changes no groups.
+    let DeactivatedUser { after, groups, .. } =
+        store.deactivate_user(&jim).await.map_err(store_error)?;
+    ensure!(groups.is_empty(), "groups changed for a user in none");
+    ensure!(after.resource.active == Some(false), "user was reactivated");
+
+    expect_error(
+        store.deactivate_user("no-such-user").await,
+        StatusCode::NOT_FOUND,
+    )?;
+
+    Ok(())
+}
+
+async fn check_group_display_names<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let sales = store
+        .create_group(group_request("Sales", &[]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let accounting = store
+        .create_group(group_request("Accounting", &[]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    store
+        .replace_group(&sales, group_request("Paper Sales", &[]))
+        .await
+        .map_err(store_error)?;
+
+    // Groups that do not exist are left out.
+    let display_names = store
+        .get_group_display_names(&BTreeSet::from([
+            sales.clone(),
+            accounting.clone(),
+            "no-such-group".to_string(),
+        ]))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        display_names
+            == BTreeMap::from([
+                (sales, "Paper Sales".to_string()),
+                (accounting, "Accounting".to_string()),
+            ]),
+        "wrong group display names {display_names:?}"
+    );
+
+    let display_names = store
+        .get_group_display_names(&BTreeSet::new())
+        .await
+        .map_err(store_error)?;
+    ensure!(display_names.is_empty(), "display names for no groups");
+
+    Ok(())
+}
+
+async fn check_filters<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let mut user_ids = Vec::new();
+    for (name, external_id) in [
+        ("jhalpert", Some("okta-1")),
+        ("dschrute", Some("okta-2")),
+        ("pbeesly", Some("OKTA-2")),
+        ("mscott", None),
+    ] {
+        let user = store
+            .create_user(user_request(name, external_id))
+            .await
+            .map_err(store_error)?;
+        user_ids.push(user.resource.id);
+    }
+
+    let sales = store
+        .create_group(group_request("Sales", &[]))
+        .await
+        .map_err(store_error)?;
+    store
+        .create_group(group_request("Accounting", &[]))
+        .await
+        .map_err(store_error)?;
+
+    let list_user_ids = async |filter| -> anyhow::Result<BTreeSet<String>> {
+        let result = store
+            .list_users(Some(filter), Pagination::default())
+            .await
+            .map_err(store_error)?;
+        ensure!(
+            result.total_results == result.resources.len(),
+            "totalResults does not match the results"
+        );
+        Ok(result.resources.into_iter().map(|user| user.resource.id).collect())
+    };
+
+    ensure!(
+        list_user_ids(Filter::attr("userName").eq("JHalpert")).await?
+            == BTreeSet::from([user_ids[0].clone()]),
+        "userName eq is wrong"
+    );
+    ensure!(
+        list_user_ids(Filter::attr("userName").eq("kmalone")).await?.is_empty(),
+        "userName eq matched a missing user"
+    );
+    ensure!(
+        list_user_ids(Filter::attr("externalId").eq("okta-2")).await?
+            == BTreeSet::from([user_ids[1].clone(), user_ids[2].clone()]),
+        "externalId eq is wrong"
+    );
+
+    let groups = store
+        .list_groups(
+            Some(Filter::attr("displayName").eq("sales")),
+            Pagination::default(),
+        )
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        groups.total_results == 1
+            && groups.resources.len() == 1
+            && groups.resources[0].resource.id == sales.resource.id,
+        "displayName eq is wrong"
+    );
+
+    // The rest of the grammar: substrings fold case like eq does, resources
+    // without an attribute are not present, and logical operators combine
+    for (filter, expected, what) in [
+        (Filter::attr("userName").sw("JH"), vec![0], "userName sw"),
+        (Filter::attr("userName").co("schr"), vec![1], "userName co"),
+        (Filter::attr("externalId").ew("TA-2"), vec![1, 2], "externalId ew"),
+        (Filter::attr("externalId").pr(), vec![0, 1, 2], "externalId pr"),
+        (!Filter::attr("externalId").pr(), vec![3], "not"),
+        (
+            Filter::attr("userName")
+                .eq("mscott")
+                .or(Filter::attr("externalId").eq("okta-1")),
+            vec![0, 3],
+            "or",
+        ),
+        (
+            Filter::attr("externalId")
+                .eq("okta-2")
+                .and(Filter::attr("userName").ne("dschrute")),
+            vec![2],
+            "and",
+        ),
+        (Filter::attr("id").eq(user_ids[3].clone()), vec![3], "id eq"),
+    ] {
+        let expected: BTreeSet<String> =
+            expected.into_iter().map(|i| user_ids[i].clone()).collect();
+        ensure!(list_user_ids(filter).await? == expected, "{what} is wrong");
+    }
+
+    // Filters on attributes the resource does not have, and comparisons the
+    // attribute's type does not allow, are rejected
+    for filter in [
+        Filter::attr("displayName").eq("Sales"),
+        Filter::attr("active").gt(true),
+        Filter::attr("meta.created").co("2025"),
+        Filter::attr("meta.lastModified").gt("yesterday"),
+        Filter::attr("userName").eq(true),
+    ] {
+        let error = expect_error(
+            store.list_users(Some(filter.clone()), Pagination::default()).await,
+            StatusCode::BAD_REQUEST,
+        )?;
+        ensure!(
+            error.error_type == Some(ErrorType::InvalidFilter),
+            "expected an invalidFilter error for {filter}, got {error:?}"
+        );
+    }
+
+    Ok(())
+}
+
+async fn check_last_modified_filter<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let mut user_ids = Vec::new();
+    for name in ["jhalpert", "dschrute"] {
+        let user = store
+            .create_user(user_request(name, None))
+            .await
+            .map_err(store_error)?;
+        user_ids.push(user.resource.id);
+    }
+    let sales = store
+        .create_group(group_request("Sales", &[]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    store
+        .create_group(group_request("Accounting", &[]))
+        .await
+        .map_err(store_error)?;
+
+    let since = instant().await;
+
+    store
+        .replace_user(&user_ids[1], user_request("dschrute", Some("okta-2")))
+        .await
+        .map_err(store_error)?;
+    let pam = store
+        .create_user(user_request("pbeesly", None))
+        .await
+        .map_err(store_error)?;
+    store
+        .replace_group(&sales, group_request("Sales", &[&user_ids[0]]))
+        .await
+        .map_err(store_error)?;
+
+    let list_user_ids = async |since| -> anyhow::Result<BTreeSet<String>> {
+        let result = store
+            .list_users(
+                Some(Filter::attr("meta.lastModified").gt(since)),
+                Pagination::default(),
+            )
+            .await
+            .map_err(store_error)?;
+        Ok(result.resources.into_iter().map(|user| user.resource.id).collect())
+    };
+
+    // Joining Sales modified jhalpert
+    ensure!(
+        list_user_ids(since).await?
+            == BTreeSet::from([
+                user_ids[0].clone(),
+                user_ids[1].clone(),
+                pam.resource.id,
+            ]),
+        "lastModified gt is wrong for users"
+    );
+    ensure!(
+        list_user_ids(Utc::now() + TimeDelta::hours(1)).await?.is_empty(),
+        "lastModified gt matched users from the future"
+    );
+
+    let list_group_ids = async |since| -> anyhow::Result<BTreeSet<String>> {
+        let result = store
+            .list_groups(
+                Some(Filter::attr("meta.lastModified").gt(since)),
+                Pagination::default(),
+            )
+            .await
+            .map_err(store_error)?;
+        ensure!(
+            result.total_results == result.resources.len(),
+            "totalResults is wrong for groups"
+        );
+        Ok(result
+            .resources
+            .into_iter()
+            .map(|group| group.resource.id)
+            .collect())
+    };
+
+    ensure!(
+        list_group_ids(since).await? == BTreeSet::from([sales.clone()]),
+        "lastModified gt is wrong for groups"
+    );
+
+    // Membership changes that cascade from another resource modify the
+    // resources on the other side of the membership too.
+    let since = instant().await;
+    store.delete_user_by_id(&user_ids[0]).await.map_err(store_error)?;
+    ensure!(
+        list_group_ids(since).await? == BTreeSet::from([sales.clone()]),
+        "deleting a member did not modify its group"
+    );
+
+    let since = instant().await;
+    store
+        .replace_group(&sales, group_request("Sales", &[&user_ids[1]]))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        list_user_ids(since).await? == BTreeSet::from([user_ids[1].clone()]),
+        "joining a group did not modify the member"
+    );
+
+    let since = instant().await;
+    store.delete_group_by_id(&sales).await.map_err(store_error)?;
+    ensure!(
+        list_user_ids(since).await? == BTreeSet::from([user_ids[1].clone()]),
+        "deleting a group did not modify its members"
+    );
+
+    Ok(())
+}
+
+/// The version of the user `user_id`
+async fn user_version<S: ProviderStore>(
+    store: &S,
+    user_id: &str,
+) -> anyhow::Result<String> {
+    Ok(store
+        .get_user_by_id(user_id)
+        .await
+        .map_err(store_error)?
+        .with_context(|| format!("user {user_id} is missing"))?
+        .meta
+        .version)
+}
+
+async fn check_versions<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        jim.meta.version != dwight.meta.version,
+        "two users share a version"
+    );
+    ensure!(
+        user_version(store, &jim.resource.id).await? == jim.meta.version,
+        "reading a user changed its version"
+    );
+
+    let replaced = store
+        .replace_user(&jim.resource.id, user_request("jhalpert", Some("okta")))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        replaced.meta.version != jim.meta.version,
+        "replacing a user kept its version"
+    );
+    ensure!(
+        user_version(store, &jim.resource.id).await? == replaced.meta.version,
+        "the version read back is not the one replace returned"
+    );
+
+    // Membership changes give both sides of the membership a new version
+    let sales = store
+        .create_group(group_request("Sales", &[&jim.resource.id]))
+        .await
+        .map_err(store_error)?;
+    let joined = user_version(store, &jim.resource.id).await?;
+    ensure!(
+        joined != replaced.meta.version,
+        "joining a group kept the member's version"
+    );
+    ensure!(
+        user_version(store, &dwight.resource.id).await? == dwight.meta.version,
+        "a group changed the version of a user that is not a member"
+    );
+
+    store.delete_user_by_id(&jim.resource.id).await.map_err(store_error)?;
+    let group = store
+        .get_group_by_id(&sales.resource.id)
+        .await
+        .map_err(store_error)?
+        .context("group is missing")?;
+    ensure!(
+        group.meta.version != sales.meta.version,
+        "deleting a member kept the group's version"
+    );
+
+    Ok(())
+}
+
+async fn check_pagination<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let mut all_ids = BTreeSet::new();
+    for i in 0..5 {
+        let user = store
+            .create_user(user_request(&format!("user{i}"), None))
+            .await
+            .map_err(store_error)?;
+        all_ids.insert(user.resource.id);
+    }
+
+    // Walking the pages must visit every user exactly once
+    let mut seen = BTreeSet::new();
+    for start_index in [1, 3, 5] {
+        let page = store
+            .list_users(None, Pagination { start_index, count: Some(2) })
+            .await
+            .map_err(store_error)?;
+
+        ensure!(page.total_results == 5, "totalResults is not the total");
+        ensure!(
+            page.resources.len() == if start_index == 5 { 1 } else { 2 },
+            "page at {start_index} has {} users",
+            page.resources.len()
+        );
+
+        for user in page.resources {
+            ensure!(
+                seen.insert(user.resource.id),
+                "a user appeared on two pages"
+            );
+        }
+    }
+    ensure!(seen == all_ids, "the pages did not cover every user");
+
+    let page = store
+        .list_users(None, Pagination { start_index: 10, count: Some(2) })
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        page.resources.is_empty() && page.total_results == 5,
+        "page past the end is wrong"
+    );
+
+    let page = store
+        .list_users(None, Pagination { start_index: 1, count: Some(0) })
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        page.resources.is_empty() && page.total_results == 5,
+        "count=0 page is wrong"
+    );
+
+    Ok(())
+}
+
+async fn check_soft_delete_user<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let sales = store
+        .create_group(group_request("Sales", &[&jim, &dwight]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    ensure!(
+        store.soft_delete_user_by_id(&jim).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::Deleted,
+        "user was not deleted"
+    );
+    ensure!(
+        store.soft_delete_user_by_id(&jim).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::NotFound,
+        "user was deleted twice"
+    );
+
+    // A soft deleted user looks deleted...
+    ensure!(
+        store.get_user_by_id(&jim).await.map_err(store_error)?.is_none(),
+        "deleted user is still there"
+    );
+    ensure!(
+        group_member_ids(store, &sales).await?
+            == BTreeSet::from([dwight.clone()]),
+        "deleted user is still a group member"
+    );
+
+    // ...but is kept, along with its memberships
+    let deleted = store.list_deleted_users().await.map_err(store_error)?;
+    ensure!(deleted.len() == 1, "expected one deleted user, got {deleted:?}");
+    ensure!(deleted[0].resource.resource.id == jim, "wrong user deleted");
+    ensure!(
+        deleted[0]
+            .resource
+            .resource
+            .groups
+            .iter()
+            .flatten()
+            .any(|group| group.value.as_deref() == Some(sales.as_str())),
+        "deleted user lost its memberships"
+    );
+
+    let restored = store
+        .restore_user_by_id(&jim)
+        .await
+        .map_err(store_error)?
+        .context("user was not restored")?;
+    ensure!(restored.resource.name == "jhalpert", "restored the wrong user");
+
+    ensure!(
+        group_member_ids(store, &sales).await?
+            == BTreeSet::from([jim.clone(), dwight]),
+        "restored user is not a group member"
+    );
+    ensure!(
+        user_group_ids(store, &jim).await? == ids([(&sales, "Sales")]),
+        "restored user does not know its groups"
+    );
+
+    ensure!(
+        store.list_deleted_users().await.map_err(store_error)?.is_empty(),
+        "restored user is still deleted"
+    );
+    ensure!(
+        store.restore_user_by_id(&jim).await.map_err(store_error)?.is_none(),
+        "user was restored twice"
+    );
+
+    Ok(())
+}
+
+async fn check_soft_delete_group<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    ensure!(
+        store.soft_delete_group_by_id(&sales).await.map_err(store_error)?
+            == ProviderStoreDeleteResult::Deleted,
+        "group was not deleted"
+    );
+
+    ensure!(
+        store.get_group_by_id(&sales).await.map_err(store_error)?.is_none(),
+        "deleted group is still there"
+    );
+    ensure!(
+        user_group_ids(store, &jim).await?.is_empty(),
+        "user is still in deleted group"
+    );
+
+    let deleted = store.list_deleted_groups().await.map_err(store_error)?;
+    ensure!(deleted.len() == 1, "expected one deleted group, got {deleted:?}");
+    ensure!(deleted[0].resource.resource.id == sales, "wrong group deleted");
+
+    store
+        .restore_group_by_id(&sales)
+        .await
+        .map_err(store_error)?
+        .context("group was not restored")?;
+
+    ensure!(
+        group_member_ids(store, &sales).await? == BTreeSet::from([jim.clone()]),
+        "restored group lost its members"
+    );
+    ensure!(
+        user_group_ids(store, &jim).await? == ids([(&sales, "Sales")]),
+        "member does not know about restored group"
+    );
+
+    ensure!(
+        store.list_deleted_groups().await.map_err(store_error)?.is_empty(),
+        "restored group is still deleted"
+    );
+
+    Ok(())
+}
+
+async fn check_restore_conflict<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("Sales", &[]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    store.soft_delete_user_by_id(&jim).await.map_err(store_error)?;
+    store.soft_delete_group_by_id(&sales).await.map_err(store_error)?;
+
+    // Soft deleted names are free to be reused...
+    store
+        .create_user(user_request("JHalpert", None))
+        .await
+        .map_err(store_error)?;
+    store
+        .create_group(group_request("sales", &[]))
+        .await
+        .map_err(store_error)?;
+
+    // ...which means restoring can conflict
+    let error = expect_error(
+        store.restore_user_by_id(&jim).await,
+        StatusCode::CONFLICT,
+    )?;
+    ensure!(
+        error.error_type == Some(ErrorType::Uniqueness),
+        "expected uniqueness error, got {error:?}"
+    );
+    expect_error(
+        store.restore_group_by_id(&sales).await,
+        StatusCode::CONFLICT,
+    )?;
+
+    // A failed restore leaves the resource deleted
+    ensure!(
+        store.list_deleted_users().await.map_err(store_error)?.len() == 1,
+        "failed restore lost the deleted user"
+    );
+    ensure!(
+        store.list_deleted_groups().await.map_err(store_error)?.len() == 1,
+        "failed restore lost the deleted group"
+    );
+
+    Ok(())
+}
+
+async fn check_restore_without_memberships<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let beets = store
+        .create_group(group_request("Beets", &[&dwight]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    // Memberships of resources that are gone by the time of the restore are
+    // not brought back.
+    store.soft_delete_user_by_id(&jim).await.map_err(store_error)?;
+    store.delete_group_by_id(&sales).await.map_err(store_error)?;
+
+    store
+        .restore_user_by_id(&jim)
+        .await
+        .map_err(store_error)?
+        .context("user was not restored")?;
+    ensure!(
+        user_group_ids(store, &jim).await?.is_empty(),
+        "restored user is in a deleted group"
+    );
+
+    store.soft_delete_group_by_id(&beets).await.map_err(store_error)?;
+    store.delete_user_by_id(&dwight).await.map_err(store_error)?;
+
+    store
+        .restore_group_by_id(&beets)
+        .await
+        .map_err(store_error)?
+        .context("group was not restored")?;
+    ensure!(
+        group_member_ids(store, &beets).await?.is_empty(),
+        "restored group has a deleted member"
+    );
+
+    Ok(())
+}
+
+async fn check_purge<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    store.soft_delete_user_by_id(&jim).await.map_err(store_error)?;
+    store.soft_delete_group_by_id(&sales).await.map_err(store_error)?;
+
+    let purged = store
+        .purge_deleted(Utc::now() - TimeDelta::hours(1))
+        .await
+        .map_err(store_error)?;
+    ensure!(purged == 0, "purged {purged} resources that were too recent");
+
+    let purged = store
+        .purge_deleted(Utc::now() + TimeDelta::seconds(1))
+        .await
+        .map_err(store_error)?;
+    ensure!(purged == 2, "expected to purge 2 resources, purged {purged}");
+
+    ensure!(
+        store.list_deleted_users().await.map_err(store_error)?.is_empty(),
+        "purged user is still deleted"
+    );
+    ensure!(
+        store.restore_user_by_id(&jim).await.map_err(store_error)?.is_none(),
+        "purged user was restored"
+    );
+    ensure!(
+        store.restore_group_by_id(&sales).await.map_err(store_error)?.is_none(),
+        "purged group was restored"
+    );
+
+    Ok(())
+}
+
+/// A short description of a change, for comparing against expectations
+fn describe(
+    kind: &ChangeEventKind,
+    names: &BTreeMap<String, String>,
+) -> String {
+    let name = |id: &String| names.get(id).cloned().unwrap_or(id.clone());
+    let names_of =
+        |ids: &[String]| ids.iter().map(name).collect::<Vec<_>>().join(",");
+
+    match kind {
+        ChangeEventKind::UserCreated { user } => {
+            format!("UserCreated {}", user.name)
+        }
+        ChangeEventKind::UserUpdated { user } => {
+            format!("UserUpdated {}", user.name)
+        }
+        ChangeEventKind::UserDeactivated { user_id } => {
+            format!("UserDeactivated {}", name(user_id))
+        }
+        ChangeEventKind::UserReactivated { user_id } => {
+            format!("UserReactivated {}", name(user_id))
+        }
+        ChangeEventKind::UserDeleted { user_id } => {
+            format!("UserDeleted {}", name(user_id))
+        }
+        ChangeEventKind::GroupCreated { group } => {
+            format!("GroupCreated {}", group.display_name)
+        }
+        ChangeEventKind::GroupUpdated { group } => {
+            format!("GroupUpdated {}", group.display_name)
+        }
+        ChangeEventKind::GroupDeleted { group_id } => {
+            format!("GroupDeleted {}", name(group_id))
+        }
+        ChangeEventKind::GroupMembersAdded { group_id, user_ids } => {
+            format!(
+                "GroupMembersAdded {} {}",
+                name(group_id),
+                names_of(user_ids)
+            )
+        }
+        ChangeEventKind::GroupMembersRemoved { group_id, user_ids } => {
+            format!(
+                "GroupMembersRemoved {} {}",
+                name(group_id),
+                names_of(user_ids)
+            )
+        }
+    }
+}
+
+async fn check_outbox_events<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    store
+        .replace_group(&sales, group_request("Sales", &[&dwight]))
+        .await
+        .map_err(store_error)?;
+
+    let mut request = user_request("jhalpert", None);
+    request.active = Some(false);
+    store.replace_user(&jim, request).await.map_err(store_error)?;
+
+    store.delete_user_by_id(&dwight).await.map_err(store_error)?;
+    store.delete_group_by_id(&sales).await.map_err(store_error)?;
+
+    let names = BTreeMap::from([
+        (jim, String::from("jim")),
+        (dwight, String::from("dwight")),
+        (sales, String::from("Sales")),
+    ]);
+
+    let entries = store.read_outbox(100).await.map_err(store_error)?;
+    let events: Vec<String> =
+        entries.iter().map(|e| describe(&e.event.kind, &names)).collect();
+
+    ensure!(
+        events
+            == [
+                "UserCreated jhalpert",
+                "UserCreated dschrute",
+                "GroupCreated Sales",
+                "GroupMembersAdded Sales jim",
+                "GroupUpdated Sales",
+                "GroupMembersAdded Sales dwight",
+                "GroupMembersRemoved Sales jim",
+                "UserUpdated jhalpert",
+                "UserDeactivated jim",
+                "UserDeleted dwight",
+                "GroupMembersRemoved Sales dwight",
+                "GroupDeleted Sales",
+            ],
+        "unexpected events {events:#?}"
+    );
+
+    ensure!(
+        entries.windows(2).all(|w| w[0].sequence < w[1].sequence),
+        "outbox sequence numbers do not increase"
+    );
+
+    Ok(())
+}
+
+async fn check_outbox_ack<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    for name in ["jhalpert", "dschrute", "pbeesly"] {
+        store
+            .create_user(user_request(name, None))
+            .await
+            .map_err(store_error)?;
+    }
+
+    let entries = store.read_outbox(2).await.map_err(store_error)?;
+    ensure!(entries.len() == 2, "read {} entries, not 2", entries.len());
+
+    // Reading again without acknowledging gives the same entries
+    ensure!(
+        store.read_outbox(2).await.map_err(store_error)? == entries,
+        "unacknowledged entries changed"
+    );
+
+    let acked =
+        store.ack_outbox(entries[1].sequence).await.map_err(store_error)?;
+    ensure!(acked == 2, "acknowledged {acked} entries, not 2");
+
+    let rest = store.read_outbox(100).await.map_err(store_error)?;
+    ensure!(rest.len() == 1, "expected one entry left, got {rest:?}");
+    ensure!(rest[0].sequence > entries[1].sequence, "entry out of order");
+
+    store.ack_outbox(rest[0].sequence).await.map_err(store_error)?;
+    ensure!(
+        store.read_outbox(100).await.map_err(store_error)?.is_empty(),
+        "outbox not empty after acknowledging everything"
+    );
+
+    // Sequence numbers are not reused once acknowledged
+    store
+        .create_user(user_request("abernard", None))
+        .await
+        .map_err(store_error)?;
+    let next = store.read_outbox(100).await.map_err(store_error)?;
+    ensure!(
+        next.len() == 1 && next[0].sequence > rest[0].sequence,
+        "sequence number was reused: {next:?}"
+    );
+
+    Ok(())
+}
+
+async fn check_outbox_cursors<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    for name in ["jhalpert", "dschrute", "pbeesly"] {
+        store
+            .create_user(user_request(name, None))
+            .await
+            .map_err(store_error)?;
+    }
+
+    let all = store.read_outbox(100).await.map_err(store_error)?;
+    ensure!(all.len() == 3, "read {} entries, not 3", all.len());
+
+    ensure!(
+        store.read_outbox_after(0, 100).await.map_err(store_error)? == all,
+        "reading after 0 did not start at the oldest entry"
+    );
+    ensure!(
+        store
+            .read_outbox_after(all[0].sequence, 1)
+            .await
+            .map_err(store_error)?
+            == all[1..2],
+        "reading after the first entry did not give the second"
+    );
+    ensure!(
+        store
+            .read_outbox_after(all[2].sequence, 100)
+            .await
+            .map_err(store_error)?
+            .is_empty(),
+        "read entries after the newest one"
+    );
+
+    // Cursors start at 0, and are independent of each other
+    ensure!(
+        store.outbox_cursor("a").await.map_err(store_error)? == 0,
+        "new cursor is not 0"
+    );
+    store.set_outbox_cursor("a", all[1].sequence).await.map_err(store_error)?;
+    store.set_outbox_cursor("b", all[0].sequence).await.map_err(store_error)?;
+    store.set_outbox_cursor("a", all[2].sequence).await.map_err(store_error)?;
+
+    ensure!(
+        store.outbox_cursor("a").await.map_err(store_error)? == all[2].sequence,
+        "cursor a was not moved"
+    );
+    ensure!(
+        store.outbox_cursor("b").await.map_err(store_error)? == all[0].sequence,
+        "cursor b changed with cursor a"
+    );
+
+    // Acknowledging entries leaves the cursors alone
+    store.ack_outbox(all[0].sequence).await.map_err(store_error)?;
+    ensure!(
+        store.outbox_cursor("b").await.map_err(store_error)? == all[0].sequence,
+        "acknowledging moved a cursor"
+    );
+    ensure!(
+        store.read_outbox_after(0, 100).await.map_err(store_error)? == all[1..],
+        "acknowledged entry was read"
+    );
+
+    Ok(())
+}
+
+async fn check_dead_letters<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?;
+    let entry = store
+        .read_outbox(1)
+        .await
+        .map_err(store_error)?
+        .pop()
+        .context("no outbox entry")?;
+
+    ensure!(
+        store.list_dead_letters().await.map_err(store_error)?.is_empty(),
+        "new store has dead letters"
+    );
+
+    let dead_letter = |endpoint: &str, attempts| DeadLetter {
+        endpoint: endpoint.to_string(),
+        sequence: entry.sequence,
+        event: entry.event.clone(),
+        attempts,
+        last_error: String::from("endpoint responded 500"),
+        failed_at: Utc::now(),
+    };
+
+    store.put_dead_letter(dead_letter("b", 1)).await.map_err(store_error)?;
+    store.put_dead_letter(dead_letter("a", 1)).await.map_err(store_error)?;
+
+    // Saving one with the same endpoint and sequence replaces it
+    let replacement = dead_letter("a", 2);
+    store.put_dead_letter(replacement.clone()).await.map_err(store_error)?;
+
+    let listed = store.list_dead_letters().await.map_err(store_error)?;
+    ensure!(
+        listed.len() == 2
+            && listed[0] == replacement
+            && listed[1].endpoint == "b",
+        "unexpected dead letters {listed:?}"
+    );
+
+    // They outlive the outbox entry
+    store.ack_outbox(entry.sequence).await.map_err(store_error)?;
+    ensure!(
+        store.list_dead_letters().await.map_err(store_error)?.len() == 2,
+        "acknowledging removed dead letters"
+    );
+
+    ensure!(
+        store
+            .remove_dead_letter("a", entry.sequence)
+            .await
+            .map_err(store_error)?,
+        "dead letter was not removed"
+    );
+    ensure!(
+        !store
+            .remove_dead_letter("a", entry.sequence)
+            .await
+            .map_err(store_error)?,
+        "dead letter was removed twice"
+    );
+
+    let listed = store.list_dead_letters().await.map_err(store_error)?;
+    ensure!(
+        listed.len() == 1 && listed[0].endpoint == "b",
+        "unexpected dead letters {listed:?}"
+    );
+
+    Ok(())
+}
+
+/// A time strictly between the changes made before and after it
+async fn instant() -> DateTime<Utc> {
+    tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    let now = Utc::now();
+    tokio::time::sleep(std::time::Duration::from_millis(2)).await;
+    now
+}
+
+async fn check_revisions<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?;
+    let jim_id = jim.resource.id;
+
+    store
+        .replace_user(&jim_id, user_request("jhalpert", Some("jim")))
+        .await
+        .map_err(store_error)?;
+    store.delete_user_by_id(&jim_id).await.map_err(store_error)?;
+
+    let revisions =
+        store.list_user_revisions(&jim_id).await.map_err(store_error)?;
+    ensure!(revisions.len() == 3, "expected 3 revisions, got {revisions:?}");
+    ensure!(
+        revisions.windows(2).all(|pair| pair[0].revision < pair[1].revision
+            && pair[0].recorded_at <= pair[1].recorded_at),
+        "revisions out of order: {revisions:?}"
+    );
+
+    let external_ids: Vec<_> = revisions
+        .iter()
+        .map(|revision| {
+            revision
+                .resource
+                .as_ref()
+                .map(|user| user.resource.external_id.clone())
+        })
+        .collect();
+    ensure!(
+        external_ids == [Some(None), Some(Some(String::from("jim"))), None],
+        "wrong revisions {external_ids:?}"
+    );
+
+    // Groups have their own revisions
+    let group = store
+        .create_group(group_request("sales", &[]))
+        .await
+        .map_err(store_error)?;
+    let group_revisions = store
+        .list_group_revisions(&group.resource.id)
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        group_revisions.len() == 1
+            && group_revisions[0].revision > revisions[2].revision,
+        "wrong group revisions {group_revisions:?}"
+    );
+
+    // A resource that never existed has no history
+    ensure!(
+        store
+            .list_user_revisions("nobody")
+            .await
+            .map_err(store_error)?
+            .is_empty(),
+        "revisions for a user that never existed"
+    );
+
+    Ok(())
+}
+
+async fn check_point_in_time<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let before_anything = instant().await;
+
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let first = instant().await;
+
+    // Jim gets an external id and leaves sales, and Dwight joins it
+    store
+        .replace_user(&jim, user_request("jhalpert", Some("jim")))
+        .await
+        .map_err(store_error)?;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    store
+        .replace_group(&sales, group_request("sales", &[&dwight]))
+        .await
+        .map_err(store_error)?;
+
+    let second = instant().await;
+
+    store.delete_user_by_id(&dwight).await.map_err(store_error)?;
+
+    let third = instant().await;
+
+    let user_at = async |id: &str, at| {
+        store.get_user_at(id, at).await.map_err(store_error)
+    };
+    let members_at = async |at| -> anyhow::Result<BTreeSet<String>> {
+        let group = store
+            .get_group_at(&sales, at)
+            .await
+            .map_err(store_error)?
+            .context("sales is missing")?;
+        Ok(group
+            .resource
+            .members
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|member| member.value.clone())
+            .collect())
+    };
+
+    ensure!(
+        user_at(&jim, before_anything).await?.is_none(),
+        "jim existed before being created"
+    );
+    ensure!(
+        store
+            .get_group_at(&sales, before_anything)
+            .await
+            .map_err(store_error)?
+            .is_none(),
+        "sales existed before being created"
+    );
+
+    let jim_first = user_at(&jim, first).await?.context("jim is missing")?;
+    ensure!(
+        jim_first.resource.external_id.is_none(),
+        "jim had an external id too early"
+    );
+    ensure!(
+        jim_first
+            .resource
+            .groups
+            .unwrap_or_default()
+            .iter()
+            .map(|group| (group.value.clone(), group.display.clone()))
+            .eq([(Some(sales.clone()), Some(String::from("sales")))]),
+        "jim was not in sales"
+    );
+    ensure!(
+        members_at(first).await? == BTreeSet::from([jim.clone()]),
+        "wrong members at first"
+    );
+
+    let jim_second = user_at(&jim, second).await?.context("jim is missing")?;
+    ensure!(
+        jim_second.resource.external_id.as_deref() == Some("jim"),
+        "jim's external id was not changed"
+    );
+    ensure!(
+        jim_second.resource.groups.unwrap_or_default().is_empty(),
+        "jim was still in sales"
+    );
+    ensure!(
+        members_at(second).await? == BTreeSet::from([dwight.clone()]),
+        "wrong members at second"
+    );
+
+    // Deleting Dwight removes him from sales as of then, and a revision of
+    // sales records it
+    ensure!(user_at(&dwight, second).await?.is_some(), "dwight is missing");
+    ensure!(
+        user_at(&dwight, third).await?.is_none(),
+        "dwight existed after being deleted"
+    );
+    ensure!(members_at(third).await?.is_empty(), "wrong members at third");
+
+    let revisions =
+        store.list_group_revisions(&sales).await.map_err(store_error)?;
+    let last = revisions
+        .last()
+        .and_then(|revision| revision.resource.as_ref())
+        .context("sales has no revisions")?;
+    ensure!(
+        revisions.len() == 3
+            && last.resource.members.iter().flatten().next().is_none(),
+        "no revision of sales for the delete of a member {revisions:?}"
+    );
+
+    Ok(())
+}
+
+async fn check_point_in_time_restore<S: ProviderStore>(
+    store: &S,
+) -> anyhow::Result<()> {
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("sales", &[&jim, &dwight]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    let first = instant().await;
+
+    store.soft_delete_user_by_id(&jim).await.map_err(store_error)?;
+
+    let second = instant().await;
+
+    // Sales changes while Jim is deleted, so its latest revision before the
+    // restore does not have him
+    store
+        .replace_group(&sales, group_request("Sales", &[&dwight]))
+        .await
+        .map_err(store_error)?;
+
+    let third = instant().await;
+
+    store
+        .restore_user_by_id(&jim)
+        .await
+        .map_err(store_error)?
+        .context("jim was not restored")?;
+
+    let fourth = instant().await;
+
+    let members_at = async |at| -> anyhow::Result<BTreeSet<String>> {
+        let group = store
+            .get_group_at(&sales, at)
+            .await
+            .map_err(store_error)?
+            .context("sales is missing")?;
+        Ok(group
+            .resource
+            .members
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|member| member.value.clone())
+            .collect())
+    };
+    let jim_groups_at = async |at| -> anyhow::Result<Vec<String>> {
+        let jim = store
+            .get_user_at(&jim, at)
+            .await
+            .map_err(store_error)?
+            .context("jim is missing")?;
+        Ok(jim
+            .resource
+            .groups
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|group| group.value)
+            .collect())
+    };
+
+    ensure!(
+        members_at(first).await?
+            == BTreeSet::from([jim.clone(), dwight.clone()]),
+        "wrong members at first"
+    );
+    ensure!(
+        members_at(second).await? == BTreeSet::from([dwight.clone()]),
+        "wrong members at second"
+    );
+    ensure!(
+        store.get_user_at(&jim, second).await.map_err(store_error)?.is_none(),
+        "jim existed while deleted"
+    );
+    ensure!(
+        members_at(third).await? == BTreeSet::from([dwight.clone()]),
+        "wrong members at third"
+    );
+    ensure!(
+        members_at(fourth).await? == BTreeSet::from([jim.clone(), dwight]),
+        "jim was not back in sales after the restore"
+    );
+    ensure!(
+        jim_groups_at(fourth).await? == [sales.clone()],
+        "jim's groups were not restored"
+    );
+
+    // Created, left by Jim, replaced, and rejoined by Jim
+    let revisions =
+        store.list_group_revisions(&sales).await.map_err(store_error)?;
+    ensure!(revisions.len() == 4, "wrong revisions of sales {revisions:?}");
+
+    Ok(())
+}
+
+async fn check_delta<S: ProviderStore>(store: &S) -> anyhow::Result<()> {
+    let set = |ids: &[String]| ids.iter().cloned().collect::<BTreeSet<_>>();
+
+    let empty = store.user_delta(None).await.map_err(store_error)?;
+    ensure!(empty.changed.is_empty(), "empty store has users {empty:?}");
+
+    let jim = store
+        .create_user(user_request("jhalpert", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let dwight = store
+        .create_user(user_request("dschrute", None))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+    let sales = store
+        .create_group(group_request("Sales", &[&jim]))
+        .await
+        .map_err(store_error)?
+        .resource
+        .id;
+
+    // Without a watermark, everything that exists
+    let full = store.user_delta(None).await.map_err(store_error)?;
+    ensure!(
+        set(&full.changed) == BTreeSet::from([jim.clone(), dwight.clone()])
+            && full.deleted.is_empty(),
+        "wrong full delta {full:?}"
+    );
+    ensure!(full.counter > empty.counter, "counter did not go up");
+
+    let unchanged =
+        store.user_delta(Some(full.counter)).await.map_err(store_error)?;
+    ensure!(
+        unchanged.changed.is_empty() && unchanged.deleted.is_empty(),
+        "delta without changes is not empty: {unchanged:?}"
+    );
+    ensure!(unchanged.counter == full.counter, "counter moved by itself");
+
+    store
+        .replace_user(&dwight, user_request("dschrute", Some("okta-2")))
+        .await
+        .map_err(store_error)?;
+    store.delete_user_by_id(&jim).await.map_err(store_error)?;
+
+    let users =
+        store.user_delta(Some(full.counter)).await.map_err(store_error)?;
+    ensure!(
+        users.changed == [dwight.clone()] && users.deleted == [jim.clone()],
+        "wrong user delta {users:?}"
+    );
+
+    // Jim leaving sales by being deleted changed sales
+    let groups =
+        store.group_delta(Some(full.counter)).await.map_err(store_error)?;
+    ensure!(
+        groups.changed == [sales.clone()] && groups.deleted.is_empty(),
+        "wrong group delta {groups:?}"
+    );
+
+    // Deleting a group changes its members
+    store
+        .replace_group(&sales, group_request("Sales", &[&dwight]))
+        .await
+        .map_err(store_error)?;
+    let before_delete = store.group_delta(None).await.map_err(store_error)?;
+    store.delete_group_by_id(&sales).await.map_err(store_error)?;
+
+    let groups = store
+        .group_delta(Some(before_delete.counter))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        groups.changed.is_empty() && groups.deleted == [sales.clone()],
+        "wrong group delta after delete {groups:?}"
+    );
+    let users = store
+        .user_delta(Some(before_delete.counter))
+        .await
+        .map_err(store_error)?;
+    ensure!(
+        users.changed == [dwight] && users.deleted.is_empty(),
+        "wrong user delta after group delete {users:?}"
+    );
+
+    // Deleted resources are remembered from further back too
+    let users =
+        store.user_delta(Some(empty.counter)).await.map_err(store_error)?;
+    ensure!(users.deleted == [jim], "jim was forgotten: {users:?}");
+
+    Ok(())
+}
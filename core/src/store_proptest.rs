@@ -0,0 +1,745 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Model-based property tests for the provider stores.
+//!
+//! Random sequences of operations are applied both to a store and to a
+//! deliberately simple model of what SCIM says should happen. After every
+//! step the outcome of the operation and the whole observable state of the
+//! store (both the groups' member lists and the users' group lists) must
+//! match the model.
+//!
+//! With the `testing` feature, other crates can check their own stores too,
+//! from a (non-async) test:
+//!
+//! ```ignore
+//! #[test]
+//! fn test_store_matches_model() {
+//!     scim2_rs::store_proptest::run(
+//!         proptest::test_runner::Config::with_cases(64),
+//!         || MyStore::new(),
+//!     )
+//!     .unwrap();
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use http::StatusCode;
+use proptest::prelude::*;
+use proptest::sample::Index;
+use proptest::test_runner::{Config as ProptestConfig, TestRunner};
+use serde_json::json;
+use unicase::UniCase;
+
+use crate::{
+    CreateGroupRequest, CreateUserRequest, GroupMember, OperationContext,
+    PATCHOP_URN, Pagination, PatchRequest, Provider, ProviderStore,
+    ProviderStoreDeleteResult, ProviderStoreError,
+};
+
+/// Names are drawn from small pools, with differently cased duplicates
+/// (including of non-ASCII letters), so that uniqueness conflicts are common.
+const USER_NAMES: &[&str] = &[
+    "jhalpert", "JHalpert", "dschrute", "pbeesly", "mscott", "émile", "ÉMILE",
+];
+const GROUP_NAMES: &[&str] =
+    &["Sales", "SALES", "Accounting", "Management", "Αθήνα", "ΑΘΉΝΑ"];
+const EXTERNAL_IDS: &[&str] = &["okta-1", "okta-2"];
+
+/// An id that no store will ever hand out
+const MISSING_ID: &str = "00000000-0000-0000-0000-000000000000";
+
+/// Which resource an operation applies to
+#[derive(Debug, Clone)]
+pub enum Target {
+    /// One of the resources that currently exist, if there are any
+    Existing(Index),
+    Missing,
+}
+
+/// An operation on a store, with its names and resources picked from the
+/// pools above and from what exists when it is applied
+#[derive(Debug, Clone)]
+pub enum Op {
+    CreateUser { name: Index, external_id: Option<Index>, active: Option<bool> },
+    ReplaceUser { user: Target, name: Index, active: Option<bool> },
+    PatchUserActive { user: Target, active: bool },
+    DeleteUser { user: Target },
+    CreateGroup { name: Index, members: Vec<Target> },
+    ReplaceGroup { group: Target, name: Index, members: Vec<Target> },
+    PatchGroupRename { group: Target, name: Index },
+    PatchGroupAddMembers { group: Target, members: Vec<Target> },
+    PatchGroupReplaceMembers { group: Target, members: Vec<Target> },
+    PatchGroupRemoveMember { group: Target, member: Target },
+    DeleteGroup { group: Target },
+}
+
+fn target() -> impl Strategy<Value = Target> {
+    prop_oneof![
+        9 => any::<Index>().prop_map(Target::Existing),
+        1 => Just(Target::Missing),
+    ]
+}
+
+fn members() -> impl Strategy<Value = Vec<Target>> {
+    prop::collection::vec(target(), 0..4)
+}
+
+/// Sequences of operations to check a store with
+pub fn ops() -> impl Strategy<Value = Vec<Op>> {
+    prop::collection::vec(op(), 1..40)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        3 => (any::<Index>(), any::<Option<Index>>(), any::<Option<bool>>())
+            .prop_map(|(name, external_id, active)| Op::CreateUser {
+                name,
+                external_id,
+                active,
+            }),
+        1 => (target(), any::<Index>(), any::<Option<bool>>()).prop_map(
+            |(user, name, active)| Op::ReplaceUser { user, name, active }
+        ),
+        1 => (target(), any::<bool>())
+            .prop_map(|(user, active)| Op::PatchUserActive { user, active }),
+        1 => target().prop_map(|user| Op::DeleteUser { user }),
+        2 => (any::<Index>(), members())
+            .prop_map(|(name, members)| Op::CreateGroup { name, members }),
+        1 => (target(), any::<Index>(), members()).prop_map(
+            |(group, name, members)| Op::ReplaceGroup { group, name, members }
+        ),
+        1 => (target(), any::<Index>())
+            .prop_map(|(group, name)| Op::PatchGroupRename { group, name }),
+        1 => (target(), members()).prop_map(|(group, members)| {
+            Op::PatchGroupAddMembers { group, members }
+        }),
+        1 => (target(), members()).prop_map(|(group, members)| {
+            Op::PatchGroupReplaceMembers { group, members }
+        }),
+        1 => (target(), target()).prop_map(|(group, member)| {
+            Op::PatchGroupRemoveMember { group, member }
+        }),
+        1 => target().prop_map(|group| Op::DeleteGroup { group }),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ModelUser {
+    name: String,
+    external_id: Option<String>,
+    active: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ModelGroup {
+    display_name: String,
+    members: BTreeSet<String>,
+}
+
+/// The reference model: users, and groups holding the only copy of
+/// membership. Everything a store denormalises is derived from this.
+#[derive(Debug, Default)]
+struct Model {
+    users: BTreeMap<String, ModelUser>,
+    groups: BTreeMap<String, ModelGroup>,
+}
+
+/// What a user looks like from outside the store
+#[derive(Debug, PartialEq)]
+struct ObservedUser {
+    user: ModelUser,
+    /// (group id, display) pairs, sorted so duplicates show up
+    groups: Vec<(String, String)>,
+}
+
+/// Everything observable about a store's contents, minus timestamps
+#[derive(Debug, PartialEq)]
+struct Observed {
+    users: BTreeMap<String, ObservedUser>,
+    groups: BTreeMap<String, ModelGroup>,
+}
+
+/// The outcome of an operation: success, or the status of the error
+type Outcome = Result<(), StatusCode>;
+
+impl Model {
+    fn pick<'a>(
+        ids: impl ExactSizeIterator<Item = &'a String>,
+        target: &Target,
+    ) -> String {
+        match target {
+            Target::Existing(index) if ids.len() > 0 => {
+                let position = index.index(ids.len());
+                ids.into_iter().nth(position).unwrap().clone()
+            }
+            _ => MISSING_ID.to_string(),
+        }
+    }
+
+    fn user_id(&self, target: &Target) -> String {
+        Self::pick(self.users.keys(), target)
+    }
+
+    fn group_id(&self, target: &Target) -> String {
+        Self::pick(self.groups.keys(), target)
+    }
+
+    fn user_ids(&self, targets: &[Target]) -> BTreeSet<String> {
+        targets.iter().map(|target| self.user_id(target)).collect()
+    }
+
+    fn user_name_taken(&self, name: &str, except: &str) -> bool {
+        self.users.iter().any(|(id, user)| {
+            id != except && UniCase::new(&user.name) == UniCase::new(name)
+        })
+    }
+
+    fn display_name_taken(&self, name: &str, except: &str) -> bool {
+        self.groups.iter().any(|(id, group)| {
+            id != except
+                && UniCase::new(&group.display_name) == UniCase::new(name)
+        })
+    }
+
+    fn check_members(&self, members: &BTreeSet<String>) -> Outcome {
+        match members.iter().all(|id| self.users.contains_key(id)) {
+            true => Ok(()),
+            false => Err(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// Replace a group as a store must: 404 for a missing group, then 409
+    /// for a taken displayName, then 404 for a missing member.
+    fn replace_group(
+        &mut self,
+        group_id: &str,
+        display_name: &str,
+        members: BTreeSet<String>,
+    ) -> Outcome {
+        if !self.groups.contains_key(group_id) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        if self.display_name_taken(display_name, group_id) {
+            return Err(StatusCode::CONFLICT);
+        }
+        self.check_members(&members)?;
+
+        self.groups.insert(
+            group_id.to_string(),
+            ModelGroup { display_name: display_name.to_string(), members },
+        );
+        Ok(())
+    }
+
+    fn observe(&self) -> Observed {
+        let users = self
+            .users
+            .iter()
+            .map(|(id, user)| {
+                let groups = self
+                    .groups
+                    .iter()
+                    .filter(|(_, group)| group.members.contains(id))
+                    .map(|(group_id, group)| {
+                        (group_id.clone(), group.display_name.clone())
+                    })
+                    .collect();
+
+                (id.clone(), ObservedUser { user: user.clone(), groups })
+            })
+            .collect();
+
+        Observed { users, groups: self.groups.clone() }
+    }
+}
+
+fn outcome<T>(result: Result<T, ProviderStoreError>) -> Result<T, StatusCode> {
+    result.map_err(|error| match error {
+        ProviderStoreError::Scim(error) => error.status,
+        ProviderStoreError::StoreError(error) => {
+            panic!("unexpected store error {error:#}")
+        }
+    })
+}
+
+fn group_request(
+    display_name: &str,
+    members: &BTreeSet<String>,
+) -> CreateGroupRequest {
+    CreateGroupRequest {
+        display_name: display_name.to_string(),
+        external_id: None,
+        members: Some(
+            members
+                .iter()
+                .map(|id| GroupMember {
+                    resource_type: None,
+                    value: Some(id.clone()),
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn patch_request(operations: serde_json::Value) -> PatchRequest {
+    serde_json::from_value(json!({
+        "schemas": [PATCHOP_URN],
+        "Operations": operations,
+    }))
+    .unwrap()
+}
+
+fn members_value(members: &BTreeSet<String>) -> serde_json::Value {
+    members.iter().map(|id| json!({ "value": id })).collect()
+}
+
+/// Read back everything in the store, through both lists and single gets.
+async fn observe<S: ProviderStore>(store: &S) -> Observed {
+    let users = outcome(store.list_users(None, Pagination::default()).await)
+        .unwrap()
+        .resources;
+    let groups = outcome(store.list_groups(None, Pagination::default()).await)
+        .unwrap()
+        .resources;
+
+    let mut observed =
+        Observed { users: BTreeMap::new(), groups: BTreeMap::new() };
+
+    for listed in users {
+        let fetched = outcome(store.get_user_by_id(&listed.resource.id).await)
+            .unwrap()
+            .expect("listed user exists");
+        assert_eq!(fetched.resource, listed.resource);
+
+        let user = fetched.resource;
+        let mut groups: Vec<_> = user
+            .groups
+            .unwrap_or_default()
+            .into_iter()
+            .map(|group| (group.value.unwrap(), group.display.unwrap()))
+            .collect();
+        groups.sort();
+
+        observed.users.insert(
+            user.id,
+            ObservedUser {
+                user: ModelUser {
+                    name: user.name,
+                    external_id: user.external_id,
+                    active: user.active,
+                },
+                groups,
+            },
+        );
+    }
+
+    for listed in groups {
+        let fetched = outcome(store.get_group_by_id(&listed.resource.id).await)
+            .unwrap()
+            .expect("listed group exists");
+        assert_eq!(fetched.resource, listed.resource);
+
+        let group = fetched.resource;
+        let members = group
+            .members
+            .unwrap_or_default()
+            .iter()
+            .map(|member| member.value.clone().unwrap())
+            .collect();
+
+        observed.groups.insert(
+            group.id,
+            ModelGroup { display_name: group.display_name, members },
+        );
+    }
+
+    observed
+}
+
+/// Apply `op` to the store (through `provider` for patches, as they are
+/// implemented on top of the store) and to the model, returning both
+/// outcomes.
+async fn apply<S: ProviderStore>(
+    provider: &Provider<S>,
+    model: &mut Model,
+    op: &Op,
+) -> (Outcome, Outcome) {
+    let store = provider.store();
+
+    match op {
+        Op::CreateUser { name, external_id, active } => {
+            let name = name.get(USER_NAMES).to_string();
+            let external_id =
+                external_id.map(|index| index.get(EXTERNAL_IDS).to_string());
+
+            let actual = outcome(
+                store
+                    .create_user(CreateUserRequest {
+                        name: name.clone(),
+                        active: *active,
+                        external_id: external_id.clone(),
+                        groups: None,
+                    })
+                    .await,
+            );
+
+            let expected = if model.user_name_taken(&name, "") {
+                Err(StatusCode::CONFLICT)
+            } else {
+                Ok(())
+            };
+
+            // The store picks the id, so the model has to learn it.
+            if let Ok(created) = &actual {
+                model.users.insert(
+                    created.resource.id.clone(),
+                    ModelUser { name, external_id, active: *active },
+                );
+            }
+
+            (actual.map(|_| ()), expected)
+        }
+
+        Op::ReplaceUser { user, name, active } => {
+            let user_id = model.user_id(user);
+            let name = name.get(USER_NAMES).to_string();
+            let external_id =
+                model.users.get(&user_id).and_then(|u| u.external_id.clone());
+
+            let actual = outcome(
+                store
+                    .replace_user(
+                        &user_id,
+                        CreateUserRequest {
+                            name: name.clone(),
+                            active: *active,
+                            external_id: external_id.clone(),
+                            groups: None,
+                        },
+                    )
+                    .await,
+            );
+
+            let expected = if !model.users.contains_key(&user_id) {
+                Err(StatusCode::NOT_FOUND)
+            } else if model.user_name_taken(&name, &user_id) {
+                Err(StatusCode::CONFLICT)
+            } else {
+                model.users.insert(
+                    user_id,
+                    ModelUser { name, external_id, active: *active },
+                );
+                Ok(())
+            };
+
+            (actual.map(|_| ()), expected)
+        }
+
+        Op::PatchUserActive { user, active } => {
+            let user_id = model.user_id(user);
+
+            let actual = provider
+                .patch_user(
+                    &OperationContext::default(),
+                    &user_id,
+                    patch_request(json!([
+                        { "op": "replace", "value": { "active": active } }
+                    ])),
+                )
+                .await
+                .map(|_| ())
+                .map_err(|error| error.status);
+
+            let expected = match model.users.get_mut(&user_id) {
+                Some(user) => {
+                    user.active = Some(*active);
+                    Ok(())
+                }
+                None => Err(StatusCode::NOT_FOUND),
+            };
+
+            (actual, expected)
+        }
+
+        Op::DeleteUser { user } => {
+            let user_id = model.user_id(user);
+
+            let actual = match outcome(store.delete_user_by_id(&user_id).await)
+            {
+                Ok(ProviderStoreDeleteResult::Deleted) => Ok(()),
+                Ok(ProviderStoreDeleteResult::NotFound) => {
+                    Err(StatusCode::NOT_FOUND)
+                }
+                Err(status) => Err(status),
+            };
+
+            let expected = match model.users.remove(&user_id) {
+                Some(_) => {
+                    for group in model.groups.values_mut() {
+                        group.members.remove(&user_id);
+                    }
+                    Ok(())
+                }
+                None => Err(StatusCode::NOT_FOUND),
+            };
+
+            (actual, expected)
+        }
+
+        Op::CreateGroup { name, members } => {
+            let display_name = name.get(GROUP_NAMES).to_string();
+            let members = model.user_ids(members);
+
+            let actual = outcome(
+                store
+                    .create_group(group_request(&display_name, &members))
+                    .await,
+            );
+
+            let expected = if model.display_name_taken(&display_name, "") {
+                Err(StatusCode::CONFLICT)
+            } else {
+                model.check_members(&members)
+            };
+
+            if let Ok(created) = &actual {
+                model.groups.insert(
+                    created.resource.id.clone(),
+                    ModelGroup { display_name, members },
+                );
+            }
+
+            (actual.map(|_| ()), expected)
+        }
+
+        Op::ReplaceGroup { group, name, members } => {
+            let group_id = model.group_id(group);
+            let display_name = name.get(GROUP_NAMES).to_string();
+            let members = model.user_ids(members);
+
+            let actual = outcome(
+                store
+                    .replace_group(
+                        &group_id,
+                        group_request(&display_name, &members),
+                    )
+                    .await,
+            );
+
+            let expected =
+                model.replace_group(&group_id, &display_name, members);
+
+            (actual.map(|_| ()), expected)
+        }
+
+        Op::PatchGroupRename { group, name } => {
+            let group_id = model.group_id(group);
+            let display_name = name.get(GROUP_NAMES).to_string();
+
+            let request = patch_request(json!([{
+                "op": "replace",
+                "value": { "id": group_id, "displayName": display_name },
+            }]));
+
+            let members = model
+                .groups
+                .get(&group_id)
+                .map(|group| group.members.clone())
+                .unwrap_or_default();
+
+            (
+                patch_group(provider, &group_id, request).await,
+                model.replace_group(&group_id, &display_name, members),
+            )
+        }
+
+        Op::PatchGroupAddMembers { group, members } => {
+            let group_id = model.group_id(group);
+            let added = model.user_ids(members);
+
+            let request = patch_request(json!([{
+                "op": "add",
+                "path": "members",
+                "value": members_value(&added),
+            }]));
+
+            let (display_name, mut members) = match model.groups.get(&group_id)
+            {
+                Some(group) => {
+                    (group.display_name.clone(), group.members.clone())
+                }
+                None => (String::new(), BTreeSet::new()),
+            };
+            members.extend(added);
+
+            (
+                patch_group(provider, &group_id, request).await,
+                model.replace_group(&group_id, &display_name, members),
+            )
+        }
+
+        Op::PatchGroupReplaceMembers { group, members } => {
+            let group_id = model.group_id(group);
+            let members = model.user_ids(members);
+
+            let request = patch_request(json!([{
+                "op": "replace",
+                "path": "members",
+                "value": members_value(&members),
+            }]));
+
+            let display_name = model
+                .groups
+                .get(&group_id)
+                .map(|group| group.display_name.clone())
+                .unwrap_or_default();
+
+            (
+                patch_group(provider, &group_id, request).await,
+                model.replace_group(&group_id, &display_name, members),
+            )
+        }
+
+        Op::PatchGroupRemoveMember { group, member } => {
+            let group_id = model.group_id(group);
+            let user_id = model.user_id(member);
+
+            let request = patch_request(json!([{
+                "op": "remove",
+                "path": format!("members[value eq \"{user_id}\"]"),
+            }]));
+
+            let (display_name, mut members) = match model.groups.get(&group_id)
+            {
+                Some(group) => {
+                    (group.display_name.clone(), group.members.clone())
+                }
+                None => (String::new(), BTreeSet::new()),
+            };
+            members.remove(&user_id);
+
+            (
+                patch_group(provider, &group_id, request).await,
+                model.replace_group(&group_id, &display_name, members),
+            )
+        }
+
+        Op::DeleteGroup { group } => {
+            let group_id = model.group_id(group);
+
+            let actual =
+                match outcome(store.delete_group_by_id(&group_id).await) {
+                    Ok(ProviderStoreDeleteResult::Deleted) => Ok(()),
+                    Ok(ProviderStoreDeleteResult::NotFound) => {
+                        Err(StatusCode::NOT_FOUND)
+                    }
+                    Err(status) => Err(status),
+                };
+
+            let expected = match model.groups.remove(&group_id) {
+                Some(_) => Ok(()),
+                None => Err(StatusCode::NOT_FOUND),
+            };
+
+            (actual, expected)
+        }
+    }
+}
+
+async fn patch_group<S: ProviderStore>(
+    provider: &Provider<S>,
+    group_id: &str,
+    request: PatchRequest,
+) -> Outcome {
+    provider
+        .patch_group(&OperationContext::default(), group_id, request)
+        .await
+        .map(|_| ())
+        .map_err(|error| error.status)
+}
+
+/// Apply `ops` to a store and the model, checking them against each other
+/// after every step.
+pub async fn check_against_model<S: ProviderStore>(
+    store: S,
+    ops: Vec<Op>,
+) -> Result<(), TestCaseError> {
+    let log = slog::Logger::root(slog::Discard, slog::o!());
+    let provider = Provider::new(log, store);
+    let mut model = Model::default();
+
+    for (step, op) in ops.iter().enumerate() {
+        let (actual, expected) = apply(&provider, &mut model, op).await;
+        prop_assert_eq!(actual, expected, "outcome of step {}: {:?}", step, op);
+
+        prop_assert_eq!(
+            observe(provider.store()).await,
+            model.observe(),
+            "state after step {}: {:?}",
+            step,
+            op
+        );
+    }
+
+    Ok(())
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Check random sequences of operations, each against a fresh store from
+/// `new_store`, returning an error that describes the smallest failing
+/// sequence found. This runs its own runtime, so it must not be called from
+/// within one.
+pub fn run<S, F>(config: ProptestConfig, new_store: F) -> anyhow::Result<()>
+where
+    S: ProviderStore,
+    F: Fn() -> S,
+{
+    TestRunner::new(config)
+        .run(&ops(), |ops| block_on(check_against_model(new_store(), ops)))
+        .map_err(|error| anyhow::anyhow!("{error}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_in_memory_store_matches_model() {
+        run(ProptestConfig::with_cases(64), crate::InMemoryProviderStore::new)
+            .unwrap();
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store_matches_model() {
+        run(ProptestConfig::with_cases(64), || {
+            crate::SqliteProviderStore::open_in_memory().unwrap()
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_model_folds_unicode_case() {
+        let mut model = Model::default();
+        model.users.insert(
+            "1".to_string(),
+            ModelUser {
+                name: "émile".to_string(),
+                external_id: None,
+                active: None,
+            },
+        );
+
+        assert!(model.user_name_taken("ÉMILE", ""));
+        assert!(!model.user_name_taken("ÉMILE", "1"));
+    }
+}
@@ -0,0 +1,219 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serving several tenants (customer organizations, say) from one deployment.
+//!
+//! Each tenant has a store of its own, so that user names and group display
+//! names only have to be unique within a tenant, and one tenant can never see
+//! another's resources. A `TenantStores` hands out the store for a tenant,
+//! and `Provider::for_store` makes a provider for it that shares everything
+//! else (hooks, audit sink, event channels and settings) with the provider
+//! it was made from. That is cheap enough to do for every request.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+
+use crate::{Error, ProviderStore};
+
+/// A tenant, as a `Provider` serving it needs to know it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub id: String,
+
+    /// The SCIM base URL that the tenant's clients use, for `meta.location`
+    pub base_url: String,
+}
+
+/// Tenant ids end up in URLs, and possibly in file names, so they are kept
+/// to letters, digits, `-` and `_`
+pub fn validate_tenant_id(tenant_id: &str) -> Result<(), Error> {
+    let valid = !tenant_id.is_empty()
+        && tenant_id.len() <= 64
+        && tenant_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::invalid_value(format!("invalid tenant id {tenant_id}")))
+    }
+}
+
+/// Hands out the store for each tenant.
+///
+/// Stores are handles: a store that keeps every tenant in one database can
+/// hand out a handle that scopes every query to the tenant, while one that
+/// keeps tenants apart (see `TenantStoreMap`) can hand out a shared
+/// reference to the tenant's own store.
+pub trait TenantStores: Sync {
+    type Store: ProviderStore;
+
+    /// The store for `tenant_id`. Return `Error::not_found` for tenants that
+    /// do not exist, or create them on first use.
+    fn store(
+        &self,
+        tenant_id: &str,
+    ) -> impl Future<Output = Result<Self::Store, Error>> + Send;
+}
+
+type StoreFactory<S> = dyn Fn(&str) -> anyhow::Result<S> + Send + Sync;
+
+/// Keeps a separate store for every tenant, creating each on first use.
+///
+/// By default any valid tenant id gets a store, so whoever can reach
+/// `store` can create tenants: only call it for authenticated clients, or
+/// limit the tenants with `with_allowed_tenants`.
+pub struct TenantStoreMap<S> {
+    stores: Mutex<BTreeMap<String, Arc<S>>>,
+    factory: Box<StoreFactory<S>>,
+
+    /// If set, the only tenants there are
+    allowed: Option<BTreeSet<String>>,
+}
+
+impl<S> TenantStoreMap<S> {
+    /// Create the store for a tenant with `factory`, which is given the
+    /// tenant's id. Ids are checked with `validate_tenant_id` first.
+    pub fn new(
+        factory: impl Fn(&str) -> anyhow::Result<S> + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            stores: Mutex::new(BTreeMap::new()),
+            factory: Box::new(factory),
+            allowed: None,
+        }
+    }
+
+    /// Only serve the tenants in `tenant_ids`. Any other is not found, and
+    /// no store is created for it.
+    pub fn with_allowed_tenants(
+        mut self,
+        tenant_ids: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        self.allowed = Some(tenant_ids.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Whether `tenant_id` already has a store, or is one of the allowed
+    /// tenants, so that `store` would not create a tenant for it
+    pub fn exists(&self, tenant_id: &str) -> bool {
+        self.stores.lock().unwrap().contains_key(tenant_id)
+            || self
+                .allowed
+                .as_ref()
+                .is_some_and(|allowed| allowed.contains(tenant_id))
+    }
+
+    /// The tenants that have a store
+    pub fn tenant_ids(&self) -> Vec<String> {
+        self.stores.lock().unwrap().keys().cloned().collect()
+    }
+
+    fn get_or_create(&self, tenant_id: &str) -> Result<Arc<S>, Error> {
+        validate_tenant_id(tenant_id)?;
+
+        if let Some(allowed) = &self.allowed
+            && !allowed.contains(tenant_id)
+        {
+            return Err(Error::not_found(tenant_id.to_string()));
+        }
+
+        let mut stores = self.stores.lock().unwrap();
+        if let Some(store) = stores.get(tenant_id) {
+            return Ok(Arc::clone(store));
+        }
+
+        let store = (self.factory)(tenant_id).map_err(|error| {
+            Error::internal_error(format!(
+                "creating the store for tenant {tenant_id} failed: {error:#}"
+            ))
+        })?;
+        let store = Arc::new(store);
+        stores.insert(tenant_id.to_string(), Arc::clone(&store));
+
+        Ok(store)
+    }
+}
+
+impl<S: ProviderStore + Send> TenantStores for TenantStoreMap<S> {
+    type Store = Arc<S>;
+
+    async fn store(&self, tenant_id: &str) -> Result<Arc<S>, Error> {
+        self.get_or_create(tenant_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{CreateUserRequest, InMemoryProviderStore, Provider};
+
+    #[test]
+    fn test_tenant_ids() {
+        for tenant_id in ["dunder-mifflin", "sabre_2", "A"] {
+            assert!(validate_tenant_id(tenant_id).is_ok(), "{tenant_id}");
+        }
+
+        for tenant_id in ["", "../etc", "a b", "é", &"a".repeat(65)] {
+            assert!(validate_tenant_id(tenant_id).is_err(), "{tenant_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tenant_stores() {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let provider = Provider::new(log, InMemoryProviderStore::new());
+        let tenants = TenantStoreMap::new(|_| Ok(InMemoryProviderStore::new()));
+
+        let request = || CreateUserRequest {
+            name: String::from("jhalpert"),
+            active: None,
+            external_id: None,
+            groups: None,
+        };
+
+        // The same user name can be used in every tenant, but only once in
+        // each
+        for tenant_id in ["dunder-mifflin", "sabre"] {
+            let tenant = Tenant {
+                id: tenant_id.to_string(),
+                base_url: format!("https://scim.example.com/{tenant_id}/v2"),
+            };
+            let provider = provider
+                .for_store(tenants.store(tenant_id).await.unwrap())
+                .with_tenant(tenant.clone());
+
+            let response = provider
+                .create_user(&Default::default(), request())
+                .await
+                .unwrap();
+            assert!(response.meta.location.starts_with(&tenant.base_url));
+
+            let error = provider
+                .create_user(&Default::default(), request())
+                .await
+                .unwrap_err();
+            assert_eq!(error.status(), http::StatusCode::CONFLICT);
+        }
+
+        assert_eq!(tenants.tenant_ids(), ["dunder-mifflin", "sabre"]);
+        assert!(tenants.store("../sabre").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_allowed_tenants() {
+        let tenants = TenantStoreMap::new(|_| Ok(InMemoryProviderStore::new()))
+            .with_allowed_tenants(["dunder-mifflin"]);
+        assert!(tenants.exists("dunder-mifflin"));
+        assert!(!tenants.exists("sabre"));
+
+        assert!(tenants.store("dunder-mifflin").await.is_ok());
+
+        // Other tenants are not found, and get no store
+        let error = tenants.store("sabre").await.err().unwrap();
+        assert_eq!(error.status(), http::StatusCode::NOT_FOUND);
+        assert_eq!(tenants.tenant_ids(), ["dunder-mifflin"]);
+    }
+}
@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+
+use schemars::JsonSchema;
+use schemars::SchemaGenerator;
+use schemars::schema::Schema;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A map that remembers what every entry it changed looked like beforehand,
+/// so that the changes can be written to a journal, or undone if they cannot
+/// be.
+///
+/// Reads go through `Deref`, and every write goes through one of the methods
+/// below, so that nothing is changed without being tracked.
+#[derive(Clone)]
+pub(crate) struct TrackedMap<K, V> {
+    entries: BTreeMap<K, V>,
+
+    /// The value of each key from before its first change since the last
+    /// commit, or None if the key was not there
+    before: BTreeMap<K, Option<V>>,
+}
+
+impl<K, V> Default for TrackedMap<K, V> {
+    fn default() -> Self {
+        Self { entries: BTreeMap::new(), before: BTreeMap::new() }
+    }
+}
+
+impl<K, V> Deref for TrackedMap<K, V> {
+    type Target = BTreeMap<K, V>;
+
+    fn deref(&self) -> &BTreeMap<K, V> {
+        &self.entries
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> TrackedMap<K, V> {
+    fn track(&mut self, key: &K) {
+        if !self.before.contains_key(key) {
+            self.before.insert(key.clone(), self.entries.get(key).cloned());
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.track(&key);
+        self.entries.insert(key, value)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+
+        self.track(&key.to_owned());
+        self.entries.remove(key)
+    }
+
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ToOwned<Owned = K> + ?Sized,
+    {
+        if !self.entries.contains_key(key) {
+            return None;
+        }
+
+        self.track(&key.to_owned());
+        self.entries.get_mut(key)
+    }
+
+    pub fn get_or_insert_default(&mut self, key: K) -> &mut V
+    where
+        V: Default,
+    {
+        self.track(&key);
+        self.entries.entry(key).or_default()
+    }
+
+    /// Change every value that `matches`, with `update`
+    pub fn update_where(
+        &mut self,
+        matches: impl Fn(&V) -> bool,
+        mut update: impl FnMut(&mut V),
+    ) {
+        let keys: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(_, value)| matches(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in keys {
+            self.track(&key);
+            update(self.entries.get_mut(&key).expect("key was just found"));
+        }
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        let keys: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(key, value)| !keep(key, value))
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// The current value of every key changed since the last commit
+    pub fn changes(&self) -> BTreeMap<K, Option<V>> {
+        self.before
+            .keys()
+            .map(|key| (key.clone(), self.entries.get(key).cloned()))
+            .collect()
+    }
+
+    /// Whether anything changed since the last commit
+    pub fn has_changes(&self) -> bool {
+        !self.before.is_empty()
+    }
+
+    /// Forget what the changed entries looked like beforehand
+    pub fn commit(&mut self) {
+        self.before.clear();
+    }
+
+    /// Undo every change since the last commit
+    pub fn rollback(&mut self) {
+        for (key, value) in std::mem::take(&mut self.before) {
+            match value {
+                Some(value) => self.entries.insert(key, value),
+                None => self.entries.remove(&key),
+            };
+        }
+    }
+
+    /// Apply `changes` from [`TrackedMap::changes`], without tracking them
+    pub fn apply(&mut self, changes: BTreeMap<K, Option<V>>) {
+        for (key, value) in changes {
+            match value {
+                Some(value) => self.entries.insert(key, value),
+                None => self.entries.remove(&key),
+            };
+        }
+    }
+}
+
+impl<K: Serialize, V: Serialize> Serialize for TrackedMap<K, V> {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        self.entries.serialize(serializer)
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for TrackedMap<K, V>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Self, D::Error> {
+        let entries = BTreeMap::deserialize(deserializer)?;
+        Ok(Self { entries, before: BTreeMap::new() })
+    }
+}
+
+impl<K: JsonSchema, V: JsonSchema> JsonSchema for TrackedMap<K, V> {
+    fn schema_name() -> String {
+        BTreeMap::<K, V>::schema_name()
+    }
+
+    fn json_schema(generator: &mut SchemaGenerator) -> Schema {
+        BTreeMap::<K, V>::json_schema(generator)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_changes_and_rollback() {
+        let mut map = TrackedMap::<String, u32>::default();
+        map.insert("a".to_string(), 1);
+        map.insert("b".to_string(), 2);
+        map.commit();
+
+        map.insert("a".to_string(), 10);
+        *map.get_mut("a").unwrap() += 1;
+        map.remove("b");
+        map.insert("c".to_string(), 3);
+        map.remove("missing");
+        map.update_where(|value| *value == 3, |value| *value = 30);
+
+        assert_eq!(
+            map.changes(),
+            BTreeMap::from([
+                ("a".to_string(), Some(11)),
+                ("b".to_string(), None),
+                ("c".to_string(), Some(30)),
+            ]),
+        );
+
+        let mut replayed = TrackedMap::default();
+        replayed.apply(BTreeMap::from([
+            ("a".to_string(), Some(1)),
+            ("b".to_string(), Some(2)),
+        ]));
+        replayed.apply(map.changes());
+        assert_eq!(*replayed, *map);
+
+        map.rollback();
+        assert_eq!(
+            *map,
+            BTreeMap::from([("a".to_string(), 1), ("b".to_string(), 2)]),
+        );
+        assert!(map.changes().is_empty());
+    }
+}
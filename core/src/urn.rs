@@ -6,3 +6,21 @@ pub const PATCHOP_URN: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
 pub const RESOURCETYPE_URN: &str =
     "urn:ietf:params:scim:schemas:core:2.0:ResourceType";
 pub const USER_URN: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+
+// Security Event Token event types for SCIM provisioning, from the IETF SCIM
+// events draft (draft-ietf-scim-events)
+pub const EVENT_CREATE_NOTICE_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:create:notice";
+pub const EVENT_CREATE_FULL_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:create:full";
+pub const EVENT_PATCH_NOTICE_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:patch:notice";
+pub const EVENT_PATCH_FULL_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:patch:full";
+pub const EVENT_PUT_NOTICE_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:put:notice";
+pub const EVENT_PUT_FULL_URN: &str = "urn:ietf:params:SCIM:event:prov:put:full";
+pub const EVENT_DELETE_URN: &str = "urn:ietf:params:SCIM:event:prov:delete";
+pub const EVENT_ACTIVATE_URN: &str = "urn:ietf:params:SCIM:event:prov:activate";
+pub const EVENT_DEACTIVATE_URN: &str =
+    "urn:ietf:params:SCIM:event:prov:deactivate";
@@ -8,15 +8,17 @@ use serde::{Deserialize, Serialize};
 use crate::utils::skip_serializing_list;
 use crate::{Resource, ResourceType, USER_URN};
 
-#[derive(Deserialize, JsonSchema, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct CreateUserRequest {
     #[serde(rename = "userName")]
     pub name: String,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub active: Option<bool>,
 
     /// An identifier for the resource as defined by the provisioning client
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub external_id: Option<String>,
 
     #[serde(skip_serializing_if = "skip_serializing_list::<UserGroup>")]
@@ -5,6 +5,7 @@
 use iddqd::IdOrdItem;
 use iddqd::IdOrdMap;
 use schemars::JsonSchema;
+use schemars::schema::{ArrayValidation, InstanceType, Schema, SchemaObject};
 use serde::Serialize;
 
 /// Skip serializing if optional list is None or empty.
@@ -32,6 +33,27 @@ where
     }
 }
 
+/// The schema of a `schemas` attribute that holds the one URN `urn`, for
+/// `#[schemars(schema_with)]`
+pub(crate) fn urn_list_schema(urn: &str) -> Schema {
+    let item = SchemaObject {
+        instance_type: Some(InstanceType::String.into()),
+        enum_values: Some(vec![urn.into()]),
+        ..Default::default()
+    };
+
+    SchemaObject {
+        instance_type: Some(InstanceType::Array.into()),
+        array: Some(Box::new(ArrayValidation {
+            items: Some(Schema::from(item).into()),
+            min_items: Some(1),
+            ..Default::default()
+        })),
+        ..Default::default()
+    }
+    .into()
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, JsonSchema)]
 pub enum ResourceType {
     User,
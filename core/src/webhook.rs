@@ -0,0 +1,857 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use slog::{Logger, debug, error, warn};
+use tokio::sync::{Notify, broadcast};
+use tokio::task::JoinHandle;
+
+use crate::{
+    ChangeEvent, DeadLetter, OutboxEntry, ProviderStore, ProviderStoreError,
+};
+
+/// The header carrying a webhook body's signature, as made by
+/// `webhook_signature`
+pub const WEBHOOK_SIGNATURE_HEADER: &str = "x-scim-signature";
+
+/// Sign a webhook body with an endpoint's secret: `sha256=` followed by the
+/// hex encoded HMAC SHA-256 of the body. Receivers should compute the same
+/// and compare in constant time.
+pub fn webhook_signature(secret: &[u8], body: &[u8]) -> String {
+    // HMAC accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(body);
+
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+
+    format!("sha256={hex}")
+}
+
+/// An HTTP endpoint that change events are POSTed to
+#[derive(Clone)]
+pub struct WebhookEndpoint {
+    pub url: String,
+
+    /// The key for the signature header
+    pub secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for WebhookEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Leave the secret out of logs
+        f.debug_struct("WebhookEndpoint").field("url", &self.url).finish()
+    }
+}
+
+/// How hard to try delivering an event before giving up on it
+#[derive(Debug, Clone)]
+pub struct WebhookRetryPolicy {
+    /// Including the first attempt
+    pub max_attempts: u32,
+
+    /// How long to wait after the first failed attempt. The wait doubles
+    /// after every failure after that, up to `max_backoff`.
+    pub initial_backoff: Duration,
+
+    pub max_backoff: Duration,
+
+    /// How long to wait for an endpoint to respond
+    pub timeout: Duration,
+}
+
+impl Default for WebhookRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 8,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl WebhookRetryPolicy {
+    /// How long to wait after `attempt` (counting from 1) failed
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// How often workers look for new outbox entries when they have not been
+/// told about any, for changes made by another process sharing the store
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The most outbox entries an endpoint has read but not finished with at
+/// once. It stops reading until some are delivered or given up on.
+const MAX_PENDING: usize = 100;
+
+/// POSTs the change events in a store's outbox to webhook endpoints.
+///
+/// Each endpoint reads the outbox from a cursor of its own, which is kept in
+/// the store, so a slow or failing endpoint does not hold up the others and
+/// delivery carries on where it left off after a restart. The outbox is
+/// acknowledged up to the oldest cursor, so the store must not have another
+/// relay acknowledging it too.
+///
+/// Events are sent in outbox order, but one that fails waits out its backoff
+/// without holding up those after it. An event that still fails after the
+/// retry policy's attempts is kept in the store as a dead letter. Dead
+/// letters can be inspected, and redelivered once the endpoint has been
+/// fixed.
+///
+/// Delivery is at least once: an endpoint may see an event again if it
+/// fails to respond in time or the process restarts, and should drop
+/// duplicates by the event's `id`.
+pub struct WebhookDispatcher<S> {
+    store: Arc<S>,
+    workers: Vec<WorkerHandle>,
+}
+
+/// What the dispatcher keeps of each endpoint's worker
+struct WorkerHandle {
+    url: String,
+    wake: Arc<Notify>,
+    redeliver: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl<S: ProviderStore + Send + 'static> WebhookDispatcher<S> {
+    /// Start a delivery task for each endpoint, reading the outbox of
+    /// `store`, which must have one. The events of a tenant's store are sent
+    /// with `tenant` set. Must be called from within a tokio runtime.
+    pub fn start(
+        log: Logger,
+        store: Arc<S>,
+        tenant: Option<String>,
+        endpoints: Vec<WebhookEndpoint>,
+        retry: WebhookRetryPolicy,
+    ) -> Self {
+        let client = reqwest::Client::new();
+        let consumers: Arc<Vec<String>> =
+            Arc::new(endpoints.iter().map(|e| e.url.clone()).collect());
+
+        let workers = endpoints
+            .into_iter()
+            .map(|endpoint| {
+                let wake = Arc::new(Notify::new());
+                let redeliver = Arc::new(AtomicBool::new(false));
+
+                let worker = Worker {
+                    log: log.new(slog::o!("webhook" => endpoint.url.clone())),
+                    client: client.clone(),
+                    store: Arc::clone(&store),
+                    tenant: tenant.clone(),
+                    consumers: Arc::clone(&consumers),
+                    endpoint,
+                    retry: retry.clone(),
+                    wake: Arc::clone(&wake),
+                    redeliver: Arc::clone(&redeliver),
+                    pending: BTreeMap::new(),
+                    read_up_to: 0,
+                    cursor: 0,
+                };
+
+                WorkerHandle {
+                    url: worker.endpoint.url.clone(),
+                    wake,
+                    redeliver,
+                    task: tokio::spawn(worker.run()),
+                }
+            })
+            .collect();
+
+        Self { store, workers }
+    }
+
+    /// Tell every endpoint that there are new entries in the outbox, rather
+    /// than leaving them to find out when they next poll it
+    pub fn notify(&self) {
+        for worker in &self.workers {
+            worker.wake.notify_one();
+        }
+    }
+
+    /// Notify the endpoints of every event received from `events`, which is
+    /// usually a `Provider::subscribe` for the same store, until the sender
+    /// is dropped. The events themselves are read from the outbox, so missing
+    /// some of them here does no harm.
+    pub fn forward(
+        self: Arc<Self>,
+        mut events: broadcast::Receiver<ChangeEvent>,
+    ) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            while let Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) =
+                events.recv().await
+            {
+                self.notify();
+            }
+        })
+    }
+
+    /// The dead letters of this dispatcher's endpoints
+    pub async fn dead_letters(
+        &self,
+    ) -> Result<Vec<DeadLetter>, ProviderStoreError> {
+        let mut dead_letters = self.store.list_dead_letters().await?;
+        dead_letters.retain(|dead_letter| {
+            self.workers.iter().any(|w| w.url == dead_letter.endpoint)
+        });
+        Ok(dead_letters)
+    }
+
+    /// Give every dead letter another round of attempts at its endpoint,
+    /// returning how many there were. Each is removed once it is delivered.
+    pub async fn redeliver_dead_letters(
+        &self,
+    ) -> Result<usize, ProviderStoreError> {
+        let dead_letters = self.dead_letters().await?;
+
+        for worker in &self.workers {
+            if dead_letters.iter().any(|d| d.endpoint == worker.url) {
+                worker.redeliver.store(true, Ordering::SeqCst);
+                worker.wake.notify_one();
+            }
+        }
+
+        Ok(dead_letters.len())
+    }
+}
+
+impl<S> Drop for WebhookDispatcher<S> {
+    fn drop(&mut self) {
+        for worker in &self.workers {
+            worker.task.abort();
+        }
+    }
+}
+
+/// An event that an endpoint has read and not finished with yet
+struct Pending {
+    event: ChangeEvent,
+
+    /// Failed attempts so far
+    attempts: u32,
+
+    /// When to make the next attempt
+    due: Instant,
+
+    /// Whether this is a dead letter being redelivered, rather than an entry
+    /// read from the outbox
+    dead_letter: bool,
+}
+
+/// Delivers the events for one endpoint
+struct Worker<S> {
+    log: Logger,
+    client: reqwest::Client,
+    store: Arc<S>,
+    tenant: Option<String>,
+
+    /// Every endpoint of the dispatcher, whose cursors are the outbox's
+    /// consumers
+    consumers: Arc<Vec<String>>,
+
+    endpoint: WebhookEndpoint,
+    retry: WebhookRetryPolicy,
+    wake: Arc<Notify>,
+    redeliver: Arc<AtomicBool>,
+
+    /// By outbox sequence
+    pending: BTreeMap<u64, Pending>,
+
+    /// The sequence of the newest outbox entry read
+    read_up_to: u64,
+
+    /// The cursor as last saved in the store
+    cursor: u64,
+}
+
+impl<S: ProviderStore + Send + 'static> Worker<S> {
+    async fn run(mut self) {
+        loop {
+            match self.store.outbox_cursor(&self.endpoint.url).await {
+                Ok(cursor) => {
+                    self.cursor = cursor;
+                    self.read_up_to = cursor;
+                    break;
+                }
+                Err(error) => {
+                    error!(self.log, "reading webhook cursor failed";
+                        "error" => ?error,
+                    );
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        }
+
+        loop {
+            if let Err(error) = self.step().await {
+                error!(self.log, "webhook store access failed";
+                    "error" => ?error,
+                );
+            }
+
+            let next_due = self.pending.values().map(|p| p.due).min();
+            let until = match next_due {
+                Some(due) => due.min(Instant::now() + POLL_INTERVAL),
+                None => Instant::now() + POLL_INTERVAL,
+            };
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep_until(until.into()) => {}
+            }
+        }
+    }
+
+    /// Read whatever is new, make every attempt that is due, and move the
+    /// cursor past everything that is finished with
+    async fn step(&mut self) -> Result<(), ProviderStoreError> {
+        if self.redeliver.swap(false, Ordering::SeqCst) {
+            self.load_dead_letters().await?;
+        }
+
+        let read = self.pending.values().filter(|p| !p.dead_letter).count();
+        if read < MAX_PENDING {
+            let entries = self
+                .store
+                .read_outbox_after(self.read_up_to, MAX_PENDING - read)
+                .await?;
+
+            for OutboxEntry { sequence, mut event } in entries {
+                if event.tenant.is_none() {
+                    event.tenant = self.tenant.clone();
+                }
+
+                self.read_up_to = sequence;
+                self.pending.entry(sequence).or_insert(Pending {
+                    event,
+                    attempts: 0,
+                    due: Instant::now(),
+                    dead_letter: false,
+                });
+            }
+        }
+
+        let now = Instant::now();
+        let due: Vec<u64> = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        for sequence in due {
+            self.attempt(sequence).await?;
+        }
+
+        self.advance_cursor().await
+    }
+
+    async fn load_dead_letters(&mut self) -> Result<(), ProviderStoreError> {
+        for dead_letter in self.store.list_dead_letters().await? {
+            if dead_letter.endpoint != self.endpoint.url {
+                continue;
+            }
+
+            self.pending.entry(dead_letter.sequence).or_insert(Pending {
+                event: dead_letter.event,
+                attempts: 0,
+                due: Instant::now(),
+                dead_letter: true,
+            });
+        }
+
+        Ok(())
+    }
+
+    async fn attempt(
+        &mut self,
+        sequence: u64,
+    ) -> Result<(), ProviderStoreError> {
+        let pending = self.pending.get_mut(&sequence).unwrap();
+        pending.attempts += 1;
+
+        let error = match deliver(
+            &self.client,
+            &self.endpoint,
+            &self.retry,
+            &pending.event,
+        )
+        .await
+        {
+            Ok(()) => {
+                debug!(self.log, "delivered webhook";
+                    "event_id" => &pending.event.id,
+                    "attempt" => pending.attempts,
+                );
+
+                if pending.dead_letter {
+                    self.store
+                        .remove_dead_letter(&self.endpoint.url, sequence)
+                        .await?;
+                }
+                self.pending.remove(&sequence);
+                return Ok(());
+            }
+
+            Err(error) => error,
+        };
+
+        if pending.attempts < self.retry.max_attempts {
+            let backoff = self.retry.backoff(pending.attempts);
+            warn!(self.log, "webhook delivery failed, will retry";
+                "event_id" => &pending.event.id,
+                "attempt" => pending.attempts,
+                "backoff" => ?backoff,
+                "error" => &error,
+            );
+
+            pending.due = Instant::now() + backoff;
+            return Ok(());
+        }
+
+        error!(self.log, "giving up on webhook";
+            "event_id" => &pending.event.id,
+            "attempts" => pending.attempts,
+            "error" => &error,
+        );
+
+        let dead_letter = DeadLetter {
+            endpoint: self.endpoint.url.clone(),
+            sequence,
+            event: pending.event.clone(),
+            attempts: pending.attempts,
+            last_error: error,
+            failed_at: Utc::now(),
+        };
+
+        // If the dead letter cannot be saved, try the event again later
+        // rather than lose it
+        pending.due = Instant::now() + self.retry.max_backoff;
+        self.store.put_dead_letter(dead_letter).await?;
+        self.pending.remove(&sequence);
+
+        Ok(())
+    }
+
+    /// Save the cursor if every entry up to a later one is finished with,
+    /// and acknowledge whatever every endpoint is finished with
+    async fn advance_cursor(&mut self) -> Result<(), ProviderStoreError> {
+        let oldest_pending = self
+            .pending
+            .iter()
+            .find(|(_, pending)| !pending.dead_letter)
+            .map(|(sequence, _)| *sequence);
+        let cursor = match oldest_pending {
+            Some(sequence) => sequence - 1,
+            None => self.read_up_to,
+        };
+
+        if cursor <= self.cursor {
+            return Ok(());
+        }
+
+        self.store.set_outbox_cursor(&self.endpoint.url, cursor).await?;
+        self.cursor = cursor;
+
+        let mut done = u64::MAX;
+        for consumer in self.consumers.iter() {
+            done = done.min(self.store.outbox_cursor(consumer).await?);
+        }
+        if done > 0 {
+            self.store.ack_outbox(done).await?;
+        }
+
+        Ok(())
+    }
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    endpoint: &WebhookEndpoint,
+    retry: &WebhookRetryPolicy,
+    event: &ChangeEvent,
+) -> Result<(), String> {
+    let body = serde_json::to_vec(event)
+        .map_err(|e| format!("serializing event failed: {e}"))?;
+    let signature = webhook_signature(&endpoint.secret, &body);
+
+    let response = client
+        .post(&endpoint.url)
+        .timeout(retry.timeout)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(WEBHOOK_SIGNATURE_HEADER, signature)
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("endpoint responded {}", response.status()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        ChangeEventKind, CreateUserRequest, InMemoryProviderStore, User,
+    };
+
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicUsize;
+
+    use dropshot::{
+        ApiDescription, ConfigDropshot, HttpError,
+        HttpResponseUpdatedNoContent, HttpServer, HttpServerStarter,
+        RequestContext, UntypedBody, endpoint,
+    };
+
+    /// Stands in for an application's webhook receiver
+    #[derive(Default)]
+    struct Receiver {
+        /// Respond with a 500 to this many requests before accepting any
+        failures_left: AtomicUsize,
+
+        /// Respond with a 500 to every request whose body contains this
+        failing: Mutex<Option<String>>,
+
+        /// (signature header, body) of each accepted request
+        received: Mutex<Vec<(String, Vec<u8>)>>,
+    }
+
+    #[endpoint {
+        method = POST,
+        path = "/hook"
+    }]
+    async fn receive(
+        rqctx: RequestContext<Arc<Receiver>>,
+        body: UntypedBody,
+    ) -> Result<HttpResponseUpdatedNoContent, HttpError> {
+        let receiver = rqctx.context();
+
+        let failed = receiver
+            .failures_left
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                n.checked_sub(1)
+            })
+            .is_ok();
+        let failing =
+            receiver.failing.lock().unwrap().as_ref().is_some_and(|failing| {
+                String::from_utf8_lossy(body.as_bytes()).contains(failing)
+            });
+        if failed || failing {
+            return Err(HttpError::for_internal_error(String::from("down")));
+        }
+
+        let signature = rqctx
+            .request
+            .headers()
+            .get(WEBHOOK_SIGNATURE_HEADER)
+            .map(|value| value.to_str().unwrap().to_string())
+            .unwrap_or_default();
+
+        receiver
+            .received
+            .lock()
+            .unwrap()
+            .push((signature, body.as_bytes().to_vec()));
+
+        Ok(HttpResponseUpdatedNoContent())
+    }
+
+    fn start_receiver(failures: usize) -> HttpServer<Arc<Receiver>> {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let mut api = ApiDescription::new();
+        api.register(receive).unwrap();
+
+        let receiver = Receiver {
+            failures_left: AtomicUsize::new(failures),
+            ..Default::default()
+        };
+
+        let config = ConfigDropshot {
+            bind_address: "127.0.0.1:0".parse().unwrap(),
+            ..Default::default()
+        };
+
+        HttpServerStarter::new(&config, api, Arc::new(receiver), &log)
+            .unwrap()
+            .start()
+    }
+
+    fn endpoint_url(server: &HttpServer<Arc<Receiver>>) -> String {
+        format!("http://{}/hook", server.local_addr())
+    }
+
+    fn retry(
+        max_attempts: u32,
+        initial_backoff: Duration,
+    ) -> WebhookRetryPolicy {
+        WebhookRetryPolicy {
+            max_attempts,
+            initial_backoff,
+            max_backoff: initial_backoff * 4,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    fn dispatcher(
+        server: &HttpServer<Arc<Receiver>>,
+        store: &Arc<InMemoryProviderStore>,
+        retry: WebhookRetryPolicy,
+    ) -> WebhookDispatcher<InMemoryProviderStore> {
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+
+        WebhookDispatcher::start(
+            log,
+            Arc::clone(store),
+            None,
+            vec![WebhookEndpoint {
+                url: endpoint_url(server),
+                secret: b"secret".to_vec(),
+            }],
+            retry,
+        )
+    }
+
+    async fn create_user(store: &InMemoryProviderStore, name: &str) {
+        store
+            .create_user(CreateUserRequest {
+                name: name.to_string(),
+                active: Some(true),
+                external_id: None,
+                groups: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    /// The names of the users created by each accepted request
+    fn received_names(receiver: &Receiver) -> Vec<String> {
+        receiver
+            .received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, body)| {
+                let event: ChangeEvent = serde_json::from_slice(body).unwrap();
+                match event.kind {
+                    ChangeEventKind::UserCreated { user } => user.name,
+                    kind => panic!("unexpected {kind:?}"),
+                }
+            })
+            .collect()
+    }
+
+    /// Wait for `condition`, or panic if it takes too long
+    async fn wait_for_async<F: Future<Output = bool>>(
+        condition: impl Fn() -> F,
+    ) {
+        for _ in 0..500 {
+            if condition().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    /// Wait for `condition`, or panic if it takes too long
+    async fn wait_for(condition: impl Fn() -> bool) {
+        for _ in 0..500 {
+            if condition() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let retry = WebhookRetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+        };
+
+        let backoffs: Vec<u64> =
+            (1..=6).map(|attempt| retry.backoff(attempt).as_secs()).collect();
+        assert_eq!(backoffs, [1, 2, 4, 8, 10, 10]);
+        assert_eq!(retry.backoff(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_signed_delivery() {
+        let server = start_receiver(0);
+        let log = slog::Logger::root(slog::Discard, slog::o!());
+        let store = Arc::new(InMemoryProviderStore::new().with_outbox());
+        let provider = crate::Provider::new(log, Arc::clone(&store));
+
+        let dispatcher = Arc::new(dispatcher(
+            &server,
+            &store,
+            retry(1, Duration::from_millis(10)),
+        ));
+        dispatcher.forward(provider.subscribe());
+
+        provider
+            .create_user(
+                &crate::OperationContext::default(),
+                CreateUserRequest {
+                    name: String::from("jhalpert"),
+                    active: Some(true),
+                    external_id: None,
+                    groups: None,
+                },
+            )
+            .await
+            .unwrap();
+
+        let receiver = server.app_private();
+        wait_for(|| !receiver.received.lock().unwrap().is_empty()).await;
+
+        let (signature, body) = receiver.received.lock().unwrap()[0].clone();
+        assert_eq!(signature, webhook_signature(b"secret", &body));
+        assert_ne!(signature, webhook_signature(b"wrong", &body));
+
+        let event: ChangeEvent = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(
+            event.kind,
+            ChangeEventKind::UserCreated { user: User { name, .. } }
+                if name == "jhalpert"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let server = start_receiver(2);
+        let store = Arc::new(InMemoryProviderStore::new().with_outbox());
+        let dispatcher =
+            dispatcher(&server, &store, retry(3, Duration::from_millis(10)));
+
+        create_user(&store, "jhalpert").await;
+        create_user(&store, "dschrute").await;
+        dispatcher.notify();
+
+        // The first event succeeds on its third attempt
+        let receiver = server.app_private();
+        wait_for(|| receiver.received.lock().unwrap().len() == 2).await;
+
+        let mut names = received_names(receiver);
+        names.sort();
+        assert_eq!(names, ["dschrute", "jhalpert"]);
+        assert!(dispatcher.dead_letters().await.unwrap().is_empty());
+
+        // Once every endpoint has the events, they leave the outbox
+        wait_for_async(|| async {
+            store.read_outbox(100).await.unwrap().is_empty()
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_failure_does_not_block() {
+        let server = start_receiver(0);
+        let receiver = server.app_private();
+        *receiver.failing.lock().unwrap() = Some(String::from("jhalpert"));
+
+        let store = Arc::new(InMemoryProviderStore::new().with_outbox());
+        let dispatcher =
+            dispatcher(&server, &store, retry(2, Duration::from_secs(60)));
+
+        create_user(&store, "jhalpert").await;
+        create_user(&store, "dschrute").await;
+        dispatcher.notify();
+
+        // The second event is delivered while the first waits out its
+        // backoff
+        wait_for(|| receiver.received.lock().unwrap().len() == 1).await;
+        assert_eq!(received_names(receiver), ["dschrute"]);
+
+        // The cursor stays before the first, so it is not lost if the process
+        // stops before it is delivered
+        let first = store.read_outbox(1).await.unwrap()[0].sequence;
+        let cursor = store.outbox_cursor(&endpoint_url(&server)).await.unwrap();
+        assert!(cursor < first, "cursor {cursor} passed {first}");
+    }
+
+    #[tokio::test]
+    async fn test_resume_from_cursor() {
+        let server = start_receiver(0);
+        let store = Arc::new(InMemoryProviderStore::new().with_outbox());
+
+        create_user(&store, "jhalpert").await;
+        create_user(&store, "dschrute").await;
+
+        // As if an earlier dispatcher delivered the first event and stopped
+        let first = store.read_outbox(1).await.unwrap()[0].sequence;
+        store.set_outbox_cursor(&endpoint_url(&server), first).await.unwrap();
+
+        let _dispatcher =
+            dispatcher(&server, &store, retry(1, Duration::from_millis(10)));
+
+        let receiver = server.app_private();
+        wait_for(|| !receiver.received.lock().unwrap().is_empty()).await;
+        assert_eq!(received_names(receiver), ["dschrute"]);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let server = start_receiver(usize::MAX);
+        let store = Arc::new(InMemoryProviderStore::new().with_outbox());
+        let dispatcher =
+            dispatcher(&server, &store, retry(3, Duration::from_millis(10)));
+
+        create_user(&store, "jhalpert").await;
+        let entry = store.read_outbox(1).await.unwrap().remove(0);
+        dispatcher.notify();
+
+        wait_for_async(|| async {
+            !dispatcher.dead_letters().await.unwrap().is_empty()
+        })
+        .await;
+        let dead_letters = dispatcher.dead_letters().await.unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].sequence, entry.sequence);
+        assert_eq!(dead_letters[0].event, entry.event);
+        assert_eq!(dead_letters[0].attempts, 3);
+        assert!(dead_letters[0].last_error.contains("500"));
+
+        // The dead letter is kept in the store, so the outbox entry can go
+        assert_eq!(store.list_dead_letters().await.unwrap(), dead_letters);
+        wait_for_async(|| async {
+            store.read_outbox(100).await.unwrap().is_empty()
+        })
+        .await;
+
+        // Once the endpoint recovers, the dead letter can be redelivered
+        let receiver = server.app_private();
+        receiver.failures_left.store(0, Ordering::SeqCst);
+        assert_eq!(dispatcher.redeliver_dead_letters().await.unwrap(), 1);
+
+        wait_for(|| receiver.received.lock().unwrap().len() == 1).await;
+        wait_for_async(|| async {
+            dispatcher.dead_letters().await.unwrap().is_empty()
+        })
+        .await;
+    }
+}
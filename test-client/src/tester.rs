@@ -16,6 +16,7 @@ use std::str::FromStr;
 use unicase::UniCase;
 use uuid::Uuid;
 
+use scim2_rs::Filter;
 use scim2_rs::Group;
 use scim2_rs::ListResponse;
 use scim2_rs::Resource;
@@ -23,6 +24,7 @@ use scim2_rs::ResourceType;
 use scim2_rs::SingleResourceResponse;
 use scim2_rs::StoredMeta;
 use scim2_rs::StoredParts;
+use scim2_rs::TypedListResponse;
 use scim2_rs::User;
 
 pub struct Tester {
@@ -166,22 +168,18 @@ impl Tester {
     {
         let response: SingleResourceResponse = result.json().await?;
 
-        if !response.resource.schemas.contains(&R::schema()) {
-            bail!("response does not contain {} schema", R::resource_type());
-        }
-
-        let resource: R =
-            serde_json::from_value(serde_json::to_value(&response.resource)?)?;
+        let location = response.meta.location.clone();
+        let parts: StoredParts<R> = response.try_into()?;
 
-        // TODO: This will work with the test suite but it does not yet work
-        // when using this test client against a real nexus implementation.
-        #[cfg(test)]
-        assert_eq!(
-            response.meta.location,
-            format!("{}/{}s/{}", self.url, R::resource_type(), resource.id())
-        );
+        // RFC 7643, section 3.1: the location is the resource's URI. A
+        // provider behind a proxy may not know the URL it is reached at, so
+        // only the path under the base URL has to match.
+        let path = format!("/{}s/{}", R::resource_type(), parts.resource.id());
+        if !location.ends_with(&path) {
+            bail!("location {location} is not a URI ending in {path}");
+        }
 
-        Ok(StoredParts { resource, meta: response.meta.into() })
+        Ok(parts)
     }
 
     async fn result_as_resource_list<R>(
@@ -192,9 +190,7 @@ impl Tester {
         R: Resource + DeserializeOwned + Serialize,
     {
         let response: ListResponse = result.json().await?;
-
-        let resources: Vec<R> =
-            serde_json::from_value(serde_json::to_value(&response.resources)?)?;
+        let response: TypedListResponse<R> = response.try_into()?;
 
         if response.total_results != response.resources.len() {
             // TODO: totalResults may be larger than the returned resources when
@@ -206,7 +202,7 @@ impl Tester {
             );
         }
 
-        Ok(resources)
+        Ok(response.resources.into_iter().map(|r| r.resource).collect())
     }
 
     async fn nonexistent_resource_tests(&self) -> anyhow::Result<()> {
@@ -450,7 +446,10 @@ impl Tester {
         jim: &User,
     ) -> anyhow::Result<()> {
         let mut url: Url = format!("{}/Users", self.url).parse().unwrap();
-        url.set_query(Some(&format!("filter=username eq \"{}\"", jim.name)));
+        url.query_pairs_mut().append_pair(
+            "filter",
+            &Filter::attr("username").eq(&jim.name).to_string(),
+        );
 
         let result = self.get(url).await?;
 
@@ -981,7 +980,7 @@ impl Tester {
           "Operations": [
             {
               "op": "remove",
-              "path": format!("members[value eq \"{}\"]", jim.id)
+              "path": format!("members[{}]", Filter::attr("value").eq(&jim.id))
             }
           ]
         });
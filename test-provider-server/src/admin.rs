@@ -0,0 +1,406 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Operator endpoints for soft deleted resources, the change event outbox,
+//! resource history and webhook dead letters. These are not part of SCIM.
+
+use super::*;
+
+use chrono::{DateTime, Utc};
+
+fn json_response<T: serde::Serialize>(
+    value: &T,
+) -> Result<Response<Body>, http::Error> {
+    let body = match serde_json::to_string(value) {
+        Ok(body) => body,
+        Err(e) => {
+            return scim2_rs::Error::internal_error(e.to_string())
+                .to_http_response();
+        }
+    };
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Body::from(body))
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/deleted/Users"
+}]
+pub async fn list_deleted_users(
+    rqctx: RequestContext<Arc<ServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.list_deleted_users().await {
+            Ok(users) => json_response(&users),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/deleted/Groups"
+}]
+pub async fn list_deleted_groups(
+    rqctx: RequestContext<Arc<ServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.list_deleted_groups().await {
+            Ok(groups) => json_response(&groups),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RestoreUserPathParam {
+    user_id: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/deleted/Users/{user_id}/restore"
+}]
+pub async fn restore_user(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<RestoreUserPathParam>,
+) -> Result<Response<Body>, HttpError> {
+    let (context, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.restore_user(&context, &path_param.user_id).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct RestoreGroupPathParam {
+    group_id: String,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/deleted/Groups/{group_id}/restore"
+}]
+pub async fn restore_group(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<RestoreGroupPathParam>,
+) -> Result<Response<Body>, HttpError> {
+    let (context, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.restore_group(&context, &path_param.group_id).await {
+            Ok(response) => response.to_http_response(StatusCode::OK),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(serde::Serialize)]
+struct PurgeResponse {
+    purged: usize,
+}
+
+/// Purge soft deleted resources that are past the retention period now,
+/// rather than waiting for the periodic purge.
+#[endpoint {
+    method = POST,
+    path = "/admin/deleted/purge"
+}]
+pub async fn purge_deleted(
+    rqctx: RequestContext<Arc<ServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.purge_expired().await {
+            Ok(purged) => json_response(&PurgeResponse { purged }),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ReadOutboxQueryParams {
+    limit: Option<usize>,
+}
+
+/// The oldest change events that have not been acknowledged yet
+#[endpoint {
+    method = GET,
+    path = "/admin/outbox"
+}]
+pub async fn read_outbox(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    query_params: Query<ReadOutboxQueryParams>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let limit = query_params.into_inner().limit.unwrap_or(100);
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.read_outbox(limit).await {
+            Ok(entries) => json_response(&entries),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AckOutboxRequest {
+    /// Acknowledge every entry up to and including this one
+    sequence: u64,
+}
+
+#[derive(serde::Serialize)]
+struct AckOutboxResponse {
+    acked: usize,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/outbox/ack"
+}]
+pub async fn ack_outbox(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    body: TypedBody<AckOutboxRequest>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let request = body.into_inner();
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.ack_outbox(request.sequence).await {
+            Ok(acked) => json_response(&AckOutboxResponse { acked }),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserHistoryPathParam {
+    user_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct GroupHistoryPathParam {
+    group_id: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct AsOfQueryParams {
+    at: DateTime<Utc>,
+}
+
+/// Every revision of a user, oldest first
+#[endpoint {
+    method = GET,
+    path = "/admin/history/Users/{user_id}"
+}]
+pub async fn list_user_revisions(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<UserHistoryPathParam>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.list_user_revisions(&path_param.user_id).await {
+            Ok(revisions) => json_response(&revisions),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+/// Every revision of a group, oldest first
+#[endpoint {
+    method = GET,
+    path = "/admin/history/Groups/{group_id}"
+}]
+pub async fn list_group_revisions(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<GroupHistoryPathParam>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+
+    let result: Result<Response<Body>, http::Error> =
+        match provider.list_group_revisions(&path_param.group_id).await {
+            Ok(revisions) => json_response(&revisions),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+/// A user as it was at a point in time
+#[endpoint {
+    method = GET,
+    path = "/admin/history/Users/{user_id}/as-of"
+}]
+pub async fn get_user_at(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<UserHistoryPathParam>,
+    query_params: Query<AsOfQueryParams>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let result: Result<Response<Body>, http::Error> = match provider
+        .get_user_at(&path_param.user_id, query_params.at)
+        .await
+    {
+        Ok(response) => response.to_http_response(StatusCode::OK),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(HttpError::from)
+}
+
+/// A group as it was at a point in time
+#[endpoint {
+    method = GET,
+    path = "/admin/history/Groups/{group_id}/as-of"
+}]
+pub async fn get_group_at(
+    rqctx: RequestContext<Arc<ServerContext>>,
+    path_param: Path<GroupHistoryPathParam>,
+    query_params: Query<AsOfQueryParams>,
+) -> Result<Response<Body>, HttpError> {
+    let (_, provider) = match authorize(&rqctx).await {
+        Ok(authorized) => authorized,
+        Err(error) => return auth_error_response(error),
+    };
+
+    let path_param = path_param.into_inner();
+    let query_params = query_params.into_inner();
+
+    let result: Result<Response<Body>, http::Error> = match provider
+        .get_group_at(&path_param.group_id, query_params.at)
+        .await
+    {
+        Ok(response) => response.to_http_response(StatusCode::OK),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(HttpError::from)
+}
+
+fn webhooks_not_configured() -> HttpError {
+    HttpError::for_not_found(None, "webhooks are not configured".to_string())
+}
+
+#[endpoint {
+    method = GET,
+    path = "/admin/webhooks/dead-letters"
+}]
+pub async fn list_webhook_dead_letters(
+    rqctx: RequestContext<Arc<ServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    if let Err(error) = authorize(&rqctx).await {
+        return auth_error_response(error);
+    }
+
+    let apictx = rqctx.context();
+
+    let Some(webhooks) = &apictx.webhooks else {
+        return Err(webhooks_not_configured());
+    };
+
+    let result: Result<Response<Body>, http::Error> =
+        match webhooks.dead_letters().await {
+            Ok(dead_letters) => json_response(&dead_letters),
+            Err(error) => error.to_http_response(),
+        };
+
+    result.map_err(HttpError::from)
+}
+
+#[derive(serde::Serialize)]
+struct RedeliverResponse {
+    redelivered: usize,
+}
+
+#[endpoint {
+    method = POST,
+    path = "/admin/webhooks/dead-letters/redeliver"
+}]
+pub async fn redeliver_webhook_dead_letters(
+    rqctx: RequestContext<Arc<ServerContext>>,
+) -> Result<Response<Body>, HttpError> {
+    if let Err(error) = authorize(&rqctx).await {
+        return auth_error_response(error);
+    }
+
+    let apictx = rqctx.context();
+
+    let Some(webhooks) = &apictx.webhooks else {
+        return Err(webhooks_not_configured());
+    };
+
+    let result: Result<Response<Body>, http::Error> = match webhooks
+        .redeliver_dead_letters()
+        .await
+    {
+        Ok(redelivered) => json_response(&RedeliverResponse { redelivered }),
+        Err(error) => error.to_http_response(),
+    };
+
+    result.map_err(HttpError::from)
+}
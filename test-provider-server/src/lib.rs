@@ -3,6 +3,7 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use anyhow::Context;
+use chrono::TimeDelta;
 use dropshot::ApiDescription;
 use dropshot::Body;
 use dropshot::ConfigDropshot;
@@ -18,43 +19,386 @@ use dropshot::endpoint;
 use http::Response;
 use http::StatusCode;
 use schemars::JsonSchema;
+use scim2_rs::Authenticator;
+use scim2_rs::ScimContext;
+use scim2_rs::ScimRequest;
+use scim2_rs::TenantStores;
 use serde::Deserialize;
 use slog::Drain;
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::time::Duration;
 
-mod groups;
-mod server;
-mod users;
+mod admin;
+mod store;
+
+pub use store::ServerStore;
+pub use store::StoreConfig;
+
+// Re-exported so that scim2-rs's own tests, which see a separate copy of the
+// crate, can build a `ServerConfig`
+pub use scim2_rs::WebhookEndpoint;
+pub use scim2_rs::WebhookRetryPolicy;
+
+/// How often soft deleted resources past their retention period are purged
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Configuration for the test provider server
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    pub store: StoreConfig,
+
+    /// If set, deletes are soft deletes, kept for this long before they are
+    /// purged.
+    pub soft_delete_retention: Option<TimeDelta>,
+
+    pub user_delete_policy: scim2_rs::UserDeletePolicy,
+
+    /// Record change events in the store's outbox
+    pub outbox: bool,
+
+    /// Keep every revision of every user and group, for point-in-time reads
+    pub history: bool,
+
+    /// POST change events to these endpoints. They are delivered from the
+    /// store's outbox, which is kept whether or not `outbox` is set.
+    pub webhooks: Vec<scim2_rs::WebhookEndpoint>,
+
+    pub webhook_retry: scim2_rs::WebhookRetryPolicy,
+
+    /// The URL clients reach the server at, under which its own SCIM base
+    /// URL is `/v2` and each tenant's is `/tenants/{tenant_id}/v2`. Used for
+    /// `meta.location`. Defaults to the address the server is bound to.
+    pub public_url: Option<String>,
+
+    /// Append an audit record for every operation to this file, as JSON lines
+    pub audit_log: Option<PathBuf>,
+
+    /// Require one of these bearer tokens on every request other than
+    /// discovery. If empty, requests are not authenticated.
+    pub bearer_tokens: Vec<String>,
+
+    /// Require a JWT access token accepted by this on every request other
+    /// than discovery. Cannot be combined with `bearer_tokens`.
+    pub jwt: Option<scim2_rs::JwtAuthenticator>,
+
+    /// Serve tenants under `/tenants/{tenant_id}/v2`, each with a store of
+    /// its own that is created on first use. Requests from clients that
+    /// belong to a tenant go to that tenant's store wherever they are made.
+    /// Only authenticated clients can create a tenant, unless it is one of
+    /// `allowed_tenants`.
+    pub tenants: bool,
+
+    /// If not empty, the only tenants that are served
+    pub allowed_tenants: Vec<String>,
+
+    /// Require one of these bearer tokens, given as tenant and token, from
+    /// the clients of a tenant. These are in addition to `bearer_tokens`.
+    pub tenant_bearer_tokens: Vec<(String, String)>,
+}
+
+type ServerAuditSink = Option<scim2_rs::JsonLinesAuditSink>;
+
+/// The provider that serves a request, scoped to the tenant it is for
+type ScopedProvider<'a> =
+    scim2_rs::Provider<Arc<ServerStore>, &'a (), &'a ServerAuditSink>;
 
 pub struct ServerContext {
-    provider: scim2_rs::Provider<scim2_rs::InMemoryProviderStore>,
+    provider: scim2_rs::Provider<Arc<ServerStore>, (), ServerAuditSink>,
+    tenants: Option<scim2_rs::TenantStoreMap<ServerStore>>,
+    webhooks: Option<Webhooks>,
+    authenticator: Option<ServerAuthenticator>,
+
+    /// `ServerConfig::public_url`, or the bound address once it is known
+    public_url: OnceLock<String>,
+}
+
+type ServerWebhookDispatcher = scim2_rs::WebhookDispatcher<ServerStore>;
+
+/// Delivers change events from the outbox of the server's own store, and of
+/// every tenant's store, to the webhook endpoints
+struct Webhooks {
+    log: slog::Logger,
+    endpoints: Vec<scim2_rs::WebhookEndpoint>,
+    retry: scim2_rs::WebhookRetryPolicy,
+    server: ServerWebhookDispatcher,
+
+    /// Started along with the tenant's store, by tenant id
+    tenants: Mutex<BTreeMap<String, Arc<ServerWebhookDispatcher>>>,
+}
+
+impl Webhooks {
+    /// Start delivering from the outbox of `tenant_id`'s store, unless that
+    /// has already started
+    fn start_tenant(&self, tenant_id: &str, store: &Arc<ServerStore>) {
+        let mut tenants = self.tenants.lock().unwrap();
+        if !tenants.contains_key(tenant_id) {
+            let dispatcher = scim2_rs::WebhookDispatcher::start(
+                self.log.new(slog::o!("tenant" => tenant_id.to_string())),
+                Arc::clone(store),
+                Some(tenant_id.to_string()),
+                self.endpoints.clone(),
+                self.retry.clone(),
+            );
+            tenants.insert(tenant_id.to_string(), Arc::new(dispatcher));
+        }
+    }
+
+    fn tenant_dispatchers(&self) -> Vec<Arc<ServerWebhookDispatcher>> {
+        self.tenants.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Tell whichever dispatcher reads the outbox that `event` was recorded
+    /// in about it
+    fn notify(&self, event: &scim2_rs::ChangeEvent) {
+        match &event.tenant {
+            None => self.server.notify(),
+            Some(tenant_id) => {
+                if let Some(dispatcher) =
+                    self.tenants.lock().unwrap().get(tenant_id)
+                {
+                    dispatcher.notify();
+                }
+            }
+        }
+    }
+
+    fn notify_all(&self) {
+        self.server.notify();
+        for dispatcher in self.tenant_dispatchers() {
+            dispatcher.notify();
+        }
+    }
+
+    async fn dead_letters(
+        &self,
+    ) -> Result<Vec<scim2_rs::DeadLetter>, scim2_rs::Error> {
+        let mut dead_letters = self
+            .server
+            .dead_letters()
+            .await
+            .map_err(|e| self.store_error(e, "reading dead letters"))?;
+        for dispatcher in self.tenant_dispatchers() {
+            dead_letters.extend(
+                dispatcher
+                    .dead_letters()
+                    .await
+                    .map_err(|e| self.store_error(e, "reading dead letters"))?,
+            );
+        }
+        Ok(dead_letters)
+    }
+
+    async fn redeliver_dead_letters(&self) -> Result<usize, scim2_rs::Error> {
+        let mut redelivered =
+            self.server.redeliver_dead_letters().await.map_err(|e| {
+                self.store_error(e, "redelivering dead letters")
+            })?;
+        for dispatcher in self.tenant_dispatchers() {
+            redelivered +=
+                dispatcher.redeliver_dead_letters().await.map_err(|e| {
+                    self.store_error(e, "redelivering dead letters")
+                })?;
+        }
+        Ok(redelivered)
+    }
+
+    /// Log a store error, and describe it with `context` alone
+    fn store_error(
+        &self,
+        error: scim2_rs::ProviderStoreError,
+        context: &str,
+    ) -> scim2_rs::Error {
+        match error {
+            scim2_rs::ProviderStoreError::StoreError(error) => {
+                slog::error!(
+                    self.log,
+                    "{:#?}",
+                    error.context(context.to_string())
+                );
+                scim2_rs::Error::internal_error(context.to_string())
+            }
+            scim2_rs::ProviderStoreError::Scim(error) => error,
+        }
+    }
+}
+
+impl ServerContext {
+    /// The provider for `tenant_id`, or for the server's own store if there
+    /// is no tenant. A tenant that does not exist yet is only created if
+    /// `create` is set.
+    async fn provider_for(
+        &self,
+        tenant_id: Option<String>,
+        create: bool,
+    ) -> Result<ScopedProvider<'_>, scim2_rs::Error> {
+        // Before the server is bound there are no requests to serve
+        let public_url = self.public_url.get().map_or("", String::as_str);
+
+        let Some(tenant_id) = tenant_id else {
+            return Ok(self
+                .provider
+                .for_store(Arc::clone(self.provider.store()))
+                .with_base_url(format!("{public_url}/v2")));
+        };
+
+        let Some(tenants) = &self.tenants else {
+            return Err(scim2_rs::Error::not_found(tenant_id));
+        };
+
+        scim2_rs::validate_tenant_id(&tenant_id)?;
+        if !create && !tenants.exists(&tenant_id) {
+            return Err(scim2_rs::Error::not_found(tenant_id));
+        }
+
+        let store = tenants.store(&tenant_id).await?;
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.start_tenant(&tenant_id, &store);
+        }
+
+        // Tenants live next to the server's own /v2
+        let base_url = format!("{public_url}/tenants/{tenant_id}/v2");
+
+        Ok(self
+            .provider
+            .for_store(store)
+            .with_tenant(scim2_rs::Tenant { id: tenant_id, base_url }))
+    }
+}
+
+/// How requests are authenticated, when they are
+enum ServerAuthenticator {
+    StaticTokens(scim2_rs::StaticTokenAuthenticator),
+    Jwt(scim2_rs::JwtAuthenticator),
+}
+
+impl Authenticator for ServerAuthenticator {
+    async fn authenticate(
+        &self,
+        headers: &http::HeaderMap,
+    ) -> Result<scim2_rs::ClientIdentity, scim2_rs::Error> {
+        match self {
+            ServerAuthenticator::StaticTokens(authenticator) => {
+                authenticator.authenticate(headers).await
+            }
+            ServerAuthenticator::Jwt(authenticator) => {
+                authenticator.authenticate(headers).await
+            }
+        }
+    }
+
+    fn authentication_schemes(&self) -> Vec<scim2_rs::AuthenticationScheme> {
+        match self {
+            ServerAuthenticator::StaticTokens(authenticator) => {
+                authenticator.authentication_schemes()
+            }
+            ServerAuthenticator::Jwt(authenticator) => {
+                authenticator.authentication_schemes()
+            }
+        }
+    }
+}
+
+impl ScimContext for ServerContext {
+    type Store = Arc<ServerStore>;
+    type Hooks = ();
+    type AuditSink = ServerAuditSink;
+
+    /// Serve a request for the tenant it is for: the one in its path if
+    /// there is one, and otherwise the one its client belongs to. Clients
+    /// that belong to a tenant cannot reach any other. Without an
+    /// authenticator every request is let in, anonymously.
+    async fn authorize(
+        &self,
+        request: ScimRequest<'_>,
+    ) -> Result<(scim2_rs::OperationContext, ScopedProvider<'_>), scim2_rs::Error>
+    {
+        let (actor, client_tenant) = match &self.authenticator {
+            Some(authenticator) => {
+                let identity =
+                    authenticator.authenticate(request.headers).await?;
+                (Some(identity.name), identity.tenant)
+            }
+            None => (None, None),
+        };
+
+        let tenant_id = match (request.tenant_id, client_tenant) {
+            (Some(tenant_id), Some(client_tenant))
+                if tenant_id != client_tenant =>
+            {
+                return Err(scim2_rs::Error::forbidden());
+            }
+            (tenant_id, client_tenant) => {
+                tenant_id.map(str::to_string).or(client_tenant)
+            }
+        };
+
+        // Anonymous clients can use tenants, but not create them
+        let provider = self.provider_for(tenant_id, actor.is_some()).await?;
+
+        let context = scim2_rs::OperationContext {
+            actor,
+            request_id: Some(request.request_id.to_string()),
+            if_match: None,
+        };
+
+        Ok((context, provider))
+    }
+
+    fn authentication_schemes(&self) -> Vec<scim2_rs::AuthenticationScheme> {
+        self.authenticator
+            .as_ref()
+            .map(|authenticator| authenticator.authentication_schemes())
+            .unwrap_or_default()
+    }
+}
+
+/// Authorize a request to an endpoint that is not for a tenant in particular
+async fn authorize(
+    rqctx: &RequestContext<Arc<ServerContext>>,
+) -> Result<(scim2_rs::OperationContext, ScopedProvider<'_>), scim2_rs::Error> {
+    let request = ScimRequest {
+        headers: rqctx.request.headers(),
+        request_id: &rqctx.request_id,
+        tenant_id: None,
+    };
+
+    rqctx.context().authorize(request).await
+}
+
+/// The response to a request that `authorize` turned away
+fn auth_error_response(
+    error: scim2_rs::Error,
+) -> Result<Response<Body>, HttpError> {
+    scim2_rs::auth_error_response(error).map_err(HttpError::from)
 }
 
 fn register_endpoints(
     api_description: &mut ApiDescription<Arc<ServerContext>>,
 ) -> Result<(), anyhow::Error> {
-    // RFC 7644, section 3.2: SCIM Endpoints and HTTP Methods
-
-    api_description.register(users::list_users)?;
-    api_description.register(users::get_user)?;
-    api_description.register(users::create_user)?;
-    api_description.register(users::put_user)?;
-    api_description.register(users::delete_user)?;
-    api_description.register(users::patch_user)?;
-
-    api_description.register(groups::list_groups)?;
-    api_description.register(groups::get_group)?;
-    api_description.register(groups::create_group)?;
-    api_description.register(groups::put_group)?;
-    api_description.register(groups::delete_group)?;
-    api_description.register(groups::patch_group)?;
-
-    api_description.register(server::get_resource_types)?;
-    api_description.register(server::get_resource_type_user)?;
-    api_description.register(server::get_resource_type_group)?;
-    api_description.register(server::get_schemas)?;
-    api_description.register(server::get_service_provider_config)?;
+    scim2_rs::register_scim_endpoints(api_description, "/v2")?;
+    scim2_rs::register_scim_endpoints(
+        api_description,
+        "/tenants/{tenant_id}/v2",
+    )?;
+
+    api_description.register(admin::list_deleted_users)?;
+    api_description.register(admin::list_deleted_groups)?;
+    api_description.register(admin::restore_user)?;
+    api_description.register(admin::restore_group)?;
+    api_description.register(admin::purge_deleted)?;
+    api_description.register(admin::read_outbox)?;
+    api_description.register(admin::ack_outbox)?;
+    api_description.register(admin::list_user_revisions)?;
+    api_description.register(admin::list_group_revisions)?;
+    api_description.register(admin::get_user_at)?;
+    api_description.register(admin::get_group_at)?;
+    api_description.register(admin::list_webhook_dead_letters)?;
+    api_description.register(admin::redeliver_webhook_dead_letters)?;
 
     api_description.register(state)?;
 
@@ -68,12 +412,28 @@ fn register_endpoints(
 pub async fn state(
     rqctx: RequestContext<Arc<ServerContext>>,
 ) -> Result<HttpResponseOk<scim2_rs::InMemoryProviderStoreState>, HttpError> {
+    if let Err(error) = authorize(&rqctx).await {
+        return Err(HttpError::for_client_error(
+            None,
+            dropshot::ClientErrorStatusCode::from_u16(error.status().as_u16())
+                .unwrap_or(dropshot::ClientErrorStatusCode::UNAUTHORIZED),
+            error.detail,
+        ));
+    }
+
     let apictx = rqctx.context();
-    Ok(HttpResponseOk(apictx.provider.state()))
+    match &**apictx.provider.store() {
+        ServerStore::InMemory(store) => Ok(HttpResponseOk(store.state().await)),
+        ServerStore::Sqlite(_) => Err(HttpError::for_not_found(
+            None,
+            "state is only available for the in-memory store".to_string(),
+        )),
+    }
 }
 
 pub fn create_http_server(
     bind_addr: Option<SocketAddr>,
+    server_config: ServerConfig,
 ) -> anyhow::Result<HttpServer<Arc<ServerContext>>> {
     // from https://docs.rs/slog/latest/slog/ - terminal out
     let decorator = slog_term::TermDecorator::new().build();
@@ -93,13 +453,157 @@ pub fn create_http_server(
         anyhow::bail!("Error from register_endpoints: {}", s);
     }
 
-    let store = scim2_rs::InMemoryProviderStore::new();
+    // Webhooks are delivered from the outbox
+    let outbox = server_config.outbox || !server_config.webhooks.is_empty();
+    let history = server_config.history;
+    let build_store = move |store_config: &StoreConfig| {
+        let mut store = store_config.build()?;
+        if outbox {
+            store = store.with_outbox();
+        }
+        if history {
+            store = store.with_history();
+        }
+        anyhow::Ok(store)
+    };
+
+    let store = Arc::new(build_store(&server_config.store)?);
+
+    let tenants = server_config.tenants.then(|| {
+        let store_config = server_config.store.clone();
+        let tenants = scim2_rs::TenantStoreMap::new(move |tenant_id| {
+            build_store(&store_config.for_tenant(tenant_id))
+        });
+        if server_config.allowed_tenants.is_empty() {
+            tenants
+        } else {
+            tenants.with_allowed_tenants(server_config.allowed_tenants.clone())
+        }
+    });
+
+    let audit_sink = server_config
+        .audit_log
+        .map(scim2_rs::JsonLinesAuditSink::open)
+        .transpose()?;
 
     let plog = log.new(slog::o!("component" => "ScimProvider"));
+    let mut provider = scim2_rs::Provider::new(plog, store)
+        .with_audit_sink(audit_sink)
+        .with_user_delete_policy(server_config.user_delete_policy);
+    if let Some(retention) = server_config.soft_delete_retention {
+        provider = provider.with_soft_delete(retention);
+    }
+
+    let webhooks = if server_config.webhooks.is_empty() {
+        None
+    } else {
+        let wlog = log.new(slog::o!("component" => "Webhooks"));
+        let server = scim2_rs::WebhookDispatcher::start(
+            wlog.clone(),
+            Arc::clone(provider.store()),
+            None,
+            server_config.webhooks.clone(),
+            server_config.webhook_retry.clone(),
+        );
+        Some(Webhooks {
+            log: wlog,
+            endpoints: server_config.webhooks,
+            retry: server_config.webhook_retry,
+            server,
+            tenants: Mutex::new(BTreeMap::new()),
+        })
+    };
+
+    // Tokens are named for the order they were given in, which is what the
+    // audit log records as the actor
+    let static_tokens = !server_config.bearer_tokens.is_empty()
+        || !server_config.tenant_bearer_tokens.is_empty();
+    let authenticator = match (static_tokens, server_config.jwt) {
+        (false, None) => None,
+        (true, None) => {
+            let mut authenticator = scim2_rs::StaticTokenAuthenticator::new();
+            for (i, token) in server_config.bearer_tokens.iter().enumerate() {
+                authenticator =
+                    authenticator.with_token(format!("token-{}", i + 1), token);
+            }
+            for (i, (tenant, token)) in
+                server_config.tenant_bearer_tokens.iter().enumerate()
+            {
+                authenticator = authenticator.with_tenant_token(
+                    format!("tenant-token-{}", i + 1),
+                    tenant,
+                    token,
+                );
+            }
+            Some(ServerAuthenticator::StaticTokens(authenticator))
+        }
+        (false, Some(jwt)) => Some(ServerAuthenticator::Jwt(jwt)),
+        (true, Some(_)) => {
+            anyhow::bail!("bearer tokens and JWTs cannot both be required");
+        }
+    };
+
+    let events = provider.subscribe();
+    let public_url = OnceLock::new();
+    if let Some(url) = server_config.public_url {
+        public_url.set(url.trim_end_matches('/').to_string()).unwrap();
+    }
+
     let ctx = Arc::new(ServerContext {
-        provider: scim2_rs::Provider::new(plog, store),
+        provider,
+        tenants,
+        webhooks,
+        authenticator,
+        public_url,
     });
 
+    // Tenants share the server's events, which say which tenant they are
+    // for. The events themselves are read from the outbox, so missing some
+    // here only delays them until the next poll.
+    if ctx.webhooks.is_some() {
+        let ctx = Arc::clone(&ctx);
+        let mut events = events;
+        tokio::spawn(async move {
+            let Some(webhooks) = &ctx.webhooks else { return };
+            loop {
+                match events.recv().await {
+                    Ok(event) => webhooks.notify(&event),
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(
+                        _,
+                    )) => webhooks.notify_all(),
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    if server_config.soft_delete_retention.is_some() {
+        let ctx = Arc::clone(&ctx);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                // Errors are logged by the provider
+                let _ = ctx.provider.purge_expired().await;
+
+                let tenant_ids = ctx
+                    .tenants
+                    .as_ref()
+                    .map(|tenants| tenants.tenant_ids())
+                    .unwrap_or_default();
+                for tenant_id in tenant_ids {
+                    if let Ok(provider) =
+                        ctx.provider_for(Some(tenant_id), false).await
+                    {
+                        let _ = provider.purge_expired().await;
+                    }
+                }
+            }
+        });
+    }
+
     let http_server = HttpServerStarter::new(
         &config,
         api_description,
@@ -112,5 +616,9 @@ pub fn create_http_server(
         .context("Error from HttpServerStarter::new")?
         .start();
 
+    // Clients cannot know the address of a server bound to port 0 until this
+    // returns, so it is set before any request can need it
+    let _ = ctx.public_url.set(format!("http://{}", http_server.local_addr()));
+
     Ok(http_server)
 }
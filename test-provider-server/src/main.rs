@@ -3,23 +3,248 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::net::SocketAddr;
+use std::path::PathBuf;
 
+use chrono::TimeDelta;
 use clap::Parser;
+use clap::ValueEnum;
+use scim2_rs::JwtAuthenticator;
+use scim2_rs::UserDeletePolicy;
+use scim2_rs::WebhookEndpoint;
+use scim2_rs::WebhookRetryPolicy;
+use scim2_rs::read_jwks_file;
+use scim2_test_provider_server::ServerConfig;
+use scim2_test_provider_server::StoreConfig;
 use scim2_test_provider_server::create_http_server;
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum UserDeletePolicyArg {
+    /// Delete users
+    Delete,
+    /// Deactivate users and remove them from their groups instead
+    Deactivate,
+    /// Refuse to delete users
+    Reject,
+}
+
+impl From<UserDeletePolicyArg> for UserDeletePolicy {
+    fn from(arg: UserDeletePolicyArg) -> Self {
+        match arg {
+            UserDeletePolicyArg::Delete => UserDeletePolicy::Delete,
+            UserDeletePolicyArg::Deactivate => UserDeletePolicy::Deactivate,
+            UserDeletePolicyArg::Reject => {
+                UserDeletePolicy::Reject(scim2_rs::Error::mutability(
+                    "users cannot be deleted, set active to false instead"
+                        .to_string(),
+                ))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Parser)]
 #[clap(about = "SCIM 2 provider server")]
 struct Args {
     // Note that port "4567" is arbitrarily chosen and is not SCIM specific.
     #[clap(long, default_value = "127.0.0.1:4567")]
     bind_addr: SocketAddr,
+
+    /// The URL clients reach the server at, if not the bind address (behind
+    /// a proxy, say). Resource locations are under it.
+    #[clap(long)]
+    public_url: Option<String>,
+
+    /// Store users and groups in the SQLite database at this path instead of
+    /// in memory
+    #[clap(long, conflicts_with = "state_file")]
+    sqlite_db: Option<PathBuf>,
+
+    /// Keep users and groups in memory, but persist them to this file (and a
+    /// journal next to it) on every change and reload them on startup
+    #[clap(long)]
+    state_file: Option<PathBuf>,
+
+    /// Make deletes soft deletes, which can be restored through the admin
+    /// endpoints until they are purged this many days later
+    #[clap(long)]
+    soft_delete_retention_days: Option<u32>,
+
+    /// What to do when asked to delete a user
+    #[clap(long, value_enum, default_value = "delete")]
+    user_delete_policy: UserDeletePolicyArg,
+
+    /// Record change events in an outbox, which can be read and acknowledged
+    /// through the admin endpoints
+    #[clap(long)]
+    outbox: bool,
+
+    /// Keep every revision of every user and group, which can be read
+    /// (including as of a point in time) through the admin endpoints
+    #[clap(long)]
+    history: bool,
+
+    /// POST change events to this URL. Can be given more than once. Events
+    /// are delivered from the outbox, which this turns on.
+    #[clap(long = "webhook-url", requires = "webhook_secret")]
+    webhook_urls: Vec<String>,
+
+    /// The key for the HMAC signature on webhook requests
+    #[clap(long, env = "SCIM_WEBHOOK_SECRET", hide_env_values = true)]
+    webhook_secret: Option<String>,
+
+    /// Append an audit record for every operation to this file, one JSON
+    /// object per line
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Require this bearer token on every request other than discovery. Can
+    /// be given more than once, so that a token can be rotated.
+    #[clap(
+        long = "bearer-token",
+        env = "SCIM_BEARER_TOKENS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    bearer_tokens: Vec<String>,
+
+    /// Require a JWT access token signed by a key from the JWKS in this file
+    #[clap(
+        long,
+        conflicts_with_all = ["jwks_url", "bearer_tokens"],
+        requires_all = ["jwt_issuer", "jwt_audience"]
+    )]
+    jwks_file: Option<PathBuf>,
+
+    /// Require a JWT access token signed by a key from the JWKS at this URL,
+    /// which is fetched on startup, and again hourly or when a token names a
+    /// key that is not in it
+    #[clap(
+        long,
+        conflicts_with = "bearer_tokens",
+        requires_all = ["jwt_issuer", "jwt_audience"]
+    )]
+    jwks_url: Option<String>,
+
+    /// The issuer JWT access tokens must come from
+    #[clap(long)]
+    jwt_issuer: Option<String>,
+
+    /// An audience JWT access tokens can be for. Can be given more than once.
+    #[clap(long)]
+    jwt_audience: Vec<String>,
+
+    /// A scope JWT access tokens must grant. Can be given more than once.
+    #[clap(long)]
+    jwt_scope: Vec<String>,
+
+    /// The claim that names the client
+    #[clap(long, default_value = "sub")]
+    jwt_client_claim: String,
+
+    /// The claim that names the client's tenant
+    #[clap(long)]
+    jwt_tenant_claim: Option<String>,
+
+    /// Serve tenants under /tenants/{tenant_id}/v2, each with a store of its
+    /// own. With a --state-file or --sqlite-db, each tenant's is next to it.
+    /// Tenants are created on first use by an authenticated client.
+    #[clap(long)]
+    tenants: bool,
+
+    /// Only serve this tenant. Can be given more than once.
+    #[clap(long = "allowed-tenant", requires = "tenants", value_parser = parse_tenant_id)]
+    allowed_tenants: Vec<String>,
+
+    /// Require this bearer token, given as TENANT:TOKEN, from the clients of
+    /// a tenant. Can be given more than once.
+    #[clap(
+        long = "tenant-bearer-token",
+        requires = "tenants",
+        value_parser = parse_tenant_token,
+    )]
+    tenant_bearer_tokens: Vec<(String, String)>,
+}
+
+fn parse_tenant_id(arg: &str) -> Result<String, String> {
+    scim2_rs::validate_tenant_id(arg).map_err(|error| error.detail)?;
+    Ok(arg.to_string())
+}
+
+fn parse_tenant_token(arg: &str) -> Result<(String, String), String> {
+    let Some((tenant, token)) = arg.split_once(':') else {
+        return Err(String::from("expected TENANT:TOKEN"));
+    };
+    scim2_rs::validate_tenant_id(tenant).map_err(|error| error.detail)?;
+
+    Ok((tenant.to_string(), token.to_string()))
+}
+
+impl Args {
+    async fn jwt_authenticator(
+        &self,
+    ) -> anyhow::Result<Option<JwtAuthenticator>> {
+        let issuer = self.jwt_issuer.clone().unwrap_or_default();
+        let audience = self.jwt_audience.clone();
+        let authenticator = match (&self.jwks_file, &self.jwks_url) {
+            (Some(path), _) => {
+                JwtAuthenticator::new(&read_jwks_file(path)?, issuer, audience)?
+            }
+            (None, Some(url)) => {
+                JwtAuthenticator::from_jwks_url(url, issuer, audience).await?
+            }
+            (None, None) => return Ok(None),
+        };
+
+        let mut authenticator =
+            authenticator.with_client_claim(&self.jwt_client_claim);
+        for scope in &self.jwt_scope {
+            authenticator = authenticator.with_required_scope(scope);
+        }
+        if let Some(claim) = &self.jwt_tenant_claim {
+            authenticator = authenticator.with_tenant_claim(claim);
+        }
+
+        Ok(Some(authenticator))
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let opt: Args = Args::try_parse()?;
+    let jwt = opt.jwt_authenticator().await?;
+
+    let store = match opt.sqlite_db {
+        Some(path) => StoreConfig::Sqlite(Some(path)),
+        None => StoreConfig::InMemory(opt.state_file),
+    };
+
+    let server_config = ServerConfig {
+        store,
+        soft_delete_retention: opt
+            .soft_delete_retention_days
+            .map(|days| TimeDelta::days(days.into())),
+        user_delete_policy: opt.user_delete_policy.into(),
+        outbox: opt.outbox,
+        history: opt.history,
+        webhooks: opt
+            .webhook_urls
+            .into_iter()
+            .map(|url| WebhookEndpoint {
+                url,
+                secret: opt.webhook_secret.clone().unwrap_or_default().into(),
+            })
+            .collect(),
+        webhook_retry: WebhookRetryPolicy::default(),
+        audit_log: opt.audit_log,
+        bearer_tokens: opt.bearer_tokens,
+        jwt,
+        tenants: opt.tenants,
+        allowed_tenants: opt.allowed_tenants,
+        public_url: opt.public_url,
+        tenant_bearer_tokens: opt.tenant_bearer_tokens,
+    };
 
-    let http_server = create_http_server(Some(opt.bind_addr))?;
+    let http_server = create_http_server(Some(opt.bind_addr), server_config)?;
     if let Err(s) = http_server.await {
         anyhow::bail!("Error from start(): {}", s);
     }
@@ -0,0 +1,541 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use scim2_rs::{
+    CreateGroupRequest, CreateUserRequest, DeactivatedUser, DeadLetter,
+    DeletedResource, Filter, Group, InMemoryProviderStore, OutboxEntry,
+    Pagination, ProviderStore, ProviderStoreDeleteResult, ProviderStoreError,
+    ProviderStoreListResult, Revision, SqliteProviderStore, StoreDelta,
+    StoredParts, User,
+};
+
+/// Which `ProviderStore` the server should be backed by
+#[derive(Debug, Clone)]
+pub enum StoreConfig {
+    /// An in-memory store, persisted to the given state file if there is
+    /// one.
+    InMemory(Option<PathBuf>),
+
+    /// A SQLite database at the given path, or a fresh in-memory database if
+    /// there is no path.
+    Sqlite(Option<PathBuf>),
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig::InMemory(None)
+    }
+}
+
+impl StoreConfig {
+    /// The store for a tenant: a file of its own next to this one, named for
+    /// the tenant, if this has a file
+    pub fn for_tenant(&self, tenant_id: &str) -> StoreConfig {
+        let tenant_path = |path: &PathBuf| {
+            let mut file_name = path.file_stem().unwrap_or_default().to_owned();
+            file_name.push(format!("-{tenant_id}"));
+            if let Some(extension) = path.extension() {
+                file_name.push(".");
+                file_name.push(extension);
+            }
+            path.with_file_name(file_name)
+        };
+
+        match self {
+            StoreConfig::InMemory(path) => {
+                StoreConfig::InMemory(path.as_ref().map(tenant_path))
+            }
+            StoreConfig::Sqlite(path) => {
+                StoreConfig::Sqlite(path.as_ref().map(tenant_path))
+            }
+        }
+    }
+
+    pub fn build(&self) -> anyhow::Result<ServerStore> {
+        Ok(match self {
+            StoreConfig::InMemory(Some(path)) => ServerStore::InMemory(
+                InMemoryProviderStore::with_state_file(path)?,
+            ),
+
+            StoreConfig::InMemory(None) => {
+                ServerStore::InMemory(InMemoryProviderStore::new())
+            }
+
+            StoreConfig::Sqlite(Some(path)) => {
+                ServerStore::Sqlite(SqliteProviderStore::open(path)?)
+            }
+
+            StoreConfig::Sqlite(None) => {
+                ServerStore::Sqlite(SqliteProviderStore::open_in_memory()?)
+            }
+        })
+    }
+}
+
+/// Dispatches to one of the provider stores that this server supports.
+// There is only ever one of these, so the size difference does not matter
+#[allow(clippy::large_enum_variant)]
+pub enum ServerStore {
+    InMemory(InMemoryProviderStore),
+    Sqlite(SqliteProviderStore),
+}
+
+impl ServerStore {
+    /// Record change events in the store's outbox
+    pub fn with_outbox(self) -> Self {
+        match self {
+            ServerStore::InMemory(store) => {
+                ServerStore::InMemory(store.with_outbox())
+            }
+            ServerStore::Sqlite(store) => {
+                ServerStore::Sqlite(store.with_outbox())
+            }
+        }
+    }
+
+    /// Record a revision of every user and group on every change
+    pub fn with_history(self) -> Self {
+        match self {
+            ServerStore::InMemory(store) => {
+                ServerStore::InMemory(store.with_history())
+            }
+            ServerStore::Sqlite(store) => {
+                ServerStore::Sqlite(store.with_history())
+            }
+        }
+    }
+}
+
+impl ProviderStore for ServerStore {
+    const VERSIONED: bool =
+        InMemoryProviderStore::VERSIONED && SqliteProviderStore::VERSIONED;
+
+    async fn get_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.get_user_by_id(user_id).await,
+            ServerStore::Sqlite(store) => store.get_user_by_id(user_id).await,
+        }
+    }
+
+    async fn create_user(
+        &self,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.create_user(user_request).await
+            }
+            ServerStore::Sqlite(store) => store.create_user(user_request).await,
+        }
+    }
+
+    async fn list_users(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<User>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.list_users(filter, pagination).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.list_users(filter, pagination).await
+            }
+        }
+    }
+
+    async fn replace_user(
+        &self,
+        user_id: &str,
+        user_request: CreateUserRequest,
+    ) -> Result<StoredParts<User>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.replace_user(user_id, user_request).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.replace_user(user_id, user_request).await
+            }
+        }
+    }
+
+    async fn delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.delete_user_by_id(user_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.delete_user_by_id(user_id).await
+            }
+        }
+    }
+
+    async fn get_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.get_group_by_id(group_id).await
+            }
+            ServerStore::Sqlite(store) => store.get_group_by_id(group_id).await,
+        }
+    }
+
+    async fn get_group_display_names(
+        &self,
+        group_ids: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, String>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.get_group_display_names(group_ids).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.get_group_display_names(group_ids).await
+            }
+        }
+    }
+
+    async fn deactivate_user(
+        &self,
+        user_id: &str,
+    ) -> Result<DeactivatedUser, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.deactivate_user(user_id).await
+            }
+            ServerStore::Sqlite(store) => store.deactivate_user(user_id).await,
+        }
+    }
+
+    async fn create_group(
+        &self,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.create_group(group_request).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.create_group(group_request).await
+            }
+        }
+    }
+
+    async fn list_groups(
+        &self,
+        filter: Option<Filter>,
+        pagination: Pagination,
+    ) -> Result<ProviderStoreListResult<Group>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.list_groups(filter, pagination).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.list_groups(filter, pagination).await
+            }
+        }
+    }
+
+    async fn replace_group(
+        &self,
+        group_id: &str,
+        group_request: CreateGroupRequest,
+    ) -> Result<StoredParts<Group>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.replace_group(group_id, group_request).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.replace_group(group_id, group_request).await
+            }
+        }
+    }
+
+    async fn delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.delete_group_by_id(group_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.delete_group_by_id(group_id).await
+            }
+        }
+    }
+
+    async fn soft_delete_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.soft_delete_user_by_id(user_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.soft_delete_user_by_id(user_id).await
+            }
+        }
+    }
+
+    async fn soft_delete_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<ProviderStoreDeleteResult, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.soft_delete_group_by_id(group_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.soft_delete_group_by_id(group_id).await
+            }
+        }
+    }
+
+    async fn list_deleted_users(
+        &self,
+    ) -> Result<Vec<DeletedResource<User>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.list_deleted_users().await,
+            ServerStore::Sqlite(store) => store.list_deleted_users().await,
+        }
+    }
+
+    async fn list_deleted_groups(
+        &self,
+    ) -> Result<Vec<DeletedResource<Group>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.list_deleted_groups().await,
+            ServerStore::Sqlite(store) => store.list_deleted_groups().await,
+        }
+    }
+
+    async fn restore_user_by_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.restore_user_by_id(user_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.restore_user_by_id(user_id).await
+            }
+        }
+    }
+
+    async fn restore_group_by_id(
+        &self,
+        group_id: &str,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.restore_group_by_id(group_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.restore_group_by_id(group_id).await
+            }
+        }
+    }
+
+    async fn purge_deleted(
+        &self,
+        deleted_before: DateTime<Utc>,
+    ) -> Result<usize, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.purge_deleted(deleted_before).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.purge_deleted(deleted_before).await
+            }
+        }
+    }
+
+    async fn read_outbox(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.read_outbox(limit).await,
+            ServerStore::Sqlite(store) => store.read_outbox(limit).await,
+        }
+    }
+
+    async fn ack_outbox(
+        &self,
+        sequence: u64,
+    ) -> Result<usize, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.ack_outbox(sequence).await,
+            ServerStore::Sqlite(store) => store.ack_outbox(sequence).await,
+        }
+    }
+
+    async fn read_outbox_after(
+        &self,
+        sequence: u64,
+        limit: usize,
+    ) -> Result<Vec<OutboxEntry>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.read_outbox_after(sequence, limit).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.read_outbox_after(sequence, limit).await
+            }
+        }
+    }
+
+    async fn outbox_cursor(
+        &self,
+        consumer: &str,
+    ) -> Result<u64, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.outbox_cursor(consumer).await,
+            ServerStore::Sqlite(store) => store.outbox_cursor(consumer).await,
+        }
+    }
+
+    async fn set_outbox_cursor(
+        &self,
+        consumer: &str,
+        sequence: u64,
+    ) -> Result<(), ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.set_outbox_cursor(consumer, sequence).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.set_outbox_cursor(consumer, sequence).await
+            }
+        }
+    }
+
+    async fn put_dead_letter(
+        &self,
+        dead_letter: DeadLetter,
+    ) -> Result<(), ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.put_dead_letter(dead_letter).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.put_dead_letter(dead_letter).await
+            }
+        }
+    }
+
+    async fn list_dead_letters(
+        &self,
+    ) -> Result<Vec<DeadLetter>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.list_dead_letters().await,
+            ServerStore::Sqlite(store) => store.list_dead_letters().await,
+        }
+    }
+
+    async fn remove_dead_letter(
+        &self,
+        endpoint: &str,
+        sequence: u64,
+    ) -> Result<bool, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.remove_dead_letter(endpoint, sequence).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.remove_dead_letter(endpoint, sequence).await
+            }
+        }
+    }
+
+    async fn list_user_revisions(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<Revision<User>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.list_user_revisions(user_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.list_user_revisions(user_id).await
+            }
+        }
+    }
+
+    async fn list_group_revisions(
+        &self,
+        group_id: &str,
+    ) -> Result<Vec<Revision<Group>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.list_group_revisions(group_id).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.list_group_revisions(group_id).await
+            }
+        }
+    }
+
+    async fn get_user_at(
+        &self,
+        user_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<User>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.get_user_at(user_id, at).await
+            }
+            ServerStore::Sqlite(store) => store.get_user_at(user_id, at).await,
+        }
+    }
+
+    async fn get_group_at(
+        &self,
+        group_id: &str,
+        at: DateTime<Utc>,
+    ) -> Result<Option<StoredParts<Group>>, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => {
+                store.get_group_at(group_id, at).await
+            }
+            ServerStore::Sqlite(store) => {
+                store.get_group_at(group_id, at).await
+            }
+        }
+    }
+
+    async fn user_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.user_delta(since).await,
+            ServerStore::Sqlite(store) => store.user_delta(since).await,
+        }
+    }
+
+    async fn group_delta(
+        &self,
+        since: Option<u64>,
+    ) -> Result<StoreDelta, ProviderStoreError> {
+        match self {
+            ServerStore::InMemory(store) => store.group_delta(since).await,
+            ServerStore::Sqlite(store) => store.group_delta(since).await,
+        }
+    }
+}